@@ -0,0 +1,1048 @@
+// ===== CHILD PROCESSES =====
+// Running other programs, like the `child_process` module in Node. `spawn`
+// starts a program with its stdin, stdout and stderr connected to pipes. Our
+// ends of the pipes are non-blocking and registered with our epoll instance,
+// so output of the child arrives as `data` events on the main thread just like
+// data from a socket, and stdin is a `Writable` with backpressure.
+//
+//     let child = ChildProcess::spawn("grep", &["-c", "a"], Default::default())?;
+//     child.stdout().unwrap().on_data(|chunk| print(format!("{:?}", chunk)));
+//     child.on_exit(|status| print(format!("grep exited with {}", status)));
+//     child.stdin().unwrap().write(b"a\nb\na\n".to_vec());
+//     child.stdin().unwrap().end();
+//
+// We don't block a thread to wait for a child to exit. A pidfd becomes
+// readable once its process has exited, so an exit is an epoll event like any
+// other and we can supervise thousands of children without using any of the
+// threads in the threadpool. The child is reaped when we handle the event,
+// which means its pid can't be reused while we might still `kill` it. Kernels
+// before 5.3 have no pidfds, there we handle SIGCHLD with `Signals` instead and
+// check which of our children have exited.
+//
+// `exec` and `exec_file` are for the common case where we just want the
+// output once the program is done. They give up on programs which run too
+// long or print too much.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::process::ExitStatusExt;
+use std::path::PathBuf;
+use std::process::{self, Command, Stdio};
+use std::rc::Rc;
+
+use crate::poll::{Interests, Registration};
+use crate::signals::{SignalHandler, Signals};
+use crate::stream::{Callback, Readable, Writable};
+use crate::{defer, Handle, Js, RUNTIME};
+
+const READ_BUFFER_SIZE: usize = 64 * 1024;
+const HIGH_WATER_MARK: usize = 16 * 1024;
+/// How much output `exec` collects by default, same as Node
+const MAX_BUFFER: usize = 1024 * 1024;
+
+type ExitCallback = Box<dyn FnMut(ExitStatus)>;
+
+pub struct ChildProcess;
+
+impl ChildProcess {
+    /// Starts `command` with `args`. The program is looked up in PATH unless
+    /// `command` contains a slash. Fails right away if it can't be started.
+    pub fn spawn(command: &str, args: &[&str], opts: SpawnOptions) -> io::Result<Child> {
+        let mut cmd = Command::new(command);
+        cmd.args(args)
+            .stdin(opts.stdin.to_stdio())
+            .stdout(opts.stdout.to_stdio())
+            .stderr(opts.stderr.to_stdio());
+        if let Some(cwd) = &opts.cwd {
+            cmd.current_dir(cwd);
+        }
+        if let Some(env) = &opts.env {
+            cmd.env_clear().envs(env);
+        }
+
+        let mut process = cmd.spawn()?;
+        let (stdin, stdout, stderr) = match take_pipes(&mut process) {
+            Ok(pipes) => pipes,
+            Err(e) => {
+                let _ = process.kill();
+                let _ = process.wait();
+                return Err(e);
+            }
+        };
+
+        let open_streams = stdout.iter().chain(stderr.iter()).count();
+        let state = ChildState {
+            pid: process.id(),
+            process: Some(process),
+            pidfd: None,
+            status: None,
+            open_streams,
+            closed: false,
+            handle: None,
+            on_exit: vec![],
+            on_close: vec![],
+        };
+        let child = Child {
+            inner: Rc::new(RefCell::new(state)),
+            stdin,
+            stdout,
+            stderr,
+        };
+
+        for readable in child.stdout.iter().chain(child.stderr.iter()) {
+            let c = child.clone();
+            readable.on_close(move |_| c.stream_closed());
+        }
+
+        if let Err(e) = child.watch_exit() {
+            let _ = child.kill(libc::SIGKILL);
+            child.reap();
+            return Err(e);
+        }
+
+        let rt = unsafe { &mut *RUNTIME };
+        let handle = rt.add_handle(Rc::new(ChildHandle(child.clone())));
+        child.inner.borrow_mut().handle = Some(handle);
+        Ok(child)
+    }
+
+    /// Runs `command` with `/bin/sh -c`, so it can use pipes, globs and
+    /// everything else the shell knows. `cb` gets the output once the
+    /// command is done.
+    pub fn exec(command: &str, opts: ExecOptions, cb: impl FnOnce(ExecOutput) + 'static) {
+        run_buffered("/bin/sh", &["-c", command], command.to_string(), opts, cb);
+    }
+
+    /// Like `exec` but runs `file` directly without a shell, so `args` are
+    /// passed as they are and need no quoting
+    pub fn exec_file(
+        file: &str,
+        args: &[&str],
+        opts: ExecOptions,
+        cb: impl FnOnce(ExecOutput) + 'static,
+    ) {
+        let mut cmdline = file.to_string();
+        for arg in args {
+            cmdline.push(' ');
+            cmdline.push_str(arg);
+        }
+        run_buffered(file, args, cmdline, opts, cb);
+    }
+}
+
+/// What one of the child's stdin, stdout and stderr is connected to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StdioMode {
+    /// A pipe to us, see `Child::stdin`, `Child::stdout` and `Child::stderr`
+    #[default]
+    Pipe,
+    /// The same file as ours, e.g. our terminal
+    Inherit,
+    /// /dev/null
+    Ignore,
+}
+
+impl StdioMode {
+    fn to_stdio(self) -> Stdio {
+        match self {
+            StdioMode::Pipe => Stdio::piped(),
+            StdioMode::Inherit => Stdio::inherit(),
+            StdioMode::Ignore => Stdio::null(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SpawnOptions {
+    /// The working directory of the child, ours if `None`
+    pub cwd: Option<PathBuf>,
+    /// The whole environment of the child, ours if `None`
+    pub env: Option<HashMap<String, String>>,
+    pub stdin: StdioMode,
+    pub stdout: StdioMode,
+    pub stderr: StdioMode,
+}
+
+#[derive(Debug, Clone)]
+pub struct ExecOptions {
+    /// The working directory of the child, ours if `None`
+    pub cwd: Option<PathBuf>,
+    /// The whole environment of the child, ours if `None`
+    pub env: Option<HashMap<String, String>>,
+    /// Kill the child if it runs longer than this, 0 means never
+    pub timeout_ms: u64,
+    /// Kill the child if it prints more than this to stdout or stderr
+    pub max_buffer: usize,
+    /// The signal we kill the child with
+    pub kill_signal: i32,
+}
+
+impl Default for ExecOptions {
+    fn default() -> Self {
+        ExecOptions {
+            cwd: None,
+            env: None,
+            timeout_ms: 0,
+            max_buffer: MAX_BUFFER,
+            kill_signal: libc::SIGTERM,
+        }
+    }
+}
+
+/// What `exec` hands to its callback. Like in Node the output is there even
+/// if the command failed.
+#[derive(Debug, Default)]
+pub struct ExecOutput {
+    /// Set if the command couldn't be started, didn't exit with 0, or we
+    /// killed it because of `timeout_ms` or `max_buffer`
+    pub error: Option<io::Error>,
+    /// `None` if the command couldn't be started
+    pub status: Option<ExitStatus>,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+}
+
+/// How a child ended: it either exited with a code or was killed by a signal
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExitStatus {
+    pub code: Option<i32>,
+    pub signal: Option<i32>,
+}
+
+impl ExitStatus {
+    pub fn success(&self) -> bool {
+        self.code == Some(0)
+    }
+}
+
+impl From<process::ExitStatus> for ExitStatus {
+    fn from(status: process::ExitStatus) -> Self {
+        ExitStatus {
+            code: status.code(),
+            signal: status.signal(),
+        }
+    }
+}
+
+impl fmt::Display for ExitStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.code, self.signal) {
+            (Some(code), _) => write!(f, "exit code {}", code),
+            (None, Some(signal)) => write!(f, "signal {}", signal),
+            (None, None) => write!(f, "unknown status"),
+        }
+    }
+}
+
+// ===== CHILD =====
+
+/// A running program. It keeps the event loop alive until it has exited.
+#[derive(Clone)]
+pub struct Child {
+    inner: Rc<RefCell<ChildState>>,
+    stdin: Option<Writable>,
+    stdout: Option<Readable>,
+    stderr: Option<Readable>,
+}
+
+struct ChildState {
+    pid: u32,
+    /// `None` once the child is reaped
+    process: Option<process::Child>,
+    /// The pidfd telling us when the child exits, `None` if we watch for
+    /// SIGCHLD instead
+    pidfd: Option<(OwnedFd, Registration)>,
+    status: Option<ExitStatus>,
+    /// stdout and stderr pipes which are not closed yet
+    open_streams: usize,
+    closed: bool,
+    /// Our id in the runtime's list of handles
+    handle: Option<usize>,
+    on_exit: Vec<ExitCallback>,
+    on_close: Vec<ExitCallback>,
+}
+
+impl Child {
+    pub fn pid(&self) -> u32 {
+        self.inner.borrow().pid
+    }
+
+    /// `None` unless stdin is a `StdioMode::Pipe`. Ending it closes the pipe,
+    /// so the child sees the end of its input.
+    pub fn stdin(&self) -> Option<&Writable> {
+        self.stdin.as_ref()
+    }
+
+    /// `None` unless stdout is a `StdioMode::Pipe`
+    pub fn stdout(&self) -> Option<&Readable> {
+        self.stdout.as_ref()
+    }
+
+    /// `None` unless stderr is a `StdioMode::Pipe`
+    pub fn stderr(&self) -> Option<&Readable> {
+        self.stderr.as_ref()
+    }
+
+    /// Emitted when the child has exited. There might still be output left to
+    /// read from stdout and stderr.
+    pub fn on_exit(&self, cb: impl FnMut(ExitStatus) + 'static) {
+        self.inner.borrow_mut().on_exit.push(Box::new(cb));
+    }
+
+    /// Emitted after `exit` once stdout and stderr are closed as well
+    pub fn on_close(&self, cb: impl FnMut(ExitStatus) + 'static) {
+        self.inner.borrow_mut().on_close.push(Box::new(cb));
+    }
+
+    /// `None` while the child is running
+    pub fn exit_status(&self) -> Option<ExitStatus> {
+        self.inner.borrow().status
+    }
+
+    /// Sends `signal` to the child, e.g. `libc::SIGTERM`. Fails if the child
+    /// has exited already.
+    pub fn kill(&self, signal: i32) -> io::Result<()> {
+        let state = self.inner.borrow();
+        if state.process.is_none() {
+            return Err(io::Error::from_raw_os_error(libc::ESRCH));
+        }
+        if unsafe { libc::kill(state.pid as libc::pid_t, signal) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Gets `exited` called once the child has exited
+    fn watch_exit(&self) -> io::Result<()> {
+        let res = unsafe { libc::syscall(libc::SYS_pidfd_open, self.pid() as libc::pid_t, 0) };
+        if res < 0 {
+            let e = io::Error::last_os_error();
+            return match e.raw_os_error() {
+                Some(libc::ENOSYS) => Reaper::watch(self.clone()),
+                _ => Err(e),
+            };
+        }
+
+        let pidfd = unsafe { OwnedFd::from_raw_fd(res as RawFd) };
+        let mut reg = Registration::new(pidfd.as_raw_fd());
+        let child = self.clone();
+        reg.arm(Interests::READABLE, move |_| child.exited())?;
+        self.inner.borrow_mut().pidfd = Some((pidfd, reg));
+        Ok(())
+    }
+
+    /// True once the child has exited, even if it's not reaped yet
+    fn has_exited(&self) -> bool {
+        let mut info: libc::siginfo_t = unsafe { mem::zeroed() };
+        let flags = libc::WEXITED | libc::WNOHANG | libc::WNOWAIT;
+        let res = unsafe { libc::waitid(libc::P_PID, self.pid(), &mut info, flags) };
+        // An error means there is nothing left to wait for
+        res < 0 || unsafe { info.si_pid() } != 0
+    }
+
+    /// Collects the exit status of a child which has exited. This blocks if
+    /// it hasn't, which is what we want if we've just killed it.
+    fn reap(&self) -> Option<ExitStatus> {
+        let mut state = self.inner.borrow_mut();
+        let mut process = state.process.take()?;
+        if let Some((_, mut reg)) = state.pidfd.take() {
+            // Has to happen before the pidfd is closed
+            reg.disarm();
+        }
+
+        let status = match process.wait() {
+            Ok(status) => status.into(),
+            Err(_) => ExitStatus {
+                code: None,
+                signal: None,
+            },
+        };
+        state.status = Some(status);
+        Some(status)
+    }
+
+    fn exited(&self) {
+        if let Some((_, reg)) = self.inner.borrow_mut().pidfd.as_mut() {
+            reg.fired();
+        }
+        self.emit_exit();
+    }
+
+    /// Reaps the child and emits `exit`, and `close` if there is no output
+    /// left to read
+    fn emit_exit(&self) {
+        let status = match self.reap() {
+            Some(status) => status,
+            None => return,
+        };
+
+        // Nobody is reading what we'd write anymore
+        if let Some(stdin) = &self.stdin {
+            stdin.destroy(None);
+        }
+
+        let listeners = mem::take(&mut self.inner.borrow_mut().on_exit);
+        for mut cb in listeners {
+            cb(status);
+        }
+
+        // Like Node we throw away output nobody is interested in, otherwise
+        // the streams would never end and we'd never emit `close`
+        for readable in self.stdout.iter().chain(self.stderr.iter()) {
+            if !readable.is_started() {
+                readable.resume();
+            }
+        }
+        self.maybe_close();
+    }
+
+    fn stream_closed(&self) {
+        self.inner.borrow_mut().open_streams -= 1;
+        self.maybe_close();
+    }
+
+    fn maybe_close(&self) {
+        let (status, listeners) = {
+            let mut state = self.inner.borrow_mut();
+            let status = match state.status {
+                Some(status) if state.open_streams == 0 && !state.closed => status,
+                _ => return,
+            };
+            state.closed = true;
+            state.on_exit.clear();
+            if let Some(handle) = state.handle.take() {
+                let rt = unsafe { &mut *RUNTIME };
+                rt.remove_handle(handle);
+            }
+            (status, mem::take(&mut state.on_close))
+        };
+
+        for mut cb in listeners {
+            cb(status);
+        }
+    }
+}
+
+/// A shutdown gives children until the grace period is over to exit, and
+/// kills the ones still running after that. A running child is kept alive by
+/// whatever watches for its exit anyway, so the handle can hold on to it.
+struct ChildHandle(Child);
+
+impl Handle for ChildHandle {
+    fn describe(&self) -> Option<String> {
+        Some(format!("child process {}", self.0.pid()))
+    }
+
+    fn force_close(&self) {
+        let child = &self.0;
+        if child.kill(libc::SIGKILL).is_ok() {
+            // Waits for the child to die, there is no time left for the event
+            child.emit_exit();
+        }
+        // Output we haven't read yet isn't going to be
+        for readable in child.stdout.iter().chain(child.stderr.iter()) {
+            readable.destroy(None);
+        }
+    }
+}
+
+/// Takes our ends of the pipes out of `process` and wraps them in streams
+#[allow(clippy::type_complexity)]
+fn take_pipes(
+    process: &mut process::Child,
+) -> io::Result<(Option<Writable>, Option<Readable>, Option<Readable>)> {
+    let stdin = match process.stdin.take() {
+        Some(stdin) => Some(Pipe::new(stdin.into())?.writable()),
+        None => None,
+    };
+    let stdout = match process.stdout.take() {
+        Some(stdout) => Some(Pipe::new(stdout.into())?.readable()),
+        None => None,
+    };
+    let stderr = match process.stderr.take() {
+        Some(stderr) => Some(Pipe::new(stderr.into())?.readable()),
+        None => None,
+    };
+    Ok((stdin, stdout, stderr))
+}
+
+// ===== SIGCHLD =====
+
+thread_local! {
+    /// Watches the children we have no pidfd for
+    static REAPER: RefCell<Option<Reaper>> = const { RefCell::new(None) };
+}
+
+/// Without pidfds all we learn is that some child has exited: we get a
+/// SIGCHLD, and then ask each of our children if it was them. The handler
+/// keeps the event loop alive while there are children to watch.
+struct Reaper {
+    handler: SignalHandler,
+    children: Vec<Child>,
+}
+
+impl Reaper {
+    fn watch(child: Child) -> io::Result<()> {
+        REAPER.with(|reaper| {
+            let mut reaper = reaper.borrow_mut();
+            if reaper.is_none() {
+                let handler = Signals::on(libc::SIGCHLD, |_| Reaper::reap())?;
+                *reaper = Some(Reaper {
+                    handler,
+                    children: vec![],
+                });
+            }
+            let reaper = reaper.as_mut().expect("reaper");
+            reaper.children.push(child);
+            reaper.handler.ref_();
+            Ok::<_, io::Error>(())
+        })?;
+
+        // The child might have exited before SIGCHLD was blocked, in which
+        // case the signal is gone
+        defer(|_| Reaper::reap());
+        Ok(())
+    }
+
+    /// Lets every child which has exited know
+    fn reap() {
+        let exited = REAPER.with(|reaper| {
+            let mut reaper = reaper.borrow_mut();
+            let reaper = match reaper.as_mut() {
+                Some(reaper) => reaper,
+                None => return vec![],
+            };
+
+            let (exited, running): (Vec<Child>, Vec<Child>) = mem::take(&mut reaper.children)
+                .into_iter()
+                .partition(Child::has_exited);
+            reaper.children = running;
+
+            if reaper.children.is_empty() {
+                reaper.handler.unref();
+            }
+            exited
+        });
+
+        for child in exited {
+            child.exited();
+        }
+    }
+}
+
+// ===== PIPES =====
+
+/// Our end of one of the pipes to a child
+#[derive(Clone)]
+struct Pipe {
+    inner: Rc<RefCell<PipeState>>,
+}
+
+struct PipeState {
+    /// `None` once it's closed
+    file: Option<File>,
+    reg: Registration,
+    /// A chunk the pipe couldn't take without blocking: the data, how much
+    /// of it is written and the callback to call once all of it is
+    pending_write: Option<(Vec<u8>, usize, Callback)>,
+}
+
+impl Pipe {
+    fn new(fd: OwnedFd) -> io::Result<Pipe> {
+        set_nonblocking(&fd)?;
+        let state = PipeState {
+            reg: Registration::new(fd.as_raw_fd()),
+            file: Some(File::from(fd)),
+            pending_write: None,
+        };
+        Ok(Pipe {
+            inner: Rc::new(RefCell::new(state)),
+        })
+    }
+
+    fn readable(self) -> Readable {
+        let pipe = self.clone();
+        let readable = Readable::new(HIGH_WATER_MARK, move |readable| pipe.read(readable));
+        readable.on_close(move |_| self.close());
+        readable
+    }
+
+    fn writable(self) -> Writable {
+        let pipe = self.clone();
+        let writable = Writable::new(HIGH_WATER_MARK, move |chunk, cb| {
+            pipe.inner.borrow_mut().pending_write = Some((chunk, 0, cb));
+            pipe.flush();
+        });
+
+        let pipe = self.clone();
+        writable.set_final(move |cb| {
+            pipe.close();
+            cb(Ok(()));
+        });
+        writable.on_close(move |_| self.close());
+        writable
+    }
+
+    /// Pushes the next chunk, or waits until there is one
+    fn read(&self, readable: &Readable) {
+        let mut buffer = vec![0; READ_BUFFER_SIZE];
+        let res = loop {
+            let mut state = self.inner.borrow_mut();
+            let file = match state.file.as_mut() {
+                Some(file) => file,
+                None => return,
+            };
+            match file.read(&mut buffer) {
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                res => break res,
+            }
+        };
+
+        match res {
+            Ok(0) => readable.push_end(),
+            Ok(n) => {
+                // `push` calls our read function again if it wants more
+                readable.push(buffer[..n].to_vec());
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                let pipe = self.clone();
+                let r = readable.clone();
+                let res = self
+                    .inner
+                    .borrow_mut()
+                    .reg
+                    .arm(Interests::READABLE, move |_| {
+                        pipe.inner.borrow_mut().reg.fired();
+                        pipe.read(&r);
+                    });
+                if let Err(e) = res {
+                    readable.destroy(Some(e));
+                }
+            }
+            Err(e) => readable.destroy(Some(e)),
+        }
+    }
+
+    /// Writes as much of the pending chunk as the pipe takes
+    fn flush(&self) {
+        let mut state = self.inner.borrow_mut();
+        let (chunk, mut written, cb) = match state.pending_write.take() {
+            Some(pending) => pending,
+            None => return,
+        };
+
+        let res = loop {
+            if written == chunk.len() {
+                break Ok(());
+            }
+            let file = match state.file.as_mut() {
+                Some(file) => file,
+                None => break Err(io::ErrorKind::BrokenPipe.into()),
+            };
+            match file.write(&chunk[written..]) {
+                Ok(n) => written += n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    let pipe = self.clone();
+                    let res = state.reg.arm(Interests::WRITABLE, move |_| {
+                        pipe.inner.borrow_mut().reg.fired();
+                        pipe.flush();
+                    });
+                    match res {
+                        Ok(()) => {
+                            state.pending_write = Some((chunk, written, cb));
+                            return;
+                        }
+                        Err(e) => break Err(e),
+                    }
+                }
+                Err(e) => break Err(e),
+            }
+        };
+        drop(state);
+
+        cb(res);
+    }
+
+    fn close(&self) {
+        let mut state = self.inner.borrow_mut();
+        // Has to happen before the fd is closed, see `Registration::disarm`
+        state.reg.disarm();
+        state.file = None;
+        state.pending_write = None;
+    }
+}
+
+fn set_nonblocking(fd: &OwnedFd) -> io::Result<()> {
+    let fd = fd.as_raw_fd();
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// ===== EXEC =====
+
+fn run_buffered(
+    file: &str,
+    args: &[&str],
+    cmdline: String,
+    opts: ExecOptions,
+    cb: impl FnOnce(ExecOutput) + 'static,
+) {
+    let spawn_opts = SpawnOptions {
+        cwd: opts.cwd.clone(),
+        env: opts.env.clone(),
+        stdin: StdioMode::Ignore,
+        ..Default::default()
+    };
+    let child = match ChildProcess::spawn(file, args, spawn_opts) {
+        Ok(child) => child,
+        Err(e) => {
+            let output = ExecOutput {
+                error: Some(e),
+                ..Default::default()
+            };
+            return defer(move |_| cb(output));
+        }
+    };
+
+    let output = Rc::new(RefCell::new(ExecOutput::default()));
+    if let Some(stdout) = child.stdout() {
+        collect(&child, stdout, &output, |o| &mut o.stdout, "stdout", &opts);
+    }
+    if let Some(stderr) = child.stderr() {
+        collect(&child, stderr, &output, |o| &mut o.stderr, "stderr", &opts);
+    }
+
+    let timer = match opts.timeout_ms {
+        0 => None,
+        ms => {
+            let child = child.clone();
+            let output = output.clone();
+            let kill_signal = opts.kill_signal;
+            let timer = crate::set_timeout(ms, move |_| {
+                output.borrow_mut().error.get_or_insert_with(|| {
+                    let msg = format!("command timed out after {} ms", ms);
+                    io::Error::new(io::ErrorKind::TimedOut, msg)
+                });
+                give_up(&child, kill_signal);
+            });
+            Some(timer)
+        }
+    };
+
+    let mut cb = Some(cb);
+    child.on_close(move |status| {
+        if let Some(timer) = timer {
+            crate::clear_timeout(timer);
+        }
+
+        let mut output = mem::take(&mut *output.borrow_mut());
+        output.status = Some(status);
+        if output.error.is_none() && !status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            let msg = format!("Command failed: {}\n{}", cmdline, stderr);
+            output.error = Some(io::Error::other(msg));
+        }
+        if let Some(cb) = cb.take() {
+            cb(output);
+        }
+    });
+}
+
+/// Appends everything `readable` emits to the buffer `slot` returns, until
+/// there is more than `max_buffer` of it
+fn collect(
+    child: &Child,
+    readable: &Readable,
+    output: &Rc<RefCell<ExecOutput>>,
+    slot: fn(&mut ExecOutput) -> &mut Vec<u8>,
+    name: &'static str,
+    opts: &ExecOptions,
+) {
+    let (max_buffer, kill_signal) = (opts.max_buffer, opts.kill_signal);
+    let child = child.clone();
+    let output = output.clone();
+    readable.on_data(move |chunk| {
+        let chunk = match chunk {
+            Js::Bytes(chunk) => chunk,
+            _ => return,
+        };
+
+        let mut output = output.borrow_mut();
+        let buffer = slot(&mut output);
+        let room = max_buffer.saturating_sub(buffer.len());
+        if chunk.len() <= room {
+            buffer.extend_from_slice(&chunk);
+            return;
+        }
+
+        buffer.extend_from_slice(&chunk[..room]);
+        let msg = format!("{} is longer than max_buffer", name);
+        output.error.get_or_insert_with(|| io::Error::other(msg));
+        drop(output);
+        give_up(&child, kill_signal);
+    });
+}
+
+/// Kills the child and stops reading its output, which is how `exec` gives
+/// up on a command. The output we have so far is kept.
+fn give_up(child: &Child, signal: i32) {
+    let _ = child.kill(signal);
+
+    // We might be called from a `data` listener of one of the streams, and
+    // a grandchild might still hold the other end of the pipes open
+    let streams: Vec<Readable> = child
+        .stdout
+        .iter()
+        .chain(child.stderr.iter())
+        .cloned()
+        .collect();
+    defer(move |_| {
+        for readable in streams {
+            readable.destroy(None);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    /// What `spawn` gave us: stdout, stderr and the status `close` got
+    type Spawned = (Vec<u8>, Vec<u8>, ExitStatus);
+
+    /// Spawns `command`, writes `input` to its stdin and ends it, and
+    /// collects the output. `f` gets the child right after it's started.
+    fn spawn(
+        command: &str,
+        args: &[&str],
+        input: &[&str],
+        f: impl Fn(&Child) + 'static,
+    ) -> Spawned {
+        let result = Rc::new(RefCell::new(None));
+        let command = command.to_string();
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        let input: Vec<String> = input.iter().map(|chunk| chunk.to_string()).collect();
+
+        let r = result.clone();
+        crate::run_test(move || {
+            let args: Vec<&str> = args.iter().map(String::as_str).collect();
+            let child = ChildProcess::spawn(&command, &args, Default::default()).unwrap();
+            let output = Rc::new(RefCell::new((vec![], vec![])));
+
+            let o = output.clone();
+            child.stdout().unwrap().on_data(move |chunk| {
+                o.borrow_mut().0.extend(chunk.into_bytes().unwrap());
+            });
+            let o = output.clone();
+            child.stderr().unwrap().on_data(move |chunk| {
+                o.borrow_mut().1.extend(chunk.into_bytes().unwrap());
+            });
+
+            let stdin = child.stdin().unwrap();
+            for chunk in &input {
+                stdin.write(chunk.as_bytes().to_vec());
+            }
+            stdin.end();
+
+            let (r, c) = (r.clone(), child.clone());
+            child.on_exit(move |status| assert_eq!(c.exit_status(), Some(status)));
+            child.on_close(move |status| {
+                let (stdout, stderr) = output.borrow_mut().clone();
+                *r.borrow_mut() = Some((stdout, stderr, status));
+            });
+            assert_eq!(child.exit_status(), None);
+            f(&child);
+        });
+
+        let result = result.borrow_mut().take();
+        result.expect("the child never closed")
+    }
+
+    /// Runs `command` with `exec` and hands back the output and how long
+    /// it took
+    fn exec(command: &str, opts: ExecOptions) -> (ExecOutput, Duration) {
+        let result = Rc::new(RefCell::new(None));
+        let command = command.to_string();
+
+        let r = result.clone();
+        crate::run_test(move || {
+            let (r, start) = (r.clone(), Instant::now());
+            ChildProcess::exec(&command, opts.clone(), move |output| {
+                *r.borrow_mut() = Some((output, start.elapsed()));
+            });
+        });
+
+        let result = result.borrow_mut().take();
+        result.expect("exec never called back")
+    }
+
+    #[test]
+    fn spawn_streams_stdin_and_stdout() {
+        let (stdout, stderr, status) = spawn("cat", &[], &["hello\n", "world\n"], |_| ());
+        assert_eq!(stdout, b"hello\nworld\n");
+        assert_eq!(stderr, b"");
+        assert!(status.success());
+
+        let (stdout, stderr, _) = spawn("sh", &["-c", "echo out; echo err >&2"], &[], |_| ());
+        assert_eq!((&stdout[..], &stderr[..]), (&b"out\n"[..], &b"err\n"[..]));
+    }
+
+    #[test]
+    fn stdio_modes() {
+        let result = Rc::new(RefCell::new(None));
+        let r = result.clone();
+        crate::run_test(move || {
+            let opts = SpawnOptions {
+                cwd: Some("/".into()),
+                stdin: StdioMode::Ignore,
+                stderr: StdioMode::Ignore,
+                ..Default::default()
+            };
+            let child = ChildProcess::spawn("sh", &["-c", "pwd; cat; echo err >&2"], opts).unwrap();
+            assert!(child.stdin().is_none() && child.stderr().is_none());
+            let (r, c) = (r.clone(), child.clone());
+            child.stdout().unwrap().read_to_end(move |res| {
+                *r.borrow_mut() = Some(res.into_bytes().unwrap());
+                assert!(c.pid() > 0);
+            });
+        });
+        assert_eq!(result.borrow_mut().take().unwrap(), b"/\n");
+    }
+
+    #[test]
+    fn exit_code_and_signal() {
+        let (_, _, status) = spawn("sh", &["-c", "exit 3"], &[], |_| ());
+        assert_eq!(
+            status,
+            ExitStatus {
+                code: Some(3),
+                signal: None
+            }
+        );
+        assert!(!status.success());
+        assert_eq!(status.to_string(), "exit code 3");
+
+        let after_exit = Rc::new(RefCell::new(None));
+        let a = after_exit.clone();
+        let (_, _, status) = spawn("sleep", &["10"], &[], move |child| {
+            child.kill(libc::SIGTERM).unwrap();
+            let (c, a) = (child.clone(), a.clone());
+            child.on_exit(move |_| *a.borrow_mut() = Some(c.kill(libc::SIGTERM)));
+        });
+        assert_eq!(
+            status,
+            ExitStatus {
+                code: None,
+                signal: Some(libc::SIGTERM)
+            }
+        );
+        assert_eq!(status.to_string(), format!("signal {}", libc::SIGTERM));
+        // The child is reaped, so its pid might belong to someone else by now
+        let err = after_exit.borrow_mut().take().unwrap().unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ESRCH));
+    }
+
+    #[test]
+    fn exec_output() {
+        let mut env = HashMap::new();
+        env.insert("GREETING".to_string(), "hi".to_string());
+        let opts = ExecOptions {
+            env: Some(env),
+            ..Default::default()
+        };
+        let (output, _) = exec("echo $GREETING | tr a-z A-Z", opts);
+        assert!(output.error.is_none());
+        assert_eq!(output.stdout, b"HI\n");
+
+        let (output, _) = exec("echo oops >&2; exit 2", Default::default());
+        assert_eq!(output.status.unwrap().code, Some(2));
+        assert_eq!(
+            output.error.unwrap().to_string(),
+            "Command failed: echo oops >&2; exit 2\noops\n"
+        );
+
+        let result = Rc::new(RefCell::new(vec![]));
+        let r = result.clone();
+        crate::run_test(move || {
+            for &file in &["echo", "does-not-exist"] {
+                let r = r.clone();
+                ChildProcess::exec_file(file, &["a  b", "c"], Default::default(), move |output| {
+                    let kind = output.error.map(|e| e.kind());
+                    r.borrow_mut()
+                        .push((output.stdout, kind, output.status.is_some()));
+                });
+            }
+        });
+        let mut result = result.borrow_mut();
+        result.sort();
+        assert_eq!(result[0], (vec![], Some(io::ErrorKind::NotFound), false));
+        assert_eq!(result[1], (b"a  b c\n".to_vec(), None, true));
+    }
+
+    #[test]
+    fn exec_max_buffer() {
+        let opts = ExecOptions {
+            max_buffer: 1000,
+            ..Default::default()
+        };
+        let (output, _) = exec("yes", opts);
+        assert_eq!(output.stdout.len(), 1000);
+        assert!(output.stdout.starts_with(b"y\ny\n"));
+        assert_eq!(
+            output.error.unwrap().to_string(),
+            "stdout is longer than max_buffer"
+        );
+        assert_eq!(output.status.unwrap().signal, Some(libc::SIGTERM));
+    }
+
+    #[test]
+    fn exec_timeout() {
+        let opts = ExecOptions {
+            timeout_ms: 50,
+            kill_signal: libc::SIGKILL,
+            ..Default::default()
+        };
+        let (output, elapsed) = exec("echo started; sleep 10", opts);
+        assert_eq!(output.stdout, b"started\n");
+        assert_eq!(output.error.unwrap().kind(), io::ErrorKind::TimedOut);
+        assert_eq!(output.status.unwrap().signal, Some(libc::SIGKILL));
+        assert!(elapsed >= Duration::from_millis(50));
+        assert!(elapsed < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn children_exit_via_pidfd_without_pool_threads() {
+        let exited = Rc::new(RefCell::new(vec![]));
+        let e = exited.clone();
+        crate::run_test(move || {
+            for i in 0..50 {
+                let delay = format!("0.{:02}", i % 10);
+                let child = ChildProcess::spawn("sleep", &[&delay], Default::default()).unwrap();
+                assert!(child.inner.borrow().pidfd.is_some());
+
+                let e = e.clone();
+                child.on_exit(move |status| {
+                    // Nobody waits for the children on the threadpool
+                    let rt = unsafe { &*RUNTIME };
+                    assert_eq!(rt.available_threads.len(), rt.thread_pool.len());
+                    assert!(rt.queued_tasks.is_empty());
+                    e.borrow_mut().push(status);
+                });
+            }
+        });
+
+        let exited = exited.borrow();
+        assert_eq!(exited.len(), 50);
+        assert!(exited.iter().all(ExitStatus::success));
+    }
+}
@@ -0,0 +1,508 @@
+// ===== DELAY SERVER =====
+// A local stand-in for slowwly.robertomurray.co.uk, the site `http_get_slow`
+// used to simulate slow responses. It runs on our own event loop, so we can
+// exercise the network code offline and get the same behavior every time.
+//
+// The path is read as a list of instructions:
+//
+//     /delay/{ms}           wait before responding
+//     /status/{code}        respond with this status instead of 200
+//     /bytes/{n}            respond with a body of `n` bytes
+//     /throttle/{bps}       send the body at `bps` bytes per second
+//     /drop                 close the connection instead of responding
+//     /url/{url}            redirect to `url`, which is the rest of the path
+//
+// They can be combined, e.g. `/delay/2000/url/http://www.google.com` waits two
+// seconds and redirects just like slowwly did, and `/delay/100/drop` drops the
+// connection after 100 ms.
+
+use std::cell::{Cell, RefCell};
+use std::io;
+use std::net::SocketAddr;
+use std::rc::Rc;
+
+use crate::http::{self, MessageKind, Parsed, Parser, StartLine};
+use crate::net::{Net, Server, Socket, ToAddress};
+use crate::{print, Js};
+
+/// How often we send the next part of a throttled body
+const THROTTLE_INTERVAL_MS: u64 = 100;
+
+#[derive(Default, Clone)]
+pub struct DelayServerOptions {
+    /// Throttles every response to this many bytes per second unless the
+    /// path says otherwise
+    pub bytes_per_sec: Option<u64>,
+}
+
+#[derive(Clone)]
+pub struct DelayServer {
+    server: Server,
+}
+
+impl DelayServer {
+    pub fn listen(
+        addr: impl ToAddress,
+        opts: DelayServerOptions,
+        cb: impl FnOnce(Js) + 'static,
+    ) -> DelayServer {
+        let server = Net::create_server(move |socket| handle_connection(socket, opts.clone()));
+        server.listen(addr, cb);
+        DelayServer { server }
+    }
+
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.server.local_addr()
+    }
+
+    /// The URL for `path` on this server
+    pub fn url(&self, path: &str) -> String {
+        let addr = self.local_addr().expect("delay server is listening");
+        format!("http://{}{}", addr, path)
+    }
+
+    pub fn close(&self, cb: impl FnOnce(Js) + 'static) {
+        self.server.close(cb);
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct Route {
+    delay_ms: u64,
+    status: Option<u16>,
+    bytes: Option<usize>,
+    bytes_per_sec: Option<u64>,
+    drop: bool,
+    location: Option<String>,
+}
+
+impl Route {
+    fn parse(path: &str) -> Option<Route> {
+        let mut route = Route::default();
+        let mut rest = path.trim_start_matches('/');
+
+        while !rest.is_empty() {
+            let (segment, tail) = rest.split_once('/').unwrap_or((rest, ""));
+            if segment == "url" {
+                route.location = Some(tail.to_string());
+                break;
+            }
+            if segment == "drop" {
+                route.drop = true;
+                rest = tail;
+                continue;
+            }
+
+            let (value, tail) = tail.split_once('/').unwrap_or((tail, ""));
+            match segment {
+                "delay" => route.delay_ms = value.parse().ok()?,
+                "status" => route.status = Some(value.parse().ok()?),
+                "bytes" => route.bytes = Some(value.parse().ok()?),
+                "throttle" => route.bytes_per_sec = Some(value.parse().ok()?),
+                _ => return None,
+            }
+            rest = tail;
+        }
+
+        Some(route)
+    }
+}
+
+fn handle_connection(socket: Socket, opts: DelayServerOptions) {
+    let parser = Rc::new(RefCell::new(Parser::new(MessageKind::Request)));
+    let target = Rc::new(RefCell::new(None));
+    let handled = Rc::new(Cell::new(false));
+
+    let sock = socket.clone();
+    socket.on_data(move |chunk| {
+        let chunk = chunk.into_bytes().unwrap();
+        let parsed = match parser.borrow_mut().feed(&chunk) {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                print(format!("Delay server: {}", e));
+                if !handled.replace(true) {
+                    respond(&sock, 400, &Default::default(), &opts);
+                }
+                return;
+            }
+        };
+
+        for parsed in parsed {
+            match parsed {
+                Parsed::Head(head) => {
+                    if let StartLine::Request { target: t, .. } = head.start {
+                        *target.borrow_mut() = Some(t);
+                    }
+                }
+                // The request body doesn't matter to us
+                Parsed::Body(_) => (),
+                Parsed::End => {
+                    // We close the connection after every response, so we
+                    // ignore anything pipelined after the first request. We
+                    // keep reading though, to notice when the client is gone.
+                    if !handled.replace(true) {
+                        let target = target.borrow_mut().take().unwrap_or_default();
+                        handle_request(&sock, &target, &opts);
+                    }
+                }
+            }
+        }
+    });
+}
+
+fn handle_request(socket: &Socket, target: &str, opts: &DelayServerOptions) {
+    let path = target.split('?').next().unwrap_or_default();
+    let route = match Route::parse(path) {
+        Some(route) => route,
+        None => {
+            respond(socket, 404, &Route::default(), opts);
+            return;
+        }
+    };
+
+    let socket = socket.clone();
+    let opts = opts.clone();
+    crate::set_timeout(route.delay_ms, move |_| {
+        if route.drop {
+            socket.destroy();
+            return;
+        }
+
+        let status = match (route.status, &route.location) {
+            (Some(status), _) => status,
+            (None, Some(_)) => 302,
+            (None, None) => 200,
+        };
+        respond(&socket, status, &route, &opts);
+    });
+}
+
+fn respond(socket: &Socket, status: u16, route: &Route, opts: &DelayServerOptions) {
+    let body = match (route.bytes, &route.location) {
+        (Some(n), _) => vec![b'x'; n],
+        (None, Some(location)) => format!("Redirecting to {}\n", location).into_bytes(),
+        (None, None) => format!("{} {}\n", status, http::status_text(status)).into_bytes(),
+    };
+
+    let mut head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n",
+        status,
+        http::status_text(status),
+        body.len()
+    );
+    if let Some(location) = &route.location {
+        head.push_str(&format!("Location: {}\r\n", location));
+    }
+    head.push_str("\r\n");
+    socket.write(head);
+
+    match route.bytes_per_sec.or(opts.bytes_per_sec) {
+        Some(bps) => {
+            let chunk_size = (bps * THROTTLE_INTERVAL_MS / 1000).max(1) as usize;
+            write_throttled(socket.clone(), body, chunk_size);
+        }
+        None => {
+            socket.write(body);
+            socket.end();
+        }
+    }
+}
+
+/// Writes one chunk at a time with a pause in between
+fn write_throttled(socket: Socket, mut body: Vec<u8>, chunk_size: usize) {
+    if socket.is_closed() {
+        return;
+    }
+
+    let rest = body.split_off(chunk_size.min(body.len()));
+    socket.write(body);
+    if rest.is_empty() {
+        socket.end();
+        return;
+    }
+
+    crate::set_timeout(THROTTLE_INTERVAL_MS, move |_| {
+        write_throttled(socket, rest, chunk_size)
+    });
+}
+
+// ===== SHARED SERVER =====
+// `http_get_slow` needs a delay server to talk to without anyone having to
+// start one. We start it on first use and close it again when no request is
+// using it anymore, so it doesn't keep the event loop alive.
+
+thread_local! {
+    static SHARED: RefCell<Option<(DelayServer, usize)>> = const { RefCell::new(None) };
+}
+
+/// Returns the base URL of the shared server, starting it if needed. Every
+/// call must be matched by a call to `release_shared`.
+pub(crate) fn acquire_shared() -> io::Result<String> {
+    SHARED.with(|shared| {
+        let mut shared = shared.borrow_mut();
+        if shared.is_none() {
+            let server = DelayServer::listen("127.0.0.1:0", Default::default(), |_| ());
+            if server.local_addr().is_none() {
+                return Err(io::Error::other("could not start the delay server"));
+            }
+            *shared = Some((server, 0));
+        }
+
+        let (server, users) = shared.as_mut().unwrap();
+        *users += 1;
+        Ok(server.url(""))
+    })
+}
+
+pub(crate) fn release_shared() {
+    SHARED.with(|shared| {
+        let mut shared = shared.borrow_mut();
+        if let Some((_, users)) = shared.as_mut() {
+            *users -= 1;
+            if *users == 0 {
+                let (server, _) = shared.take().unwrap();
+                server.close(|_| ());
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::io::AsRawFd;
+    use std::time::{Duration, Instant};
+
+    /// Starts a server, requests `path` from it and closes it again. `f` gets
+    /// the URL and a callback to call once it's done.
+    fn with_server(f: impl Fn(String, Box<dyn FnOnce()>) + 'static) {
+        let f = Rc::new(f);
+        crate::run_test(move || {
+            let f = f.clone();
+            let server = Rc::new(RefCell::new(None));
+            let s = server.clone();
+            let delay_server = DelayServer::listen("127.0.0.1:0", Default::default(), move |_| {
+                let server: DelayServer = s.borrow().clone().unwrap();
+                let url = server.url("");
+                f(url, Box::new(move || server.close(|_| ())));
+            });
+            *server.borrow_mut() = Some(delay_server);
+        });
+    }
+
+    /// Requests `path` and returns the result
+    fn get(path: &'static str) -> Js {
+        let result = Rc::new(RefCell::new(None));
+        let r = result.clone();
+        with_server(move |url, done| {
+            let r = r.clone();
+            http::request(http::RequestOptions::get(url + path), move |res| {
+                *r.borrow_mut() = Some(res);
+                done();
+            });
+        });
+        let result = result.borrow_mut().take();
+        result.unwrap()
+    }
+
+    #[test]
+    fn parse_routes() {
+        let route = Route::parse("/delay/100/status/503/bytes/10/throttle/20/drop").unwrap();
+        let expected = Route {
+            delay_ms: 100,
+            status: Some(503),
+            bytes: Some(10),
+            bytes_per_sec: Some(20),
+            drop: true,
+            location: None,
+        };
+        assert_eq!(route, expected);
+
+        let route = Route::parse("/url/http://localhost/a/b").unwrap();
+        assert_eq!(route.location.as_deref(), Some("http://localhost/a/b"));
+        assert_eq!(Route::parse("/"), Some(Route::default()));
+        assert_eq!(Route::parse("/delay/soon"), None);
+        assert_eq!(Route::parse("/unknown/1"), None);
+    }
+
+    #[test]
+    fn delay_doesnt_block_the_loop() {
+        let events = Rc::new(RefCell::new(vec![]));
+
+        let e = events.clone();
+        with_server(move |url, done| {
+            let started = Instant::now();
+            let e2 = e.clone();
+            crate::set_timeout(50, move |_| e2.borrow_mut().push("timer".to_string()));
+
+            let e = e.clone();
+            http::request(http::RequestOptions::get(url + "/delay/150"), move |res| {
+                let status = res.into_response().unwrap().status;
+                let elapsed = started.elapsed();
+                e.borrow_mut().push(format!("response {}", status));
+                assert!(elapsed >= Duration::from_millis(150), "{:?}", elapsed);
+                done();
+            });
+        });
+
+        assert_eq!(*events.borrow(), ["timer", "response 200"]);
+    }
+
+    #[test]
+    fn status() {
+        let res = get("/status/503").into_response().unwrap();
+        assert_eq!(res.status, 503);
+        assert_eq!(res.text(), "503 Service Unavailable\n");
+
+        let res = get("/nothing/here").into_response().unwrap();
+        assert_eq!(res.status, 404);
+    }
+
+    #[test]
+    fn throttled_body_arrives_in_chunks() {
+        let chunks = Rc::new(RefCell::new(vec![]));
+
+        let c = chunks.clone();
+        with_server(move |url, done| {
+            // 100 bytes every 100 ms
+            let opts = http::RequestOptions::get(url + "/bytes/300/throttle/1000");
+            let c = c.clone();
+            let done = RefCell::new(Some(done));
+            crate::Http::request_stream(opts, move |res| {
+                let body = res.unwrap().body;
+                let c = c.clone();
+                body.on_data(move |chunk| c.borrow_mut().push(chunk.into_bytes().unwrap()));
+                body.on_end(move |_| done.borrow_mut().take().unwrap()());
+            });
+        });
+
+        let chunks = chunks.borrow();
+        assert_eq!(chunks.concat(), vec![b'x'; 300]);
+        assert!(chunks.len() >= 3, "{} chunks", chunks.len());
+    }
+
+    #[test]
+    fn drop_is_a_client_error() {
+        match get("/delay/10/drop") {
+            Js::Error(e) => {
+                assert_eq!(e.kind(), io::ErrorKind::ConnectionReset);
+                assert_eq!(e.to_string(), "socket hang up");
+            }
+            _ => panic!("expected an error"),
+        }
+    }
+
+    /// Sends `opts` with `url` prefixed to its URL and returns the result
+    fn request(opts: http::RequestOptions) -> Js {
+        let result = Rc::new(RefCell::new(None));
+        let r = result.clone();
+        with_server(move |url, done| {
+            let mut opts = opts.clone();
+            opts.url = opts.url.replace("{url}", &url);
+            let r = r.clone();
+            http::request(opts, move |res| {
+                *r.borrow_mut() = Some(res);
+                done();
+            });
+        });
+        let result = result.borrow_mut().take();
+        result.unwrap()
+    }
+
+    fn error(res: Js) -> io::Error {
+        match res {
+            Js::Error(e) => e,
+            res => panic!("expected an error, got {:?}", res.into_response()),
+        }
+    }
+
+    #[test]
+    fn redirects() {
+        let mut opts = http::RequestOptions::get("{url}/url/{url}/url/{url}/status/201");
+        opts.max_redirects = 2;
+        let res = request(opts.clone()).into_response().unwrap();
+        assert_eq!(res.status, 201);
+        assert!(res.url.ends_with("/status/201"), "{}", res.url);
+
+        opts.max_redirects = 1;
+        assert_eq!(
+            error(request(opts.clone())).to_string(),
+            "more than 1 redirects"
+        );
+
+        // Without following them we get the redirect itself
+        opts.max_redirects = 0;
+        let res = request(opts).into_response().unwrap();
+        assert_eq!(res.status, 302);
+        assert!(res
+            .headers
+            .get("Location")
+            .unwrap()
+            .ends_with("/status/201"));
+    }
+
+    #[test]
+    fn relative_redirect() {
+        // The server redirects to `../../status/201`, relative to the
+        // directory `/delay/0/url/../../status/`
+        let mut opts = http::RequestOptions::get("{url}/delay/0/url/../../status/201");
+        opts.max_redirects = 1;
+        let res = request(opts).into_response().unwrap();
+        assert_eq!(res.status, 201);
+        assert!(res.url.ends_with("/status/201"), "{}", res.url);
+    }
+
+    #[test]
+    fn response_timeout() {
+        let mut opts = http::RequestOptions::get("{url}/delay/500");
+        opts.timeout_ms = 50;
+        let e = error(request(opts));
+        assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(e.to_string(), "no response after 50 ms");
+    }
+
+    #[test]
+    fn connect_timeout() {
+        // A listener which never accepts, with a backlog of one that we fill.
+        // The server ignores any connection attempt after that.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        assert_eq!(unsafe { libc::listen(listener.as_raw_fd(), 0) }, 0);
+        let backlog: Vec<_> = (0..4)
+            .map_while(|_| {
+                std::net::TcpStream::connect_timeout(&addr, Duration::from_millis(50)).ok()
+            })
+            .collect();
+        assert!(backlog.len() < 4);
+
+        let mut opts = http::RequestOptions::get(format!("http://{}/", addr));
+        opts.connect_timeout_ms = 50;
+        let started = Instant::now();
+        let e = error(request(opts));
+        assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(e.to_string(), "connect timed out after 50 ms");
+        assert!(started.elapsed() < Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn retries_back_off() {
+        let mut opts = http::RequestOptions::get("{url}/drop");
+        opts.retries = 2;
+        opts.retry_delay_ms = 40;
+
+        // 40 ms before the first retry and 80 ms before the second
+        let started = Instant::now();
+        let e = error(request(opts.clone()));
+        assert_eq!(e.kind(), io::ErrorKind::ConnectionReset);
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(120), "{:?}", elapsed);
+
+        // A POST might have been handled before the connection dropped
+        opts.method = "POST".to_string();
+        let started = Instant::now();
+        let e = error(request(opts));
+        assert_eq!(e.kind(), io::ErrorKind::ConnectionReset);
+        let elapsed = started.elapsed();
+        assert!(elapsed < Duration::from_millis(40), "{:?}", elapsed);
+    }
+}
@@ -0,0 +1,864 @@
+// ===== DGRAM =====
+// UDP sockets, like the `dgram` module in Node. There are no connections:
+// every datagram we send carries its own address, and every datagram we
+// receive comes with the address it was sent from. A socket of the `Unix`
+// kind sends datagrams between `unix:` paths on the same machine instead.
+//
+// The socket is non-blocking and registered with our epoll instance while
+// it's bound, so receiving a datagram is an event on the main thread just
+// like data on a TCP socket. Sending almost never blocks, but if the send
+// buffer is full the datagram is queued until epoll says there is room.
+//
+//     let socket = Dgram::create_socket(Default::default())?;
+//     socket.on_message(|msg, from| print(format!("{} bytes from {}", msg.len(), from)));
+//     socket.bind("0.0.0.0:8125", |_| print("statsd listening"));
+//
+//     let client = Dgram::create_socket(Default::default())?;
+//     client.send("requests:1|c", "127.0.0.1:8125", |_| ());
+//
+// A Unix socket bound to a path removes the socket file again when it's
+// closed. One which sends without being bound gets an abstract name from the
+// kernel, so it can still receive answers.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fs;
+use std::io;
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+use std::rc::{Rc, Weak};
+
+use crate::net::{
+    is_stale_socket, raw_socket_addr, raw_unix_addr, setsockopt, unix_address, Address, ToAddress,
+};
+use crate::poll::{Interests, Registration};
+use crate::stream::{emit, Listeners};
+use crate::{defer, print, Handle, Js, RUNTIME};
+
+/// Big enough for the largest datagram there is
+const RECV_BUFFER_SIZE: usize = 64 * 1024;
+
+type MessageCallback = Box<dyn FnMut(Vec<u8>, Address)>;
+type SendCallback = Box<dyn FnOnce(Js)>;
+
+pub struct Dgram;
+
+impl Dgram {
+    /// Creates an unbound socket. Call `bind` to receive datagrams, or just
+    /// `send`, which binds to a random port first.
+    pub fn create_socket(opts: DgramOptions) -> io::Result<DgramSocket> {
+        let domain = match opts.kind {
+            SocketType::Udp4 => libc::AF_INET,
+            SocketType::Udp6 => libc::AF_INET6,
+            SocketType::Unix => libc::AF_UNIX,
+        };
+        let flags = libc::SOCK_DGRAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC;
+        let fd = unsafe { libc::socket(domain, flags, 0) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // From here on the fd is closed when `socket` is dropped
+        let socket = match opts.kind {
+            SocketType::Unix => Inner::Unix(unsafe { UnixDatagram::from_raw_fd(fd) }),
+            _ => Inner::Udp(unsafe { UdpSocket::from_raw_fd(fd) }),
+        };
+
+        if opts.reuse_addr {
+            setsockopt(
+                &socket,
+                libc::SOL_SOCKET,
+                libc::SO_REUSEADDR,
+                1 as libc::c_int,
+            )?;
+        }
+        if opts.kind == SocketType::Udp6 && opts.ipv6_only {
+            setsockopt(
+                &socket,
+                libc::IPPROTO_IPV6,
+                libc::IPV6_V6ONLY,
+                1 as libc::c_int,
+            )?;
+        }
+
+        let state = DgramState {
+            reg: Registration::new(socket.as_raw_fd()),
+            socket: Some(socket),
+            kind: opts.kind,
+            bound: false,
+            queue: VecDeque::new(),
+            closed: false,
+            handle: 0,
+            on_message: vec![],
+            on_error: Listeners::default(),
+            on_close: Listeners::default(),
+        };
+        let inner = Rc::new(RefCell::new(state));
+
+        let rt = unsafe { &mut *RUNTIME };
+        let handle = DgramHandle(Rc::downgrade(&inner));
+        inner.borrow_mut().handle = rt.add_handle(Rc::new(handle));
+        Ok(DgramSocket { inner })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SocketType {
+    #[default]
+    Udp4,
+    Udp6,
+    /// Bound to and sending to `unix:` paths
+    Unix,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DgramOptions {
+    pub kind: SocketType,
+    /// Lets several sockets bind to the same address and port, which is what
+    /// you want when more than one process listens to a multicast group
+    pub reuse_addr: bool,
+    /// A `Udp6` socket bound to `::` only gets IPv6 datagrams, not IPv4 ones
+    pub ipv6_only: bool,
+}
+
+// ===== DGRAM SOCKET =====
+
+/// A UDP socket. While it's bound it keeps the event loop alive, unless
+/// `unref` is used.
+#[derive(Clone)]
+pub struct DgramSocket {
+    inner: Rc<RefCell<DgramState>>,
+}
+
+struct DgramState {
+    /// `None` once it's closed
+    socket: Option<Inner>,
+    reg: Registration,
+    kind: SocketType,
+    bound: bool,
+    /// Datagrams the socket couldn't take without blocking, sent in order
+    /// once it's writable
+    queue: VecDeque<(Vec<u8>, Address, SendCallback)>,
+    closed: bool,
+    /// Our id in the runtime's list of handles
+    handle: usize,
+    on_message: Vec<MessageCallback>,
+    on_error: Listeners,
+    on_close: Listeners,
+}
+
+impl DgramSocket {
+    /// Binds to `addr` and starts receiving. `cb` gets `Js::Undefined` once
+    /// we're bound, or the error if we couldn't bind. Port 0 picks a random
+    /// port, see `local_addr`.
+    pub fn bind(&self, addr: impl ToAddress, cb: impl FnOnce(Js) + 'static) {
+        let res = self.resolve(addr).and_then(|addr| self.bind_to(addr));
+        match res {
+            Ok(()) => {
+                if let Some(addr) = self.address() {
+                    print(format!("Datagram socket bound to {}", addr));
+                }
+                defer(cb);
+            }
+            Err(e) => defer(move |_| cb(Js::Error(e))),
+        }
+    }
+
+    /// Sends `data` as one datagram to `addr`. `cb` gets `Js::Undefined` once
+    /// the datagram is handed to the OS, or the error if it couldn't be sent.
+    /// Host names are resolved synchronously for now.
+    pub fn send(
+        &self,
+        data: impl Into<Vec<u8>>,
+        addr: impl ToAddress,
+        cb: impl FnOnce(Js) + 'static,
+    ) {
+        let data = data.into();
+        let res = self.resolve(addr).and_then(|addr| {
+            // Without a port of our own we'd never see the answers
+            if !self.inner.borrow().bound {
+                self.bind_to(self.any_addr())?;
+            }
+            Ok(addr)
+        });
+
+        let addr = match res {
+            Ok(addr) => addr,
+            Err(e) => return defer(move |_| cb(Js::Error(e))),
+        };
+
+        // Datagrams go out in order, so nothing can skip the queue
+        self.inner
+            .borrow_mut()
+            .queue
+            .push_back((data, addr, Box::new(cb)));
+        self.flush_queue();
+        self.update_interests();
+    }
+
+    /// Called with the data and the sender of every datagram we receive
+    pub fn on_message(&self, cb: impl FnMut(Vec<u8>, Address) + 'static) {
+        self.inner.borrow_mut().on_message.push(Box::new(cb));
+    }
+
+    /// Emitted if receiving fails. Errors sending a datagram go to the
+    /// callback of `send` instead.
+    pub fn on_error(&self, cb: impl FnMut(Js) + 'static) {
+        self.inner.borrow_mut().on_error.add(cb);
+    }
+
+    pub fn on_close(&self, cb: impl FnMut(Js) + 'static) {
+        self.inner.borrow_mut().on_close.add(cb);
+    }
+
+    /// The UDP address we're bound to, `None` for Unix sockets
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        match self.address()? {
+            Address::Ip(addr) => Some(addr),
+            _ => None,
+        }
+    }
+
+    /// The address we're bound to, UDP or Unix
+    pub fn address(&self) -> Option<Address> {
+        let state = self.inner.borrow();
+        match state.socket.as_ref() {
+            Some(Inner::Udp(socket)) if state.bound => socket.local_addr().ok().map(Address::Ip),
+            Some(Inner::Unix(socket)) if state.bound => {
+                socket.local_addr().ok().map(|addr| unix_address(&addr))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.inner.borrow().closed
+    }
+
+    /// Stops receiving and closes the socket. Datagrams still waiting to be
+    /// sent are dropped and their callbacks get an error.
+    pub fn close(&self) {
+        let queue = {
+            let mut state = self.inner.borrow_mut();
+            if state.closed {
+                return;
+            }
+            state.closed = true;
+            state.reg.disarm();
+            let rt = unsafe { &mut *RUNTIME };
+            rt.remove_handle(state.handle);
+            if let Some(Inner::Unix(socket)) = state.socket.take() {
+                let path = socket
+                    .local_addr()
+                    .ok()
+                    .and_then(|a| a.as_pathname().map(PathBuf::from));
+                if let Some(path) = path {
+                    let _ = fs::remove_file(path);
+                }
+            }
+            mem::take(&mut state.queue)
+        };
+
+        for (_, _, cb) in queue {
+            cb(Js::Error(io::ErrorKind::NotConnected.into()));
+        }
+        emit(&self.inner, |s| &mut s.on_close, Js::Undefined);
+
+        let mut state = self.inner.borrow_mut();
+        state.on_message.clear();
+        state.on_error = Listeners::default();
+        state.on_close = Listeners::default();
+    }
+
+    /// Lets the event loop exit even though the socket is bound
+    pub fn unref(&self) {
+        self.inner.borrow_mut().reg.set_ref(false);
+    }
+
+    /// Undoes `unref`
+    pub fn ref_(&self) {
+        self.inner.borrow_mut().reg.set_ref(true);
+    }
+
+    /// Allows sending to broadcast addresses like `255.255.255.255`
+    pub fn set_broadcast(&self, on: bool) -> io::Result<()> {
+        self.with_udp(|socket| socket.set_broadcast(on))
+    }
+
+    /// How many hops a datagram may take before it's dropped
+    pub fn set_ttl(&self, ttl: u32) -> io::Result<()> {
+        self.with_udp(|socket| socket.set_ttl(ttl))
+    }
+
+    /// Joins a multicast group. `interface` picks the local interface by its
+    /// address, `None` lets the OS choose. IPv6 groups always use the default
+    /// interface.
+    pub fn add_membership(&self, group: IpAddr, interface: Option<Ipv4Addr>) -> io::Result<()> {
+        self.with_udp(|socket| match group {
+            IpAddr::V4(group) => {
+                socket.join_multicast_v4(&group, &interface.unwrap_or(Ipv4Addr::UNSPECIFIED))
+            }
+            IpAddr::V6(group) => socket.join_multicast_v6(&group, 0),
+        })
+    }
+
+    pub fn drop_membership(&self, group: IpAddr, interface: Option<Ipv4Addr>) -> io::Result<()> {
+        self.with_udp(|socket| match group {
+            IpAddr::V4(group) => {
+                socket.leave_multicast_v4(&group, &interface.unwrap_or(Ipv4Addr::UNSPECIFIED))
+            }
+            IpAddr::V6(group) => socket.leave_multicast_v6(&group, 0),
+        })
+    }
+
+    /// How many hops a multicast datagram may take. The default is 1, which
+    /// keeps it on the local network.
+    pub fn set_multicast_ttl(&self, ttl: u32) -> io::Result<()> {
+        self.with_udp(|socket| socket.set_multicast_ttl_v4(ttl))
+    }
+
+    /// Whether we receive the multicast datagrams we send ourselves
+    pub fn set_multicast_loopback(&self, on: bool) -> io::Result<()> {
+        let kind = self.inner.borrow().kind;
+        self.with_udp(|socket| match kind {
+            SocketType::Udp6 => socket.set_multicast_loop_v6(on),
+            _ => socket.set_multicast_loop_v4(on),
+        })
+    }
+
+    /// The interface multicast datagrams are sent from, by its address
+    pub fn set_multicast_interface(&self, interface: Ipv4Addr) -> io::Result<()> {
+        let addr = libc::in_addr {
+            s_addr: u32::from_ne_bytes(interface.octets()),
+        };
+        self.with_udp(|socket| setsockopt(socket, libc::IPPROTO_IP, libc::IP_MULTICAST_IF, addr))
+    }
+
+    /// For the options only UDP sockets have
+    fn with_udp<T>(&self, f: impl FnOnce(&UdpSocket) -> io::Result<T>) -> io::Result<T> {
+        match self.inner.borrow().socket.as_ref() {
+            Some(Inner::Udp(socket)) => f(socket),
+            Some(Inner::Unix(_)) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "not supported on Unix sockets",
+            )),
+            None => Err(io::ErrorKind::NotConnected.into()),
+        }
+    }
+
+    /// The first address of our kind, since a UDP4 socket can't send to an
+    /// IPv6 address and the other way around, and only Unix sockets can
+    /// send to paths
+    fn resolve(&self, addr: impl ToAddress) -> io::Result<Address> {
+        let kind = self.inner.borrow().kind;
+        let invalid = |msg| io::Error::new(io::ErrorKind::InvalidInput, msg);
+        match (addr.to_address()?, kind) {
+            (addr @ Address::Unix(_), SocketType::Unix) => Ok(addr),
+            (_, SocketType::Unix) => Err(invalid("a Unix socket needs a unix: path")),
+            (Address::Unix(_), _) => Err(invalid("a UDP socket can't use a unix: path")),
+            (addr, _) => addr
+                .resolve()?
+                .into_iter()
+                .find(|addr| addr.is_ipv4() == (kind == SocketType::Udp4))
+                .map(Address::Ip)
+                .ok_or_else(|| match kind {
+                    SocketType::Udp4 => invalid("no IPv4 address for a udp4 socket"),
+                    _ => invalid("no IPv6 address for a udp6 socket"),
+                }),
+        }
+    }
+
+    /// A random port, or for Unix sockets an empty path, which makes the
+    /// kernel pick an abstract name
+    fn any_addr(&self) -> Address {
+        match self.inner.borrow().kind {
+            SocketType::Udp4 => Address::Ip((Ipv4Addr::UNSPECIFIED, 0).into()),
+            SocketType::Udp6 => Address::Ip((Ipv6Addr::UNSPECIFIED, 0).into()),
+            SocketType::Unix => Address::Unix(PathBuf::new()),
+        }
+    }
+
+    fn bind_to(&self, addr: Address) -> io::Result<()> {
+        {
+            let mut state = self.inner.borrow_mut();
+            let fd = match state.socket.as_ref() {
+                Some(socket) => socket.as_raw_fd(),
+                None => return Err(io::ErrorKind::NotConnected.into()),
+            };
+            if state.bound {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    "socket is already bound",
+                ));
+            }
+
+            let res = match addr {
+                Address::Ip(addr) => {
+                    let (raw_addr, len) = raw_socket_addr(&addr);
+                    bind_raw(fd, &raw_addr, len)
+                }
+                Address::Unix(ref path) if path.as_os_str().is_empty() => {
+                    // Only the family, that's how autobind is asked for
+                    let raw_addr = libc::AF_UNIX as libc::sa_family_t;
+                    bind_raw(
+                        fd,
+                        &raw_addr,
+                        mem::size_of_val(&raw_addr) as libc::socklen_t,
+                    )
+                }
+                Address::Unix(ref path) => {
+                    let (raw_addr, len) = raw_unix_addr(path)?;
+                    match bind_raw(fd, &raw_addr, len) {
+                        Err(ref e)
+                            if e.kind() == io::ErrorKind::AddrInUse && is_stale_socket(path) =>
+                        {
+                            print(format!("Removing stale socket file {}", path.display()));
+                            fs::remove_file(path)?;
+                            bind_raw(fd, &raw_addr, len)
+                        }
+                        res => res,
+                    }
+                }
+                Address::Host(..) => unreachable!("resolved by `resolve`"),
+            };
+            res?;
+            state.bound = true;
+        }
+
+        self.update_interests();
+        Ok(())
+    }
+
+    /// Once bound we always wait for datagrams, and for room to send while
+    /// there are datagrams queued
+    fn update_interests(&self) {
+        let res = {
+            let mut state = self.inner.borrow_mut();
+            if state.closed || !state.bound {
+                return;
+            }
+            let interests = match state.queue.is_empty() {
+                true => Interests::READABLE,
+                false => Interests::READABLE | Interests::WRITABLE,
+            };
+            let socket = self.clone();
+            state.reg.arm(interests, move |_| socket.on_ready())
+        };
+
+        if let Err(e) = res {
+            emit(&self.inner, |s| &mut s.on_error, Js::Error(e));
+            self.close();
+        }
+    }
+
+    fn on_ready(&self) {
+        self.inner.borrow_mut().reg.fired();
+        self.flush_queue();
+        self.receive();
+        self.update_interests();
+    }
+
+    /// Sends queued datagrams until the socket would block
+    fn flush_queue(&self) {
+        loop {
+            let (res, cb) = {
+                let mut state = self.inner.borrow_mut();
+                let state = &mut *state;
+                let socket = match state.socket.as_ref() {
+                    Some(socket) => socket,
+                    None => return,
+                };
+                let (data, addr, _) = match state.queue.front() {
+                    Some(next) => next,
+                    None => return,
+                };
+
+                match socket.send_to(data, addr) {
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    res => (res, state.queue.pop_front().unwrap().2),
+                }
+            };
+
+            match res {
+                Ok(_) => cb(Js::Undefined),
+                Err(e) => cb(Js::Error(e)),
+            }
+        }
+    }
+
+    /// Reads datagrams until there are no more
+    fn receive(&self) {
+        let mut buffer = vec![0; RECV_BUFFER_SIZE];
+
+        loop {
+            let res = match self.inner.borrow().socket.as_ref() {
+                Some(socket) => socket.recv_from(&mut buffer),
+                None => return,
+            };
+
+            match res {
+                Ok((n, from)) => self.emit_message(buffer[..n].to_vec(), from),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                // An ICMP error from an earlier send, like port unreachable.
+                // It doesn't stop us from receiving.
+                Err(e) => {
+                    emit(&self.inner, |s| &mut s.on_error, Js::Error(e));
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Same as `emit` but with our typed listeners, which may add new ones
+    fn emit_message(&self, data: Vec<u8>, from: Address) {
+        let mut listeners = mem::take(&mut self.inner.borrow_mut().on_message);
+        for cb in listeners.iter_mut() {
+            cb(data.clone(), from.clone());
+        }
+
+        let mut state = self.inner.borrow_mut();
+        if !state.closed {
+            listeners.append(&mut state.on_message);
+            state.on_message = listeners;
+        }
+    }
+}
+
+/// A shutdown treats a socket like a server and closes it, unless it still
+/// has datagrams to send. Those get until the grace period is over.
+struct DgramHandle(Weak<RefCell<DgramState>>);
+
+impl Handle for DgramHandle {
+    fn describe(&self) -> Option<String> {
+        let socket = DgramSocket {
+            inner: self.0.upgrade()?,
+        };
+        match socket.address() {
+            Some(addr) => Some(format!("datagram socket bound to {}", addr)),
+            None => Some("datagram socket".to_string()),
+        }
+    }
+
+    fn stop(&self) {
+        if let Some(inner) = self.0.upgrade() {
+            if inner.borrow().queue.is_empty() {
+                DgramSocket { inner }.close();
+            }
+        }
+    }
+
+    fn force_close(&self) {
+        if let Some(inner) = self.0.upgrade() {
+            DgramSocket { inner }.close();
+        }
+    }
+}
+
+/// The socket itself, UDP or Unix
+enum Inner {
+    Udp(UdpSocket),
+    Unix(UnixDatagram),
+}
+
+impl Inner {
+    fn send_to(&self, data: &[u8], addr: &Address) -> io::Result<usize> {
+        match (self, addr) {
+            (Inner::Udp(socket), Address::Ip(addr)) => socket.send_to(data, addr),
+            (Inner::Unix(socket), Address::Unix(path)) => {
+                let (raw_addr, len) = raw_unix_addr(path)?;
+                let n = unsafe {
+                    libc::sendto(
+                        socket.as_raw_fd(),
+                        data.as_ptr() as *const libc::c_void,
+                        data.len(),
+                        0,
+                        &raw_addr as *const _ as *const libc::sockaddr,
+                        len,
+                    )
+                };
+                if n < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(n as usize)
+            }
+            _ => Err(io::ErrorKind::InvalidInput.into()),
+        }
+    }
+
+    fn recv_from(&self, buffer: &mut [u8]) -> io::Result<(usize, Address)> {
+        match self {
+            Inner::Udp(socket) => {
+                let (n, from) = socket.recv_from(buffer)?;
+                Ok((n, Address::Ip(from)))
+            }
+            Inner::Unix(socket) => {
+                let (n, from) = socket.recv_from(buffer)?;
+                Ok((n, unix_address(&from)))
+            }
+        }
+    }
+}
+
+impl AsRawFd for Inner {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Inner::Udp(socket) => socket.as_raw_fd(),
+            Inner::Unix(socket) => socket.as_raw_fd(),
+        }
+    }
+}
+
+fn bind_raw<T>(fd: RawFd, raw_addr: &T, len: libc::socklen_t) -> io::Result<()> {
+    let res = unsafe { libc::bind(fd, raw_addr as *const T as *const libc::sockaddr, len) };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    fn error_kind(res: Js) -> Option<io::ErrorKind> {
+        match res {
+            Js::Error(e) => Some(e.kind()),
+            _ => None,
+        }
+    }
+
+    /// What an echo round trip looked like from both ends
+    #[derive(Debug, Default)]
+    struct RoundTrip {
+        /// The datagram and its sender, as the server saw it
+        request: Option<(Vec<u8>, Address)>,
+        /// What came back to the client
+        reply: Option<(Vec<u8>, Address)>,
+        client_addr: Option<Address>,
+        closed: usize,
+    }
+
+    /// Binds an echo server of `kind` to `addr` and sends it `ping` from a
+    /// socket which isn't bound
+    fn round_trip(opts: DgramOptions, addr: String) -> RoundTrip {
+        let result = Rc::new(RefCell::new(RoundTrip::default()));
+
+        let r = result.clone();
+        crate::run_test(move || {
+            let server = Dgram::create_socket(opts.clone()).unwrap();
+            let (s, r2) = (server.clone(), r.clone());
+            server.on_message(move |data, from| {
+                r2.borrow_mut().request = Some((data.clone(), from.clone()));
+                s.send(data, from, |res| assert!(matches!(res, Js::Undefined)));
+            });
+            let r2 = r.clone();
+            server.on_close(move |_| r2.borrow_mut().closed += 1);
+
+            let (s, r, opts) = (server.clone(), r.clone(), opts.clone());
+            server.bind(addr.clone(), move |res| {
+                assert_eq!(error_kind(res), None);
+                let to = s.address().unwrap();
+
+                let client = Dgram::create_socket(opts).unwrap();
+                assert_eq!(client.address(), None);
+                let (c, r2) = (client.clone(), r.clone());
+                client.on_message(move |data, from| {
+                    r2.borrow_mut().reply = Some((data, from));
+                    c.close();
+                    s.close();
+                });
+                let r2 = r.clone();
+                client.on_close(move |_| r2.borrow_mut().closed += 1);
+                client.send("ping", to, |res| assert!(matches!(res, Js::Undefined)));
+                // Sending bound it to a random address
+                r.borrow_mut().client_addr = client.address();
+            });
+        });
+
+        Rc::try_unwrap(result).unwrap().into_inner()
+    }
+
+    #[test]
+    fn udp4_round_trip() {
+        let result = round_trip(Default::default(), "127.0.0.1:0".to_string());
+
+        let (request, from) = result.request.unwrap();
+        assert_eq!(request, b"ping");
+        // The client is bound to 0.0.0.0 and sent from 127.0.0.1
+        let port = |addr: &Address| match addr {
+            Address::Ip(addr) => addr.port(),
+            addr => panic!("not an IP address: {}", addr),
+        };
+        assert_eq!(port(&from), port(&result.client_addr.unwrap()));
+        let (reply, _) = result.reply.unwrap();
+        assert_eq!(reply, b"ping");
+        assert_eq!(result.closed, 2);
+    }
+
+    #[test]
+    fn udp6_round_trip() {
+        let opts = DgramOptions {
+            kind: SocketType::Udp6,
+            ipv6_only: true,
+            ..Default::default()
+        };
+        let result = round_trip(opts, "[::1]:0".to_string());
+
+        let (request, from) = result.request.unwrap();
+        assert_eq!(request, b"ping");
+        assert!(matches!(from, Address::Ip(SocketAddr::V6(_))), "{}", from);
+        assert_eq!(result.reply.unwrap().0, b"ping");
+    }
+
+    #[test]
+    fn unix_round_trip() {
+        let path = crate::temp_path("dgram.sock");
+        let opts = DgramOptions {
+            kind: SocketType::Unix,
+            ..Default::default()
+        };
+        let addr = format!("unix:{}", path.display());
+        let result = round_trip(opts, addr);
+
+        // The client got an abstract name from the kernel, and we could
+        // answer it there
+        let (request, from) = result.request.unwrap();
+        assert_eq!(request, b"ping");
+        assert!(from.to_string().starts_with("unix:@"), "{}", from);
+        let (reply, from) = result.reply.unwrap();
+        assert_eq!(reply, b"ping");
+        assert_eq!(from, Address::Unix(path.clone()));
+        // Closing removed the socket file
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn send_errors() {
+        let errors = Rc::new(RefCell::new(vec![]));
+
+        let e = errors.clone();
+        crate::run_test(move || {
+            let socket = Dgram::create_socket(Default::default()).unwrap();
+            let invalid = ["[::1]:80", "unix:/tmp/x.sock", "no port", "127.0.0.1:0"];
+            for &addr in invalid.iter() {
+                let (e, s) = (e.clone(), socket.clone());
+                socket.send("data", addr, move |res| {
+                    if let Js::Error(err) = res {
+                        e.borrow_mut().push((addr, err.kind()));
+                    }
+                    if e.borrow().len() == invalid.len() {
+                        s.close();
+                    }
+                });
+            }
+        });
+
+        // The OS rejects the last one while we send it, the others don't get
+        // that far
+        let mut errors = errors.borrow().clone();
+        errors.sort();
+        let expected = [
+            ("127.0.0.1:0", io::ErrorKind::InvalidInput),
+            ("[::1]:80", io::ErrorKind::InvalidInput),
+            ("no port", io::ErrorKind::InvalidInput),
+            ("unix:/tmp/x.sock", io::ErrorKind::InvalidInput),
+        ];
+        assert_eq!(errors, expected);
+    }
+
+    #[test]
+    fn bind_errors() {
+        let results = Rc::new(RefCell::new(vec![]));
+
+        let r = results.clone();
+        crate::run_test(move || {
+            let opts = DgramOptions {
+                reuse_addr: true,
+                ..Default::default()
+            };
+            let first = Dgram::create_socket(opts.clone()).unwrap();
+            let (f, r) = (first.clone(), r.clone());
+            first.bind("127.0.0.1:0", move |_| {
+                let addr = f.local_addr().unwrap();
+                let r2 = r.clone();
+                f.bind("127.0.0.1:0", move |res| {
+                    r2.borrow_mut().push(error_kind(res))
+                });
+
+                // With SO_REUSEADDR on both another socket can share the port
+                let second = Dgram::create_socket(opts.clone()).unwrap();
+                let plain = Dgram::create_socket(Default::default()).unwrap();
+                let (f, s, p, r) = (f.clone(), second.clone(), plain.clone(), r.clone());
+                second.bind(addr, move |res| {
+                    r.borrow_mut().push(error_kind(res));
+                    let (r, plain) = (r.clone(), p.clone());
+                    p.bind(addr, move |res| {
+                        r.borrow_mut().push(error_kind(res));
+                        f.close();
+                        s.close();
+                        plain.close();
+                    });
+                });
+            });
+        });
+
+        let in_use = Some(io::ErrorKind::AddrInUse);
+        assert_eq!(*results.borrow(), [in_use, None, in_use]);
+    }
+
+    #[test]
+    fn socket_options() {
+        crate::run_test(|| {
+            let socket = Dgram::create_socket(Default::default()).unwrap();
+            let s = socket.clone();
+            socket.bind("127.0.0.1:0", move |_| {
+                let group = IpAddr::V4(Ipv4Addr::new(239, 1, 2, 3));
+                let lo = Some(Ipv4Addr::LOCALHOST);
+                s.set_broadcast(true).unwrap();
+                s.set_ttl(10).unwrap();
+                s.set_multicast_ttl(2).unwrap();
+                s.set_multicast_loopback(false).unwrap();
+                s.set_multicast_interface(Ipv4Addr::LOCALHOST).unwrap();
+                s.add_membership(group, lo).unwrap();
+                s.drop_membership(group, lo).unwrap();
+                s.close();
+                let closed = s.set_ttl(10).unwrap_err();
+                assert_eq!(closed.kind(), io::ErrorKind::NotConnected);
+            });
+
+            let opts = DgramOptions {
+                kind: SocketType::Unix,
+                ..Default::default()
+            };
+            let unix = Dgram::create_socket(opts).unwrap();
+            let e = unix.set_broadcast(true).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+            assert_eq!(unix.local_addr(), None);
+            assert!(!unix.is_closed());
+            unix.close();
+            assert!(unix.is_closed());
+        });
+    }
+
+    #[test]
+    fn unref_lets_the_loop_exit() {
+        let bound = Rc::new(Cell::new(false));
+
+        let b = bound.clone();
+        crate::run_test(move || {
+            let socket = Dgram::create_socket(Default::default()).unwrap();
+            socket.unref();
+            socket.ref_();
+            socket.unref();
+            let b = b.clone();
+            socket.bind("127.0.0.1:0", move |_| b.set(true));
+        });
+
+        assert!(bound.get());
+    }
+}
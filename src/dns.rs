@@ -0,0 +1,746 @@
+// ===== DNS =====
+// Turning host names into addresses, like the `dns` module in Node. There are
+// two ways to do it, and they can give different answers.
+//
+// `Dns::lookup` asks the system with `getaddrinfo`, which knows about
+// /etc/hosts and everything else configured in nsswitch.conf. It blocks, so
+// it runs on the threadpool just like reading a file does. This is what
+// `Net::connect` uses for host names.
+//
+// `Dns::resolve` and `Resolver` speak DNS themselves and don't need any
+// threads: the question goes out as a UDP datagram and the answer arrives as
+// an event on the main thread like any other datagram. They only ever ask DNS
+// servers, the ones in /etc/resolv.conf unless told otherwise.
+//
+//     Dns::lookup("localhost", |res| print(format!("{:?}", res)));
+//
+//     let resolver = Resolver::new(ResolverOptions {
+//         servers: vec!["127.0.0.1:5353".parse().unwrap()],
+//         ..Default::default()
+//     });
+//     resolver.resolve("example.com", RecordType::A, |res| print(format!("{:?}", res)));
+//
+// Answers which don't fit into one datagram would have to be asked for again
+// over TCP, which we don't do. A and AAAA answers practically always fit.
+
+use std::cell::RefCell;
+use std::convert::TryInto;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+
+use crate::dgram::{Dgram, DgramOptions, DgramSocket, SocketType};
+use crate::net::Address;
+use crate::util::random_bytes;
+use crate::{defer, Js, ThreadPoolTaskKind, RUNTIME};
+
+/// Used when /etc/resolv.conf doesn't name any servers, same as glibc
+const FALLBACK_SERVER: &str = "127.0.0.1:53";
+const DNS_PORT: u16 = 53;
+
+/// Flags of a query: recursion desired
+const FLAG_RD: u16 = 0x0100;
+/// Flags of an answer: this is a response, the answer is truncated
+const FLAG_QR: u16 = 0x8000;
+const FLAG_TC: u16 = 0x0200;
+const CLASS_IN: u16 = 1;
+
+type ResolveCallback = Box<dyn FnOnce(io::Result<Vec<IpAddr>>)>;
+
+thread_local! {
+    /// The servers `Dns::resolve` asks, `None` until `set_servers` is called
+    static SERVERS: RefCell<Option<Vec<SocketAddr>>> = const { RefCell::new(None) };
+}
+
+pub struct Dns;
+
+impl Dns {
+    /// Looks `host` up with `getaddrinfo` on the threadpool. `cb` gets the
+    /// addresses in the order the system prefers them. IP addresses are
+    /// handed back right away without asking anyone.
+    pub fn lookup(host: &str, cb: impl FnOnce(io::Result<Vec<IpAddr>>) + 'static) {
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return defer(move |_| cb(Ok(vec![ip])));
+        }
+
+        // The addresses aren't a `Js` value, so we hand them over through a
+        // shared slot like `Promise::from_threadpool` does
+        let slot = Arc::new(Mutex::new(None));
+        let task_slot = slot.clone();
+        let host = host.to_string();
+        let work = move || {
+            let res = (host.as_str(), 0).to_socket_addrs().and_then(|addrs| {
+                let mut ips: Vec<IpAddr> = vec![];
+                for ip in addrs.map(|addr| addr.ip()) {
+                    if !ips.contains(&ip) {
+                        ips.push(ip);
+                    }
+                }
+                match ips.is_empty() {
+                    true => Err(not_found(&host)),
+                    false => Ok(ips),
+                }
+            });
+            *task_slot.lock().unwrap() = Some(res);
+            Js::Undefined
+        };
+
+        let rt = unsafe { &mut *RUNTIME };
+        rt.register_event_threadpool(work, ThreadPoolTaskKind::Lookup, move |_| {
+            let res = slot.lock().unwrap().take().expect("lookup result");
+            cb(res);
+        });
+    }
+
+    /// Asks a DNS server for the `kind` records of `host`, without using
+    /// the threadpool. See `set_servers` for which server.
+    pub fn resolve(
+        host: &str,
+        kind: RecordType,
+        cb: impl FnOnce(io::Result<Vec<IpAddr>>) + 'static,
+    ) {
+        Resolver::new(Default::default()).resolve(host, kind, cb);
+    }
+
+    /// Makes `resolve` ask these servers instead of the ones in
+    /// /etc/resolv.conf
+    pub fn set_servers(servers: Vec<SocketAddr>) {
+        SERVERS.with(|s| *s.borrow_mut() = Some(servers));
+    }
+
+    /// The servers `resolve` asks, in order
+    pub fn servers() -> Vec<SocketAddr> {
+        SERVERS
+            .with(|s| s.borrow().clone())
+            .unwrap_or_else(system_servers)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordType {
+    /// IPv4 addresses
+    A,
+    /// IPv6 addresses
+    Aaaa,
+}
+
+impl RecordType {
+    fn code(self) -> u16 {
+        match self {
+            RecordType::A => 1,
+            RecordType::Aaaa => 28,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            RecordType::A => "A",
+            RecordType::Aaaa => "AAAA",
+        }
+    }
+}
+
+// ===== RESOLVER =====
+
+#[derive(Debug, Clone)]
+pub struct ResolverOptions {
+    /// Asked in order until one answers. Defaults to what `Dns::servers`
+    /// returns.
+    pub servers: Vec<SocketAddr>,
+    /// How long we wait for an answer before we ask again
+    pub timeout_ms: u64,
+    /// How many times we ask each server before we give up
+    pub tries: usize,
+}
+
+impl Default for ResolverOptions {
+    fn default() -> Self {
+        ResolverOptions {
+            servers: Dns::servers(),
+            timeout_ms: 5000,
+            tries: 2,
+        }
+    }
+}
+
+/// Sends DNS queries over UDP. Every question gets a socket of its own on a
+/// random port and a random id, and we only take an answer which comes from
+/// the server we asked and has the right id.
+#[derive(Clone)]
+pub struct Resolver {
+    opts: ResolverOptions,
+}
+
+impl Resolver {
+    pub fn new(opts: ResolverOptions) -> Self {
+        Resolver { opts }
+    }
+
+    /// `cb` gets the addresses in the answer, or an error if the name
+    /// doesn't exist, has no records of this kind, or no server answered
+    pub fn resolve(
+        &self,
+        host: &str,
+        kind: RecordType,
+        cb: impl FnOnce(io::Result<Vec<IpAddr>>) + 'static,
+    ) {
+        let query = Query {
+            name: host.trim_end_matches('.').to_string(),
+            kind,
+            opts: self.opts.clone(),
+            attempts: 0,
+            pending: None,
+            timer: None,
+            cb: Some(Box::new(cb)),
+        };
+        ask(&Rc::new(RefCell::new(query)));
+    }
+}
+
+struct Query {
+    name: String,
+    kind: RecordType,
+    opts: ResolverOptions,
+    /// How many times we have asked so far
+    attempts: usize,
+    /// The socket, the server and the id of the question we're waiting for
+    /// an answer to
+    pending: Option<(DgramSocket, SocketAddr, u16)>,
+    timer: Option<usize>,
+    /// `None` once we have an answer
+    cb: Option<ResolveCallback>,
+}
+
+impl Query {
+    /// Forgets the question we're waiting for. The socket has to be closed
+    /// once we don't borrow the query anymore, since closing it calls the
+    /// callback of its send.
+    fn stop(&mut self) -> Option<DgramSocket> {
+        if let Some(timer) = self.timer.take() {
+            crate::clear_timeout(timer);
+        }
+        self.pending.take().map(|(socket, _, _)| socket)
+    }
+}
+
+/// Asks the next server, or gives up if we have asked them all often enough
+fn ask(query: &Rc<RefCell<Query>>) {
+    if let Some(socket) = query.borrow_mut().stop() {
+        socket.close();
+    }
+
+    let (server, message, attempt) = {
+        let mut q = query.borrow_mut();
+        if q.cb.is_none() {
+            return;
+        }
+
+        if q.opts.servers.is_empty() || q.attempts >= q.opts.servers.len() * q.opts.tries {
+            let err = io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("no DNS server answered for {}", q.name),
+            );
+            drop(q);
+            return finish(query, Err(err));
+        }
+
+        let server = q.opts.servers[q.attempts % q.opts.servers.len()];
+        q.attempts += 1;
+        let id = u16::from_ne_bytes(random_bytes());
+        let message = match build_query(id, &q.name, q.kind) {
+            Ok(message) => message,
+            Err(e) => {
+                drop(q);
+                return finish(query, Err(e));
+            }
+        };
+
+        let kind = match server {
+            SocketAddr::V4(_) => SocketType::Udp4,
+            SocketAddr::V6(_) => SocketType::Udp6,
+        };
+        let socket = match Dgram::create_socket(DgramOptions {
+            kind,
+            ..Default::default()
+        }) {
+            Ok(socket) => socket,
+            Err(e) => {
+                drop(q);
+                return finish(query, Err(e));
+            }
+        };
+        q.pending = Some((socket, server, id));
+        (server, message, q.attempts)
+    };
+
+    // Everything below checks that it's still about this attempt, since an
+    // older socket fails its send when we close it
+    let current = {
+        let query = query.clone();
+        move || {
+            let q = query.borrow();
+            q.attempts == attempt && q.pending.is_some()
+        }
+    };
+
+    let socket = query.borrow().pending.as_ref().unwrap().0.clone();
+    let (q, is_current) = (query.clone(), current.clone());
+    socket.on_message(move |msg, from| {
+        if is_current() && from == Address::Ip(server) {
+            on_answer(&q, &msg);
+        }
+    });
+    // Receiving failed, maybe the next server has more luck. A server nobody
+    // listens on just never answers, the socket isn't connected to hear
+    // about it.
+    let (q, is_current) = (query.clone(), current.clone());
+    socket.on_error(move |_| {
+        if is_current() {
+            ask(&q);
+        }
+    });
+
+    // The timer goes first since a send can fail right away, in which case
+    // we're on to the next attempt before `send` returns
+    let (q, is_current) = (query.clone(), current.clone());
+    let timeout_ms = query.borrow().opts.timeout_ms;
+    let timer = crate::set_timeout(timeout_ms, move |_| {
+        if is_current() {
+            q.borrow_mut().timer = None;
+            ask(&q);
+        }
+    });
+    query.borrow_mut().timer = Some(timer);
+
+    let q = query.clone();
+    socket.send(message, server, move |res| {
+        if let Js::Error(_) = res {
+            if current() {
+                ask(&q);
+            }
+        }
+    });
+}
+
+fn on_answer(query: &Rc<RefCell<Query>>, msg: &[u8]) {
+    let res = {
+        let q = query.borrow();
+        let id = match q.pending {
+            Some((_, _, id)) => id,
+            None => return,
+        };
+        parse_answer(msg, id, &q.name, q.kind)
+    };
+
+    // `None` means it's not an answer to our question
+    if let Some(res) = res {
+        finish(query, res);
+    }
+}
+
+fn finish(query: &Rc<RefCell<Query>>, res: io::Result<Vec<IpAddr>>) {
+    let (socket, cb) = {
+        let mut q = query.borrow_mut();
+        (q.stop(), q.cb.take())
+    };
+    if let Some(socket) = socket {
+        socket.close();
+    }
+    if let Some(cb) = cb {
+        cb(res);
+    }
+}
+
+// ===== MESSAGES =====
+
+/// A question for the `kind` records of `name`, with recursion desired
+fn build_query(id: u16, name: &str, kind: RecordType) -> io::Result<Vec<u8>> {
+    let invalid = || {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid host name: {}", name),
+        )
+    };
+    if name.is_empty() || name.len() > 253 {
+        return Err(invalid());
+    }
+
+    let mut msg = Vec::with_capacity(18 + name.len());
+    msg.extend_from_slice(&id.to_be_bytes());
+    msg.extend_from_slice(&FLAG_RD.to_be_bytes());
+    // One question, no answers, authority or additional records
+    msg.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+
+    for label in name.split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(invalid());
+        }
+        msg.push(label.len() as u8);
+        msg.extend_from_slice(label.as_bytes());
+    }
+    msg.push(0);
+    msg.extend_from_slice(&kind.code().to_be_bytes());
+    msg.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(msg)
+}
+
+/// The addresses in the answer to question `id`. `None` if `msg` isn't an
+/// answer to it.
+fn parse_answer(
+    msg: &[u8],
+    id: u16,
+    name: &str,
+    kind: RecordType,
+) -> Option<io::Result<Vec<IpAddr>>> {
+    let mut reader = Reader { msg, pos: 0 };
+    if reader.u16()? != id {
+        return None;
+    }
+    let flags = reader.u16()?;
+    if flags & FLAG_QR == 0 {
+        return None;
+    }
+
+    let err = |kind, msg: String| Some(Err(io::Error::new(kind, msg)));
+    if flags & FLAG_TC != 0 {
+        return err(
+            io::ErrorKind::InvalidData,
+            format!("the answer for {} is too big for UDP", name),
+        );
+    }
+    match flags & 0x000f {
+        0 => (),
+        3 => return Some(Err(not_found(name))),
+        2 => return err(io::ErrorKind::Other, format!("server failure for {}", name)),
+        5 => {
+            return err(
+                io::ErrorKind::PermissionDenied,
+                format!("query for {} refused", name),
+            )
+        }
+        code => {
+            return err(
+                io::ErrorKind::Other,
+                format!("DNS error {} for {}", code, name),
+            )
+        }
+    }
+
+    let malformed = || io::Error::new(io::ErrorKind::InvalidData, "malformed DNS answer");
+    let questions = reader.u16()?;
+    let answers = reader.u16()?;
+    // Skip the counts of authority and additional records
+    reader.take(4)?;
+
+    let mut parse = || {
+        for _ in 0..questions {
+            reader.skip_name()?;
+            reader.take(4)?;
+        }
+
+        let mut ips = vec![];
+        for _ in 0..answers {
+            reader.skip_name()?;
+            let (rtype, class) = (reader.u16()?, reader.u16()?);
+            // Time to live, we don't cache
+            reader.take(4)?;
+            let len = reader.u16()? as usize;
+            let data = reader.take(len)?;
+
+            // A CNAME chain comes before the records it leads to, which are
+            // all we care about
+            if class != CLASS_IN || rtype != kind.code() {
+                continue;
+            }
+            match kind {
+                RecordType::A if len == 4 => {
+                    let octets: [u8; 4] = data.try_into().ok()?;
+                    ips.push(IpAddr::V4(Ipv4Addr::from(octets)));
+                }
+                RecordType::Aaaa if len == 16 => {
+                    let octets: [u8; 16] = data.try_into().ok()?;
+                    ips.push(IpAddr::V6(Ipv6Addr::from(octets)));
+                }
+                _ => return None,
+            }
+        }
+        Some(ips)
+    };
+
+    let res = match parse() {
+        Some(ips) if ips.is_empty() => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no {} records for {}", kind.name(), name),
+        )),
+        Some(ips) => Ok(ips),
+        None => Err(malformed()),
+    };
+    Some(res)
+}
+
+/// Reads a message front to back. Everything returns `None` at the end of
+/// the message.
+struct Reader<'a> {
+    msg: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        let bytes = self.msg.get(self.pos..self.pos.checked_add(n)?)?;
+        self.pos += n;
+        Some(bytes)
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        let bytes = self.take(2)?;
+        Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    /// Names are labels which end with an empty one, or with a pointer to
+    /// the rest of the name somewhere else in the message
+    fn skip_name(&mut self) -> Option<()> {
+        loop {
+            let len = self.take(1)?[0];
+            match len {
+                0 => return Some(()),
+                len if len & 0xc0 == 0xc0 => {
+                    self.take(1)?;
+                    return Some(());
+                }
+                len => {
+                    self.take(len as usize)?;
+                }
+            }
+        }
+    }
+}
+
+fn not_found(host: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("{} not found", host))
+}
+
+/// The `nameserver` lines in /etc/resolv.conf
+fn system_servers() -> Vec<SocketAddr> {
+    let conf = fs::read_to_string("/etc/resolv.conf").unwrap_or_default();
+    let servers: Vec<SocketAddr> = conf
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("nameserver"))
+        .filter_map(|ip| ip.trim().parse::<IpAddr>().ok())
+        .map(|ip| SocketAddr::new(ip, DNS_PORT))
+        .collect();
+
+    match servers.is_empty() {
+        true => vec![FALLBACK_SERVER.parse().unwrap()],
+        false => servers,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    /// How our stub server answers: with records for `example.test` and
+    /// `v6.test`, NXDOMAIN for `missing.test`, a truncated answer for
+    /// `big.test` and not at all for `silent.test`
+    fn stub_answer(query: &[u8]) -> Option<Vec<u8>> {
+        // The question starts after the header and is all there is
+        let mut reader = Reader {
+            msg: query,
+            pos: 12,
+        };
+        let mut labels = vec![];
+        loop {
+            let len = reader.take(1)?[0] as usize;
+            if len == 0 {
+                break;
+            }
+            labels.push(String::from_utf8(reader.take(len)?.to_vec()).ok()?);
+        }
+        let kind = reader.u16()?;
+        reader.take(2)?;
+
+        // Recursion available, on top of what the query asked for
+        let mut flags = FLAG_QR | FLAG_RD | 0x0080;
+        // A CNAME pointing back at the question comes first, like a real
+        // server answering for an alias
+        let cname = (5, vec![0xc0, 0x0c]);
+        let mut answers: Vec<(u16, Vec<u8>)> = vec![];
+        match (labels.join(".").as_str(), kind) {
+            ("example.test", 1) => {
+                answers.push(cname);
+                answers.push((1, vec![10, 0, 0, 1]));
+                answers.push((1, vec![10, 0, 0, 2]));
+            }
+            ("v6.test", 28) => {
+                answers.push((28, Ipv6Addr::LOCALHOST.octets().to_vec()));
+            }
+            ("example.test", _) | ("v6.test", _) => (),
+            ("missing.test", _) => flags |= 3,
+            ("big.test", _) => flags |= FLAG_TC,
+            _ => return None,
+        }
+
+        let mut msg = query[..2].to_vec();
+        msg.extend_from_slice(&flags.to_be_bytes());
+        msg.extend_from_slice(&[0, 1, 0, answers.len() as u8, 0, 0, 0, 0]);
+        msg.extend_from_slice(&query[12..reader.pos]);
+        for (rtype, data) in answers {
+            msg.extend_from_slice(&[0xc0, 0x0c]);
+            msg.extend_from_slice(&rtype.to_be_bytes());
+            msg.extend_from_slice(&CLASS_IN.to_be_bytes());
+            msg.extend_from_slice(&[0, 0, 0, 60]);
+            msg.extend_from_slice(&(data.len() as u16).to_be_bytes());
+            msg.extend_from_slice(&data);
+        }
+        Some(msg)
+    }
+
+    /// Starts a stub server on 127.0.0.1 and calls `ask` with its address
+    /// and the callback for the answer. Returns the result and how many
+    /// questions the stub got.
+    fn with_stub(
+        ask: impl Fn(SocketAddr, ResolveCallback) + 'static,
+    ) -> (io::Result<Vec<IpAddr>>, usize) {
+        let result = Rc::new(RefCell::new(None));
+        let questions = Rc::new(Cell::new(0));
+
+        let (r, q, ask) = (result.clone(), questions.clone(), Rc::new(ask));
+        crate::run_test(move || {
+            let stub = Dgram::create_socket(DgramOptions::default()).unwrap();
+            let (s, q) = (stub.clone(), q.clone());
+            stub.on_message(move |msg, from| {
+                q.set(q.get() + 1);
+                if let Some(answer) = stub_answer(&msg) {
+                    s.send(answer, from, |_| ());
+                }
+            });
+
+            let (s, r, ask) = (stub.clone(), r.clone(), ask.clone());
+            stub.bind("127.0.0.1:0", move |_| {
+                let (s2, r) = (s.clone(), r.clone());
+                ask(
+                    s.local_addr().unwrap(),
+                    Box::new(move |res| {
+                        *r.borrow_mut() = Some(res);
+                        s2.close();
+                    }),
+                );
+            });
+        });
+
+        let result = result
+            .borrow_mut()
+            .take()
+            .expect("resolve never called back");
+        (result, questions.get())
+    }
+
+    /// Resolves `host` with the stub server as the only server
+    fn resolve(host: &str, kind: RecordType) -> (io::Result<Vec<IpAddr>>, usize) {
+        let host = host.to_string();
+        with_stub(move |server, cb| {
+            let resolver = Resolver::new(ResolverOptions {
+                servers: vec![server],
+                timeout_ms: 50,
+                tries: 2,
+            });
+            resolver.resolve(&host, kind, cb);
+        })
+    }
+
+    /// Looks `host` up with `Dns::lookup`
+    fn lookup(host: &'static str) -> io::Result<Vec<IpAddr>> {
+        let result = Rc::new(RefCell::new(None));
+        let r = result.clone();
+        crate::run_test(move || {
+            let r = r.clone();
+            Dns::lookup(host, move |res| *r.borrow_mut() = Some(res));
+        });
+        let result = result.borrow_mut().take();
+        result.expect("lookup never called back")
+    }
+
+    #[test]
+    fn resolves_records() {
+        let (res, questions) = resolve("example.test", RecordType::A);
+        let ips: Vec<IpAddr> = vec![[10, 0, 0, 1].into(), [10, 0, 0, 2].into()];
+        assert_eq!(res.unwrap(), ips);
+        assert_eq!(questions, 1);
+
+        let (res, _) = resolve("v6.test", RecordType::Aaaa);
+        assert_eq!(res.unwrap(), vec![IpAddr::V6(Ipv6Addr::LOCALHOST)]);
+
+        let (res, _) = resolve("example.test", RecordType::Aaaa);
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn nxdomain() {
+        let (res, questions) = resolve("missing.test", RecordType::A);
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert_eq!(questions, 1);
+    }
+
+    #[test]
+    fn truncated_answer() {
+        let (res, _) = resolve("big.test", RecordType::A);
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn times_out_after_all_tries() {
+        let (res, questions) = resolve("silent.test", RecordType::A);
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::TimedOut);
+        assert_eq!(questions, 2);
+    }
+
+    #[test]
+    fn invalid_host_names() {
+        let long = "a".repeat(64);
+        for &host in &["", "a..test", ".", ".test", &long] {
+            let (res, questions) = resolve(host, RecordType::A);
+            assert_eq!(res.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+            assert_eq!(questions, 0);
+        }
+        // A trailing dot is fine
+        let (res, _) = resolve("example.test.", RecordType::A);
+        assert_eq!(res.unwrap().len(), 2);
+    }
+
+    #[test]
+    fn dns_resolve_asks_the_servers_we_set() {
+        // Whatever /etc/resolv.conf says, there's always a server
+        let system = Dns::servers();
+        assert!(!system.is_empty());
+        assert!(system.iter().all(|server| server.port() == DNS_PORT));
+
+        let (res, questions) = with_stub(|server, cb| {
+            Dns::set_servers(vec![server]);
+            assert_eq!(Dns::servers(), vec![server]);
+            Dns::resolve("v6.test", RecordType::Aaaa, cb);
+        });
+        assert_eq!(res.unwrap(), vec![IpAddr::V6(Ipv6Addr::LOCALHOST)]);
+        assert_eq!(questions, 1);
+    }
+
+    #[test]
+    fn lookup_hosts() {
+        let ips = lookup("localhost").unwrap();
+        assert!(ips.iter().all(IpAddr::is_loopback));
+        assert!(!ips.is_empty());
+
+        // IP addresses don't need the threadpool
+        assert_eq!(
+            lookup("10.0.0.1").unwrap(),
+            vec![IpAddr::from([10, 0, 0, 1])]
+        );
+        assert_eq!(
+            lookup("::1").unwrap(),
+            vec![IpAddr::V6(Ipv6Addr::LOCALHOST)]
+        );
+    }
+}
@@ -0,0 +1,600 @@
+// ===== FETCH =====
+// The `fetch` API from the browser (and Node 18+) on top of our HTTP client.
+// `fetch` returns a `Promise` which resolves as soon as the head of the
+// response has arrived. The body is still a stream at that point, and
+// `text()`, `json()` and `bytes()` return a new promise which resolves once
+// all of it is read:
+//
+//     async fn get_user() -> io::Result<()> {
+//         let res = fetch::fetch("http://localhost:8080/user", RequestInit::default()).await?;
+//         if !res.ok() {
+//             print(format!("failed: {}", res.status));
+//         }
+//         let user = res.json().await?;
+//         print(format!("name: {}", user.get("name").unwrap()));
+//         Ok(())
+//     }
+//
+//     spawn(async { get_user().await.unwrap() });
+//
+// A request can be aborted with an `AbortController`, or with
+// `AbortSignal::timeout` if it shouldn't take longer than some deadline.
+
+use std::cell::RefCell;
+use std::io;
+use std::rc::Rc;
+
+pub use crate::http::Headers;
+use crate::http::{self, IncomingResponse, RequestOptions, Url};
+use crate::json::Json;
+use crate::promise::Promise;
+use crate::stream::Readable;
+use crate::{set_event_ref, set_timeout, Js};
+
+/// Browsers give up after 20 redirects, so we do too
+const MAX_REDIRECTS: usize = 20;
+
+/// Starts the request right away. The promise resolves with the response
+/// once its head has arrived, whatever the status code is. It only fails if
+/// we don't get a response at all, or if the request is aborted.
+pub fn fetch(input: impl Into<Request>, init: RequestInit) -> Promise<io::Result<Response>> {
+    let mut request = input.into();
+    request.apply(init);
+    Promise::new(move |resolve| request.send(resolve))
+}
+
+// ===== REQUEST =====
+
+#[derive(Clone)]
+pub struct Request {
+    pub method: String,
+    pub url: String,
+    pub headers: Headers,
+    /// GET and HEAD requests can't have a body
+    pub body: Option<Vec<u8>>,
+    pub redirect: Redirect,
+    pub signal: Option<AbortSignal>,
+}
+
+/// The options `fetch` and `Request::new` take. Anything left as `None`
+/// keeps its default, or what the `Request` passed to `fetch` has.
+#[derive(Clone, Default)]
+pub struct RequestInit {
+    pub method: Option<String>,
+    pub headers: Option<Headers>,
+    pub body: Option<Vec<u8>>,
+    pub redirect: Option<Redirect>,
+    pub signal: Option<AbortSignal>,
+}
+
+/// What `fetch` does when the response is a redirect
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Redirect {
+    /// Follows the redirect and resolves with the final response
+    #[default]
+    Follow,
+    /// Fails with an error instead
+    Error,
+    /// Resolves with the redirect itself
+    Manual,
+}
+
+impl Request {
+    pub fn new(url: impl Into<String>, init: RequestInit) -> Self {
+        let mut request = Request {
+            method: "GET".to_string(),
+            url: url.into(),
+            headers: Headers::new(),
+            body: None,
+            redirect: Redirect::default(),
+            signal: None,
+        };
+        request.apply(init);
+        request
+    }
+
+    fn apply(&mut self, init: RequestInit) {
+        if let Some(method) = init.method {
+            self.method = method.to_ascii_uppercase();
+        }
+        if let Some(headers) = init.headers {
+            self.headers = headers;
+        }
+        if init.body.is_some() {
+            self.body = init.body;
+        }
+        if let Some(redirect) = init.redirect {
+            self.redirect = redirect;
+        }
+        if init.signal.is_some() {
+            self.signal = init.signal;
+        }
+    }
+
+    fn send(self, resolve: Box<dyn FnOnce(io::Result<Response>)>) {
+        if let Some(reason) = self.signal.as_ref().and_then(AbortSignal::reason) {
+            return resolve(Err(reason));
+        }
+        if self.body.is_some() && matches!(self.method.as_str(), "GET" | "HEAD") {
+            let msg = format!("{} requests can't have a body", self.method);
+            return resolve(Err(io::Error::new(io::ErrorKind::InvalidInput, msg)));
+        }
+
+        // We compare the normalized URLs to see if we ended up somewhere else
+        let original_url = Url::parse(&self.url).map(|url| url.to_string());
+        let opts = RequestOptions {
+            method: self.method,
+            url: self.url,
+            headers: self.headers,
+            body: self.body.unwrap_or_default(),
+            max_redirects: match self.redirect {
+                Redirect::Follow => MAX_REDIRECTS,
+                Redirect::Error | Redirect::Manual => 0,
+            },
+            ..Default::default()
+        };
+
+        let redirect = self.redirect;
+        let signal = self.signal.clone();
+        let client = http::request_stream(opts, move |res| {
+            let res = match res {
+                Ok(res) if redirect == Redirect::Error && is_redirect(&res) => {
+                    res.body.destroy(None);
+                    Err(io::Error::other(format!(
+                        "redirect to {} is not allowed",
+                        res.headers.get("Location").unwrap_or_default()
+                    )))
+                }
+                Ok(res) => {
+                    let redirected = original_url.ok().as_ref() != Some(&res.url);
+                    Ok(Response::new(res, redirected, signal))
+                }
+                Err(e) => Err(e),
+            };
+            resolve(res);
+        });
+
+        if let Some(signal) = self.signal {
+            signal.on_abort(move |reason| client.destroy(Some(reason)));
+        }
+    }
+}
+
+impl From<&str> for Request {
+    fn from(url: &str) -> Self {
+        Request::new(url, RequestInit::default())
+    }
+}
+
+impl From<String> for Request {
+    fn from(url: String) -> Self {
+        Request::new(url, RequestInit::default())
+    }
+}
+
+fn is_redirect(res: &IncomingResponse) -> bool {
+    matches!(res.status, 301 | 302 | 303 | 307 | 308) && res.headers.has("Location")
+}
+
+// ===== RESPONSE =====
+
+pub struct Response {
+    /// The URL of the last request if we followed redirects
+    pub url: String,
+    pub status: u16,
+    pub status_text: String,
+    pub headers: Headers,
+    /// True if `url` is not the URL we asked for
+    pub redirected: bool,
+    /// The body as it arrives. `text()`, `json()` and `bytes()` read it for
+    /// you, so only use it directly if you want to handle it chunk by chunk.
+    pub body: Readable,
+    /// The signal of the request, which fails reading the body once it's
+    /// aborted
+    signal: Option<AbortSignal>,
+}
+
+impl Response {
+    fn new(res: IncomingResponse, redirected: bool, signal: Option<AbortSignal>) -> Self {
+        Response {
+            url: res.url,
+            status: res.status,
+            status_text: res.status_text,
+            headers: res.headers,
+            redirected,
+            body: res.body,
+            signal,
+        }
+    }
+
+    /// True for a 2xx status
+    pub fn ok(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+
+    pub fn bytes(self) -> Promise<io::Result<Vec<u8>>> {
+        self.consume(Ok)
+    }
+
+    /// Invalid UTF-8 is replaced, same as in the browser
+    pub fn text(self) -> Promise<io::Result<String>> {
+        self.consume(|body| Ok(String::from_utf8_lossy(&body).into_owned()))
+    }
+
+    pub fn json(self) -> Promise<io::Result<Json>> {
+        self.consume(|body| Json::parse(&String::from_utf8_lossy(&body)))
+    }
+
+    /// The body can only be read once, which is why these take `self`
+    fn consume<T: 'static>(
+        self,
+        f: impl FnOnce(Vec<u8>) -> io::Result<T> + 'static,
+    ) -> Promise<io::Result<T>> {
+        Promise::new(move |resolve| {
+            // The body was destroyed when the signal fired, so it won't end
+            if let Some(reason) = self.signal.as_ref().and_then(AbortSignal::reason) {
+                return resolve(Err(reason));
+            }
+            self.body.read_to_end(move |data| {
+                resolve(match data {
+                    Js::Bytes(body) => f(body),
+                    Js::Error(e) => Err(e),
+                    _ => unreachable!("read_to_end gives bytes or an error"),
+                })
+            })
+        })
+    }
+}
+
+// ===== ABORT =====
+
+/// Aborts the requests its signal was passed to
+#[derive(Default)]
+pub struct AbortController {
+    signal: AbortSignal,
+}
+
+impl AbortController {
+    pub fn new() -> Self {
+        AbortController::default()
+    }
+
+    pub fn signal(&self) -> AbortSignal {
+        self.signal.clone()
+    }
+
+    /// Pending requests fail with an `Interrupted` error. If the response has
+    /// arrived already, reading its body fails instead.
+    pub fn abort(&self) {
+        self.signal
+            .abort(io::ErrorKind::Interrupted, "the operation was aborted");
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct AbortSignal {
+    inner: Rc<RefCell<AbortState>>,
+}
+
+#[derive(Default)]
+struct AbortState {
+    /// `io::Error` isn't `Clone`, so we keep what we need to make a new one
+    /// for every listener
+    reason: Option<(io::ErrorKind, String)>,
+    listeners: Vec<Box<dyn FnOnce(io::Error)>>,
+}
+
+impl AbortSignal {
+    /// A signal which aborts by itself with a `TimedOut` error after `ms`.
+    /// The timer doesn't keep the program alive.
+    pub fn timeout(ms: u64) -> Self {
+        let signal = AbortSignal::default();
+        let s = signal.clone();
+        let timer = set_timeout(ms, move |_| {
+            s.abort(io::ErrorKind::TimedOut, "the operation timed out")
+        });
+        set_event_ref(timer, false);
+        signal
+    }
+
+    pub fn aborted(&self) -> bool {
+        self.inner.borrow().reason.is_some()
+    }
+
+    /// The error the signal was aborted with
+    pub fn reason(&self) -> Option<io::Error> {
+        let state = self.inner.borrow();
+        let (kind, msg) = state.reason.as_ref()?;
+        Some(io::Error::new(*kind, msg.clone()))
+    }
+
+    /// Calls `cb` with the reason when the signal is aborted. Just like an
+    /// `abort` listener in javascript it's never called if the signal is
+    /// aborted already.
+    pub fn on_abort(&self, cb: impl FnOnce(io::Error) + 'static) {
+        let mut state = self.inner.borrow_mut();
+        if state.reason.is_none() {
+            state.listeners.push(Box::new(cb));
+        }
+    }
+
+    fn abort(&self, kind: io::ErrorKind, msg: &str) {
+        let listeners = {
+            let mut state = self.inner.borrow_mut();
+            if state.reason.is_some() {
+                return;
+            }
+            state.reason = Some((kind, msg.to_string()));
+            std::mem::take(&mut state.listeners)
+        };
+
+        for cb in listeners {
+            cb(io::Error::new(kind, msg));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http_server::{HttpServer, Router};
+    use std::future::Future;
+
+    fn routes() -> Router {
+        Router::new()
+            .get("/hello", |_req, res| {
+                res.set_header("Content-Type", "text/plain");
+                res.set_header("X-Test", "yes");
+                res.end("hello");
+            })
+            .get("/json", |_req, res| {
+                res.end(r#"{"name": "Ann", "age": 7, "admin": false, "boss": null, "tags": ["a"]}"#)
+            })
+            .get("/status/:code", |req, res| {
+                res.set_status(req.param("code").unwrap().parse().unwrap());
+                res.end("");
+            })
+            .get("/redirect", |_req, res| {
+                res.set_status(302);
+                res.set_header("Location", "/hello");
+                res.end("");
+            })
+            .post("/echo", |req, res| {
+                let method = req.method.clone();
+                req.read_body(move |body| {
+                    let body = String::from_utf8(body.into_bytes().unwrap()).unwrap();
+                    res.end(format!("{} {}", method, body));
+                });
+            })
+            .get("/chunks", |_req, res| {
+                res.write("a");
+                crate::set_timeout(20, move |_| {
+                    res.write("b");
+                    crate::set_timeout(20, move |_| res.end("c"));
+                });
+            })
+            .get("/slow", |_req, res| {
+                crate::set_timeout(200, move |_| res.end("finally"));
+            })
+    }
+
+    /// Runs the future `f` returns on a new runtime with a server listening,
+    /// and returns what it resolved to. `f` gets the URL of the server.
+    fn with_server<T: 'static, F: Future<Output = T> + 'static>(
+        f: impl FnOnce(String) -> F + 'static,
+    ) -> T {
+        let f = RefCell::new(Some(f));
+        let result = Rc::new(RefCell::new(None));
+        let r = result.clone();
+        crate::run_test(move || {
+            let server = HttpServer::new(routes().into_handler());
+            let (s, r, f) = (server.clone(), r.clone(), f.borrow_mut().take().unwrap());
+            server.listen("127.0.0.1:0", move |_| {
+                let fut = f(format!("http://{}", s.local_addr().unwrap()));
+                crate::spawn(async move {
+                    *r.borrow_mut() = Some(fut.await);
+                    s.close(|_| ());
+                });
+            });
+        });
+        let result = result.borrow_mut().take();
+        result.expect("the future never resolved")
+    }
+
+    #[test]
+    fn status_and_headers() {
+        let (hello, missing) = with_server(|url| async move {
+            let hello = fetch(format!("{}/hello", url), RequestInit::default()).await?;
+            let missing = fetch(url + "/status/404", RequestInit::default()).await?;
+            io::Result::Ok((hello, missing))
+        })
+        .unwrap();
+
+        assert_eq!(hello.status, 200);
+        assert_eq!(hello.status_text, "OK");
+        assert!(hello.ok());
+        assert!(!hello.redirected);
+        assert_eq!(hello.headers.get("x-test"), Some("yes"));
+        assert_eq!(hello.headers.get("Content-Type"), Some("text/plain"));
+
+        // Error statuses are responses too
+        assert_eq!(missing.status, 404);
+        assert!(!missing.ok());
+    }
+
+    #[test]
+    fn read_the_body() {
+        let (text, json, bytes) = with_server(|url| async move {
+            let init = RequestInit::default;
+            let text = fetch(format!("{}/hello", url), init())
+                .await?
+                .text()
+                .await?;
+            let json = fetch(format!("{}/json", url), init()).await?.json().await?;
+            let bytes = fetch(url + "/hello", init()).await?.bytes().await?;
+            io::Result::Ok((text, json, bytes))
+        })
+        .unwrap();
+
+        assert_eq!(text, "hello");
+        assert_eq!(bytes, b"hello");
+        assert_eq!(json.get("name").and_then(Json::as_str), Some("Ann"));
+        assert_eq!(json.get("age").and_then(Json::as_f64), Some(7.0));
+        assert_eq!(json.get("admin").and_then(Json::as_bool), Some(false));
+        assert!(json.get("boss").unwrap().is_null());
+        let tags = json.get("tags").and_then(Json::as_array).unwrap();
+        assert_eq!(tags, [Json::String("a".to_string())]);
+    }
+
+    #[test]
+    fn invalid_json_rejects() {
+        let e = with_server(|url| async move {
+            let res = fetch(url + "/hello", RequestInit::default()).await?;
+            res.json().await.map(|_| ())
+        })
+        .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn body_stream() {
+        let chunks = with_server(|url| async move {
+            let res = fetch(url + "/chunks", RequestInit::default()).await?;
+            let chunks = Rc::new(RefCell::new(vec![]));
+            let c = chunks.clone();
+            res.body
+                .on_data(move |chunk| c.borrow_mut().push(chunk.into_bytes().unwrap()));
+            Promise::new(|resolve| {
+                let mut resolve = Some(resolve);
+                res.body.on_end(move |_| resolve.take().unwrap()(()));
+            })
+            .await;
+            let chunks = chunks.borrow().clone();
+            io::Result::Ok(chunks)
+        })
+        .unwrap();
+
+        assert_eq!(chunks, [b"a", b"b", b"c"]);
+    }
+
+    #[test]
+    fn request_with_a_body() {
+        let (echo, get) = with_server(|url| async move {
+            let init = RequestInit {
+                method: Some("post".to_string()),
+                body: Some(b"hi there".to_vec()),
+                ..Default::default()
+            };
+            let request = Request::new(format!("{}/echo", url), init);
+            let echo = fetch(request, RequestInit::default()).await?.text().await?;
+
+            let init = RequestInit {
+                body: Some(b"nope".to_vec()),
+                ..Default::default()
+            };
+            let get = fetch(url + "/echo", init).await.map(|_| ());
+            io::Result::Ok((echo, get))
+        })
+        .unwrap();
+
+        assert_eq!(echo, "POST hi there");
+        assert_eq!(get.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn redirects() {
+        let results = with_server(|url| async move {
+            let mut results = vec![];
+            for redirect in [Redirect::Follow, Redirect::Manual, Redirect::Error] {
+                let init = RequestInit {
+                    redirect: Some(redirect),
+                    ..Default::default()
+                };
+                let res = fetch(format!("{}/redirect", url), init).await;
+                results.push(res.map(|res| (res.status, res.redirected, res.url)));
+            }
+            results
+        });
+
+        let mut results = results.into_iter();
+        let (status, redirected, url) = results.next().unwrap().unwrap();
+        assert_eq!((status, redirected), (200, true));
+        assert!(url.ends_with("/hello"), "{}", url);
+
+        let (status, redirected, url) = results.next().unwrap().unwrap();
+        assert_eq!((status, redirected), (302, false));
+        assert!(url.ends_with("/redirect"), "{}", url);
+
+        let e = results.next().unwrap().unwrap_err();
+        assert!(e.to_string().contains("not allowed"), "{}", e);
+    }
+
+    #[test]
+    fn abort_in_flight() {
+        let (result, aborted, reason) = with_server(|url| async move {
+            let controller = AbortController::new();
+            let init = RequestInit {
+                signal: Some(controller.signal()),
+                ..Default::default()
+            };
+            let signal = controller.signal();
+            let listened = Rc::new(RefCell::new(None));
+            let l = listened.clone();
+            signal.on_abort(move |e| *l.borrow_mut() = Some(e.kind()));
+
+            crate::set_timeout(20, move |_| controller.abort());
+            let result = fetch(url + "/slow", init).await.map(|_| ());
+            let kind = *listened.borrow();
+            (
+                result,
+                signal.aborted(),
+                signal.reason().map(|e| e.kind()).zip(kind),
+            )
+        });
+
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::Interrupted);
+        assert!(aborted);
+        let interrupted = io::ErrorKind::Interrupted;
+        assert_eq!(reason, Some((interrupted, interrupted)));
+    }
+
+    #[test]
+    fn abort_while_reading_the_body() {
+        let result = with_server(|url| async move {
+            let controller = AbortController::new();
+            let init = RequestInit {
+                signal: Some(controller.signal()),
+                ..Default::default()
+            };
+            let res = fetch(url + "/chunks", init).await?;
+            controller.abort();
+            res.text().await
+        });
+
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::Interrupted);
+    }
+
+    #[test]
+    fn abort_signals() {
+        let (timed_out, already) = with_server(|url| async move {
+            let init = RequestInit {
+                signal: Some(AbortSignal::timeout(20)),
+                ..Default::default()
+            };
+            let timed_out = fetch(format!("{}/slow", url), init).await.map(|_| ());
+
+            // An aborted signal fails the request before it's sent
+            let controller = AbortController::new();
+            controller.abort();
+            let init = RequestInit {
+                signal: Some(controller.signal()),
+                ..Default::default()
+            };
+            let already = fetch(url + "/hello", init).await.map(|_| ());
+            (timed_out, already)
+        });
+
+        assert_eq!(timed_out.unwrap_err().kind(), io::ErrorKind::TimedOut);
+        assert_eq!(already.unwrap_err().kind(), io::ErrorKind::Interrupted);
+    }
+}
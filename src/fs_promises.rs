@@ -0,0 +1,274 @@
+// ===== FS PROMISES =====
+// The same as `fs.promises` in Node. Every function starts the work on the
+// threadpool right away and returns a `Promise` which resolves on the event
+// loop thread, so async code spawned on the runtime can use `?` instead of
+// nesting callbacks:
+//
+//     async fn copy() -> io::Result<()> {
+//         let text = fs_promises::read_to_string("test.txt").await?;
+//         fs_promises::write_file("copy.txt", text).await
+//     }
+//
+//     spawn(async { copy().await.unwrap() });
+
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use crate::promise::Promise;
+use crate::{Stats, ThreadPoolTaskKind};
+
+pub fn read_file(path: impl AsRef<Path>) -> Promise<io::Result<Vec<u8>>> {
+    let path = path.as_ref().to_path_buf();
+    Promise::from_threadpool(ThreadPoolTaskKind::FileRead, move || fs::read(path))
+}
+
+pub fn read_to_string(path: impl AsRef<Path>) -> Promise<io::Result<String>> {
+    let path = path.as_ref().to_path_buf();
+    Promise::from_threadpool(ThreadPoolTaskKind::FileRead, move || {
+        fs::read_to_string(path)
+    })
+}
+
+/// Creates the file if it doesn't exist and replaces its content if it does
+pub fn write_file(path: impl AsRef<Path>, data: impl Into<Vec<u8>>) -> Promise<io::Result<()>> {
+    let path = path.as_ref().to_path_buf();
+    let data = data.into();
+    Promise::from_threadpool(ThreadPoolTaskKind::FileWrite, move || fs::write(path, data))
+}
+
+/// Returns the names of the entries in the directory. Names which are not
+/// valid UTF-8 are converted lossily, just like Node does by default.
+pub fn readdir(path: impl AsRef<Path>) -> Promise<io::Result<Vec<String>>> {
+    let path = path.as_ref().to_path_buf();
+    Promise::from_threadpool(ThreadPoolTaskKind::ReadDir, move || {
+        fs::read_dir(path)?
+            .map(|entry| entry.map(|e| e.file_name().to_string_lossy().into_owned()))
+            .collect()
+    })
+}
+
+pub fn stat(path: impl AsRef<Path>) -> Promise<io::Result<Stats>> {
+    let path = path.as_ref().to_path_buf();
+    Promise::from_threadpool(ThreadPoolTaskKind::Stat, move || {
+        fs::metadata(path).map(Stats::from)
+    })
+}
+
+/// Opens a file with the same flags as Node: "r", "r+", "w", "w+", "a" or "a+"
+pub fn open(path: impl AsRef<Path>, flags: &str) -> Promise<io::Result<FileHandle>> {
+    let path = path.as_ref().to_path_buf();
+    let mut opts = fs::OpenOptions::new();
+
+    match flags {
+        "r" => opts.read(true),
+        "r+" => opts.read(true).write(true),
+        "w" => opts.write(true).create(true).truncate(true),
+        "w+" => opts.read(true).write(true).create(true).truncate(true),
+        "a" => opts.append(true).create(true),
+        "a+" => opts.read(true).append(true).create(true),
+        _ => {
+            let msg = format!("invalid flags: {}", flags);
+            return Promise::resolve(Err(io::Error::new(io::ErrorKind::InvalidInput, msg)));
+        }
+    };
+
+    Promise::from_threadpool(ThreadPoolTaskKind::Open, move || {
+        let file = opts.open(path)?;
+        Ok(FileHandle {
+            file: Arc::new(Mutex::new(Some(file))),
+        })
+    })
+}
+
+/// An open file. All operations run on the threadpool. The file is closed when
+/// `close` is called or when the last handle to it is dropped.
+#[derive(Clone)]
+pub struct FileHandle {
+    file: Arc<Mutex<Option<fs::File>>>,
+}
+
+impl FileHandle {
+    /// Reads up to `len` bytes. If `position` is None we read from the
+    /// current position in the file and advance it. An empty result means
+    /// we've reached the end of the file.
+    pub fn read(&self, len: usize, position: Option<u64>) -> Promise<io::Result<Vec<u8>>> {
+        self.run(ThreadPoolTaskKind::FileRead, move |file| {
+            let mut buffer = vec![0; len];
+            let n = match position {
+                Some(pos) => file.read_at(&mut buffer, pos)?,
+                None => file.read(&mut buffer)?,
+            };
+            buffer.truncate(n);
+            Ok(buffer)
+        })
+    }
+
+    /// Reads from the current position to the end of the file
+    pub fn read_file(&self) -> Promise<io::Result<Vec<u8>>> {
+        self.run(ThreadPoolTaskKind::FileRead, |file| {
+            let mut buffer = vec![];
+            file.read_to_end(&mut buffer)?;
+            Ok(buffer)
+        })
+    }
+
+    /// Writes all of `data` and resolves to the number of bytes written. If
+    /// `position` is None we write at the current position in the file.
+    pub fn write(
+        &self,
+        data: impl Into<Vec<u8>>,
+        position: Option<u64>,
+    ) -> Promise<io::Result<usize>> {
+        let data = data.into();
+        self.run(ThreadPoolTaskKind::FileWrite, move |file| {
+            match position {
+                Some(pos) => file.write_all_at(&data, pos)?,
+                None => file.write_all(&data)?,
+            }
+            Ok(data.len())
+        })
+    }
+
+    pub fn stat(&self) -> Promise<io::Result<Stats>> {
+        self.run(ThreadPoolTaskKind::Stat, |file| {
+            file.metadata().map(Stats::from)
+        })
+    }
+
+    /// Closes the file. Using the handle after this results in an error.
+    pub fn close(&self) -> Promise<io::Result<()>> {
+        let file = self.file.clone();
+        Promise::from_threadpool(ThreadPoolTaskKind::FileClose, move || {
+            match file.lock().unwrap().take() {
+                Some(file) => file.sync_all(),
+                None => Err(closed_error()),
+            }
+        })
+    }
+
+    fn run<T: Send + 'static>(
+        &self,
+        kind: ThreadPoolTaskKind,
+        f: impl FnOnce(&mut fs::File) -> io::Result<T> + Send + 'static,
+    ) -> Promise<io::Result<T>> {
+        let file = self.file.clone();
+        Promise::from_threadpool(kind, move || match file.lock().unwrap().as_mut() {
+            Some(file) => f(file),
+            None => Err(closed_error()),
+        })
+    }
+}
+
+fn closed_error() -> io::Error {
+    io::Error::other("file handle is closed")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::future::Future;
+    use std::path::PathBuf;
+    use std::rc::Rc;
+
+    /// Runs the future returned by `f` on a new runtime and returns what it
+    /// resolved to. Promises start right away, so they can only be created
+    /// once the runtime is running.
+    fn run_async<T: 'static, F: Future<Output = T> + 'static>(
+        f: impl FnOnce() -> F + 'static,
+    ) -> T {
+        let f = RefCell::new(Some(f));
+        let result = Rc::new(RefCell::new(None));
+        let r = result.clone();
+        crate::run_test(move || {
+            let fut = f.borrow_mut().take().unwrap()();
+            let r = r.clone();
+            crate::spawn(async move { *r.borrow_mut() = Some(fut.await) });
+        });
+        let result = result.borrow_mut().take();
+        result.expect("the future never resolved")
+    }
+
+    #[test]
+    fn write_read_and_stat() {
+        let path = crate::temp_path("promises_write");
+        let p = path.clone();
+        let (bytes, text, stats) = run_async(move || async move {
+            write_file(&p, "hello").await?;
+            let bytes = read_file(&p).await?;
+            let text = read_to_string(&p).await?;
+            let stats = stat(&p).await?;
+            io::Result::Ok((bytes, text, stats))
+        })
+        .unwrap();
+
+        assert_eq!(bytes, b"hello");
+        assert_eq!(text, "hello");
+        assert_eq!(stats.size, 5);
+        assert!(stats.is_file);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn readdir_lists_entries() {
+        let dir = crate::temp_path("promises_readdir");
+        fs::create_dir_all(dir.join("b")).unwrap();
+        fs::write(dir.join("a"), "").unwrap();
+
+        let d = dir.clone();
+        let mut names = run_async(move || readdir(d)).unwrap();
+        names.sort();
+        assert_eq!(names, ["a", "b"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn file_handle() {
+        let path = crate::temp_path("promises_handle");
+        let p = path.clone();
+        let res = run_async(move || async move {
+            let file = open(&p, "w+").await?;
+            assert_eq!(file.write("hello world", None).await?, 11);
+            file.write("J", Some(0)).await?;
+            let start = file.read(5, Some(0)).await?;
+            // Reading at a position doesn't move us, we're still at the end
+            let rest = file.read_file().await?;
+            let size = file.stat().await?.size;
+            file.close().await?;
+
+            let closed = file.read(5, Some(0)).await.unwrap_err();
+            io::Result::Ok((start, rest, size, closed.to_string()))
+        });
+
+        let (start, rest, size, closed) = res.unwrap();
+        assert_eq!(start, b"Jello");
+        assert!(rest.is_empty());
+        assert_eq!(size, 11);
+        assert_eq!(closed, "file handle is closed");
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn open_rejects_unknown_flags() {
+        let err = run_async(|| open("test.txt", "rw")).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn errors_propagate_with_question_mark() {
+        async fn copy(from: PathBuf, to: PathBuf) -> io::Result<()> {
+            let text = read_to_string(from).await?;
+            write_file(to, text).await
+        }
+
+        let to = crate::temp_path("promises_copy");
+        let from = to.join("missing");
+        let t = to.clone();
+        let err = run_async(move || copy(from, t)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(!to.exists());
+    }
+}
@@ -0,0 +1,1305 @@
+// ===== HTTP =====
+// A small HTTP/1.1 implementation on top of our TCP sockets. The parser is
+// incremental: we feed it whatever a `data` event gives us and it hands back
+// the parts of the message it could make sense of so far. It doesn't care if
+// the message arrives in one chunk or one byte at a time.
+//
+// With the `tls` feature the client speaks `https://` as well.
+
+use std::cell::RefCell;
+use std::fmt;
+use std::io;
+use std::iter::FromIterator;
+use std::mem;
+use std::rc::Rc;
+
+use crate::http_agent::Agent;
+#[cfg(feature = "tls")]
+use crate::net::{Net, Socket};
+use crate::stream::Readable;
+#[cfg(feature = "tls")]
+use crate::tls::TlsConnectOptions;
+use crate::{defer, print, Js};
+
+/// We give up on messages with a bigger head than this, same limit as Node
+const MAX_HEAD_SIZE: usize = 16 * 1024;
+
+// ===== URL =====
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Url {
+    pub scheme: String,
+    pub host: String,
+    pub port: u16,
+    /// The path including the query string, always starting with a `/`
+    pub path: String,
+}
+
+impl Url {
+    /// Parses an absolute URL like `http://localhost:8080/index.html?a=1`.
+    /// User info and fragments are dropped.
+    pub fn parse(url: &str) -> io::Result<Url> {
+        let invalid =
+            || io::Error::new(io::ErrorKind::InvalidInput, format!("invalid URL: {}", url));
+
+        let (scheme, rest) = url.split_once("://").ok_or_else(invalid)?;
+        let scheme = scheme.to_ascii_lowercase();
+        let default_port = default_port(&scheme).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unsupported protocol: {}", scheme),
+            )
+        })?;
+
+        let rest = rest.split('#').next().unwrap_or_default();
+        let authority_end = rest.find(['/', '?']).unwrap_or(rest.len());
+        let (authority, path) = rest.split_at(authority_end);
+        let authority = authority.rsplit('@').next().unwrap_or_default();
+
+        // IPv6 addresses are written in brackets since they contain colons
+        let (host, port) = if let Some(v6) = authority.strip_prefix('[') {
+            let (host, port) = v6.split_once(']').ok_or_else(invalid)?;
+            (host, port.strip_prefix(':'))
+        } else {
+            match authority.split_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (authority, None),
+            }
+        };
+
+        if host.is_empty() {
+            return Err(invalid());
+        }
+
+        let port = match port {
+            Some(port) if !port.is_empty() => port.parse().map_err(|_| invalid())?,
+            _ => default_port,
+        };
+
+        let path = match path {
+            "" => "/".to_string(),
+            p if p.starts_with('?') => format!("/{}", p),
+            p => p.to_string(),
+        };
+
+        Ok(Url {
+            scheme,
+            host: host.to_ascii_lowercase(),
+            port,
+            path,
+        })
+    }
+
+    /// Resolves a reference like the `Location` of a redirect against this
+    /// URL. It can be absolute, start with `//` or `/`, or be relative to the
+    /// directory of our path. `.` and `..` segments are resolved, so
+    /// `../c` from `/a/b/` is `/a/c`.
+    pub fn join(&self, reference: &str) -> io::Result<Url> {
+        if reference.contains("://") || reference.starts_with("//") {
+            let mut url = match reference.starts_with("//") {
+                true => Url::parse(&format!("{}:{}", self.scheme, reference))?,
+                false => Url::parse(reference)?,
+            };
+            url.path = remove_dot_segments(&url.path);
+            return Ok(url);
+        }
+
+        let reference = reference.split('#').next().unwrap_or_default();
+        let path = if reference.starts_with('/') {
+            reference.to_string()
+        } else if reference.is_empty() {
+            self.path.clone()
+        } else if reference.starts_with('?') {
+            let path = self.path.split('?').next().unwrap_or_default();
+            format!("{}{}", path, reference)
+        } else {
+            let path = self.path.split('?').next().unwrap_or_default();
+            let dir = &path[..path.rfind('/').map_or(0, |i| i + 1)];
+            format!("{}{}", dir, reference)
+        };
+        let path = match reference.is_empty() {
+            true => path,
+            false => remove_dot_segments(&path),
+        };
+
+        Ok(Url {
+            path,
+            ..self.clone()
+        })
+    }
+
+    /// What goes in the `Host` header. The port is left out if it's the
+    /// default one for the scheme.
+    pub fn host_header(&self) -> String {
+        let host = if self.host.contains(':') {
+            format!("[{}]", self.host)
+        } else {
+            self.host.clone()
+        };
+
+        match default_port(&self.scheme) {
+            Some(port) if port == self.port => host,
+            _ => format!("{}:{}", host, self.port),
+        }
+    }
+}
+
+/// Removes the `.` and `..` segments from the path part of `path` the way
+/// RFC 3986 section 5.2.4 does. A `..` above the root is dropped.
+fn remove_dot_segments(path: &str) -> String {
+    let (path, query) = path.split_at(path.find('?').unwrap_or(path.len()));
+    let mut output = vec![];
+    let mut segments = path.split('/').skip(1).peekable();
+    while let Some(segment) = segments.next() {
+        match segment {
+            "." | ".." => {
+                if segment == ".." {
+                    output.pop();
+                }
+                // `/a/b/..` is the directory `/a/`
+                if segments.peek().is_none() {
+                    output.push("");
+                }
+            }
+            _ => output.push(segment),
+        }
+    }
+    format!("/{}{}", output.join("/"), query)
+}
+
+impl fmt::Display for Url {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}://{}{}", self.scheme, self.host_header(), self.path)
+    }
+}
+
+fn default_port(scheme: &str) -> Option<u16> {
+    match scheme {
+        "http" => Some(80),
+        "https" => Some(443),
+        "ws" => Some(80),
+        "wss" => Some(443),
+        _ => None,
+    }
+}
+
+// ===== HEADERS =====
+
+/// Header names are case insensitive, but we keep them the way they were
+/// written and in the order they were added
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Headers(Vec<(String, String)>);
+
+impl Headers {
+    pub fn new() -> Self {
+        Headers::default()
+    }
+
+    /// The first value of the header `name`
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.0
+            .iter()
+            .filter(move |(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn has(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// Replaces all values of `name` with `value`
+    pub fn set(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        let name = name.into();
+        self.remove(&name);
+        self.0.push((name, value.into()));
+    }
+
+    /// Adds a value without touching the ones already there
+    pub fn append(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        self.0.push((name.into(), value.into()));
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.0.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0.iter().map(|(n, v)| (n.as_str(), v.as_str()))
+    }
+
+    /// True if the comma separated header `name` contains `token`, like
+    /// `Connection: keep-alive, Upgrade`
+    pub fn has_token(&self, name: &str, token: &str) -> bool {
+        self.get_all(name)
+            .flat_map(|v| v.split(','))
+            .any(|t| t.trim().eq_ignore_ascii_case(token))
+    }
+}
+
+impl<N: Into<String>, V: Into<String>> FromIterator<(N, V)> for Headers {
+    fn from_iter<I: IntoIterator<Item = (N, V)>>(iter: I) -> Self {
+        Headers(
+            iter.into_iter()
+                .map(|(n, v)| (n.into(), v.into()))
+                .collect(),
+        )
+    }
+}
+
+// ===== PARSER =====
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum StartLine {
+    Request { method: String, target: String },
+    Response { status: u16, reason: String },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct MessageHead {
+    pub(crate) start: StartLine,
+    /// The minor version, 0 for HTTP/1.0 and 1 for HTTP/1.1
+    pub(crate) version: u8,
+    pub(crate) headers: Headers,
+}
+
+impl MessageHead {
+    /// HTTP/1.1 connections stay open unless one side says otherwise, while
+    /// HTTP/1.0 connections have to ask for it
+    pub(crate) fn keep_alive(&self) -> bool {
+        if self.version >= 1 {
+            !self.headers.has_token("Connection", "close")
+        } else {
+            self.headers.has_token("Connection", "keep-alive")
+        }
+    }
+
+    /// The request asks to switch to another protocol, like the handshake
+    /// which opens a WebSocket
+    pub(crate) fn is_upgrade(&self) -> bool {
+        self.headers.has_token("Connection", "upgrade") && self.headers.has("Upgrade")
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum Parsed {
+    Head(MessageHead),
+    Body(Vec<u8>),
+    /// The message is complete. If the connection is kept open the parser is
+    /// ready for the next message.
+    End,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum MessageKind {
+    Request,
+    Response,
+}
+
+enum ParseState {
+    Head,
+    Length(u64),
+    ChunkSize,
+    ChunkData(u64),
+    /// The CRLF after the data of a chunk
+    ChunkDataEnd,
+    Trailers,
+    /// The body lasts until the connection is closed
+    UntilClose,
+    /// The connection has switched to another protocol, so what follows the
+    /// head isn't HTTP anymore. It stays in the buffer for `take_buffer`.
+    Upgraded,
+}
+
+pub(crate) struct Parser {
+    kind: MessageKind,
+    state: ParseState,
+    buffer: Vec<u8>,
+    /// The response we're parsing is to a HEAD request, so it has no body
+    /// whatever its headers say
+    head_request: bool,
+}
+
+impl Parser {
+    pub(crate) fn new(kind: MessageKind) -> Self {
+        Parser {
+            kind,
+            state: ParseState::Head,
+            buffer: vec![],
+            head_request: false,
+        }
+    }
+
+    pub(crate) fn set_head_request(&mut self, head_request: bool) {
+        self.head_request = head_request;
+    }
+
+    /// Parses as much of `data` as possible. Anything we can't parse yet is
+    /// kept until more data arrives.
+    pub(crate) fn feed(&mut self, data: &[u8]) -> io::Result<Vec<Parsed>> {
+        self.buffer.extend_from_slice(data);
+        let mut parsed = vec![];
+
+        loop {
+            match self.state {
+                ParseState::Head => match self.take_line_block()? {
+                    Some(block) => {
+                        let head = parse_head(self.kind, &block)?;
+                        // Informational responses like `100 Continue` are
+                        // followed by the real response
+                        if let StartLine::Response { status, .. } = head.start {
+                            if (100..200).contains(&status) && status != 101 {
+                                continue;
+                            }
+                        }
+                        self.state = self.body_state(&head)?;
+                        parsed.push(Parsed::Head(head));
+                        if let ParseState::Head | ParseState::Upgraded = self.state {
+                            parsed.push(Parsed::End);
+                        }
+                    }
+                    None => break,
+                },
+                ParseState::Length(remaining) => {
+                    if self.buffer.is_empty() {
+                        break;
+                    }
+                    let n = (remaining as usize).min(self.buffer.len());
+                    parsed.push(Parsed::Body(self.buffer.drain(..n).collect()));
+                    if remaining == n as u64 {
+                        self.state = ParseState::Head;
+                        parsed.push(Parsed::End);
+                    } else {
+                        self.state = ParseState::Length(remaining - n as u64);
+                    }
+                }
+                ParseState::ChunkSize => match self.take_line()? {
+                    Some(line) => {
+                        let size = line.split(';').next().unwrap_or_default().trim();
+                        let size = u64::from_str_radix(size, 16)
+                            .map_err(|_| parse_error("invalid chunk size"))?;
+                        self.state = match size {
+                            0 => ParseState::Trailers,
+                            n => ParseState::ChunkData(n),
+                        };
+                    }
+                    None => break,
+                },
+                ParseState::ChunkData(remaining) => {
+                    if self.buffer.is_empty() {
+                        break;
+                    }
+                    let n = (remaining as usize).min(self.buffer.len());
+                    parsed.push(Parsed::Body(self.buffer.drain(..n).collect()));
+                    self.state = match remaining - n as u64 {
+                        0 => ParseState::ChunkDataEnd,
+                        left => ParseState::ChunkData(left),
+                    };
+                }
+                ParseState::ChunkDataEnd => match self.take_line()? {
+                    Some(line) if line.is_empty() => self.state = ParseState::ChunkSize,
+                    Some(_) => return Err(parse_error("missing CRLF after chunk")),
+                    None => break,
+                },
+                ParseState::Trailers => match self.take_line()? {
+                    // We don't do anything with trailers
+                    Some(line) if !line.is_empty() => (),
+                    Some(_) => {
+                        self.state = ParseState::Head;
+                        parsed.push(Parsed::End);
+                    }
+                    None => break,
+                },
+                ParseState::UntilClose => {
+                    if !self.buffer.is_empty() {
+                        parsed.push(Parsed::Body(mem::take(&mut self.buffer)));
+                    }
+                    break;
+                }
+                ParseState::Upgraded => break,
+            }
+        }
+
+        Ok(parsed)
+    }
+
+    /// Called when the connection is closed. Returns `End` if that's how the
+    /// message ends, and an error if the message was cut short.
+    pub(crate) fn finish(&mut self) -> io::Result<Option<Parsed>> {
+        match self.state {
+            ParseState::UntilClose => {
+                self.state = ParseState::Head;
+                Ok(Some(Parsed::End))
+            }
+            ParseState::Head if self.buffer.is_empty() => Ok(None),
+            ParseState::Upgraded => Ok(None),
+            _ => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "connection closed in the middle of a message",
+            )),
+        }
+    }
+
+    /// Data which is buffered but not parsed yet, e.g. what follows the head
+    /// of an upgrade response
+    pub(crate) fn take_buffer(&mut self) -> Vec<u8> {
+        mem::take(&mut self.buffer)
+    }
+
+    /// Figures out how the body of the message is delimited
+    fn body_state(&self, head: &MessageHead) -> io::Result<ParseState> {
+        if let StartLine::Response { status, .. } = head.start {
+            if status == 101 {
+                return Ok(ParseState::Upgraded);
+            }
+            if self.head_request || status == 204 || status == 304 {
+                return Ok(ParseState::Head);
+            }
+        } else if head.is_upgrade() {
+            return Ok(ParseState::Upgraded);
+        }
+
+        let headers = &head.headers;
+        if headers.has_token("Transfer-Encoding", "chunked") {
+            return Ok(ParseState::ChunkSize);
+        }
+
+        match headers.get("Content-Length") {
+            Some(len) => match len.trim().parse::<u64>() {
+                Ok(0) => Ok(ParseState::Head),
+                Ok(len) => Ok(ParseState::Length(len)),
+                Err(_) => Err(parse_error("invalid Content-Length")),
+            },
+            None if self.kind == MessageKind::Response => Ok(ParseState::UntilClose),
+            None => Ok(ParseState::Head),
+        }
+    }
+
+    /// Takes everything up to and including the empty line ending a head
+    fn take_line_block(&mut self) -> io::Result<Option<Vec<u8>>> {
+        // Some clients send an extra CRLF after the body of a request
+        while self.buffer.starts_with(b"\r\n") {
+            self.buffer.drain(..2);
+        }
+
+        match find(&self.buffer, b"\r\n\r\n") {
+            Some(pos) => Ok(Some(self.buffer.drain(..pos + 4).collect())),
+            None if self.buffer.len() > MAX_HEAD_SIZE => Err(parse_error("header too large")),
+            None => Ok(None),
+        }
+    }
+
+    fn take_line(&mut self) -> io::Result<Option<String>> {
+        match find(&self.buffer, b"\r\n") {
+            Some(pos) => {
+                let line: Vec<u8> = self.buffer.drain(..pos + 2).collect();
+                Ok(Some(String::from_utf8_lossy(&line[..pos]).into_owned()))
+            }
+            None if self.buffer.len() > MAX_HEAD_SIZE => Err(parse_error("line too long")),
+            None => Ok(None),
+        }
+    }
+}
+
+fn parse_head(kind: MessageKind, block: &[u8]) -> io::Result<MessageHead> {
+    let text = String::from_utf8_lossy(block);
+    let mut lines = text.split("\r\n");
+    let start = lines.next().unwrap_or_default();
+
+    let mut parts = start.splitn(3, ' ');
+    let (first, second, third) = (
+        parts.next().unwrap_or_default(),
+        parts.next().unwrap_or_default(),
+        parts.next().unwrap_or_default(),
+    );
+
+    let parse_version = |v: &str| match v {
+        "HTTP/1.1" => Ok(1),
+        "HTTP/1.0" => Ok(0),
+        _ => Err(parse_error("unsupported HTTP version")),
+    };
+
+    let (start, version) = match kind {
+        MessageKind::Request => {
+            if first.is_empty() || second.is_empty() {
+                return Err(parse_error("invalid request line"));
+            }
+            let start = StartLine::Request {
+                method: first.to_string(),
+                target: second.to_string(),
+            };
+            (start, parse_version(third)?)
+        }
+        MessageKind::Response => {
+            let status = second
+                .parse()
+                .map_err(|_| parse_error("invalid status code"))?;
+            let start = StartLine::Response {
+                status,
+                reason: third.to_string(),
+            };
+            (start, parse_version(first)?)
+        }
+    };
+
+    let mut headers = Headers::new();
+    for line in lines.filter(|l| !l.is_empty()) {
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| parse_error("invalid header line"))?;
+        headers.append(name.trim(), value.trim());
+    }
+
+    Ok(MessageHead {
+        start,
+        version,
+        headers,
+    })
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+fn parse_error(msg: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("HTTP parse error: {}", msg),
+    )
+}
+
+// ===== CLIENT =====
+
+#[derive(Clone)]
+pub struct RequestOptions {
+    pub method: String,
+    pub url: String,
+    pub headers: Headers,
+    pub body: Vec<u8>,
+    /// The agent which picks the connection, `None` means the global agent
+    pub agent: Option<Agent>,
+    /// Follow up to this many redirects. With 0 the redirect itself is the
+    /// response.
+    pub max_redirects: usize,
+    /// Fail if we're not connected after this long, 0 means we wait as long
+    /// as the OS does
+    pub connect_timeout_ms: u64,
+    /// Fail if the response hasn't started this long after the request was
+    /// sent, 0 means we wait forever
+    pub timeout_ms: u64,
+    /// How many times we try again if the request fails before we get a
+    /// response. Only idempotent requests like GET are retried.
+    pub retries: u32,
+    /// How long we wait before the first retry. The wait doubles with every
+    /// retry after that.
+    pub retry_delay_ms: u64,
+    /// Used for `https://` URLs, like the CA to trust or a client certificate
+    #[cfg(feature = "tls")]
+    pub tls: TlsConnectOptions,
+}
+
+impl Default for RequestOptions {
+    fn default() -> Self {
+        RequestOptions {
+            method: "GET".to_string(),
+            url: String::new(),
+            headers: Headers::new(),
+            body: vec![],
+            agent: None,
+            max_redirects: 0,
+            connect_timeout_ms: 0,
+            timeout_ms: 0,
+            retries: 0,
+            retry_delay_ms: 100,
+            #[cfg(feature = "tls")]
+            tls: TlsConnectOptions::default(),
+        }
+    }
+}
+
+impl RequestOptions {
+    /// A GET request for `url`
+    pub fn get(url: impl Into<String>) -> Self {
+        RequestOptions {
+            url: url.into(),
+            ..Default::default()
+        }
+    }
+
+    /// Sending an idempotent request twice has the same effect as sending it
+    /// once, so it's safe to retry
+    fn is_idempotent(&self) -> bool {
+        matches!(
+            self.method.to_ascii_uppercase().as_str(),
+            "GET" | "HEAD" | "PUT" | "DELETE" | "OPTIONS" | "TRACE"
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Response {
+    /// Where the response came from, which differs from the URL we asked
+    /// for if we followed a redirect
+    pub url: String,
+    pub status: u16,
+    pub status_text: String,
+    pub headers: Headers,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    /// The body as text. Invalid UTF-8 is replaced, just like `res.text()`
+    /// does in the browser.
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+}
+
+/// Prints the response the way it looks on the wire
+impl fmt::Display for Response {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "HTTP/1.1 {} {}\r\n", self.status, self.status_text)?;
+        for (name, value) in self.headers.iter() {
+            write!(f, "{}: {}\r\n", name, value)?;
+        }
+        write!(f, "\r\n{}", self.text())
+    }
+}
+
+/// A response whose body is still arriving
+#[derive(Clone)]
+pub struct IncomingResponse {
+    pub url: String,
+    pub status: u16,
+    pub status_text: String,
+    pub headers: Headers,
+    /// Emits the body in chunks as they arrive. The connection stops reading
+    /// while the stream is paused.
+    pub body: Readable,
+}
+
+impl IncomingResponse {
+    /// Collects the whole body and calls `cb` with `Js::Response`, or with
+    /// the error if the connection fails before the body is complete
+    pub fn read_body(self, cb: impl FnOnce(Js) + 'static) {
+        let body = self.body.clone();
+        body.read_to_end(move |data| match data {
+            Js::Bytes(body) => cb(Js::Response(Response {
+                url: self.url,
+                status: self.status,
+                status_text: self.status_text,
+                headers: self.headers,
+                body,
+            })),
+            err => cb(err),
+        });
+    }
+}
+
+pub(crate) type ResponseCallback = Box<dyn FnOnce(io::Result<IncomingResponse>)>;
+
+/// Sends the request and calls `cb` with `Js::Response` once the whole
+/// response is received, or with `Js::Error` if anything fails
+pub fn request(opts: RequestOptions, cb: impl FnOnce(Js) + 'static) -> ClientRequest {
+    request_stream(opts, move |res| match res {
+        Ok(res) => res.read_body(cb),
+        Err(e) => cb(Js::Error(e)),
+    })
+}
+
+/// Like `request`, but calls `cb` as soon as the head of the response has
+/// arrived, so a big body can be handled chunk by chunk instead of being
+/// collected in memory
+pub fn request_stream(
+    opts: RequestOptions,
+    cb: impl FnOnce(io::Result<IncomingResponse>) + 'static,
+) -> ClientRequest {
+    let request = ClientRequest::new(Box::new(cb));
+    match Url::parse(&opts.url).and_then(check_scheme) {
+        Ok(url) => {
+            let attempt = Attempt {
+                opts,
+                url,
+                redirects: 0,
+                retries: 0,
+                request: request.clone(),
+            };
+            attempt.send();
+        }
+        Err(e) => {
+            let request = request.clone();
+            defer(move |_| request.respond(Err(e)));
+        }
+    }
+    request
+}
+
+/// A request on its way. It's returned by `request` and `request_stream` so
+/// it can be aborted.
+#[derive(Clone)]
+pub struct ClientRequest {
+    inner: Rc<RefCell<ClientRequestState>>,
+}
+
+struct ClientRequestState {
+    /// `None` once we have handed over the response or an error
+    cb: Option<ResponseCallback>,
+    /// Stops whatever the request is waiting for right now, like the
+    /// response on a connection or the timer before a retry
+    cancel: Option<Box<dyn FnOnce()>>,
+    /// The body of the response we handed over
+    body: Option<Readable>,
+    destroyed: bool,
+}
+
+impl ClientRequest {
+    fn new(cb: ResponseCallback) -> Self {
+        let state = ClientRequestState {
+            cb: Some(cb),
+            cancel: None,
+            body: None,
+            destroyed: false,
+        };
+        ClientRequest {
+            inner: Rc::new(RefCell::new(state)),
+        }
+    }
+
+    /// Aborts the request. If we're still waiting for the response the
+    /// callback gets `err`, otherwise the body is destroyed with it. Without
+    /// an `err` we use a "request aborted" error.
+    pub fn destroy(&self, err: Option<io::Error>) {
+        let (cb, cancel, body) = {
+            let mut state = self.inner.borrow_mut();
+            if state.destroyed {
+                return;
+            }
+            state.destroyed = true;
+            (state.cb.take(), state.cancel.take(), state.body.take())
+        };
+
+        let err =
+            err.unwrap_or_else(|| io::Error::new(io::ErrorKind::Interrupted, "request aborted"));
+        if let Some(cancel) = cancel {
+            cancel();
+        }
+        match (cb, body) {
+            (Some(cb), _) => defer(move |_| cb(Err(err))),
+            (None, Some(body)) => body.destroy(Some(err)),
+            (None, None) => (),
+        }
+    }
+
+    pub fn is_destroyed(&self) -> bool {
+        self.inner.borrow().destroyed
+    }
+
+    pub(crate) fn set_cancel(&self, cancel: impl FnOnce() + 'static) {
+        self.inner.borrow_mut().cancel = Some(Box::new(cancel));
+    }
+
+    fn respond(&self, res: io::Result<IncomingResponse>) {
+        let cb = {
+            let mut state = self.inner.borrow_mut();
+            state.cancel = None;
+            if let Ok(res) = &res {
+                state.body = Some(res.body.clone());
+            }
+            state.cb.take()
+        };
+
+        match (cb, res) {
+            (Some(cb), res) => cb(res),
+            // Nobody wants the response anymore
+            (None, Ok(res)) => res.body.destroy(None),
+            (None, Err(_)) => (),
+        }
+    }
+}
+
+fn check_scheme(url: Url) -> io::Result<Url> {
+    match url.scheme.as_str() {
+        "http" => Ok(url),
+        #[cfg(feature = "tls")]
+        "https" => Ok(url),
+        scheme => Err(io::Error::other(format!(
+            "protocol not supported: {}",
+            scheme
+        ))),
+    }
+}
+
+/// Opens a connection to the host in `url`, with TLS for `https://` and
+/// `wss://`. We only speak HTTP/1.1, so that's what we offer with ALPN
+/// unless `tls` says otherwise.
+#[cfg(feature = "tls")]
+pub(crate) fn connect(url: &Url, tls: &TlsConnectOptions) -> Socket {
+    if !matches!(url.scheme.as_str(), "https" | "wss") {
+        return Net::connect((url.host.as_str(), url.port));
+    }
+
+    let mut tls = tls.clone();
+    if tls.alpn_protocols.is_empty() {
+        tls.alpn_protocols = vec!["http/1.1".to_string()];
+    }
+    Net::connect_tls(&url.host, url.port, tls)
+}
+
+/// Keeps track of a request through its redirects and retries
+struct Attempt {
+    opts: RequestOptions,
+    url: Url,
+    redirects: usize,
+    retries: u32,
+    request: ClientRequest,
+}
+
+impl Attempt {
+    fn send(self) {
+        if self.request.is_destroyed() {
+            return;
+        }
+        print(format!("{} {}", self.opts.method, self.url));
+        let agent = self.opts.agent.clone().unwrap_or_else(Agent::global);
+        let opts = self.opts.clone();
+        let url = self.url.clone();
+        let request = self.request.clone();
+        agent.add_request(opts, url, request, Box::new(move |res| self.on_result(res)));
+    }
+
+    fn on_result(mut self, res: io::Result<IncomingResponse>) {
+        match res {
+            Ok(res) => match self.redirect(&res) {
+                Some(Ok(url)) => {
+                    // We don't care about the body, but the connection can
+                    // only be reused once it's read
+                    res.body.resume();
+                    print(format!("Redirected to {}", url));
+                    self.redirects += 1;
+                    self.url = url;
+                    self.send();
+                }
+                Some(Err(e)) => {
+                    res.body.destroy(None);
+                    self.request.respond(Err(e));
+                }
+                None => self.request.respond(Ok(res)),
+            },
+            Err(e) if self.retries < self.opts.retries && self.opts.is_idempotent() => {
+                let delay = self
+                    .opts
+                    .retry_delay_ms
+                    .saturating_mul(1 << self.retries.min(32));
+                print(format!(
+                    "{} {} failed: {}, retrying in {} ms",
+                    self.opts.method, self.url, e, delay
+                ));
+                self.retries += 1;
+                let request = self.request.clone();
+                let timer = crate::set_timeout(delay, move |_| self.send());
+                request.set_cancel(move || crate::clear_timeout(timer));
+            }
+            res => self.request.respond(res),
+        }
+    }
+
+    /// Returns where to go next if `res` is a redirect we should follow
+    fn redirect(&mut self, res: &IncomingResponse) -> Option<io::Result<Url>> {
+        if self.opts.max_redirects == 0 || !matches!(res.status, 301 | 302 | 303 | 307 | 308) {
+            return None;
+        }
+        let location = res.headers.get("Location")?;
+        if self.redirects == self.opts.max_redirects {
+            let msg = format!("more than {} redirects", self.opts.max_redirects);
+            return Some(Err(io::Error::other(msg)));
+        }
+
+        let url = match self.url.join(location).and_then(check_scheme) {
+            Ok(url) => url,
+            Err(e) => return Some(Err(e)),
+        };
+
+        // Browsers turn a POST into a GET for all of these, even though only
+        // 303 says to
+        let method = self.opts.method.to_ascii_uppercase();
+        if res.status == 303 && method != "HEAD"
+            || matches!(res.status, 301 | 302) && method == "POST"
+        {
+            self.opts.method = "GET".to_string();
+            self.opts.body.clear();
+            for name in &["Content-Length", "Content-Type", "Transfer-Encoding"] {
+                self.opts.headers.remove(name);
+            }
+        }
+
+        // Credentials are only for the host they were meant for
+        if url.host != self.url.host || url.port != self.url.port {
+            self.opts.headers.remove("Host");
+            self.opts.headers.remove("Authorization");
+        }
+
+        Some(Ok(url))
+    }
+}
+
+pub(crate) fn serialize_request(opts: &RequestOptions, url: &Url, keep_alive: bool) -> Vec<u8> {
+    let mut head = format!("{} {} HTTP/1.1\r\n", opts.method, url.path);
+
+    let mut headers = opts.headers.clone();
+    if !headers.has("Host") {
+        headers.set("Host", url.host_header());
+    }
+    if !headers.has("Connection") {
+        headers.set(
+            "Connection",
+            if keep_alive { "keep-alive" } else { "close" },
+        );
+    }
+    let has_body =
+        !opts.body.is_empty() || matches!(opts.method.as_str(), "POST" | "PUT" | "PATCH");
+    if has_body && !headers.has("Content-Length") && !headers.has("Transfer-Encoding") {
+        headers.set("Content-Length", opts.body.len().to_string());
+    }
+
+    for (name, value) in headers.iter() {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("\r\n");
+
+    let mut data = head.into_bytes();
+    data.extend_from_slice(&opts.body);
+    data
+}
+
+/// The reason phrase for the status codes we're likely to use
+pub fn status_text(status: u16) -> &'static str {
+    match status {
+        100 => "Continue",
+        101 => "Switching Protocols",
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        204 => "No Content",
+        206 => "Partial Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        303 => "See Other",
+        304 => "Not Modified",
+        307 => "Temporary Redirect",
+        308 => "Permanent Redirect",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        413 => "Payload Too Large",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ => "Unknown",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::Net;
+
+    /// What the parser gave us, with the body chunks joined since how the
+    /// body is split depends on how the data arrived
+    fn summarize(parsed: Vec<Parsed>) -> Vec<String> {
+        let mut summary: Vec<String> = vec![];
+        for p in parsed {
+            let line = match p {
+                Parsed::Head(head) => match head.start {
+                    StartLine::Request { method, target } => format!("head {} {}", method, target),
+                    StartLine::Response { status, .. } => format!("head {}", status),
+                },
+                Parsed::Body(data) => {
+                    let data = String::from_utf8(data).unwrap();
+                    match summary.last_mut() {
+                        Some(last) if last.starts_with("body ") => {
+                            last.push_str(&data);
+                            continue;
+                        }
+                        _ => format!("body {}", data),
+                    }
+                }
+                Parsed::End => "end".to_string(),
+            };
+            summary.push(line);
+        }
+        summary
+    }
+
+    /// Feeds `pieces` one after the other and closes the connection
+    fn parse(
+        kind: MessageKind,
+        head_request: bool,
+        pieces: &[&[u8]],
+    ) -> Result<Vec<String>, io::ErrorKind> {
+        let mut parser = Parser::new(kind);
+        parser.set_head_request(head_request);
+        let mut parsed = vec![];
+        for piece in pieces {
+            parsed.extend(parser.feed(piece).map_err(|e| e.kind())?);
+        }
+        parsed.extend(parser.finish().map_err(|e| e.kind())?);
+        Ok(summarize(parsed))
+    }
+
+    struct Case {
+        name: &'static str,
+        kind: MessageKind,
+        head_request: bool,
+        input: &'static str,
+        expected: Result<&'static [&'static str], io::ErrorKind>,
+    }
+
+    fn response(
+        name: &'static str,
+        input: &'static str,
+        expected: &'static [&'static str],
+    ) -> Case {
+        Case {
+            name,
+            kind: MessageKind::Response,
+            head_request: false,
+            input,
+            expected: Ok(expected),
+        }
+    }
+
+    fn invalid(name: &'static str, kind: MessageKind, input: &'static str) -> Case {
+        Case {
+            name,
+            kind,
+            head_request: false,
+            input,
+            expected: Err(io::ErrorKind::InvalidData),
+        }
+    }
+
+    #[test]
+    fn parser_table() {
+        use MessageKind::{Request, Response};
+        let cases = vec![
+            response(
+                "content length",
+                "HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello",
+                &["head 200", "body hello", "end"],
+            ),
+            response(
+                "chunked with extensions and trailers",
+                "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+                 5;name=value\r\nhello\r\n6\r\n world\r\n0;last\r\n\
+                 Expires: never\r\nX-Trailer: yes\r\n\r\n",
+                &["head 200", "body hello world", "end"],
+            ),
+            response(
+                "informational responses first",
+                "HTTP/1.1 100 Continue\r\n\r\n\
+                 HTTP/1.1 103 Early Hints\r\nLink: </style.css>\r\n\r\n\
+                 HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok",
+                &["head 200", "body ok", "end"],
+            ),
+            Case {
+                name: "response to HEAD",
+                kind: Response,
+                head_request: true,
+                input: "HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\n",
+                expected: Ok(&["head 200", "end"]),
+            },
+            response(
+                "204 and 304 have no body",
+                "HTTP/1.1 204 No Content\r\nContent-Length: 10\r\n\r\n\
+                 HTTP/1.1 304 Not Modified\r\nTransfer-Encoding: chunked\r\n\r\n",
+                &["head 204", "end", "head 304", "end"],
+            ),
+            response(
+                "body until close",
+                "HTTP/1.1 200 OK\r\nConnection: close\r\n\r\nall of this",
+                &["head 200", "body all of this", "end"],
+            ),
+            Case {
+                name: "pipelined requests",
+                kind: Request,
+                head_request: false,
+                input: "GET / HTTP/1.1\r\nHost: a\r\n\r\n\
+                        POST /form HTTP/1.1\r\nContent-Length: 3\r\n\r\nabc\
+                        \r\nGET /last HTTP/1.0\r\n\r\n",
+                expected: Ok(&[
+                    "head GET /",
+                    "end",
+                    "head POST /form",
+                    "body abc",
+                    "end",
+                    "head GET /last",
+                    "end",
+                ]),
+            },
+            invalid(
+                "chunk size which isn't hex",
+                Response,
+                "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n",
+            ),
+            invalid(
+                "chunk size which overflows",
+                Response,
+                "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n1ffffffffffffffff\r\n",
+            ),
+            invalid(
+                "chunk without CRLF after the data",
+                Response,
+                "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhelloXX\r\n0\r\n\r\n",
+            ),
+            invalid(
+                "header without a colon",
+                Response,
+                "HTTP/1.1 200 OK\r\nBroken\r\n\r\n",
+            ),
+            invalid(
+                "invalid content length",
+                Response,
+                "HTTP/1.1 200 OK\r\nContent-Length: ten\r\n\r\n",
+            ),
+            invalid("invalid status", Response, "HTTP/1.1 abc OK\r\n\r\n"),
+            invalid("unsupported version", Request, "GET / HTTP/2.0\r\n\r\n"),
+            invalid("no request target", Request, "GET\r\n\r\n"),
+            Case {
+                name: "body cut short",
+                kind: Response,
+                head_request: false,
+                input: "HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nhello",
+                expected: Err(io::ErrorKind::UnexpectedEof),
+            },
+        ];
+
+        for case in cases {
+            let input = case.input.as_bytes();
+            let expected = case
+                .expected
+                .map(|lines| lines.iter().map(|l| l.to_string()).collect::<Vec<_>>());
+            let parse = |pieces: &[&[u8]]| parse(case.kind, case.head_request, pieces);
+
+            assert_eq!(parse(&[input]), expected, "{}", case.name);
+            for i in 1..input.len() {
+                let (a, b) = input.split_at(i);
+                assert_eq!(parse(&[a, b]), expected, "{} split at {}", case.name, i);
+            }
+            let bytes: Vec<&[u8]> = input.chunks(1).collect();
+            assert_eq!(parse(&bytes), expected, "{} byte by byte", case.name);
+        }
+    }
+
+    #[test]
+    fn request_against_a_local_server() {
+        let received = Rc::new(RefCell::new(vec![]));
+        let response = Rc::new(RefCell::new(None));
+
+        let (rec, res) = (received.clone(), response.clone());
+        crate::run_test(move || {
+            // Answers the first request it has read in full
+            let rec = rec.clone();
+            let server = Net::create_server(move |socket| {
+                let (rec, s) = (rec.clone(), socket.clone());
+                socket.on_data(move |data| {
+                    rec.borrow_mut().extend(data.into_bytes().unwrap());
+                    if rec.borrow().ends_with(b"\r\n\r\nhi") {
+                        s.write(
+                            "HTTP/1.1 201 Created\r\nX-Test: yes\r\nConnection: close\r\n\
+                             Transfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n0\r\n\r\n",
+                        );
+                        s.end();
+                    }
+                });
+            });
+
+            let (s, res) = (server.clone(), res.clone());
+            server.listen("127.0.0.1:0", move |_| {
+                let opts = RequestOptions {
+                    method: "POST".to_string(),
+                    url: format!("http://{}/echo?x=1", s.local_addr().unwrap()),
+                    body: b"hi".to_vec(),
+                    ..Default::default()
+                };
+                let (s, res) = (s.clone(), res.clone());
+                crate::Http::request(opts, move |result| {
+                    *res.borrow_mut() = result.into_response();
+                    s.close(|_| ());
+                });
+            });
+        });
+
+        let received = String::from_utf8(received.take()).unwrap();
+        assert!(
+            received.starts_with("POST /echo?x=1 HTTP/1.1\r\n"),
+            "{}",
+            received
+        );
+        assert!(received.contains("\r\nHost: 127.0.0.1:"), "{}", received);
+        assert!(
+            received.contains("\r\nContent-Length: 2\r\n"),
+            "{}",
+            received
+        );
+
+        let response = response.take().unwrap();
+        assert_eq!(response.status, 201);
+        assert_eq!(response.status_text, "Created");
+        assert_eq!(response.headers.get("x-test"), Some("yes"));
+        assert_eq!(response.body, b"abc");
+    }
+
+    #[test]
+    fn join_urls() {
+        // The examples from RFC 3986 section 5.4 which we support
+        let base = Url::parse("http://a/b/c/d;p?q").unwrap();
+        let cases = [
+            // We don't know the `g` scheme, so it's a relative path to us
+            ("g:h", "http://a/b/c/g:h"),
+            ("g", "http://a/b/c/g"),
+            ("./g", "http://a/b/c/g"),
+            ("g/", "http://a/b/c/g/"),
+            ("/g", "http://a/g"),
+            ("//g", "http://g/"),
+            ("?y", "http://a/b/c/d;p?y"),
+            ("g?y", "http://a/b/c/g?y"),
+            ("#s", "http://a/b/c/d;p?q"),
+            ("g#s", "http://a/b/c/g"),
+            (";x", "http://a/b/c/;x"),
+            ("", "http://a/b/c/d;p?q"),
+            (".", "http://a/b/c/"),
+            ("./", "http://a/b/c/"),
+            ("..", "http://a/b/"),
+            ("../", "http://a/b/"),
+            ("../g", "http://a/b/g"),
+            ("../..", "http://a/"),
+            ("../../g", "http://a/g"),
+            ("../../../g", "http://a/g"),
+            ("/./g", "http://a/g"),
+            ("/../g", "http://a/g"),
+            ("g.", "http://a/b/c/g."),
+            ("..g", "http://a/b/c/..g"),
+            ("./../g", "http://a/b/g"),
+            ("g/./h", "http://a/b/c/g/h"),
+            ("g/../h", "http://a/b/c/h"),
+            ("g?y/../x", "http://a/b/c/g?y/../x"),
+            ("http://x:8080/a/../b", "http://x:8080/b"),
+        ];
+        for (reference, expected) in cases {
+            let url = base.join(reference).unwrap();
+            assert_eq!(url.to_string(), expected, "{}", reference);
+        }
+    }
+}
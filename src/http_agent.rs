@@ -0,0 +1,774 @@
+// ===== HTTP AGENT =====
+// Decides which connection a request goes out on, like `http.Agent` in Node.
+// With keep-alive on, a connection goes back to a pool once its response is
+// done, and the next request to the same origin reuses it instead of
+// opening a new one. Idle connections are closed after a while, and they
+// don't keep the event loop alive while they wait.
+//
+// Requests which don't name an agent use the global one. It has keep-alive
+// off, so every request gets a connection of its own which is closed once the
+// response is done.
+
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::rc::Rc;
+
+use crate::http::{
+    self, ClientRequest, IncomingResponse, MessageHead, MessageKind, Parsed, Parser,
+    RequestOptions, ResponseCallback, StartLine, Url,
+};
+use crate::net::Socket;
+use crate::stream::Readable;
+use crate::Js;
+
+/// How much of a response body we buffer before we stop reading
+const HIGH_WATER_MARK: usize = 16 * 1024;
+
+#[derive(Clone)]
+pub struct AgentOptions {
+    /// Keep connections open after a response so later requests can use them
+    pub keep_alive: bool,
+    /// The most connections we open to one host at a time. Requests beyond
+    /// that wait until one of them is free.
+    pub max_sockets: usize,
+    /// The most idle connections we keep per host
+    pub max_free_sockets: usize,
+    /// How long an idle connection is kept before we close it. Should be
+    /// shorter than the server's keep-alive timeout, or we risk sending a
+    /// request on a connection the server is closing.
+    pub idle_timeout_ms: u64,
+}
+
+impl Default for AgentOptions {
+    fn default() -> Self {
+        AgentOptions {
+            keep_alive: true,
+            max_sockets: usize::MAX,
+            max_free_sockets: 256,
+            idle_timeout_ms: 4000,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Agent {
+    inner: Rc<RefCell<AgentState>>,
+}
+
+struct AgentState {
+    opts: AgentOptions,
+    /// One pool for every `scheme://host:port`, and for `https://` every set
+    /// of TLS options
+    pools: HashMap<String, Pool>,
+}
+
+#[derive(Default)]
+struct Pool {
+    /// Idle connections, the last one is reused first
+    free: Vec<Connection>,
+    /// Connections which are connecting or busy with a request
+    active: usize,
+    /// Requests waiting for a connection
+    pending: VecDeque<PendingRequest>,
+}
+
+impl Pool {
+    /// The next request in line which hasn't been aborted while it waited
+    fn next_pending(&mut self) -> Option<PendingRequest> {
+        let mut req = self.pending.pop_front()?;
+        while req.request.is_destroyed() {
+            req = self.pending.pop_front()?;
+        }
+        Some(req)
+    }
+}
+
+struct PendingRequest {
+    opts: RequestOptions,
+    url: Url,
+    request: ClientRequest,
+    cb: ResponseCallback,
+}
+
+thread_local! {
+    static GLOBAL: Agent = Agent::new(AgentOptions {
+        keep_alive: false,
+        ..Default::default()
+    });
+}
+
+impl Agent {
+    pub fn new(opts: AgentOptions) -> Self {
+        let state = AgentState {
+            opts,
+            pools: HashMap::new(),
+        };
+        Agent {
+            inner: Rc::new(RefCell::new(state)),
+        }
+    }
+
+    /// The agent used by requests which don't name one
+    pub fn global() -> Agent {
+        GLOBAL.with(|agent| agent.clone())
+    }
+
+    /// Number of connections which are connecting or busy with a request
+    pub fn sockets(&self) -> usize {
+        self.inner.borrow().pools.values().map(|p| p.active).sum()
+    }
+
+    /// Number of idle connections waiting to be reused
+    pub fn free_sockets(&self) -> usize {
+        self.inner
+            .borrow()
+            .pools
+            .values()
+            .map(|p| p.free.len())
+            .sum()
+    }
+
+    /// Number of requests waiting for a connection
+    pub fn pending_requests(&self) -> usize {
+        self.inner
+            .borrow()
+            .pools
+            .values()
+            .map(|p| p.pending.len())
+            .sum()
+    }
+
+    /// Closes all idle connections. Busy ones are closed once their response
+    /// is done instead of going back to the pool.
+    pub fn destroy(&self) {
+        let free: Vec<Connection> = {
+            let mut state = self.inner.borrow_mut();
+            state.opts.keep_alive = false;
+            state
+                .pools
+                .values_mut()
+                .flat_map(|p| p.free.drain(..))
+                .collect()
+        };
+        for conn in free {
+            conn.socket().destroy();
+        }
+    }
+
+    pub(crate) fn add_request(
+        &self,
+        opts: RequestOptions,
+        url: Url,
+        request: ClientRequest,
+        cb: ResponseCallback,
+    ) {
+        let key = format!("{}://{}:{}", url.scheme, url.host, url.port);
+        #[cfg(feature = "tls")]
+        let key = match url.scheme.as_str() {
+            "https" => format!("{}#{:x}", key, opts.tls.fingerprint()),
+            _ => key,
+        };
+        let req = PendingRequest {
+            opts,
+            url,
+            request,
+            cb,
+        };
+
+        let mut state = self.inner.borrow_mut();
+        let max_sockets = state.opts.max_sockets;
+        let pool = state.pools.entry(key.clone()).or_default();
+
+        if let Some(conn) = pool.free.pop() {
+            pool.active += 1;
+            drop(state);
+            conn.inner.borrow_mut().free = false;
+            conn.send(req);
+        } else if pool.active < max_sockets {
+            pool.active += 1;
+            drop(state);
+            Connection::open(self, key.into(), &req).send(req);
+        } else {
+            pool.pending.push_back(req);
+        }
+    }
+
+    /// Called when a response is done. The connection is handed to the next
+    /// request in line, put in the pool or closed.
+    fn release(&self, conn: &Connection, reusable: bool) {
+        let mut state = self.inner.borrow_mut();
+        let AgentState { opts, pools } = &mut *state;
+        let pool = pools.get_mut(&*conn.key).expect("pool of a connection");
+
+        let keep = reusable && opts.keep_alive && !conn.socket().is_closed();
+        if keep {
+            if let Some(req) = pool.next_pending() {
+                drop(state);
+                conn.send(req);
+                return;
+            }
+            if pool.free.len() < opts.max_free_sockets {
+                pool.active -= 1;
+                pool.free.push(conn.clone());
+                let timeout_ms = opts.idle_timeout_ms;
+                drop(state);
+                conn.idle(timeout_ms);
+                return;
+            }
+        }
+
+        // The close listener does the bookkeeping
+        drop(state);
+        conn.socket().destroy();
+    }
+
+    /// Called when a connection has closed, which might make room for a
+    /// request which is waiting
+    fn remove(&self, conn: &Connection) {
+        let was_free = conn.inner.borrow().free;
+        let mut state = self.inner.borrow_mut();
+        let max_sockets = state.opts.max_sockets;
+        let pool = match state.pools.get_mut(&*conn.key) {
+            Some(pool) => pool,
+            None => return,
+        };
+
+        if was_free {
+            pool.free.retain(|c| !Rc::ptr_eq(&c.inner, &conn.inner));
+        } else {
+            pool.active -= 1;
+        }
+
+        if pool.active < max_sockets {
+            if let Some(req) = pool.next_pending() {
+                pool.active += 1;
+                drop(state);
+                Connection::open(self, conn.key.clone(), &req).send(req);
+                return;
+            }
+        }
+        if pool.active == 0 && pool.free.is_empty() {
+            state.pools.remove(&*conn.key);
+        }
+    }
+}
+
+// ===== CONNECTION =====
+
+/// A connection to one host which sends one request at a time. The socket
+/// listeners are set up once and pass everything on to the current request.
+#[derive(Clone)]
+struct Connection {
+    inner: Rc<RefCell<ConnectionState>>,
+    /// The key of the pool it belongs to
+    key: Rc<str>,
+}
+
+struct ConnectionState {
+    socket: Socket,
+    agent: Agent,
+    parser: Parser,
+    /// The request we're waiting for a response to, `None` while idle
+    exchange: Option<Exchange>,
+    /// Counts the requests sent, to tell them apart
+    exchanges: usize,
+    /// It's in the pool waiting for a request
+    free: bool,
+    idle_timer: Option<usize>,
+    connect_timer: Option<usize>,
+}
+
+struct Exchange {
+    id: usize,
+    url: String,
+    /// Both we and the server have to agree to keep the connection open
+    keep_alive: bool,
+    /// Called once the head of the response has arrived, `None` after that
+    cb: Option<ResponseCallback>,
+    /// Where we push the body once we have the head
+    body: Option<Readable>,
+    /// Fails the request if the response doesn't start in time
+    timer: Option<usize>,
+}
+
+impl Connection {
+    fn open(agent: &Agent, key: Rc<str>, req: &PendingRequest) -> Connection {
+        #[cfg(feature = "tls")]
+        let socket = http::connect(&req.url, &req.opts.tls);
+        #[cfg(not(feature = "tls"))]
+        let socket = crate::net::Net::connect((req.url.host.as_str(), req.url.port));
+        let state = ConnectionState {
+            socket: socket.clone(),
+            agent: agent.clone(),
+            parser: Parser::new(MessageKind::Response),
+            exchange: None,
+            exchanges: 0,
+            free: false,
+            idle_timer: None,
+            connect_timer: None,
+        };
+        let conn = Connection {
+            inner: Rc::new(RefCell::new(state)),
+            key,
+        };
+
+        let c = conn.clone();
+        socket.on_connect(move |_| {
+            if let Some(timer) = c.inner.borrow_mut().connect_timer.take() {
+                crate::clear_timeout(timer);
+            }
+        });
+
+        let c = conn.clone();
+        socket.on_data(move |chunk| {
+            let chunk = chunk.into_bytes().unwrap();
+            c.on_data(&chunk);
+        });
+
+        let c = conn.clone();
+        socket.on_end(move |_| {
+            let res = c.inner.borrow_mut().parser.finish();
+            match res {
+                // A body which lasts until the connection is closed
+                Ok(Some(Parsed::End)) => c.finish(false),
+                Ok(_) => (),
+                Err(e) => c.fail(e),
+            }
+        });
+
+        let c = conn.clone();
+        socket.on_error(move |err| {
+            if let Js::Error(e) = err {
+                c.fail(e);
+            }
+        });
+
+        let c = conn.clone();
+        socket.on_close(move |_| {
+            let agent = {
+                let mut state = c.inner.borrow_mut();
+                for timer in state
+                    .idle_timer
+                    .take()
+                    .into_iter()
+                    .chain(state.connect_timer.take())
+                {
+                    crate::clear_timeout(timer);
+                }
+                state.agent.clone()
+            };
+            c.fail(io::Error::new(
+                io::ErrorKind::ConnectionReset,
+                "socket hang up",
+            ));
+            agent.remove(&c);
+        });
+
+        conn
+    }
+
+    fn socket(&self) -> Socket {
+        self.inner.borrow().socket.clone()
+    }
+
+    fn send(&self, req: PendingRequest) {
+        let mut state = self.inner.borrow_mut();
+        if let Some(timer) = state.idle_timer.take() {
+            crate::clear_timeout(timer);
+        }
+        state.socket.ref_();
+
+        // Only the request which opened the connection waits for it to connect
+        let opts = &req.opts;
+        if opts.connect_timeout_ms > 0 && state.socket.is_connecting() {
+            let c = self.clone();
+            let ms = opts.connect_timeout_ms;
+            let timer = crate::set_timeout(ms, move |_| {
+                c.inner.borrow_mut().connect_timer = None;
+                c.time_out(format!("connect timed out after {} ms", ms));
+            });
+            state.connect_timer = Some(timer);
+        }
+
+        let timer = match opts.timeout_ms {
+            0 => None,
+            ms => {
+                let c = self.clone();
+                Some(crate::set_timeout(ms, move |_| {
+                    if let Some(exchange) = c.inner.borrow_mut().exchange.as_mut() {
+                        exchange.timer = None;
+                    }
+                    c.time_out(format!("no response after {} ms", ms));
+                }))
+            }
+        };
+
+        let agent_keep_alive = state.agent.inner.borrow().opts.keep_alive;
+        let keep_alive = agent_keep_alive && !opts.headers.has_token("Connection", "close");
+        state
+            .parser
+            .set_head_request(opts.method.eq_ignore_ascii_case("HEAD"));
+        state.exchanges += 1;
+        let id = state.exchanges;
+        let c = self.clone();
+        req.request.set_cancel(move || c.cancel(id));
+        state.exchange = Some(Exchange {
+            id,
+            url: req.url.to_string(),
+            keep_alive,
+            cb: Some(req.cb),
+            body: None,
+            timer,
+        });
+
+        let data = http::serialize_request(opts, &req.url, keep_alive);
+        let socket = state.socket.clone();
+        drop(state);
+        socket.write(data);
+    }
+
+    /// Waits for the next request. The connection is closed if none comes
+    /// along in time, and it doesn't keep the event loop alive meanwhile.
+    fn idle(&self, timeout_ms: u64) {
+        let socket = self.socket();
+        socket.unref();
+
+        let s = socket.clone();
+        let timer = crate::set_timeout(timeout_ms, move |_| s.destroy());
+        crate::set_event_ref(timer, false);
+        let mut state = self.inner.borrow_mut();
+        state.free = true;
+        state.idle_timer = Some(timer);
+    }
+
+    fn on_data(&self, chunk: &[u8]) {
+        let res = {
+            let mut state = self.inner.borrow_mut();
+            if state.exchange.is_none() {
+                // Servers don't talk unless they're asked something
+                Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "unexpected data on an idle connection",
+                ))
+            } else {
+                state.parser.feed(chunk)
+            }
+        };
+
+        let parsed = match res {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                self.fail(e);
+                self.socket().destroy();
+                return;
+            }
+        };
+
+        for parsed in parsed {
+            match parsed {
+                Parsed::Head(head) => self.on_head(head),
+                Parsed::Body(data) => self.on_body(data),
+                Parsed::End => self.finish(true),
+            }
+        }
+    }
+
+    fn on_head(&self, head: MessageHead) {
+        let (cb, res) = {
+            let mut state = self.inner.borrow_mut();
+            let socket = state.socket.clone();
+            let exchange = match state.exchange.as_mut() {
+                Some(exchange) => exchange,
+                None => return,
+            };
+            if let Some(timer) = exchange.timer.take() {
+                crate::clear_timeout(timer);
+            }
+            // A connection which has switched protocols doesn't speak HTTP
+            // anymore
+            let upgraded = matches!(head.start, StartLine::Response { status: 101, .. });
+            exchange.keep_alive &= head.keep_alive() && !upgraded;
+
+            // We stop reading when nobody reads the body and start again
+            // once they do
+            let s = socket.clone();
+            let body = Readable::new(HIGH_WATER_MARK, move |_| s.resume());
+
+            // Throwing the body away before it's complete leaves the rest of
+            // it on the connection, so we can't use it anymore
+            let c = self.clone();
+            let id = exchange.id;
+            body.on_close(move |_| {
+                let aborted = matches!(&c.inner.borrow().exchange, Some(e) if e.id == id);
+                if aborted {
+                    c.inner.borrow_mut().exchange = None;
+                    c.socket().destroy();
+                }
+            });
+            exchange.body = Some(body.clone());
+
+            let (status, status_text) = match head.start {
+                StartLine::Response { status, reason } => (status, reason),
+                StartLine::Request { .. } => unreachable!("parsed a request"),
+            };
+            let res = IncomingResponse {
+                url: exchange.url.clone(),
+                status,
+                status_text,
+                headers: head.headers,
+                body,
+            };
+            (exchange.cb.take(), res)
+        };
+
+        if let Some(cb) = cb {
+            cb(Ok(res));
+        }
+    }
+
+    fn on_body(&self, data: Vec<u8>) {
+        let (body, socket) = {
+            let state = self.inner.borrow();
+            match state.exchange.as_ref().and_then(|e| e.body.clone()) {
+                Some(body) => (body, state.socket.clone()),
+                None => return,
+            }
+        };
+        if !body.push(data) {
+            socket.pause();
+        }
+    }
+
+    /// The response is complete. `reusable` is false if the connection can't
+    /// be used for anything else no matter what the headers say.
+    fn finish(&self, reusable: bool) {
+        let (exchange, agent, socket) = {
+            let mut state = self.inner.borrow_mut();
+            match state.exchange.take() {
+                Some(exchange) => (exchange, state.agent.clone(), state.socket.clone()),
+                None => return,
+            }
+        };
+
+        // The whole body is in the stream now, so a slow reader doesn't stop
+        // the connection from noticing if the server closes it
+        socket.resume();
+        agent.release(self, reusable && exchange.keep_alive);
+        if let Some(body) = exchange.body {
+            body.push_end();
+        }
+    }
+
+    fn fail(&self, err: io::Error) {
+        let exchange = self.inner.borrow_mut().exchange.take();
+        if let Some(exchange) = exchange {
+            if let Some(timer) = exchange.timer {
+                crate::clear_timeout(timer);
+            }
+            match (exchange.cb, exchange.body) {
+                (Some(cb), _) => cb(Err(err)),
+                (None, Some(body)) => body.destroy(Some(err)),
+                (None, None) => (),
+            }
+        }
+    }
+
+    /// Drops the request with this id if it's still waiting for its response
+    fn cancel(&self, id: usize) {
+        let exchange = {
+            let mut state = self.inner.borrow_mut();
+            match &state.exchange {
+                Some(exchange) if exchange.id == id => state.exchange.take(),
+                _ => None,
+            }
+        };
+
+        if let Some(exchange) = exchange {
+            if let Some(timer) = exchange.timer {
+                crate::clear_timeout(timer);
+            }
+            self.socket().destroy();
+        }
+    }
+
+    fn time_out(&self, msg: String) {
+        self.fail(io::Error::new(io::ErrorKind::TimedOut, msg));
+        self.socket().destroy();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http_server::HttpServer;
+    use std::cell::Cell;
+
+    /// Runs a server which answers every request with the port of the client
+    /// after `delay_ms`, and calls `f` with its URL once it's listening
+    fn with_server(delay_ms: u64, f: impl Fn(String, HttpServer) + 'static) {
+        let f = Rc::new(f);
+        crate::run_test(move || {
+            let server = HttpServer::new(move |_req, res| {
+                let port = res.socket().remote_addr().unwrap().port();
+                crate::set_timeout(delay_ms, move |_| res.end(port.to_string()));
+            });
+            let (s, f) = (server.clone(), f.clone());
+            server.listen("127.0.0.1:0", move |_| {
+                let url = format!("http://{}/", s.local_addr().unwrap());
+                f(url, s.clone());
+            });
+        });
+    }
+
+    /// Sends a GET through `agent` and calls `cb` with the body
+    fn get(agent: &Agent, url: &str, cb: impl FnOnce(String) + 'static) {
+        let mut opts = RequestOptions::get(url);
+        opts.agent = Some(agent.clone());
+        http::request(opts, move |res| cb(res.into_response().unwrap().text()));
+    }
+
+    #[test]
+    fn second_request_reuses_the_socket() {
+        let ports = Rc::new(RefCell::new(vec![]));
+        let counts = Rc::new(RefCell::new(vec![]));
+
+        let (p, c) = (ports.clone(), counts.clone());
+        with_server(0, move |url, server| {
+            let agent = Agent::new(AgentOptions::default());
+            let (p, c, a, next) = (p.clone(), c.clone(), agent.clone(), url.clone());
+            get(&agent, &url, move |port| {
+                p.borrow_mut().push(port);
+                c.borrow_mut().push((a.sockets(), a.free_sockets()));
+
+                let (c2, agent) = (c.clone(), a.clone());
+                get(&a, &next, move |port| {
+                    p.borrow_mut().push(port);
+                    c2.borrow_mut()
+                        .push((agent.sockets(), agent.free_sockets()));
+                    agent.destroy();
+                    c2.borrow_mut()
+                        .push((agent.sockets(), agent.free_sockets()));
+                    server.close(|_| ());
+                });
+                c.borrow_mut().push((a.sockets(), a.free_sockets()));
+            });
+        });
+
+        let ports = ports.borrow();
+        assert_eq!(ports.len(), 2);
+        assert_eq!(ports[0], ports[1]);
+        // Free after the first response, busy with the second request, free
+        // again and then destroyed
+        assert_eq!(*counts.borrow(), [(0, 1), (1, 0), (0, 1), (0, 0)]);
+    }
+
+    #[test]
+    fn global_agent_doesnt_reuse_sockets() {
+        let ports = Rc::new(RefCell::new(vec![]));
+
+        let p = ports.clone();
+        with_server(0, move |url, server| {
+            let p = p.clone();
+            http::request(RequestOptions::get(&url), move |res| {
+                p.borrow_mut().push(res.into_response().unwrap().text());
+                http::request(RequestOptions::get(url), move |res| {
+                    p.borrow_mut().push(res.into_response().unwrap().text());
+                    assert_eq!(Agent::global().free_sockets(), 0);
+                    server.close(|_| ());
+                });
+            });
+        });
+
+        let ports = ports.borrow();
+        assert_eq!(ports.len(), 2);
+        assert_ne!(ports[0], ports[1]);
+    }
+
+    #[test]
+    fn max_sockets_queues_requests() {
+        let bodies = Rc::new(RefCell::new(vec![]));
+        let counts = Rc::new(RefCell::new(vec![]));
+
+        let (b, c) = (bodies.clone(), counts.clone());
+        with_server(10, move |url, server| {
+            let agent = Agent::new(AgentOptions {
+                max_sockets: 2,
+                ..Default::default()
+            });
+            let done = Rc::new(Cell::new(0));
+            for i in 0..5 {
+                let (b, c, a) = (b.clone(), c.clone(), agent.clone());
+                let (done, server) = (done.clone(), server.clone());
+                get(&agent, &url, move |port| {
+                    b.borrow_mut().push((i, port));
+                    c.borrow_mut().push((a.sockets(), a.pending_requests()));
+                    done.set(done.get() + 1);
+                    if done.get() == 5 {
+                        a.destroy();
+                        server.close(|_| ());
+                    }
+                });
+            }
+            c.borrow_mut()
+                .push((agent.sockets(), agent.pending_requests()));
+        });
+
+        // Each response hands its socket to the next request in line
+        assert_eq!(
+            *counts.borrow(),
+            [(2, 3), (2, 2), (2, 1), (2, 0), (1, 0), (0, 0)]
+        );
+        let bodies = bodies.borrow();
+        let mut done: Vec<usize> = bodies.iter().map(|(i, _)| *i).collect();
+        done.sort();
+        assert_eq!(done, [0, 1, 2, 3, 4]);
+        let mut ports: Vec<&str> = bodies.iter().map(|(_, port)| port.as_str()).collect();
+        ports.sort();
+        ports.dedup();
+        assert_eq!(ports.len(), 2);
+    }
+
+    #[test]
+    fn idle_sockets_are_closed() {
+        let free = Rc::new(RefCell::new(vec![]));
+
+        let f = free.clone();
+        with_server(0, move |url, server| {
+            let agent = Agent::new(AgentOptions {
+                idle_timeout_ms: 20,
+                ..Default::default()
+            });
+            let (f, a) = (f.clone(), agent.clone());
+            get(&agent, &url, move |_| {
+                f.borrow_mut().push(a.free_sockets());
+                crate::set_timeout(100, move |_| {
+                    f.borrow_mut().push(a.free_sockets());
+                    server.close(|_| ());
+                });
+            });
+        });
+
+        assert_eq!(*free.borrow(), [1, 0]);
+    }
+
+    #[test]
+    fn destroy_closes_busy_sockets_once_done() {
+        let counts = Rc::new(RefCell::new(vec![]));
+
+        let c = counts.clone();
+        with_server(20, move |url, server| {
+            let agent = Agent::new(AgentOptions::default());
+            let (c2, a) = (c.clone(), agent.clone());
+            get(&agent, &url, move |_| {
+                c2.borrow_mut().push((a.sockets(), a.free_sockets()));
+                server.close(|_| ());
+            });
+            // Still connecting, so there's nothing to close yet
+            agent.destroy();
+            c.borrow_mut().push((agent.sockets(), agent.free_sockets()));
+        });
+
+        assert_eq!(*counts.borrow(), [(1, 0), (0, 0)]);
+    }
+}
@@ -0,0 +1,1095 @@
+// ===== HTTP SERVER =====
+// `Http::create_server` calls a handler with a request and a response for
+// every request, just like Node. Connections are kept alive between requests
+// unless the client asks us not to, and clients may send several requests
+// without waiting for the responses (pipelining). We answer those one at a
+// time, in order: the next request is handed to the handler once the
+// response to the previous one has ended.
+//
+// A request asking to switch protocols (`Connection: Upgrade`) goes to the
+// `on_upgrade` handler instead, which gets the socket itself. From then on
+// the connection is none of our business.
+//
+// With the `tls` feature `Http::create_secure_server` makes an HTTPS server.
+// Requests come in over TLS, but apart from that nothing changes.
+
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::SocketAddr;
+use std::rc::Rc;
+
+use crate::http::{self, Headers, MessageHead, MessageKind, Parsed, Parser, StartLine};
+use crate::net::{Net, Server, Socket, ToAddress};
+use crate::stream::Readable;
+#[cfg(feature = "tls")]
+use crate::tls::TlsServerOptions;
+use crate::{defer, print, Js};
+
+/// Idle keep-alive connections are closed after this long, same as Node
+const KEEP_ALIVE_TIMEOUT_MS: u64 = 5000;
+
+type Handler = Box<dyn FnMut(IncomingRequest, ServerResponse)>;
+/// Gets the request, the socket and whatever the client sent after the head
+type UpgradeHandler = Box<dyn FnMut(IncomingRequest, Socket, Vec<u8>)>;
+
+#[derive(Clone)]
+pub struct HttpServer {
+    server: Server,
+    upgrade: Rc<RefCell<Option<UpgradeHandler>>>,
+}
+
+impl HttpServer {
+    pub(crate) fn new(handler: impl FnMut(IncomingRequest, ServerResponse) + 'static) -> Self {
+        let res = HttpServer::with_server(handler, |on_connection| {
+            Ok(Net::create_server(on_connection))
+        });
+        res.expect("creating a TCP server can't fail")
+    }
+
+    #[cfg(feature = "tls")]
+    pub(crate) fn new_secure(
+        opts: TlsServerOptions,
+        handler: impl FnMut(IncomingRequest, ServerResponse) + 'static,
+    ) -> io::Result<Self> {
+        HttpServer::with_server(handler, |on_connection| {
+            Net::create_tls_server(opts, on_connection)
+        })
+    }
+
+    /// `create` makes the server which hands us the connections
+    fn with_server(
+        handler: impl FnMut(IncomingRequest, ServerResponse) + 'static,
+        create: impl FnOnce(Box<dyn FnMut(Socket)>) -> io::Result<Server>,
+    ) -> io::Result<Self> {
+        let handler: Rc<RefCell<Handler>> = Rc::new(RefCell::new(Box::new(handler)));
+        let upgrade: Rc<RefCell<Option<UpgradeHandler>>> = Rc::new(RefCell::new(None));
+        let u = upgrade.clone();
+        let server = create(Box::new(move |socket| {
+            Connection::start(socket, handler.clone(), u.clone())
+        }))?;
+        Ok(HttpServer { server, upgrade })
+    }
+
+    /// Takes over connections which ask to switch protocols, like the
+    /// `upgrade` event in Node. Without an upgrade handler such requests go
+    /// to the normal handler and the connection is closed after the
+    /// response.
+    pub fn on_upgrade(&self, cb: impl FnMut(IncomingRequest, Socket, Vec<u8>) + 'static) {
+        *self.upgrade.borrow_mut() = Some(Box::new(cb));
+    }
+
+    pub fn listen(&self, addr: impl ToAddress, cb: impl FnOnce(Js) + 'static) -> HttpServer {
+        self.server.listen(addr, cb);
+        self.clone()
+    }
+
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.server.local_addr()
+    }
+
+    /// Stops accepting connections. `cb` is called once the open connections
+    /// have closed, which for idle keep-alive connections happens when they
+    /// time out.
+    pub fn close(&self, cb: impl FnOnce(Js) + 'static) {
+        self.server.close(cb);
+    }
+
+    pub fn on_error(&self, cb: impl FnMut(Js) + 'static) {
+        self.server.on_error(cb);
+    }
+}
+
+// ===== CONNECTION =====
+
+struct Connection {
+    socket: Socket,
+    parser: Parser,
+    handler: Rc<RefCell<Handler>>,
+    upgrade: Rc<RefCell<Option<UpgradeHandler>>>,
+    /// Parsed parts of requests we haven't handed to the handler yet
+    queue: VecDeque<Parsed>,
+    /// The body of the request we're receiving
+    body: Option<Readable>,
+    /// A response is in progress, so the next request has to wait
+    responding: bool,
+    /// We've decided to close the connection after the current response
+    closing: bool,
+    /// The client has ended its side, so we end ours once we have responded
+    /// to everything it sent
+    client_ended: bool,
+    /// The socket has been handed to the upgrade handler
+    upgraded: bool,
+}
+
+impl Connection {
+    fn start(
+        socket: Socket,
+        handler: Rc<RefCell<Handler>>,
+        upgrade: Rc<RefCell<Option<UpgradeHandler>>>,
+    ) {
+        let conn = Rc::new(RefCell::new(Connection {
+            socket: socket.clone(),
+            parser: Parser::new(MessageKind::Request),
+            handler,
+            upgrade,
+            queue: VecDeque::new(),
+            body: None,
+            responding: false,
+            closing: false,
+            client_ended: false,
+            upgraded: false,
+        }));
+
+        // The client may end its side right after sending a request and
+        // still wait for the response
+        socket.set_allow_half_open(true);
+
+        let c = conn.clone();
+        socket.on_data(move |chunk| {
+            if c.borrow().upgraded {
+                return;
+            }
+            let chunk = chunk.into_bytes().unwrap();
+            let res = c.borrow_mut().parser.feed(&chunk);
+            match res {
+                Ok(parsed) => {
+                    c.borrow_mut().queue.extend(parsed);
+                    Connection::process(&c);
+                }
+                Err(e) => Connection::bad_request(&c, e),
+            }
+        });
+
+        let c = conn.clone();
+        socket.on_end(move |_| {
+            // A request body which is cut short is an error for whoever
+            // reads it
+            let body = {
+                let mut conn = c.borrow_mut();
+                if conn.upgraded {
+                    return;
+                }
+                conn.client_ended = true;
+                conn.body.take()
+            };
+            if let Some(body) = body {
+                let err = io::Error::new(io::ErrorKind::UnexpectedEof, "request aborted");
+                body.destroy(Some(err));
+            }
+            Connection::maybe_end(&c);
+        });
+
+        // The socket can time out in the middle of a response, so the
+        // listener has to stay for the next time
+        let c = conn;
+        socket.on_timeout(move |_| {
+            let conn = c.borrow();
+            if !conn.responding && !conn.upgraded {
+                conn.socket.destroy();
+            }
+        });
+        socket.set_timeout(KEEP_ALIVE_TIMEOUT_MS, |_| ());
+    }
+
+    /// Hands queued requests to the handler until we have to wait for a
+    /// response to end
+    fn process(conn: &Rc<RefCell<Connection>>) {
+        loop {
+            let mut c = conn.borrow_mut();
+            if c.closing {
+                return;
+            }
+
+            match c.queue.front() {
+                Some(Parsed::Head(_)) if c.responding => return,
+                Some(_) => (),
+                None => return,
+            }
+
+            match c.queue.pop_front().unwrap() {
+                Parsed::Head(head) => {
+                    drop(c);
+                    Connection::dispatch(conn, head);
+                }
+                Parsed::Body(data) => {
+                    if let Some(body) = c.body.clone() {
+                        let socket = c.socket.clone();
+                        drop(c);
+                        // The body resumes the socket once it's read from
+                        if !body.push(data) {
+                            socket.pause();
+                        }
+                    }
+                }
+                Parsed::End => {
+                    if let Some(body) = c.body.take() {
+                        drop(c);
+                        body.push_end();
+                    }
+                }
+            }
+        }
+    }
+
+    fn dispatch(conn: &Rc<RefCell<Connection>>, head: MessageHead) {
+        // The parser stops after an upgrade request, so the connection can't
+        // be used for another request if we answer it ourselves
+        let upgrade = head.is_upgrade();
+        let keep_alive = head.keep_alive() && !upgrade;
+        let (method, target) = match head.start {
+            StartLine::Request { method, target } => (method, target),
+            StartLine::Response { .. } => unreachable!("parsed a response"),
+        };
+
+        let socket = conn.borrow().socket.clone();
+        if head.headers.has_token("Expect", "100-continue") {
+            socket.write("HTTP/1.1 100 Continue\r\n\r\n");
+        }
+
+        // The body is pushed to the request as it arrives, and we stop reading
+        // from the socket while it's full
+        let body = {
+            let socket = socket.clone();
+            Readable::new(16 * 1024, move |_| socket.resume())
+        };
+        let (path, query) = match target.split_once('?') {
+            Some((path, query)) => (path.to_string(), query.to_string()),
+            None => (target.clone(), String::new()),
+        };
+
+        let req = IncomingRequest {
+            method: method.clone(),
+            url: target,
+            path,
+            query,
+            version: head.version,
+            headers: head.headers.clone(),
+            params: HashMap::new(),
+            body: body.clone(),
+        };
+
+        if upgrade && conn.borrow().upgrade.borrow().is_some() {
+            Connection::upgrade(conn, req);
+            return;
+        }
+
+        let c = conn.clone();
+        let res = ServerResponse::new(
+            socket,
+            head.version,
+            keep_alive,
+            method == "HEAD",
+            Box::new(move |keep_alive| Connection::on_response_end(&c, keep_alive)),
+        );
+
+        let handler = {
+            let mut c = conn.borrow_mut();
+            c.body = Some(body);
+            c.responding = true;
+            c.handler.clone()
+        };
+
+        (handler.borrow_mut())(req, res);
+    }
+
+    /// Hands the socket to the upgrade handler
+    fn upgrade(conn: &Rc<RefCell<Connection>>, req: IncomingRequest) {
+        let (socket, head, upgrade) = {
+            let mut c = conn.borrow_mut();
+            c.upgraded = true;
+            c.closing = true;
+            c.queue.clear();
+            (c.socket.clone(), c.parser.take_buffer(), c.upgrade.clone())
+        };
+        socket.set_timeout(0, |_| ());
+        req.body.push_end();
+
+        let handler = upgrade.borrow_mut().take();
+        if let Some(mut handler) = handler {
+            handler(req, socket, head);
+            // The handler may have replaced itself
+            let mut upgrade = upgrade.borrow_mut();
+            if upgrade.is_none() {
+                *upgrade = Some(handler);
+            }
+        }
+    }
+
+    fn on_response_end(conn: &Rc<RefCell<Connection>>, keep_alive: bool) {
+        let mut c = conn.borrow_mut();
+        c.responding = false;
+        if !keep_alive {
+            c.closing = true;
+            c.queue.clear();
+            c.socket.end();
+            return;
+        }
+        let body = c.body.clone();
+        drop(c);
+
+        // Nobody is going to read the rest of the body, but the socket has to
+        // get past it to the next request, so we throw it away
+        if let Some(body) = body.filter(|body| !body.is_started()) {
+            body.resume();
+        }
+
+        // The response might be ended from inside the handler, so we move on
+        // to the next request on the next tick instead of calling the handler
+        // again from inside itself
+        let conn = conn.clone();
+        defer(move |_| {
+            Connection::process(&conn);
+            Connection::maybe_end(&conn);
+        });
+    }
+
+    /// Ends our side if the client has ended its side and there's nothing
+    /// left to respond to
+    fn maybe_end(conn: &Rc<RefCell<Connection>>) {
+        let mut c = conn.borrow_mut();
+        if c.client_ended && !c.responding && !c.closing && c.queue.is_empty() {
+            c.closing = true;
+            c.socket.end();
+        }
+    }
+
+    fn bad_request(conn: &Rc<RefCell<Connection>>, err: io::Error) {
+        let mut c = conn.borrow_mut();
+        if c.closing {
+            return;
+        }
+        print(format!("Bad request: {}", err));
+        c.closing = true;
+        c.queue.clear();
+
+        // If a response is in progress we can't interrupt it with another one
+        if !c.responding {
+            let body = format!("{}\n", err);
+            c.socket.write(format!(
+                "HTTP/1.1 400 Bad Request\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            ));
+        }
+        c.socket.end();
+    }
+}
+
+// ===== REQUEST =====
+
+#[derive(Clone)]
+pub struct IncomingRequest {
+    pub method: String,
+    /// The request target as sent by the client, e.g. `/users/1?full=true`
+    pub url: String,
+    /// The part of `url` before the `?`
+    pub path: String,
+    /// The part of `url` after the `?`
+    pub query: String,
+    /// 0 for HTTP/1.0 and 1 for HTTP/1.1
+    pub version: u8,
+    pub headers: Headers,
+    /// Path parameters filled in by the `Router`
+    pub params: HashMap<String, String>,
+    /// The request body. We don't wait for it before calling the handler.
+    pub body: Readable,
+}
+
+impl IncomingRequest {
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params.get(name).map(|p| p.as_str())
+    }
+
+    /// Collects the whole body and calls `cb` with `Js::Bytes`, or with the
+    /// error if the request is aborted
+    pub fn read_body(&self, cb: impl FnOnce(Js) + 'static) {
+        self.body.read_to_end(cb);
+    }
+}
+
+// ===== RESPONSE =====
+
+#[derive(Clone)]
+pub struct ServerResponse {
+    inner: Rc<RefCell<ResponseState>>,
+}
+
+struct ResponseState {
+    socket: Socket,
+    status: u16,
+    headers: Headers,
+    version: u8,
+    keep_alive: bool,
+    /// Responses to HEAD requests have headers but no body
+    head_request: bool,
+    headers_sent: bool,
+    chunked: bool,
+    finished: bool,
+    /// Lets the connection know we're done and if it can be reused
+    on_end: Option<Box<dyn FnOnce(bool)>>,
+}
+
+impl ServerResponse {
+    fn new(
+        socket: Socket,
+        version: u8,
+        keep_alive: bool,
+        head_request: bool,
+        on_end: Box<dyn FnOnce(bool)>,
+    ) -> Self {
+        let state = ResponseState {
+            socket,
+            status: 200,
+            headers: Headers::new(),
+            version,
+            keep_alive,
+            head_request,
+            headers_sent: false,
+            chunked: false,
+            finished: false,
+            on_end: Some(on_end),
+        };
+
+        ServerResponse {
+            inner: Rc::new(RefCell::new(state)),
+        }
+    }
+
+    pub fn set_status(&self, status: u16) {
+        self.inner.borrow_mut().status = status;
+    }
+
+    /// Sets a header which is sent with the head. Does nothing once the head
+    /// is sent.
+    pub fn set_header(&self, name: impl Into<String>, value: impl Into<String>) {
+        let mut state = self.inner.borrow_mut();
+        if !state.headers_sent {
+            state.headers.set(name, value);
+        }
+    }
+
+    pub fn headers_sent(&self) -> bool {
+        self.inner.borrow().headers_sent
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.inner.borrow().finished
+    }
+
+    /// Sends the status line and headers right away. If there's no
+    /// `Content-Length` header the body is sent with chunked encoding.
+    pub fn write_head<N, V>(&self, status: u16, headers: impl IntoIterator<Item = (N, V)>)
+    where
+        N: Into<String>,
+        V: Into<String>,
+    {
+        {
+            let mut state = self.inner.borrow_mut();
+            if state.headers_sent {
+                return;
+            }
+            state.status = status;
+            for (name, value) in headers {
+                state.headers.set(name, value);
+            }
+        }
+        self.send_head();
+    }
+
+    /// Writes part of the body. Returns false if the data was buffered
+    /// because the client can't keep up, see `on_drain`.
+    pub fn write(&self, data: impl Into<Vec<u8>>) -> bool {
+        if self.inner.borrow().finished {
+            return false;
+        }
+        self.send_head();
+        self.write_body(data.into())
+    }
+
+    pub fn on_drain(&self, cb: impl FnMut(Js) + 'static) {
+        self.inner.borrow().socket.on_drain(cb);
+    }
+
+    /// The connection the response is sent on, like `res.socket` in Node
+    pub fn socket(&self) -> Socket {
+        self.inner.borrow().socket.clone()
+    }
+
+    /// Writes `data` as the last part of the body and ends the response. If
+    /// nothing was written before, `data` is sent with a `Content-Length`.
+    pub fn end(&self, data: impl Into<Vec<u8>>) {
+        let data = data.into();
+        {
+            let mut state = self.inner.borrow_mut();
+            if state.finished {
+                return;
+            }
+            if !state.headers_sent && !state.headers.has("Content-Length") {
+                state.headers.set("Content-Length", data.len().to_string());
+            }
+        }
+
+        self.send_head();
+        if !data.is_empty() {
+            self.write_body(data);
+        }
+
+        let (on_end, keep_alive) = {
+            let mut state = self.inner.borrow_mut();
+            state.finished = true;
+            if state.chunked && !state.head_request {
+                state.socket.write("0\r\n\r\n");
+            }
+            (state.on_end.take(), state.keep_alive)
+        };
+
+        if let Some(on_end) = on_end {
+            on_end(keep_alive);
+        }
+    }
+
+    fn send_head(&self) {
+        let mut state = self.inner.borrow_mut();
+        if state.headers_sent {
+            return;
+        }
+        state.headers_sent = true;
+
+        let status = state.status;
+        let has_body = !(100..200).contains(&status) && status != 204 && status != 304;
+        let has_length =
+            state.headers.has("Content-Length") || state.headers.has("Transfer-Encoding");
+
+        if has_body && !has_length {
+            if state.version >= 1 {
+                state.chunked = true;
+                state.headers.set("Transfer-Encoding", "chunked");
+            } else {
+                // An HTTP/1.0 client can only tell the body has ended when we
+                // close the connection
+                state.keep_alive = false;
+            }
+        }
+
+        if state.headers.has_token("Connection", "close") {
+            state.keep_alive = false;
+        }
+        let connection = if state.keep_alive {
+            "keep-alive"
+        } else {
+            "close"
+        };
+        state.headers.set("Connection", connection);
+
+        let mut head = format!("HTTP/1.1 {} {}\r\n", status, http::status_text(status));
+        for (name, value) in state.headers.iter() {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str("\r\n");
+        state.socket.write(head);
+    }
+
+    fn write_body(&self, data: Vec<u8>) -> bool {
+        let state = self.inner.borrow();
+        if state.head_request || data.is_empty() {
+            return true;
+        }
+
+        if state.chunked {
+            let mut chunk = format!("{:x}\r\n", data.len()).into_bytes();
+            chunk.extend(data);
+            chunk.extend(b"\r\n");
+            state.socket.write(chunk)
+        } else {
+            state.socket.write(data)
+        }
+    }
+}
+
+// ===== ROUTER =====
+
+/// Picks a handler based on the method and the path. Paths can have
+/// parameters like `/users/:id`, and a `*` at the end matches the rest of the
+/// path, which ends up in the `*` parameter.
+#[derive(Default)]
+pub struct Router {
+    routes: Vec<RouteEntry>,
+    not_found: Option<Handler>,
+}
+
+struct RouteEntry {
+    method: String,
+    segments: Vec<String>,
+    handler: Handler,
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Router::default()
+    }
+
+    pub fn route(
+        mut self,
+        method: &str,
+        path: &str,
+        handler: impl FnMut(IncomingRequest, ServerResponse) + 'static,
+    ) -> Self {
+        self.routes.push(RouteEntry {
+            method: method.to_ascii_uppercase(),
+            segments: split_path(path).map(String::from).collect(),
+            handler: Box::new(handler),
+        });
+        self
+    }
+
+    pub fn get(
+        self,
+        path: &str,
+        handler: impl FnMut(IncomingRequest, ServerResponse) + 'static,
+    ) -> Self {
+        self.route("GET", path, handler)
+    }
+
+    pub fn post(
+        self,
+        path: &str,
+        handler: impl FnMut(IncomingRequest, ServerResponse) + 'static,
+    ) -> Self {
+        self.route("POST", path, handler)
+    }
+
+    pub fn put(
+        self,
+        path: &str,
+        handler: impl FnMut(IncomingRequest, ServerResponse) + 'static,
+    ) -> Self {
+        self.route("PUT", path, handler)
+    }
+
+    pub fn delete(
+        self,
+        path: &str,
+        handler: impl FnMut(IncomingRequest, ServerResponse) + 'static,
+    ) -> Self {
+        self.route("DELETE", path, handler)
+    }
+
+    /// Called when no route matches. The default responds with a 404.
+    pub fn not_found(
+        mut self,
+        handler: impl FnMut(IncomingRequest, ServerResponse) + 'static,
+    ) -> Self {
+        self.not_found = Some(Box::new(handler));
+        self
+    }
+
+    /// Turns the router into a handler for `Http::create_server`
+    pub fn into_handler(mut self) -> impl FnMut(IncomingRequest, ServerResponse) {
+        move |req, res| self.handle(req, res)
+    }
+
+    pub fn handle(&mut self, mut req: IncomingRequest, res: ServerResponse) {
+        for route in self.routes.iter_mut() {
+            // GET routes answer HEAD requests too, the body is left out
+            let method_matches =
+                route.method == req.method || (route.method == "GET" && req.method == "HEAD");
+            if !method_matches {
+                continue;
+            }
+
+            if let Some(params) = match_path(&route.segments, &req.path) {
+                req.params = params;
+                (route.handler)(req, res);
+                return;
+            }
+        }
+
+        match self.not_found.as_mut() {
+            Some(handler) => handler(req, res),
+            None => {
+                res.set_status(404);
+                res.set_header("Content-Type", "text/plain");
+                res.end(format!("Cannot {} {}\n", req.method, req.path));
+            }
+        }
+    }
+}
+
+fn split_path(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|s| !s.is_empty())
+}
+
+fn match_path(pattern: &[String], path: &str) -> Option<HashMap<String, String>> {
+    let mut params = HashMap::new();
+    let mut segments = split_path(path);
+
+    for (i, expected) in pattern.iter().enumerate() {
+        if expected == "*" && i == pattern.len() - 1 {
+            let rest: Vec<&str> = segments.collect();
+            params.insert("*".to_string(), rest.join("/"));
+            return Some(params);
+        }
+
+        let segment = segments.next()?;
+        match expected.strip_prefix(':') {
+            Some(name) => {
+                params.insert(name.to_string(), segment.to_string());
+            }
+            None if expected == segment => (),
+            None => return None,
+        }
+    }
+
+    match segments.next() {
+        Some(_) => None,
+        None => Some(params),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stream::AsReadable;
+    use std::cell::Cell;
+
+    /// Starts a server with `handler`, sends `request` to it and calls `cb`
+    /// with everything the server sent back once it closes the connection
+    fn exchange(
+        handler: impl FnMut(IncomingRequest, ServerResponse) + Clone + 'static,
+        request: Vec<u8>,
+        cb: impl FnOnce(String) + Clone + 'static,
+    ) {
+        crate::run_test(move || {
+            let server = HttpServer::new(handler.clone());
+            let (s, request, cb) = (server.clone(), request.clone(), cb.clone());
+            server.listen("127.0.0.1:0", move |_| {
+                let socket = Net::connect(s.local_addr().unwrap().to_string());
+                let received = Rc::new(RefCell::new(vec![]));
+                let r = received.clone();
+                socket.on_data(move |chunk| r.borrow_mut().extend(chunk.into_bytes().unwrap()));
+                let mut cb = Some(cb);
+                socket.on_end(move |_| {
+                    let cb = cb.take().unwrap();
+                    cb(String::from_utf8_lossy(&received.borrow()).into_owned());
+                    s.close(|_| ());
+                });
+                socket.write(request);
+            });
+        });
+    }
+
+    fn post(body_len: usize, connection: &str) -> Vec<u8> {
+        let head = format!(
+            "POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\nConnection: {}\r\n\r\n",
+            body_len, connection
+        );
+        let mut request = head.into_bytes();
+        request.resize(request.len() + body_len, b'x');
+        request
+    }
+
+    #[test]
+    fn full_body_pauses_the_socket() {
+        const BODY_LEN: usize = 4 * 1024 * 1024;
+        let paused = Rc::new(Cell::new(false));
+        let resumed = Rc::new(Cell::new(false));
+        let received = Rc::new(Cell::new(0));
+
+        let (p, r, n) = (paused.clone(), resumed.clone(), received.clone());
+        let handler = move |req: IncomingRequest, res: ServerResponse| {
+            let (p, r, n) = (p.clone(), r.clone(), n.clone());
+            // Nobody reads the body for a while, so it fills up
+            crate::set_timeout(100, move |_| {
+                let socket = res.socket();
+                p.set(socket.readable().is_paused());
+                req.read_body(move |data| {
+                    r.set(!socket.readable().is_paused());
+                    n.set(data.into_bytes().unwrap().len());
+                    res.end("done");
+                });
+            });
+        };
+
+        exchange(handler, post(BODY_LEN, "close"), |response| {
+            assert!(response.starts_with("HTTP/1.1 200 OK"));
+        });
+
+        assert!(paused.get());
+        assert!(resumed.get());
+        assert_eq!(received.get(), BODY_LEN);
+    }
+
+    #[test]
+    fn unread_body_is_skipped() {
+        let requests = Rc::new(Cell::new(0));
+
+        let r = requests.clone();
+        let handler = move |_req: IncomingRequest, res: ServerResponse| {
+            r.set(r.get() + 1);
+            res.end("ok");
+        };
+
+        // The second request is behind a body which is too big to buffer
+        let mut request = post(1024 * 1024, "keep-alive");
+        request.extend(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+        exchange(handler, request, |response| {
+            assert_eq!(response.matches("HTTP/1.1 200 OK").count(), 2);
+        });
+
+        assert_eq!(requests.get(), 2);
+    }
+
+    fn get(path: &str, connection: &str) -> String {
+        format!(
+            "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: {}\r\n\r\n",
+            path, connection
+        )
+    }
+
+    #[test]
+    fn keep_alive_reuses_the_connection() {
+        let peers = Rc::new(RefCell::new(vec![]));
+        let received = Rc::new(RefCell::new(String::new()));
+
+        let (p, r) = (peers.clone(), received.clone());
+        crate::run_test(move || {
+            let p = p.clone();
+            let server = crate::Http::create_server(move |_req, res| {
+                p.borrow_mut().push(res.socket().remote_addr().unwrap());
+                res.end("ok");
+            });
+
+            let (s, r) = (server.clone(), r.clone());
+            server.listen("127.0.0.1:0", move |_| {
+                let socket = Net::connect(s.local_addr().unwrap());
+                socket.write(get("/", "keep-alive"));
+
+                // The second request is sent once the first is answered
+                let (r, sock) = (r.clone(), socket.clone());
+                socket.on_data(move |chunk| {
+                    let mut r = r.borrow_mut();
+                    r.push_str(&String::from_utf8(chunk.into_bytes().unwrap()).unwrap());
+                    if r.ends_with("\r\n\r\nok") && r.matches("HTTP/1.1").count() == 1 {
+                        sock.write(get("/", "close"));
+                    }
+                });
+                let s = s.clone();
+                socket.on_end(move |_| s.close(|_| ()));
+            });
+        });
+
+        let peers = peers.borrow();
+        assert_eq!(peers.len(), 2);
+        assert_eq!(peers[0], peers[1]);
+
+        let received = received.borrow();
+        let responses: Vec<&str> = received.split("HTTP/1.1 200 OK").skip(1).collect();
+        assert_eq!(responses.len(), 2);
+        assert!(responses[0].contains("Connection: keep-alive"));
+        assert!(responses[1].contains("Connection: close"));
+    }
+
+    #[test]
+    fn pipelined_requests_are_answered_in_order() {
+        // The first response takes the longest
+        let handler = |req: IncomingRequest, res: ServerResponse| {
+            let delay = match req.path.as_str() {
+                "/1" => 50,
+                "/2" => 10,
+                _ => 0,
+            };
+            crate::set_timeout(delay, move |_| res.end(req.path.clone()));
+        };
+
+        let request = get("/1", "keep-alive") + &get("/2", "keep-alive") + &get("/3", "close");
+        exchange(handler, request.into_bytes(), |response| {
+            let bodies: Vec<&str> = response
+                .split("HTTP/1.1 200 OK")
+                .skip(1)
+                .map(|r| r.split("\r\n\r\n").nth(1).unwrap())
+                .collect();
+            assert_eq!(bodies, ["/1", "/2", "/3"]);
+        });
+    }
+
+    #[test]
+    fn write_streams_a_chunked_body() {
+        let handler = |_req: IncomingRequest, res: ServerResponse| {
+            assert!(res.write("hello "));
+            assert!(res.headers_sent());
+            let res2 = res.clone();
+            crate::set_timeout(10, move |_| {
+                res2.write("world");
+                res2.end("");
+                assert!(res2.is_finished());
+            });
+        };
+
+        exchange(handler, get("/", "close").into_bytes(), |response| {
+            assert!(response.contains("Transfer-Encoding: chunked\r\n"));
+            assert!(!response.contains("Content-Length"));
+            assert!(response.ends_with("\r\n\r\n6\r\nhello \r\n5\r\nworld\r\n0\r\n\r\n"));
+        });
+    }
+
+    #[test]
+    fn write_head() {
+        let handler = |_req: IncomingRequest, res: ServerResponse| {
+            res.set_header("X-Early", "yes");
+            res.write_head(201, [("X-Test", "a"), ("Content-Length", "2")]);
+            // Too late for these
+            res.set_header("X-Late", "yes");
+            res.write_head(500, Vec::<(String, String)>::new());
+            res.end("ok");
+        };
+
+        exchange(handler, get("/", "close").into_bytes(), |response| {
+            assert!(response.starts_with("HTTP/1.1 201 Created\r\n"));
+            assert!(response.contains("X-Early: yes\r\n"));
+            assert!(response.contains("X-Test: a\r\n"));
+            assert!(!response.contains("X-Late"));
+            assert!(!response.contains("chunked"));
+            assert!(response.ends_with("\r\n\r\nok"));
+        });
+    }
+
+    #[test]
+    fn head_response_has_no_body() {
+        let handler = |_req: IncomingRequest, res: ServerResponse| res.end("hello");
+        let request = "HEAD / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n";
+
+        exchange(handler, request.as_bytes().to_vec(), |response| {
+            assert!(response.contains("Content-Length: 5\r\n"));
+            assert!(response.ends_with("\r\n\r\n"));
+        });
+    }
+
+    #[test]
+    fn http_1_0_closes_the_connection() {
+        // Without a length the body lasts until we close the connection
+        let handler = |_req: IncomingRequest, res: ServerResponse| {
+            res.write("old ");
+            res.end("school");
+        };
+
+        exchange(handler, b"GET / HTTP/1.0\r\n\r\n".to_vec(), |response| {
+            assert!(response.contains("Connection: close\r\n"));
+            assert!(!response.contains("chunked"));
+            assert!(response.ends_with("\r\n\r\nold school"));
+        });
+    }
+
+    #[test]
+    fn malformed_request_gets_400() {
+        let called = Rc::new(Cell::new(false));
+
+        let c = called.clone();
+        let handler = move |_req: IncomingRequest, _res: ServerResponse| c.set(true);
+        exchange(handler, b"GET / HTTP/9\r\n\r\n".to_vec(), |response| {
+            assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+            assert!(response.contains("Connection: close\r\n"));
+        });
+
+        assert!(!called.get());
+    }
+
+    #[test]
+    fn router() {
+        let router = Router::new()
+            .get("/users/:id", |req, res| {
+                res.end(format!("user {}", req.param("id").unwrap()))
+            })
+            .post("/users/:id/posts/:post", |req, res| {
+                let (id, post) = (req.param("id").unwrap(), req.param("post").unwrap());
+                res.end(format!("post {} by {}", post, id))
+            })
+            .put("/files/*", |req, res| {
+                res.end(format!("file {}", req.param("*").unwrap()))
+            })
+            .delete("/users/:id", |req, res| {
+                res.end(format!("deleted {}", req.param("id").unwrap()))
+            });
+        let router = Rc::new(RefCell::new(router));
+        let handler = move |req, res| router.borrow_mut().handle(req, res);
+
+        let request = |method: &str, path: &str| {
+            format!(
+                "{} {} HTTP/1.1\r\nHost: localhost\r\nContent-Length: 0\r\n\r\n",
+                method, path
+            )
+        };
+        let requests = [
+            request("GET", "/users/7?full=true"),
+            request("POST", "/users/7/posts/3"),
+            request("PUT", "/files/a/b.txt"),
+            request("DELETE", "/users/7"),
+            request("POST", "/users/7"),
+            request("GET", "/nothing"),
+            get("/users/8/", "close"),
+        ];
+
+        exchange(handler, requests.concat().into_bytes(), |response| {
+            let responses: Vec<(&str, &str)> = response
+                .split("HTTP/1.1 ")
+                .skip(1)
+                .map(|r| (&r[..3], r.split("\r\n\r\n").nth(1).unwrap()))
+                .collect();
+            let expected = [
+                ("200", "user 7"),
+                ("200", "post 3 by 7"),
+                ("200", "file a/b.txt"),
+                ("200", "deleted 7"),
+                ("404", "Cannot POST /users/7\n"),
+                ("404", "Cannot GET /nothing\n"),
+                ("200", "user 8"),
+            ];
+            assert_eq!(responses, expected);
+        });
+    }
+
+    #[test]
+    fn router_not_found_handler() {
+        let router = Router::new().not_found(|req, res| {
+            res.set_status(410);
+            res.end(format!("{} is gone", req.path));
+        });
+        let router = Rc::new(RefCell::new(router));
+        let handler = move |req, res| router.borrow_mut().handle(req, res);
+
+        exchange(handler, get("/old", "close").into_bytes(), |response| {
+            assert!(response.starts_with("HTTP/1.1 410 Unknown\r\n"));
+            assert!(response.ends_with("/old is gone"));
+        });
+    }
+
+    #[test]
+    fn match_paths() {
+        let pattern = |p: &str| split_path(p).map(String::from).collect::<Vec<_>>();
+        let params = |pairs: &[(&str, &str)]| {
+            let params = pairs.iter().map(|(k, v)| (k.to_string(), v.to_string()));
+            Some(params.collect::<HashMap<_, _>>())
+        };
+
+        assert_eq!(match_path(&pattern("/"), "/"), params(&[]));
+        assert_eq!(match_path(&pattern("/a/b"), "/a/b/"), params(&[]));
+        assert_eq!(match_path(&pattern("/a/b"), "/a"), None);
+        assert_eq!(match_path(&pattern("/a"), "/a/b"), None);
+        assert_eq!(
+            match_path(&pattern("/a/:id"), "/a/1"),
+            params(&[("id", "1")])
+        );
+        assert_eq!(match_path(&pattern("/a/:id"), "/b/1"), None);
+        assert_eq!(
+            match_path(&pattern("/:a/x/:b"), "/1/x/2"),
+            params(&[("a", "1"), ("b", "2")])
+        );
+        assert_eq!(match_path(&pattern("/a/*"), "/a"), params(&[("*", "")]));
+        assert_eq!(
+            match_path(&pattern("/a/*"), "/a/b/c"),
+            params(&[("*", "b/c")])
+        );
+        // Only a `*` at the end matches the rest
+        assert_eq!(match_path(&pattern("/*/b"), "/a/b"), None);
+    }
+}
@@ -0,0 +1,465 @@
+// ===== JSON =====
+// Just enough JSON for `res.json()` and friends: `Json::parse` does what
+// `JSON.parse` does, and printing a `Json` value does what `JSON.stringify`
+// does. Objects keep their keys in the order they were written.
+
+use std::fmt;
+use std::io;
+
+/// We give up on documents nested deeper than this instead of overflowing
+/// the stack
+const MAX_DEPTH: usize = 256;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    pub fn parse(text: &str) -> io::Result<Json> {
+        let mut parser = JsonParser {
+            bytes: text.as_bytes(),
+            pos: 0,
+            depth: 0,
+        };
+        let value = parser.value()?;
+        parser.skip_whitespace();
+        if parser.pos < parser.bytes.len() {
+            return Err(parser.error("unexpected data after the value"));
+        }
+        Ok(value)
+    }
+
+    /// The value of `key` if this is an object which has it
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(entries) => entries.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Json::Number(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Json::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Json]> {
+        match self {
+            Json::Array(items) => Some(items),
+            _ => None,
+        }
+    }
+
+    pub fn is_null(&self) -> bool {
+        matches!(self, Json::Null)
+    }
+}
+
+/// Prints the value as compact JSON, like `JSON.stringify(value)`
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            // JSON has no NaN or Infinity, javascript prints them as null too
+            Json::Number(n) if !n.is_finite() => write!(f, "null"),
+            Json::Number(n) => write!(f, "{}", n),
+            Json::String(s) => write_string(f, s),
+            Json::Array(items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, "]")
+            }
+            Json::Object(entries) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in entries.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write_string(f, key)?;
+                    write!(f, ":{}", value)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+struct JsonParser<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    depth: usize,
+}
+
+impl<'a> JsonParser<'a> {
+    fn value(&mut self) -> io::Result<Json> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some(b'{') => self.nested(JsonParser::object),
+            Some(b'[') => self.nested(JsonParser::array),
+            Some(b'"') => self.string().map(Json::String),
+            Some(b't') => self.literal("true", Json::Bool(true)),
+            Some(b'f') => self.literal("false", Json::Bool(false)),
+            Some(b'n') => self.literal("null", Json::Null),
+            Some(b'-') | Some(b'0'..=b'9') => self.number(),
+            Some(_) => Err(self.error("unexpected character")),
+            None => Err(self.error("unexpected end of input")),
+        }
+    }
+
+    fn nested(&mut self, f: fn(&mut Self) -> io::Result<Json>) -> io::Result<Json> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return Err(self.error("nested too deep"));
+        }
+        let value = f(self);
+        self.depth -= 1;
+        value
+    }
+
+    fn object(&mut self) -> io::Result<Json> {
+        self.pos += 1;
+        let mut entries = vec![];
+        self.skip_whitespace();
+        if self.eat(b'}') {
+            return Ok(Json::Object(entries));
+        }
+
+        loop {
+            self.skip_whitespace();
+            if self.peek() != Some(b'"') {
+                return Err(self.error("expected a string key"));
+            }
+            let key = self.string()?;
+            self.skip_whitespace();
+            if !self.eat(b':') {
+                return Err(self.error("expected ':'"));
+            }
+            entries.push((key, self.value()?));
+
+            self.skip_whitespace();
+            if self.eat(b'}') {
+                return Ok(Json::Object(entries));
+            }
+            if !self.eat(b',') {
+                return Err(self.error("expected ',' or '}'"));
+            }
+        }
+    }
+
+    fn array(&mut self) -> io::Result<Json> {
+        self.pos += 1;
+        let mut items = vec![];
+        self.skip_whitespace();
+        if self.eat(b']') {
+            return Ok(Json::Array(items));
+        }
+
+        loop {
+            items.push(self.value()?);
+            self.skip_whitespace();
+            if self.eat(b']') {
+                return Ok(Json::Array(items));
+            }
+            if !self.eat(b',') {
+                return Err(self.error("expected ',' or ']'"));
+            }
+        }
+    }
+
+    fn string(&mut self) -> io::Result<String> {
+        self.pos += 1;
+        let mut out = Vec::new();
+
+        loop {
+            let b = self
+                .next()
+                .ok_or_else(|| self.error("unterminated string"))?;
+            match b {
+                b'"' => break,
+                b'\\' => {
+                    let escaped = self
+                        .next()
+                        .ok_or_else(|| self.error("unterminated string"))?;
+                    let c = match escaped {
+                        b'"' => '"',
+                        b'\\' => '\\',
+                        b'/' => '/',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'u' => self.unicode_escape()?,
+                        _ => return Err(self.error("invalid escape")),
+                    };
+                    let mut buf = [0; 4];
+                    out.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                }
+                b if b < 0x20 => return Err(self.error("control character in string")),
+                b => out.push(b),
+            }
+        }
+
+        // The input is a `&str`, so everything between the escapes is valid
+        Ok(String::from_utf8(out).expect("valid UTF-8"))
+    }
+
+    /// The part after `\u`. Characters outside the basic plane are written
+    /// as two escapes, a surrogate pair.
+    fn unicode_escape(&mut self) -> io::Result<char> {
+        let first = self.hex4()?;
+        let code = if (0xD800..0xDC00).contains(&first) {
+            if !(self.eat(b'\\') && self.eat(b'u')) {
+                return Err(self.error("unpaired surrogate"));
+            }
+            let second = self.hex4()?;
+            if !(0xDC00..0xE000).contains(&second) {
+                return Err(self.error("unpaired surrogate"));
+            }
+            0x10000 + ((first - 0xD800) << 10) + (second - 0xDC00)
+        } else {
+            first
+        };
+        char::from_u32(code).ok_or_else(|| self.error("invalid unicode escape"))
+    }
+
+    fn hex4(&mut self) -> io::Result<u32> {
+        let digits = self
+            .bytes
+            .get(self.pos..self.pos + 4)
+            .and_then(|d| std::str::from_utf8(d).ok())
+            .and_then(|d| u32::from_str_radix(d, 16).ok())
+            .ok_or_else(|| self.error("invalid unicode escape"))?;
+        self.pos += 4;
+        Ok(digits)
+    }
+
+    fn number(&mut self) -> io::Result<Json> {
+        let start = self.pos;
+        self.eat(b'-');
+        while let Some(b'0'..=b'9' | b'.' | b'e' | b'E' | b'+' | b'-') = self.peek() {
+            self.pos += 1;
+        }
+
+        let text = std::str::from_utf8(&self.bytes[start..self.pos]).unwrap();
+        // Rust accepts a few things JSON doesn't, like `1.` and `.5`
+        let valid = !text.ends_with('.') && !text.contains(".e") && !text.contains(".E");
+        let int_part = text.trim_start_matches('-');
+        let leading_zero = int_part.len() > 1
+            && int_part.starts_with('0')
+            && int_part.as_bytes()[1].is_ascii_digit();
+        match text.parse() {
+            Ok(n) if valid && !leading_zero && !int_part.starts_with('.') => Ok(Json::Number(n)),
+            _ => Err(self.error("invalid number")),
+        }
+    }
+
+    fn literal(&mut self, word: &str, value: Json) -> io::Result<Json> {
+        if self.bytes[self.pos..].starts_with(word.as_bytes()) {
+            self.pos += word.len();
+            Ok(value)
+        } else {
+            Err(self.error("unexpected character"))
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(b' ' | b'\t' | b'\n' | b'\r') = self.peek() {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.bytes.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Option<u8> {
+        let b = self.peek()?;
+        self.pos += 1;
+        Some(b)
+    }
+
+    fn eat(&mut self, b: u8) -> bool {
+        if self.peek() == Some(b) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn error(&self, msg: &str) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("JSON parse error at position {}: {}", self.pos, msg),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string(s: &str) -> Json {
+        Json::String(s.to_string())
+    }
+
+    #[test]
+    fn parse_values() {
+        let json = Json::parse(r#" {"a": [1, -2.5, 3e2, true, false, null], "b": {}, "c": []} "#);
+        let expected = Json::Object(vec![
+            (
+                "a".to_string(),
+                Json::Array(vec![
+                    Json::Number(1.0),
+                    Json::Number(-2.5),
+                    Json::Number(300.0),
+                    Json::Bool(true),
+                    Json::Bool(false),
+                    Json::Null,
+                ]),
+            ),
+            ("b".to_string(), Json::Object(vec![])),
+            ("c".to_string(), Json::Array(vec![])),
+        ]);
+        assert_eq!(json.unwrap(), expected);
+    }
+
+    #[test]
+    fn escapes() {
+        let json = Json::parse(r#""\"\\\/\b\f\n\r\t\u0041\u00e9\u20ac""#).unwrap();
+        assert_eq!(json, string("\"\\/\u{8}\u{c}\n\r\t\u{41}é€"));
+
+        // Printing escapes what JSON requires and nothing else
+        let printed = string("\"\\/\n\r\t\u{1}é").to_string();
+        assert_eq!(printed, r#""\"\\/\n\r\t\u0001é""#);
+        assert_eq!(Json::parse(&printed).unwrap(), string("\"\\/\n\r\t\u{1}é"));
+    }
+
+    #[test]
+    fn surrogate_pairs() {
+        assert_eq!(Json::parse(r#""\ud83d\ude00""#).unwrap(), string("😀"));
+        assert_eq!(Json::parse(r#""😀""#).unwrap(), string("😀"));
+
+        for unpaired in [r#""\ud83d""#, r#""\ud83dx""#, r#""\ud83d\u0041""#] {
+            assert!(Json::parse(unpaired).is_err(), "{}", unpaired);
+        }
+        // A lone low surrogate isn't a character either
+        assert!(Json::parse(r#""\ude00""#).is_err());
+    }
+
+    #[test]
+    fn numbers() {
+        let valid = [
+            ("0", 0.0),
+            ("-0", -0.0),
+            ("12", 12.0),
+            ("1.5", 1.5),
+            ("-1.5e3", -1500.0),
+            ("1E-2", 0.01),
+            ("2e+1", 20.0),
+        ];
+        for (text, expected) in valid {
+            assert_eq!(
+                Json::parse(text).unwrap(),
+                Json::Number(expected),
+                "{}",
+                text
+            );
+        }
+
+        let invalid = [
+            "01", "-", "1.", ".5", "-.5", "1.e3", "+1", "1e", "--1", "0x10",
+        ];
+        for text in invalid {
+            assert!(Json::parse(text).is_err(), "{}", text);
+        }
+
+        assert_eq!(Json::Number(1.5).to_string(), "1.5");
+        assert_eq!(Json::Number(f64::NAN).to_string(), "null");
+    }
+
+    #[test]
+    fn malformed_input() {
+        let malformed = [
+            ("", "unexpected end of input"),
+            ("[1, ", "unexpected end of input"),
+            ("[1 2]", "expected ',' or ']'"),
+            ("{\"a\" 1}", "expected ':'"),
+            ("{a: 1}", "expected a string key"),
+            ("{\"a\": 1,}", "expected a string key"),
+            ("\"abc", "unterminated string"),
+            ("\"a\nb\"", "control character in string"),
+            ("\"\\x\"", "invalid escape"),
+            ("\"\\u12\"", "invalid unicode escape"),
+            ("tru", "unexpected character"),
+            ("[] []", "unexpected data after the value"),
+        ];
+        for (text, msg) in malformed {
+            let e = Json::parse(text).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+            assert!(e.to_string().ends_with(msg), "{}: {}", text, e);
+        }
+
+        let deep = "[".repeat(MAX_DEPTH + 1) + &"]".repeat(MAX_DEPTH + 1);
+        assert!(Json::parse(&deep)
+            .unwrap_err()
+            .to_string()
+            .ends_with("nested too deep"));
+        let ok = "[".repeat(MAX_DEPTH) + &"]".repeat(MAX_DEPTH);
+        assert!(Json::parse(&ok).is_ok());
+    }
+
+    #[test]
+    fn stringify_keeps_the_key_order() {
+        let text = r#"{"z":1,"a":[true,null],"m":{"k":"v"}}"#;
+        let json = Json::parse(text).unwrap();
+        assert_eq!(json.to_string(), text);
+        assert_eq!(json.get("m").and_then(|m| m.get("k")), Some(&string("v")));
+        assert_eq!(json.get("nope"), None);
+        assert_eq!(string("x").get("x"), None);
+    }
+}
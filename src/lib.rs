@@ -0,0 +1,1668 @@
+// ===== THIS IS OUR "NODE LIBRARY" =====
+// The javascript program which uses it is in main.rs
+pub mod child_process;
+pub mod delay_server;
+pub mod dgram;
+pub mod dns;
+pub mod fetch;
+pub mod fs_promises;
+pub mod http;
+pub mod http_agent;
+pub mod http_server;
+pub mod json;
+pub mod net;
+mod poll;
+pub mod promise;
+pub mod signals;
+pub mod sse;
+pub mod stream;
+#[cfg(feature = "tls")]
+pub mod tls;
+mod util;
+pub mod watch;
+pub mod websocket;
+
+use std::cell::Cell;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::fs;
+use std::future::Future;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Wake, Waker};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
+
+use stream::{Readable, Writable};
+use watch::{FsEventKind, FsWatcher, StatWatcher, WatchFileOptions, WatchOptions};
+
+static mut RUNTIME: *mut Runtime = std::ptr::null_mut();
+
+/// How many rounds of close callbacks we run when a shutdown closes handles
+const CLOSE_ROUNDS: usize = 8;
+
+struct Task {
+    task: Box<dyn FnOnce() -> Js + Send + 'static>,
+    callback_id: usize,
+    kind: ThreadPoolTaskKind,
+}
+
+impl Task {
+    fn close() -> Self {
+        Task {
+            task: Box::new(|| Js::Undefined),
+            callback_id: 0,
+            kind: ThreadPoolTaskKind::Close,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum ThreadPoolTaskKind {
+    FileRead,
+    FileWrite,
+    FileClose,
+    Stat,
+    ReadDir,
+    Open,
+    Encrypt,
+    Lookup,
+    Close,
+}
+
+impl fmt::Display for ThreadPoolTaskKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use ThreadPoolTaskKind::*;
+        match self {
+            FileRead => write!(f, "File read"),
+            FileWrite => write!(f, "File write"),
+            FileClose => write!(f, "File close"),
+            Stat => write!(f, "Stat"),
+            ReadDir => write!(f, "Read dir"),
+            Open => write!(f, "Open"),
+            Encrypt => write!(f, "Encrypt"),
+            Lookup => write!(f, "DNS lookup"),
+            Close => write!(f, "Close"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Js {
+    Undefined,
+    String(String),
+    Int(usize),
+    Bytes(Vec<u8>),
+    Error(io::Error),
+    Stats(Stats),
+    Response(http::Response),
+}
+
+// `io::Error` is not `Clone` so we need to implement this ourselves
+impl Clone for Js {
+    fn clone(&self) -> Self {
+        match self {
+            Js::Undefined => Js::Undefined,
+            Js::String(s) => Js::String(s.clone()),
+            Js::Int(n) => Js::Int(*n),
+            Js::Bytes(b) => Js::Bytes(b.clone()),
+            Js::Error(e) => Js::Error(io::Error::new(e.kind(), e.to_string())),
+            Js::Stats(s) => Js::Stats(s.clone()),
+            Js::Response(r) => Js::Response(r.clone()),
+        }
+    }
+}
+
+impl Js {
+    /// Convenience method since we know the types
+    pub fn into_string(self) -> Option<String> {
+        match self {
+            Js::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    /// Convenience method since we know the types
+    pub fn into_int(self) -> Option<usize> {
+        match self {
+            Js::Int(n) => Some(n),
+            _ => None,
+        }
+    }
+
+    /// Convenience method since we know the types
+    pub fn into_bytes(self) -> Option<Vec<u8>> {
+        match self {
+            Js::Bytes(b) => Some(b),
+            _ => None,
+        }
+    }
+
+    /// Convenience method since we know the types
+    pub fn into_stats(self) -> Option<Stats> {
+        match self {
+            Js::Stats(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    /// Convenience method since we know the types
+    pub fn into_response(self) -> Option<http::Response> {
+        match self {
+            Js::Response(r) => Some(r),
+            _ => None,
+        }
+    }
+}
+
+/// The parts of a file's metadata we care about
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Stats {
+    pub size: u64,
+    pub mode: u32,
+    pub is_file: bool,
+    pub is_dir: bool,
+    pub modified: Option<SystemTime>,
+}
+
+impl From<fs::Metadata> for Stats {
+    fn from(meta: fs::Metadata) -> Self {
+        Stats {
+            size: meta.len(),
+            mode: meta.mode(),
+            is_file: meta.is_file(),
+            is_dir: meta.is_dir(),
+            modified: meta.modified().ok(),
+        }
+    }
+}
+
+/// NodeTheread represents a thread in our threadpool. Each event has a Joinhandle
+/// and a transmitter part of a channel which is used to inform our main loop
+/// about what events has occurred.
+#[derive(Debug)]
+struct NodeThread {
+    pub(crate) handle: JoinHandle<()>,
+    sender: Sender<Task>,
+    /// The kind of task the thread is busy with, if any
+    running: Option<ThreadPoolTaskKind>,
+}
+
+/// Something which holds resources open, like a socket, a server or a child
+/// process. The runtime keeps a list of them so a shutdown knows what to stop
+/// and what to close when the grace period is over. The list isn't supposed
+/// to keep anything alive, so most handles hold a weak reference.
+pub(crate) trait Handle {
+    /// What the handle is, for the list of what a shutdown abandoned. `None`
+    /// if it's gone already.
+    fn describe(&self) -> Option<String>;
+
+    /// Called when a shutdown starts. Handles which take in new work, like a
+    /// listening server, stop doing that here.
+    fn stop(&self) {}
+
+    /// Closes the handle right away
+    fn force_close(&self);
+}
+
+pub struct Runtime {
+    /// What a shutdown closed by force or gave up on once its grace period
+    /// was over
+    abandoned: Vec<String>,
+    /// Available threads for the threadpool
+    available_threads: Vec<usize>,
+    /// Callbacks scheduled to run
+    callbacks_to_run: Vec<(usize, Js)>,
+    /// All registered callbacks
+    callback_queue: HashMap<usize, Box<dyn FnOnce(Js)>>,
+    /// Number of pending epoll events, only used by us to print for this example
+    epoll_pending_events: usize,
+    /// Our event registrator which registers interest in events with the OS
+    epoll_registrator: poll::Registrator,
+    // The handle to our epoll thread
+    epoll_thread: thread::JoinHandle<()>,
+    /// None = infinite, Some(n) = timeout in n ms, Some(0) = immidiate
+    epoll_timeout: Arc<Mutex<Option<i32>>>,
+    /// Channel used by both our threadpool and our epoll thread to send events
+    /// to the main loop
+    event_reciever: Receiver<PollEvent>,
+    /// Open sockets, servers and other handles, see `Handle`
+    handles: HashMap<usize, Rc<dyn Handle>>,
+    /// Creates an unique identity for our callbacks
+    identity_token: usize,
+    /// The code `run` returns, set by `exit`
+    exit_code: i32,
+    /// Set by `exit`. Nothing runs after that except the `exit` listeners.
+    exiting: bool,
+    on_before_exit: Vec<Box<dyn FnMut()>>,
+    on_exit: Vec<Box<dyn FnMut(i32)>>,
+    /// The number of events pending. When this is zero, we're done
+    pending_events: usize,
+    /// Set once `shutdown` is called. Whatever is still open at this point is
+    /// closed by force.
+    shutdown_deadline: Option<Instant>,
+    /// Handles to our threads in the threadpool
+    thread_pool: Vec<NodeThread>,
+    /// Tasks waiting for a thread in the threadpool to become available
+    queued_tasks: VecDeque<Task>,
+    /// Futures spawned with `spawn` which have not completed yet
+    tasks: HashMap<usize, Pin<Box<dyn Future<Output = ()>>>>,
+    /// Ids of the futures which are woken up and need to be polled
+    ready_tasks: Arc<Mutex<Vec<usize>>>,
+    /// Holds all our timers, and an Id for the callback to run once they expire
+    timers: BTreeMap<Instant, usize>,
+    /// Callbacks which don't count as pending events, see `set_ref`
+    unrefed: HashSet<usize>,
+    /// A struct to temporarely hold timers to remove. We let Runtinme have
+    /// ownership so we can reuse the same memory
+    timers_to_remove: Vec<Instant>,
+}
+
+impl Default for Runtime {
+    fn default() -> Self {
+        Runtime::new()
+    }
+}
+
+/// Describes the three main events our epoll-eventloop handles
+enum PollEvent {
+    /// An event from the `threadpool` with a tuple containing the `thread id`,
+    /// the `callback_id` and the data which the we expect to process in our
+    /// callback 
+    Threadpool((usize, usize, Js)),
+    /// An event from the epoll-based eventloop holding the `event_id` for the
+    /// event
+    Epoll(usize),
+    Timeout,
+}
+
+impl Runtime {
+    pub fn new() -> Self {
+        // Our threads never handle signals. A new thread starts with the signal
+        // mask of the thread which creates it, so we block everything until our
+        // threads are running. Signals are left for the main thread, where we
+        // read them from a signalfd.
+        let signal_mask = block_all_signals();
+
+        // ===== THE REGULAR THREADPOOL =====
+        let (event_sender, event_reciever) = channel::<PollEvent>();
+        let mut threads = Vec::with_capacity(4);
+
+        for i in 0..4 {
+            let (evt_sender, evt_reciever) = channel::<Task>();
+            let event_sender = event_sender.clone();
+
+            let handle = thread::Builder::new()
+                .name(format!("pool{}", i))
+                .spawn(move || {
+
+                    while let Ok(task) = evt_reciever.recv() {
+                        print(format!("recived a task of type: {}", task.kind));
+                        
+                        if let ThreadPoolTaskKind::Close = task.kind {
+                            break;
+                        };
+
+                        let res = (task.task)();
+                        print(format!("finished running a task of type: {}.", task.kind));
+
+                        // The main loop is gone if it gave up on us during a shutdown
+                        let event = PollEvent::Threadpool((i, task.callback_id, res));
+                        if event_sender.send(event).is_err() {
+                            break;
+                        }
+                    }
+                })
+                .expect("Couldn't initialize thread pool.");
+
+            let node_thread = NodeThread {
+                handle,
+                sender: evt_sender,
+                running: None,
+            };
+
+            threads.push(node_thread);
+        }
+
+        // ===== EPOLL THREAD =====
+        let mut poll = poll::Poll::new().expect("Error creating epoll queue");
+        let registrator = poll.registrator();
+        let epoll_timeout = Arc::new(Mutex::new(None));
+        let epoll_timeout_clone = epoll_timeout.clone();
+
+        let epoll_thread = thread::Builder::new()
+            .name("epoll".to_string())
+            .spawn(move || {
+                let mut events = poll::Events::with_capacity(1024);
+                
+                loop {
+                    let epoll_timeout_handle = epoll_timeout_clone.lock().unwrap();
+                    let timeout = *epoll_timeout_handle;
+                    drop(epoll_timeout_handle);
+
+                    let started = Instant::now();
+                    match poll.poll(&mut events, timeout) {
+                        Ok(v) if v > 0 => {
+                            for i in 0..v {
+                                let event = events.get_mut(i).expect("No events in event list.");
+                                print(format!("epoll event {} is ready", event.id()));
+                                
+                                let event = PollEvent::Epoll(event.id());
+                                event_sender.send(event).expect("epoll event");
+                            }
+                        }
+                        Ok(0) => {
+                            // If we return before the timeout has expired we're woken
+                            // up by the main loop because it has a new timeout for us
+                            let expired = timeout
+                                .map(|ms| started.elapsed() >= Duration::from_millis(ms as u64))
+                                .unwrap_or(false);
+
+                            if expired {
+                                print("epoll event timeout is ready");
+                                event_sender.send(PollEvent::Timeout).expect("epoll timeout");
+                            }
+                        }
+                        Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {
+                            print("recieved event of type: Close");
+                            break;
+                        }
+                        Err(e) => panic!("{:?}", e),
+                        _ => unreachable!(),
+                    }
+                }
+            })
+            .expect("Error creating epoll thread");
+
+        set_signal_mask(&signal_mask);
+
+        Runtime {
+            abandoned: vec![],
+            available_threads: (0..4).collect(),
+            callbacks_to_run: vec![],
+            callback_queue: HashMap::new(),
+            epoll_pending_events: 0,
+            epoll_registrator: registrator,
+            epoll_thread,
+            epoll_timeout,
+            event_reciever,
+            handles: HashMap::new(),
+            identity_token: 0,
+            exit_code: 0,
+            exiting: false,
+            on_before_exit: vec![],
+            on_exit: vec![],
+            pending_events: 0,
+            shutdown_deadline: None,
+            thread_pool: threads,
+            queued_tasks: VecDeque::new(),
+            tasks: HashMap::new(),
+            ready_tasks: Arc::new(Mutex::new(vec![])),
+            timers: BTreeMap::new(),
+            unrefed: HashSet::new(),
+            timers_to_remove: vec![],
+        }
+    }
+
+    /// This is the event loop. There are several things we could do here to
+    /// make it a better implementation. One is to set a max backlog of callbacks
+    /// to execute in a single tick, so we don't starve the threadpool or file
+    /// handlers. Another is to dynamically decide if/and how long the thread
+    /// could be allowed to be parked for example by looking at the backlog of
+    /// events, and if there is any backlog disable it. Some of our Vec's will
+    /// only grow, and not resize, so if we have a period of very high load, the
+    /// memory will stay higher than we need until a restart. This could be
+    /// dealt by using a different kind of data structure like a `LinkedList`.
+    ///
+    /// Returns the exit code, which is 0 unless `exit` says otherwise.
+    pub fn run(mut self, f: impl Fn()) -> i32 {
+        let rt_ptr: *mut Runtime = &mut self;
+        unsafe { RUNTIME = rt_ptr };
+
+        // just for us priting out during execution
+        let mut ticks = 0; 
+
+        // First we run our "main" function
+        f();
+        self.run_tasks();
+
+        // ===== EVENT LOOP =====
+        while !self.exiting {
+            // When there is nothing left to do we emit `before_exit`, and the
+            // listeners might give us more
+            if self.pending_events == 0 {
+                self.emit_before_exit();
+                if self.pending_events == 0 || self.exiting {
+                    break;
+                }
+            }
+
+            ticks += 1;
+            // NOT PART OF LOOP, JUST FOR US TO SEE WHAT TICK IS EXCECUTING
+            print(format!("===== TICK {} =====", ticks));
+
+            // ===== 2. TIMERS =====
+            self.process_expired_timers();
+
+            // ===== 2. CALLBACKS =====
+            // Timer callbacks and if for some reason we have postponed callbacks
+            // to run on the next tick. Not possible in our implementation though.
+            self.run_callbacks();
+
+            // Once the grace period of a shutdown is over we stop waiting for
+            // what's left and close it instead
+            if self.shutdown_deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                self.force_shutdown();
+                break;
+            }
+
+            // ===== 3. IDLE/PREPARE =====
+            // Signals we raised ourselves never wake the epoll thread, see
+            // `signals::run_raised`
+            signals::run_raised();
+
+            // ===== 4. POLL =====
+            // First we need to check if we have any outstanding events at all
+            // and if not we're finished. If not we will wait forever.
+            if self.pending_events == 0 || self.exiting {
+                continue;
+            }
+
+            // We want to get the time to the next timeout (if any) and we
+            // set the timeout of our epoll wait to the same as the timeout
+            // for the next timer. If there is none, we set it to infinite (None)
+            let next_timeout = self.get_next_timer();
+
+            let mut epoll_timeout_lock = self.epoll_timeout.lock().unwrap();
+            *epoll_timeout_lock = next_timeout;
+            // We release the lock before we wait in `recv`
+            drop(epoll_timeout_lock);
+
+            // The epoll thread reads the timeout before it starts waiting, so it
+            // might be waiting with an old one. We wake it up so it starts over
+            // with the new timeout.
+            self.epoll_registrator.wake().expect("epoll wake");
+
+            // We handle one and one event but multiple events could be returned
+            // on the same poll. We won't cover that here though but there are
+            // several ways of handling this.
+            if let Ok(event) = self.event_reciever.recv() {
+                match event {
+                    PollEvent::Timeout => (),
+                    PollEvent::Threadpool((thread_id, callback_id, data)) => {
+                        self.process_threadpool_events(thread_id, callback_id, data);
+                    }
+                    PollEvent::Epoll(event_id) => {
+                        self.process_epoll_events(event_id);
+                    }
+                }
+            }
+            self.run_callbacks();
+
+            // ===== 5. CHECK =====
+            // an set immidiate function could be added pretty easily but we
+            // won't do that here
+
+            // ===== 6. CLOSE CALLBACKS ======
+            // Release resources, we won't do that here, but this is typically
+            // where sockets etc are closed.
+        }
+
+        if self.shutdown_deadline.is_some() && !self.exiting {
+            print("Shutdown complete, nothing was abandoned");
+        }
+
+        // The `exit` listeners run while everything is still in place, but
+        // any work they schedule is never run
+        self.exiting = true;
+        self.emit_exit();
+
+        // We clean up our resources, makes sure all destructors runs. A thread
+        // still busy with a task a shutdown or `exit` gave up on is left to
+        // finish on its own, we don't wait for it.
+        for thread in self.thread_pool.into_iter() {
+            thread.sender.send(Task::close()).expect("threadpool cleanup");
+            if thread.running.is_none() {
+                thread.handle.join().unwrap();
+            }
+        }
+
+        self.epoll_registrator.close_loop().unwrap();
+        self.epoll_thread.join().unwrap();
+
+        print("FINISHED");
+        self.exit_code
+    }
+
+    fn process_expired_timers(&mut self) {
+        // Need an intermediate variable to please the borrowchecker
+        let timers_to_remove = &mut self.timers_to_remove;
+
+        self.timers
+            .range(..=Instant::now())
+            .for_each(|(k, _)| timers_to_remove.push(*k));
+
+        while let Some(key) = self.timers_to_remove.pop() {
+            let callback_id = self.timers.remove(&key).unwrap();
+            self.callbacks_to_run.push((callback_id, Js::Undefined));
+        }
+    }
+
+    fn get_next_timer(&self) -> Option<i32> {
+        self.timers.iter().nth(0).map(|(&instant, _)| {
+            let time_to_next_timeout = instant.saturating_duration_since(Instant::now());
+
+            // Rounded up, since epoll would wake us too early and we'd spin
+            // until the timer is due if we rounded a sub-millisecond wait down
+            // to 0
+            (time_to_next_timeout + Duration::from_nanos(999_999)).as_millis() as i32
+        })
+    }
+
+    fn run_callbacks(&mut self) {
+        while !self.exiting {
+            let (callback_id, data) = match self.callbacks_to_run.pop() {
+                Some(callback) => callback,
+                None => break,
+            };
+
+            // The callback is gone if it was cancelled after its event was ready
+            if let Some(cb) = self.callback_queue.remove(&callback_id) {
+                // The callback might register a new one with the same id
+                let refed = !self.unrefed.remove(&callback_id);
+                cb(data);
+                if refed {
+                    self.pending_events -= 1;
+                }
+            }
+
+            // Futures woken by the callback run right after it, just like
+            // microtasks in Node
+            self.run_tasks();
+        }
+    }
+
+    /// Polls all futures which have been woken up since the last time
+    fn run_tasks(&mut self) {
+        loop {
+            let ready = std::mem::take(&mut *self.ready_tasks.lock().unwrap());
+            if ready.is_empty() {
+                break;
+            }
+
+            for id in ready {
+                if self.exiting {
+                    return;
+                }
+
+                // A task can be woken more than once before it's polled
+                let mut task = match self.tasks.remove(&id) {
+                    Some(task) => task,
+                    None => continue,
+                };
+
+                let waker = Waker::from(Arc::new(TaskWaker {
+                    id,
+                    ready_tasks: self.ready_tasks.clone(),
+                }));
+
+                let mut cx = Context::from_waker(&waker);
+                if task.as_mut().poll(&mut cx).is_pending() {
+                    self.tasks.insert(id, task);
+                }
+            }
+        }
+    }
+
+    fn process_epoll_events(&mut self, event_id: usize) {
+        // Events for registrations we have cancelled might still be in the queue
+        if self.callback_queue.contains_key(&event_id) {
+            self.callbacks_to_run.push((event_id, Js::Undefined));
+            self.epoll_pending_events -= 1;
+        }
+    }
+
+    fn process_threadpool_events(&mut self, thread_id: usize, callback_id: usize, data: Js) {
+        self.callbacks_to_run.push((callback_id, data));
+        self.thread_pool[thread_id].running = None;
+
+        // The thread goes straight to the next task in the queue if there is one
+        match self.queued_tasks.pop_front() {
+            Some(task) => self.send_task(thread_id, task),
+            None => self.available_threads.push(thread_id),
+        }
+    }
+
+    fn send_task(&mut self, thread_id: usize, task: Task) {
+        let thread = &mut self.thread_pool[thread_id];
+        thread.running = Some(task.kind);
+        thread.sender.send(task).expect("register work");
+    }
+
+    /// If we hit max we just wrap around
+    fn generate_identity(&mut self) -> usize {
+        self.identity_token = self.identity_token.wrapping_add(1);
+        self.identity_token
+    }
+
+    fn generate_cb_identity(&mut self) -> usize {
+        let ident = self.generate_identity();
+        let taken = self.callback_queue.contains_key(&ident);
+
+        // if there is a collision or the identity is already there we loop until we find a new one
+        // we don't cover the case where there are `usize::MAX` number of callbacks waiting since
+        // that if we're fast and queue a new event every nanosecond that will still take 585.5 years
+        // to do on a 64 bit system.
+        if !taken {
+            ident
+        } else {
+            loop {
+                let possible_ident = self.generate_identity();
+                if self.callback_queue.contains_key(&possible_ident) {
+                    break possible_ident;
+                }
+            }
+        }
+    }
+
+    /// Adds a callback to the queue and returns the key
+    fn add_callback(&mut self, ident: usize, cb: impl FnOnce(Js) + 'static) {
+        let boxed_cb = Box::new(cb);
+        self.callback_queue.insert(ident, boxed_cb);
+    }
+
+    /// Removes a callback which hasn't run yet so it no longer keeps the loop
+    /// alive. Returns false if it has already run.
+    fn remove_callback(&mut self, ident: usize) -> bool {
+        let removed = self.callback_queue.remove(&ident).is_some();
+        if removed && !self.unrefed.remove(&ident) {
+            self.pending_events -= 1;
+        }
+        removed
+    }
+
+    /// An unref'd callback doesn't keep the event loop alive while it waits,
+    /// just like a handle after `unref()` in Node. It still runs if its event
+    /// happens before the loop is done.
+    fn set_ref(&mut self, ident: usize, refed: bool) {
+        if !self.callback_queue.contains_key(&ident) {
+            return;
+        }
+        if refed {
+            if self.unrefed.remove(&ident) {
+                self.pending_events += 1;
+            }
+        } else if self.unrefed.insert(ident) {
+            self.pending_events -= 1;
+        }
+    }
+
+    pub fn register_event_epoll(&mut self, token: usize, cb: impl FnOnce(Js) + 'static) {
+        self.add_callback(token, cb);
+
+        print(format!("Event with id: {} registered.", token));
+        self.pending_events += 1;
+        self.epoll_pending_events += 1;
+    }
+
+    /// Cancels an event registered with `register_event_epoll`. The caller is
+    /// responsible for deregistering the source with the `epoll_registrator`.
+    pub fn deregister_event_epoll(&mut self, token: usize) {
+        if self.remove_callback(token) {
+            self.epoll_pending_events -= 1;
+            print(format!("Event with id: {} deregistered.", token));
+        }
+    }
+
+    pub fn register_event_threadpool(
+        &mut self,
+        task: impl FnOnce() -> Js + Send + 'static,
+        kind: ThreadPoolTaskKind,
+        cb: impl FnOnce(Js) + 'static,
+    ) {
+        let callback_id = self.generate_cb_identity();
+        self.add_callback(callback_id, cb);
+
+        let event = Task {
+            task: Box::new(task),
+            callback_id,
+            kind,
+        };
+
+        // we are not going to implement a real scheduler here, just a LIFO queue
+        // of available threads. If they're all busy the task waits in line.
+        match self.available_threads.pop() {
+            Some(available) => self.send_task(available, event),
+            None => self.queued_tasks.push_back(event),
+        }
+        self.pending_events += 1;
+    }
+
+    /// Adds a handle to the list a shutdown goes through. The returned id is
+    /// passed to `remove_handle` once the handle is closed.
+    pub(crate) fn add_handle(&mut self, handle: Rc<dyn Handle>) -> usize {
+        let id = self.generate_identity();
+        // Anything opened while we're shutting down is stopped right away
+        if self.shutdown_deadline.is_some() {
+            let handle = handle.clone();
+            defer(move |_| handle.stop());
+        }
+        self.handles.insert(id, handle);
+        id
+    }
+
+    pub(crate) fn remove_handle(&mut self, id: usize) {
+        self.handles.remove(&id);
+    }
+
+    /// Starts a graceful shutdown. Servers stop accepting connections, while
+    /// the connections they have, tasks on the threadpool and everything else
+    /// already going on get until `grace` has passed to finish. After that the
+    /// handles still open are closed by force, their close callbacks run and
+    /// whatever is still waiting is abandoned and printed. Calling it again
+    /// can only make the grace period shorter.
+    pub fn shutdown(&mut self, grace: Duration) {
+        let deadline = Instant::now() + grace;
+        let starting = self.shutdown_deadline.is_none();
+        if self.shutdown_deadline.is_some_and(|current| current <= deadline) {
+            return;
+        }
+        self.shutdown_deadline = Some(deadline);
+        print(format!("Shutting down, {} ms grace period", grace.as_millis()));
+
+        // Wakes the loop up when the time is up, without keeping it alive
+        let timer = self.set_timeout(grace.as_millis() as u64, |_| ());
+        self.set_ref(timer, false);
+
+        if starting {
+            let mut handles: Vec<_> = self
+                .handles
+                .iter()
+                .map(|(id, handle)| (*id, handle.clone()))
+                .collect();
+            handles.sort_by_key(|(id, _)| *id);
+            for (_, handle) in handles {
+                handle.stop();
+            }
+        }
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutdown_deadline.is_some()
+    }
+
+    /// Stops the event loop as soon as the current callback returns, like
+    /// `process.exit` in Node. Nothing else runs after that, except the `exit`
+    /// listeners, and `run` returns `code`. Tasks still running on the
+    /// threadpool are abandoned.
+    pub fn exit(&mut self, code: i32) {
+        if !self.exiting {
+            print(format!("Exiting with code {}", code));
+        }
+        self.exit_code = code;
+        self.exiting = true;
+    }
+
+    /// Emitted when the event loop has nothing left to do. Unlike `exit`
+    /// listeners these can schedule more work, which keeps the loop going,
+    /// and then they're emitted again once it's done. Not emitted if `exit`
+    /// is called.
+    pub fn on_before_exit(&mut self, cb: impl FnMut() + 'static) {
+        self.on_before_exit.push(Box::new(cb));
+    }
+
+    /// Emitted with the exit code right before `run` returns. Any work the
+    /// listeners schedule is never run.
+    pub fn on_exit(&mut self, cb: impl FnMut(i32) + 'static) {
+        self.on_exit.push(Box::new(cb));
+    }
+
+    fn emit_before_exit(&mut self) {
+        if self.on_before_exit.is_empty() {
+            return;
+        }
+
+        print("Emitting before exit");
+        let mut listeners = std::mem::take(&mut self.on_before_exit);
+        for cb in listeners.iter_mut() {
+            cb();
+            self.run_tasks();
+            if self.exiting {
+                break;
+            }
+        }
+        // The listeners might have added new ones
+        listeners.append(&mut self.on_before_exit);
+        self.on_before_exit = listeners;
+    }
+
+    fn emit_exit(&mut self) {
+        for mut cb in std::mem::take(&mut self.on_exit) {
+            cb(self.exit_code);
+        }
+    }
+
+    /// Ends a shutdown whose grace period is over
+    fn force_shutdown(&mut self) {
+        print("Shutdown grace period is over, closing what's left");
+
+        let mut handles: Vec<_> = self.handles.drain().collect();
+        handles.sort_by_key(|(id, _)| *id);
+        for (_, handle) in handles {
+            if let Some(what) = handle.describe() {
+                self.abandoned.push(what);
+                handle.force_close();
+            }
+        }
+
+        // Close callbacks mostly run on the next tick, and might defer more
+        // work of their own. We give them a few rounds.
+        for _ in 0..CLOSE_ROUNDS {
+            self.process_expired_timers();
+            if self.callbacks_to_run.is_empty() {
+                break;
+            }
+            self.run_callbacks();
+        }
+
+        for thread in &self.thread_pool {
+            if let Some(kind) = thread.running {
+                self.abandoned.push(format!("{} task (running)", kind));
+            }
+        }
+        for task in &self.queued_tasks {
+            self.abandoned.push(format!("{} task (queued)", task.kind));
+        }
+        let timers = self
+            .timers
+            .values()
+            .filter(|id| !self.unrefed.contains(id))
+            .count();
+        if timers > 0 {
+            self.abandoned.push(format!("{} timer(s)", timers));
+        }
+
+        // Dropping the callbacks might drop things which call back into the
+        // runtime, so we take everything out before we drop it
+        let callbacks = std::mem::take(&mut self.callback_queue);
+        let queued_tasks = std::mem::take(&mut self.queued_tasks);
+        let tasks = std::mem::take(&mut self.tasks);
+        self.callbacks_to_run.clear();
+        self.timers.clear();
+        self.unrefed.clear();
+        self.pending_events = 0;
+        self.epoll_pending_events = 0;
+        self.shutdown_deadline = None;
+        drop((callbacks, queued_tasks, tasks));
+
+        if self.abandoned.is_empty() {
+            print("Shutdown complete, nothing was abandoned");
+        } else {
+            print(format!("Shutdown abandoned: {}", self.abandoned.join(", ")));
+        }
+    }
+
+    fn spawn(&mut self, fut: impl Future<Output = ()> + 'static) {
+        let id = self.generate_identity();
+        self.tasks.insert(id, Box::pin(fut));
+        self.ready_tasks.lock().unwrap().push(id);
+    }
+
+    fn set_timeout(&mut self, ms: u64, cb: impl FnOnce(Js) + 'static) -> usize {
+        let now = Instant::now();
+
+        let cb_id = self.generate_cb_identity();
+        self.add_callback(cb_id, cb);
+        
+        // Two timers can end up with the same instant, especially when we defer
+        // a lot of callbacks with a 0 ms timeout. The later one moves back a bit
+        // so it doesn't replace the first one.
+        let mut timeout = now + Duration::from_millis(ms);
+        while self.timers.contains_key(&timeout) {
+            timeout += Duration::from_nanos(1);
+        }
+        self.timers.insert(timeout, cb_id);
+        
+        self.pending_events += 1;
+        print(format!("Registered timer event id: {}", cb_id));
+        cb_id
+    }
+
+    fn clear_timeout(&mut self, id: usize) {
+        self.timers.retain(|_, cb_id| *cb_id != id);
+        if self.remove_callback(id) {
+            print(format!("Cleared timer event id: {}", id));
+        }
+    }
+}
+
+/// Returns an id which can be passed to `clear_timeout`
+pub fn set_timeout(ms: u64, cb: impl FnOnce(Js) + 'static) -> usize {
+    let rt = unsafe { &mut *RUNTIME };
+    rt.set_timeout(ms, cb)
+}
+
+/// Cancels a timer if it hasn't fired yet
+pub fn clear_timeout(id: usize) {
+    let rt = unsafe { &mut *RUNTIME };
+    rt.clear_timeout(id);
+}
+
+/// Decides if a timer or another waiting callback keeps the event loop alive
+fn set_event_ref(id: usize, refed: bool) {
+    let rt = unsafe { &mut *RUNTIME };
+    rt.set_ref(id, refed);
+}
+
+/// Runs `cb` on the next turn of the event loop. Node never calls a callback
+/// synchronously from the function it was passed to, and neither do we.
+fn defer(cb: impl FnOnce(Js) + 'static) {
+    set_timeout(0, cb);
+}
+
+/// Stops the event loop with `code`, see `Runtime::exit`
+pub fn exit(code: i32) {
+    let rt = unsafe { &mut *RUNTIME };
+    rt.exit(code);
+}
+
+/// See `Runtime::on_before_exit`
+pub fn on_before_exit(cb: impl FnMut() + 'static) {
+    let rt = unsafe { &mut *RUNTIME };
+    rt.on_before_exit(cb);
+}
+
+/// See `Runtime::on_exit`
+pub fn on_exit(cb: impl FnMut(i32) + 'static) {
+    let rt = unsafe { &mut *RUNTIME };
+    rt.on_exit(cb);
+}
+
+/// Shuts the runtime down gracefully, see `Runtime::shutdown`
+pub fn shutdown(grace: Duration) {
+    let rt = unsafe { &mut *RUNTIME };
+    rt.shutdown(grace);
+}
+
+/// Shuts the runtime down when the process gets `signal`, usually SIGTERM.
+/// Getting it again while we're shutting down ends the grace period right
+/// away.
+pub fn shutdown_on(signal: i32, grace: Duration) -> io::Result<signals::SignalHandler> {
+    signals::Signals::on(signal, move |_| {
+        let rt = unsafe { &mut *RUNTIME };
+        let grace = if rt.is_shutting_down() { Duration::ZERO } else { grace };
+        rt.shutdown(grace);
+    })
+}
+
+/// Blocks all signals in the calling thread and returns the mask it had
+fn block_all_signals() -> libc::sigset_t {
+    unsafe {
+        let mut all: libc::sigset_t = std::mem::zeroed();
+        let mut old: libc::sigset_t = std::mem::zeroed();
+        libc::sigfillset(&mut all);
+        libc::pthread_sigmask(libc::SIG_SETMASK, &all, &mut old);
+        old
+    }
+}
+
+fn set_signal_mask(mask: &libc::sigset_t) {
+    unsafe { libc::pthread_sigmask(libc::SIG_SETMASK, mask, std::ptr::null_mut()) };
+}
+
+/// Runs a future on the event loop thread. It's first polled once the current
+/// callback has returned. A future waiting for something that never happens
+/// does not keep the loop alive, just like a pending promise in javascript.
+pub fn spawn(fut: impl Future<Output = ()> + 'static) {
+    let rt = unsafe { &mut *RUNTIME };
+    rt.spawn(fut);
+}
+
+/// Runs `f` on a new runtime, just like `main` runs our program. The runtime
+/// is a global, so tests which need one take turns.
+#[cfg(test)]
+fn run_test(f: impl Fn()) -> i32 {
+    static RUNNING: Mutex<()> = Mutex::new(());
+    let _running = RUNNING.lock().unwrap_or_else(|e| e.into_inner());
+    Runtime::new().run(f)
+}
+
+/// A path in the temp directory which no other test uses. Whatever is there
+/// from an earlier run is removed.
+#[cfg(test)]
+fn temp_path(name: &str) -> PathBuf {
+    let name = format!("event_loop_test_{}_{}", std::process::id(), name);
+    let path = std::env::temp_dir().join(name);
+    let _ = fs::remove_dir_all(&path);
+    let _ = fs::remove_file(&path);
+    path
+}
+
+/// Wakes a future spawned on the runtime by putting its id in the ready queue.
+/// Our futures are only ever woken from the event loop thread, which checks
+/// the queue after every callback.
+struct TaskWaker {
+    id: usize,
+    ready_tasks: Arc<Mutex<Vec<usize>>>,
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.ready_tasks.lock().unwrap().push(self.id);
+    }
+}
+
+// ===== THIS IS PLUGINS CREATED IN C++ FOR THE NODE RUNTIME OR PART OF THE RUNTIME ITSELF =====
+// The pointer dereferencing of our runtime is not striclty needed but is mostly for trying to
+// emulate a bit of the same feeling as when you use modules in javascript. We could pass the runtime in
+// as a reference to our startup function.
+
+pub struct Crypto;
+impl Crypto {
+    pub fn encrypt(n: usize, cb: impl Fn(Js) + 'static + Clone) {
+        let work = move || {
+            fn fibonacchi(n: usize) -> usize {
+                match n {
+                    0 => 0,
+                    1 => 1,
+                    _ => fibonacchi(n - 1) + fibonacchi(n - 2),
+                }
+            }
+
+            let fib = fibonacchi(n);
+            Js::Int(fib)
+        };
+
+        let rt = unsafe { &mut *RUNTIME };
+        rt.register_event_threadpool(work, ThreadPoolTaskKind::Encrypt, cb);
+    }
+}
+
+pub struct Fs;
+impl Fs {
+    pub fn read(path: impl AsRef<Path> + Send + 'static, cb: impl FnOnce(Js) + 'static) {
+        let work = move || {
+            // Let's simulate that there is a very large file we're reading allowing us to actually
+            // observe how the code is executed
+            thread::sleep(std::time::Duration::from_secs(1));
+            let mut buffer = String::new();
+            let res = fs::File::open(path).and_then(|mut file| file.read_to_string(&mut buffer));
+            match res {
+                Ok(_) => Js::String(buffer),
+                Err(e) => Js::Error(e),
+            }
+        };
+        let rt = unsafe { &mut *RUNTIME };
+        rt.register_event_threadpool(work, ThreadPoolTaskKind::FileRead, cb);
+    }
+
+    pub fn stat(path: impl AsRef<Path> + Send + 'static, cb: impl FnOnce(Js) + 'static) {
+        let work = move || match fs::metadata(path) {
+            Ok(meta) => Js::Stats(meta.into()),
+            Err(e) => Js::Error(e),
+        };
+        let rt = unsafe { &mut *RUNTIME };
+        rt.register_event_threadpool(work, ThreadPoolTaskKind::Stat, cb);
+    }
+
+    /// Watches a file or a directory for changes using inotify. The listener
+    /// gets the kind of change and the name of the file relative to `path`.
+    pub fn watch(
+        path: impl AsRef<Path>,
+        opts: WatchOptions,
+        cb: impl FnMut(FsEventKind, PathBuf) + 'static,
+    ) -> io::Result<FsWatcher> {
+        FsWatcher::new(path.as_ref(), opts, cb)
+    }
+
+    /// Polls the file with `stat` on the threadpool, calling the listener with
+    /// the current and the previous stats whenever they differ. Slower than
+    /// `watch` but works on any file system, including network shares.
+    pub fn watch_file(
+        path: impl AsRef<Path>,
+        opts: WatchFileOptions,
+        cb: impl FnMut(Stats, Stats) + 'static,
+    ) -> StatWatcher {
+        StatWatcher::new(path.as_ref().to_path_buf(), opts, cb)
+    }
+
+    /// Reads the file in chunks of at most `high_water_mark` bytes instead of
+    /// reading everything into memory. Nothing is read before a `data` listener
+    /// is attached or `resume` is called.
+    pub fn create_read_stream(path: impl AsRef<Path>, opts: ReadStreamOptions) -> Readable {
+        let path: Arc<Path> = path.as_ref().into();
+        let file = Arc::new(Mutex::new(None));
+        let pos = Rc::new(Cell::new(opts.start));
+        let end = opts.end;
+        let high_water_mark = opts.high_water_mark.max(1);
+
+        let read_file = file.clone();
+        let read = move |readable: &Readable| {
+            let len = match end {
+                Some(end) if end < pos.get() => 0,
+                Some(end) => (end - pos.get() + 1).min(high_water_mark as u64) as usize,
+                None => high_water_mark,
+            };
+
+            if len == 0 {
+                readable.push_end();
+                return;
+            }
+
+            let file = read_file.clone();
+            let path = path.clone();
+            let offset = pos.get();
+            let work = move || {
+                let mut file = file.lock().unwrap();
+                match read_chunk(&mut file, &path, offset, len) {
+                    Ok(chunk) => Js::Bytes(chunk),
+                    Err(e) => Js::Error(e),
+                }
+            };
+
+            let readable = readable.clone();
+            let pos = pos.clone();
+            let rt = unsafe { &mut *RUNTIME };
+            rt.register_event_threadpool(work, ThreadPoolTaskKind::FileRead, move |res| {
+                match res {
+                    Js::Bytes(chunk) if chunk.is_empty() => readable.push_end(),
+                    Js::Bytes(chunk) => {
+                        pos.set(pos.get() + chunk.len() as u64);
+                        readable.push(chunk);
+                    }
+                    Js::Error(e) => readable.destroy(Some(e)),
+                    _ => unreachable!(),
+                }
+            });
+        };
+
+        let readable = Readable::new(high_water_mark, read);
+        readable.on_close(move |_| close_file(&file));
+        readable
+    }
+
+    /// Opens a file for writing. `write` returns false once more than
+    /// `high_water_mark` bytes are waiting to be written, and `drain` is
+    /// emitted when the buffer has been flushed to the file.
+    pub fn create_write_stream(path: impl AsRef<Path>, opts: WriteStreamOptions) -> Writable {
+        let path: Arc<Path> = path.as_ref().into();
+        let file = Arc::new(Mutex::new(None));
+        let append = opts.append;
+
+        let write_file = file.clone();
+        let write_path = path.clone();
+        let write = move |chunk: Vec<u8>, cb: stream::Callback| {
+            let file = write_file.clone();
+            let path = write_path.clone();
+            let work = move || {
+                let mut file = file.lock().unwrap();
+                match write_chunk(&mut file, &path, append, &chunk) {
+                    Ok(()) => Js::Undefined,
+                    Err(e) => Js::Error(e),
+                }
+            };
+
+            let rt = unsafe { &mut *RUNTIME };
+            rt.register_event_threadpool(work, ThreadPoolTaskKind::FileWrite, move |res| {
+                match res {
+                    Js::Error(e) => cb(Err(e)),
+                    _ => cb(Ok(())),
+                }
+            });
+        };
+
+        let writable = Writable::new(opts.high_water_mark, write);
+
+        // Closes the file on the threadpool. If nothing was written we still
+        // want the file to be created, just as opening it for writing would.
+        let final_file = file.clone();
+        writable.set_final(move |cb| {
+            let work = move || {
+                let mut file = final_file.lock().unwrap();
+                let res = write_chunk(&mut file, &path, append, &[])
+                    .and_then(|_| file.take().unwrap().sync_all());
+
+                match res {
+                    Ok(()) => Js::Undefined,
+                    Err(e) => Js::Error(e),
+                }
+            };
+
+            let rt = unsafe { &mut *RUNTIME };
+            rt.register_event_threadpool(work, ThreadPoolTaskKind::FileClose, move |res| {
+                match res {
+                    Js::Error(e) => cb(Err(e)),
+                    _ => cb(Ok(())),
+                }
+            });
+        });
+
+        writable.on_close(move |_| close_file(&file));
+        writable
+    }
+}
+
+#[derive(Clone)]
+pub struct ReadStreamOptions {
+    /// Max number of bytes read from the file in one chunk
+    pub high_water_mark: usize,
+    /// Byte offset to start reading from
+    pub start: u64,
+    /// Byte offset to stop reading at (inclusive). None = end of file
+    pub end: Option<u64>,
+}
+
+impl Default for ReadStreamOptions {
+    fn default() -> Self {
+        ReadStreamOptions {
+            high_water_mark: 64 * 1024,
+            start: 0,
+            end: None,
+        }
+    }
+}
+
+fn read_chunk(
+    file: &mut Option<fs::File>,
+    path: &Path,
+    pos: u64,
+    len: usize,
+) -> io::Result<Vec<u8>> {
+    if file.is_none() {
+        *file = Some(fs::File::open(path)?);
+    }
+
+    let file = file.as_mut().unwrap();
+    file.seek(SeekFrom::Start(pos))?;
+
+    let mut chunk = Vec::with_capacity(len);
+    file.take(len as u64).read_to_end(&mut chunk)?;
+    Ok(chunk)
+}
+
+pub struct WriteStreamOptions {
+    /// Number of buffered bytes before `write` starts returning false
+    pub high_water_mark: usize,
+    /// Append to the file instead of truncating it
+    pub append: bool,
+}
+
+impl Default for WriteStreamOptions {
+    fn default() -> Self {
+        WriteStreamOptions {
+            high_water_mark: 16 * 1024,
+            append: false,
+        }
+    }
+}
+
+fn write_chunk(
+    file: &mut Option<fs::File>,
+    path: &Path,
+    append: bool,
+    data: &[u8],
+) -> io::Result<()> {
+    if file.is_none() {
+        let opened = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .append(append)
+            .truncate(!append)
+            .open(path)?;
+        *file = Some(opened);
+    }
+
+    file.as_mut().unwrap().write_all(data)
+}
+
+/// The file is opened by the first read or write and shared with the threadpool.
+/// When the stream closes we drop it right away, unless a task is still using it
+/// in which case it's dropped when the task finishes.
+fn close_file(file: &Arc<Mutex<Option<fs::File>>>) {
+    if let Ok(mut file) = file.try_lock() {
+        file.take();
+    }
+}
+
+pub struct Http;
+impl Http {
+    pub fn http_get_slow(url: &str, delay_ms: u32, cb: impl Fn(Js) + 'static + Clone) {
+        // http://slowwly.robertomurray.co.uk was a site for simulating a delayed response
+        // from a server. It's gone, so we run our own version of it on the event loop.
+        let base = match delay_server::acquire_shared() {
+            Ok(base) => base,
+            Err(e) => return defer(move |_| cb(Js::Error(e))),
+        };
+        let url = format!("{}/delay/{}/url/http://{}", base, delay_ms, url);
+
+        // slowwly answered with a redirect, and the redirect is what we want
+        // to look at, so we don't follow it
+        let opts = http::RequestOptions {
+            max_redirects: 0,
+            ..http::RequestOptions::get(url)
+        };
+        Http::request(opts, move |result| {
+            delay_server::release_shared();
+            cb(result)
+        });
+    }
+
+    /// Sends a request to the host in `opts.url` and calls `cb` with
+    /// `Js::Response` once the whole response has arrived
+    pub fn request(
+        opts: http::RequestOptions,
+        cb: impl FnOnce(Js) + 'static,
+    ) -> http::ClientRequest {
+        http::request(opts, cb)
+    }
+
+    /// Like `request`, but `cb` gets the response as soon as its head has
+    /// arrived and the body is streamed
+    pub fn request_stream(
+        opts: http::RequestOptions,
+        cb: impl FnOnce(io::Result<http::IncomingResponse>) + 'static,
+    ) -> http::ClientRequest {
+        http::request_stream(opts, cb)
+    }
+
+    /// Creates a server which calls `handler` for every request. Nothing
+    /// happens before `listen` is called on it.
+    pub fn create_server(
+        handler: impl FnMut(http_server::IncomingRequest, http_server::ServerResponse) + 'static,
+    ) -> http_server::HttpServer {
+        http_server::HttpServer::new(handler)
+    }
+
+    /// Like `create_server`, but the server speaks HTTPS with the
+    /// certificate in `opts`. Fails if the certificate or key can't be used.
+    #[cfg(feature = "tls")]
+    pub fn create_secure_server(
+        opts: tls::TlsServerOptions,
+        handler: impl FnMut(http_server::IncomingRequest, http_server::ServerResponse) + 'static,
+    ) -> io::Result<http_server::HttpServer> {
+        http_server::HttpServer::new_secure(opts, handler)
+    }
+}
+
+pub fn print(t: impl std::fmt::Display) {
+    println!("Thread: {}\t {}", current(), t);
+}
+
+pub fn current() -> String {
+    thread::current().name().unwrap().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::child_process::ChildProcess;
+    use std::cell::RefCell;
+
+    #[test]
+    fn read_reports_errors() {
+        let path = temp_path("read");
+        fs::write(&path, "hello").unwrap();
+        let results = Rc::new(RefCell::new(vec![]));
+
+        let (p, r) = (path.clone(), results.clone());
+        run_test(move || {
+            let r2 = r.clone();
+            Fs::read(p.clone(), move |res| r2.borrow_mut().push(res));
+            let r = r.clone();
+            Fs::read(p.join("missing"), move |res| r.borrow_mut().push(res));
+        });
+
+        let results = results.take();
+        assert_eq!(results.len(), 2);
+        assert!(results.iter().any(|res| matches!(res, Js::String(s) if s == "hello")));
+        let kind = results.iter().find_map(|res| match res {
+            Js::Error(e) => Some(e.kind()),
+            _ => None,
+        });
+        assert_eq!(kind, Some(io::ErrorKind::NotADirectory));
+        fs::remove_file(path).unwrap();
+    }
+
+    /// Reads the whole stream, returning the chunks as they arrived
+    fn read_chunks(path: PathBuf, opts: ReadStreamOptions) -> Vec<Vec<u8>> {
+        let chunks = Rc::new(RefCell::new(vec![]));
+        let c = chunks.clone();
+        run_test(move || {
+            let stream = Fs::create_read_stream(&path, opts.clone());
+            let c = c.clone();
+            stream.on_data(move |chunk| c.borrow_mut().push(chunk.into_bytes().unwrap()));
+        });
+        chunks.take()
+    }
+
+    #[test]
+    fn read_stream_range() {
+        let path = temp_path("read_stream_range");
+        fs::write(&path, "0123456789").unwrap();
+
+        let opts = ReadStreamOptions {
+            start: 2,
+            end: Some(5),
+            ..Default::default()
+        };
+        assert_eq!(read_chunks(path.clone(), opts).concat(), b"2345");
+
+        // An end past the end of the file just reads the rest
+        let opts = ReadStreamOptions {
+            start: 7,
+            end: Some(100),
+            ..Default::default()
+        };
+        assert_eq!(read_chunks(path.clone(), opts).concat(), b"789");
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn read_stream_chunk_size() {
+        let path = temp_path("read_stream_chunk_size");
+        fs::write(&path, "0123456789").unwrap();
+
+        let opts = ReadStreamOptions {
+            high_water_mark: 4,
+            ..Default::default()
+        };
+        let chunks = read_chunks(path.clone(), opts);
+        assert_eq!(chunks, vec![b"0123".to_vec(), b"4567".to_vec(), b"89".to_vec()]);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn read_stream_pause_resume() {
+        let path = temp_path("read_stream_pause_resume");
+        fs::write(&path, "0123456789").unwrap();
+        let received = Rc::new(Cell::new(0));
+        let while_paused = Rc::new(Cell::new(None));
+
+        let (r, w, p) = (received.clone(), while_paused.clone(), path.clone());
+        run_test(move || {
+            let opts = ReadStreamOptions {
+                high_water_mark: 2,
+                ..Default::default()
+            };
+            let stream = Fs::create_read_stream(&p, opts);
+            let (r, w, s) = (r.clone(), w.clone(), stream.clone());
+            stream.on_data(move |_| {
+                r.set(r.get() + 1);
+                if r.get() == 1 {
+                    s.pause();
+                    let (r, w, s) = (r.clone(), w.clone(), s.clone());
+                    set_timeout(50, move |_| {
+                        w.set(Some(r.get()));
+                        s.resume();
+                    });
+                }
+            });
+        });
+
+        assert_eq!(while_paused.get(), Some(1));
+        assert_eq!(received.get(), 5);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn write_stream_backpressure() {
+        let path = temp_path("write_stream_backpressure");
+        let accepted = Rc::new(RefCell::new(vec![]));
+        let drained = Rc::new(Cell::new(0));
+
+        let (a, d, p) = (accepted.clone(), drained.clone(), path.clone());
+        run_test(move || {
+            let opts = WriteStreamOptions {
+                high_water_mark: 4,
+                ..Default::default()
+            };
+            let stream = Fs::create_write_stream(&p, opts);
+            a.borrow_mut().push(stream.write(b"ab".to_vec()));
+            a.borrow_mut().push(stream.write(b"cdef".to_vec()));
+
+            let (d, s) = (d.clone(), stream.clone());
+            stream.on_drain(move |_| {
+                d.set(d.get() + 1);
+                s.end();
+            });
+        });
+
+        assert_eq!(*accepted.borrow(), vec![true, false]);
+        assert_eq!(drained.get(), 1);
+        assert_eq!(fs::read(&path).unwrap(), b"abcdef");
+        fs::remove_file(path).unwrap();
+    }
+
+    /// Runs `f` and hands back what `run` returned, how long it took and
+    /// what the shutdown abandoned
+    fn run_timed(f: impl Fn() + 'static) -> (i32, Duration, Vec<String>) {
+        let abandoned = Rc::new(RefCell::new(vec![]));
+        let (a, start) = (abandoned.clone(), Instant::now());
+        let code = run_test(move || {
+            let a = a.clone();
+            on_exit(move |_| {
+                let rt = unsafe { &*RUNTIME };
+                *a.borrow_mut() = rt.abandoned.clone();
+            });
+            f();
+        });
+        let abandoned = abandoned.take();
+        (code, start.elapsed(), abandoned)
+    }
+
+    #[test]
+    fn shutdown_force_closes_after_the_grace_period() {
+        let events = Rc::new(RefCell::new(vec![]));
+        let pid = Rc::new(Cell::new(0));
+
+        let (e, p) = (events.clone(), pid.clone());
+        let (code, elapsed, abandoned) = run_timed(move || {
+            let child = ChildProcess::spawn("sleep", &["10"], Default::default()).unwrap();
+            p.set(child.pid());
+            let e2 = e.clone();
+            child.on_close(move |status| e2.borrow_mut().push(format!("child {}", status)));
+
+            // Finishes within the grace period, the other timer doesn't
+            let e2 = e.clone();
+            set_timeout(10, move |_| e2.borrow_mut().push("timer".to_string()));
+            let e = e.clone();
+            set_timeout(10_000, move |_| e.borrow_mut().push("late timer".to_string()));
+            shutdown(Duration::from_millis(100));
+            assert!(unsafe { &*RUNTIME }.is_shutting_down());
+        });
+
+        assert_eq!(code, 0);
+        assert!(elapsed >= Duration::from_millis(100));
+        assert!(elapsed < Duration::from_secs(5));
+        let signal = format!("child signal {}", libc::SIGKILL);
+        assert_eq!(*events.borrow(), vec!["timer".to_string(), signal]);
+        let child = format!("child process {}", pid.get());
+        assert_eq!(abandoned, vec![child, "1 timer(s)".to_string()]);
+    }
+
+    #[test]
+    fn shutdown_ends_early_once_everything_is_done() {
+        let listening = Rc::new(Cell::new(true));
+        let l = listening.clone();
+        let (code, elapsed, abandoned) = run_timed(move || {
+            // Stops listening right away
+            let server = crate::net::Net::create_server(|_| ());
+            let (s, l) = (server.clone(), l.clone());
+            server.listen("127.0.0.1:0", move |_| {
+                shutdown(Duration::from_secs(10));
+                let (s, l) = (s.clone(), l.clone());
+                set_timeout(10, move |_| l.set(s.is_listening()));
+            });
+        });
+
+        assert_eq!(code, 0);
+        assert!(!listening.get());
+        assert!(elapsed < Duration::from_secs(5));
+        assert!(abandoned.is_empty());
+    }
+
+    #[test]
+    fn before_exit_can_schedule_more_work() {
+        let events = Rc::new(RefCell::new(vec![]));
+        let e = events.clone();
+        let code = run_test(move || {
+            let (e2, rounds) = (e.clone(), Rc::new(Cell::new(0)));
+            on_before_exit(move || {
+                rounds.set(rounds.get() + 1);
+                e2.borrow_mut().push(format!("before exit {}", rounds.get()));
+                if rounds.get() < 3 {
+                    let e = e2.clone();
+                    set_timeout(10, move |_| e.borrow_mut().push("timer".to_string()));
+                }
+            });
+            let e = e.clone();
+            on_exit(move |code| e.borrow_mut().push(format!("exit {}", code)));
+        });
+
+        assert_eq!(code, 0);
+        let expected = [
+            "before exit 1",
+            "timer",
+            "before exit 2",
+            "timer",
+            "before exit 3",
+            "exit 0",
+        ];
+        assert_eq!(*events.borrow(), expected);
+    }
+
+    #[test]
+    fn exit_stops_the_loop_early() {
+        let events = Rc::new(RefCell::new(vec![]));
+        let start = Instant::now();
+        let e = events.clone();
+        let code = run_test(move || {
+            let e2 = e.clone();
+            on_before_exit(move || e2.borrow_mut().push("before exit".to_string()));
+            let e2 = e.clone();
+            on_exit(move |code| {
+                e2.borrow_mut().push(format!("exit {}", code));
+                // Never runs
+                let e = e2.clone();
+                defer(move |_| e.borrow_mut().push("deferred".to_string()));
+            });
+
+            let e2 = e.clone();
+            set_timeout(10, move |_| {
+                exit(3);
+                e2.borrow_mut().push("exit called".to_string());
+            });
+            let e = e.clone();
+            set_timeout(10_000, move |_| e.borrow_mut().push("late timer".to_string()));
+        });
+
+        assert_eq!(code, 3);
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_eq!(*events.borrow(), ["exit called", "exit 3"]);
+    }
+}
@@ -0,0 +1,2590 @@
+// ===== NET =====
+// TCP and Unix domain socket servers and sockets. All sockets are non-blocking
+// and registered with our epoll instance, so accepting, reading and writing
+// happens on the main thread whenever the OS tells us a socket is ready, just
+// like libuv does it.
+//
+// A `Socket` is a duplex stream: the readable side emits the data we receive
+// and the writable side sends what we write to it, so sockets can be piped
+// to and from files and transforms.
+//
+// Addresses are anything `ToAddress` understands: "127.0.0.1:8080",
+// ("example.com", 80), a `SocketAddr`, or a Unix socket path written as
+// "unix:/run/app.sock". A server listening on a path removes the socket file
+// again when it's closed, and replaces a stale one a crashed process left
+// behind.
+//
+//     let server = Net::create_server(|socket| {
+//         socket.readable().pipe(socket.clone());
+//     });
+//     server.listen("unix:/tmp/echo.sock", |_| print("echo server ready"));
+//
+// Over a Unix socket we can also pass file descriptors to the other process
+// with `write_fds`, which the other end picks up with `take_fds`.
+//
+// With the `tls` feature a socket can also speak TLS, see src/tls.rs. The
+// encryption happens between the TCP stream and the duplex, so everything
+// built on sockets works the same with or without it.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::net::{
+    IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs,
+};
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::{self as unix, UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::rc::{Rc, Weak};
+#[cfg(feature = "tls")]
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::dns::Dns;
+use crate::poll::{Interests, Registration};
+use crate::stream::{
+    emit, AsReadable, AsWritable, Callback, Duplex, Listeners, Readable, Writable,
+};
+#[cfg(feature = "tls")]
+use crate::tls::{self, TlsConnectOptions, TlsServerOptions, TlsStream};
+use crate::{defer, print, Handle, Js, RUNTIME};
+
+/// How much we read from a socket in one go
+const READ_BUFFER_SIZE: usize = 64 * 1024;
+/// Same as the default in Node
+const HIGH_WATER_MARK: usize = 16 * 1024;
+/// How many file descriptors we take from one read, more than that are
+/// closed by the kernel
+const MAX_FDS: usize = 64;
+
+/// Turns a TCP stream we've started to connect into the transport we want,
+/// like a TLS stream
+type WrapStream = dyn Fn(TcpStream) -> io::Result<Transport>;
+
+pub struct Net;
+
+impl Net {
+    /// Creates a server which calls `on_connection` with every new connection.
+    /// Nothing happens before `listen` is called.
+    pub fn create_server(on_connection: impl FnMut(Socket) + 'static) -> Server {
+        let state = ServerState {
+            listener: None,
+            reg: None,
+            connections: 0,
+            closing: false,
+            handle: None,
+            on_connection: Some(Box::new(on_connection)),
+            on_error: Listeners::default(),
+            on_close: Listeners::default(),
+            #[cfg(feature = "tls")]
+            tls: None,
+        };
+
+        Server {
+            inner: Rc::new(RefCell::new(state)),
+        }
+    }
+
+    /// Like `create_server`, but every connection does a TLS handshake first.
+    /// `on_connection` gets the socket once the handshake is done, and a
+    /// failed handshake is emitted as `error` on the server. Fails if the
+    /// certificates or keys in `opts` can't be used.
+    #[cfg(feature = "tls")]
+    pub fn create_tls_server(
+        opts: TlsServerOptions,
+        on_connection: impl FnMut(Socket) + 'static,
+    ) -> io::Result<Server> {
+        let config = tls::server_config(&opts)?;
+        let server = Net::create_server(on_connection);
+        server.inner.borrow_mut().tls = Some(config);
+        Ok(server)
+    }
+
+    /// Opens a connection to `addr`, over TCP or to a `unix:` path. The
+    /// socket can be written to right away, the data is sent once we're
+    /// connected. Host names are looked up with `Dns::lookup` first. Errors
+    /// are emitted as `error` on the socket.
+    pub fn connect(addr: impl ToAddress) -> Socket {
+        match addr.to_address() {
+            Ok(Address::Unix(path)) => {
+                print(format!("Connecting to unix:{}", path.display()));
+                Socket::connecting(connect_unix(&path).map(Transport::Unix))
+            }
+            Ok(addr) => connect_tcp(addr, |stream| Ok(Transport::Tcp(stream))),
+            Err(e) => Socket::failed(e),
+        }
+    }
+
+    /// Opens a TLS connection to `host`. `connect` is emitted once the
+    /// handshake is done, and the data written before that is sent after it.
+    /// The server's certificate has to be valid for `opts.server_name`, or
+    /// for `host` if it's not set.
+    #[cfg(feature = "tls")]
+    pub fn connect_tls(host: &str, port: u16, opts: TlsConnectOptions) -> Socket {
+        let res =
+            tls::client_config(&opts).and_then(|config| Ok((config, (host, port).to_address()?)));
+        let (config, addr) = match res {
+            Ok(res) => res,
+            Err(e) => return Socket::failed(e),
+        };
+        let server_name = opts.server_name.unwrap_or_else(|| host.to_string());
+        connect_tcp(addr, move |stream| {
+            let stream = TlsStream::client(stream, config.clone(), &server_name)?;
+            Ok(Transport::Tls(Box::new(stream)))
+        })
+    }
+}
+
+// ===== ADDRESSES =====
+
+/// Where to listen or connect to
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Address {
+    Ip(SocketAddr),
+    /// A host name we still have to look up
+    Host(String, u16),
+    /// A Unix socket path. Linux also has sockets with abstract names which
+    /// aren't files, those are written as "unix:@name".
+    Unix(PathBuf),
+}
+
+impl Address {
+    /// The IP addresses to try, in order. Host names are looked up
+    /// synchronously, `Net::connect` uses `Dns::lookup` instead.
+    pub fn resolve(&self) -> io::Result<Vec<SocketAddr>> {
+        match self {
+            Address::Ip(addr) => Ok(vec![*addr]),
+            Address::Host(host, port) => Ok((host.as_str(), *port).to_socket_addrs()?.collect()),
+            Address::Unix(_) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "a Unix socket path has no IP address",
+            )),
+        }
+    }
+
+    /// For the abstract names, which start with a NUL byte
+    fn abstract_name(path: &Path) -> Option<&[u8]> {
+        path.as_os_str().as_bytes().strip_prefix(b"\0")
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Address::Ip(addr) => write!(f, "{}", addr),
+            Address::Host(host, port) => write!(f, "{}:{}", host, port),
+            Address::Unix(path) => match Address::abstract_name(path) {
+                Some(name) => write!(f, "unix:@{}", String::from_utf8_lossy(name)),
+                None => write!(f, "unix:{}", path.display()),
+            },
+        }
+    }
+}
+
+/// Everything that can be turned into an `Address`, like `ToSocketAddrs`
+/// but without resolving anything and with Unix sockets
+pub trait ToAddress {
+    fn to_address(&self) -> io::Result<Address>;
+}
+
+impl ToAddress for Address {
+    fn to_address(&self) -> io::Result<Address> {
+        Ok(self.clone())
+    }
+}
+
+/// "unix:/path", "unix:@name", "1.2.3.4:80", "[::1]:80" or "host:80"
+impl ToAddress for str {
+    fn to_address(&self) -> io::Result<Address> {
+        if let Some(path) = self.strip_prefix("unix:") {
+            return Path::new(path).to_address();
+        }
+        if let Ok(addr) = self.parse() {
+            return Ok(Address::Ip(addr));
+        }
+
+        let invalid = || io::Error::new(io::ErrorKind::InvalidInput, "invalid address");
+        let (host, port) = self.rsplit_once(':').ok_or_else(invalid)?;
+        let port = port.parse().map_err(|_| invalid())?;
+        (host, port).to_address()
+    }
+}
+
+impl ToAddress for String {
+    fn to_address(&self) -> io::Result<Address> {
+        self.as_str().to_address()
+    }
+}
+
+impl ToAddress for Path {
+    fn to_address(&self) -> io::Result<Address> {
+        let path = match self.as_os_str().as_bytes().strip_prefix(b"@") {
+            Some(name) => PathBuf::from(std::ffi::OsStr::from_bytes(&[b"\0", name].concat())),
+            None => self.to_path_buf(),
+        };
+        Ok(Address::Unix(path))
+    }
+}
+
+impl ToAddress for PathBuf {
+    fn to_address(&self) -> io::Result<Address> {
+        self.as_path().to_address()
+    }
+}
+
+impl ToAddress for SocketAddr {
+    fn to_address(&self) -> io::Result<Address> {
+        Ok(Address::Ip(*self))
+    }
+}
+
+impl ToAddress for (&str, u16) {
+    fn to_address(&self) -> io::Result<Address> {
+        let (host, port) = *self;
+        // IPv6 addresses come in brackets in URLs and host:port strings
+        let ip = host.trim_start_matches('[').trim_end_matches(']');
+        match ip.parse::<IpAddr>() {
+            Ok(ip) => Ok(Address::Ip((ip, port).into())),
+            Err(_) => Ok(Address::Host(host.to_string(), port)),
+        }
+    }
+}
+
+impl ToAddress for (String, u16) {
+    fn to_address(&self) -> io::Result<Address> {
+        (self.0.as_str(), self.1).to_address()
+    }
+}
+
+impl ToAddress for (IpAddr, u16) {
+    fn to_address(&self) -> io::Result<Address> {
+        Ok(Address::Ip((*self).into()))
+    }
+}
+
+impl ToAddress for (Ipv4Addr, u16) {
+    fn to_address(&self) -> io::Result<Address> {
+        Ok(Address::Ip((*self).into()))
+    }
+}
+
+impl ToAddress for (Ipv6Addr, u16) {
+    fn to_address(&self) -> io::Result<Address> {
+        Ok(Address::Ip((*self).into()))
+    }
+}
+
+impl<T: ToAddress + ?Sized> ToAddress for &T {
+    fn to_address(&self) -> io::Result<Address> {
+        (**self).to_address()
+    }
+}
+
+// ===== SERVER =====
+
+/// A handle to a TCP server. The server keeps the event loop alive while it's
+/// listening.
+#[derive(Clone)]
+pub struct Server {
+    inner: Rc<RefCell<ServerState>>,
+}
+
+struct ServerState {
+    listener: Option<Listener>,
+    reg: Option<Registration>,
+    /// Connections which are not closed yet
+    connections: usize,
+    /// `close` is called and we're waiting for the connections to close
+    closing: bool,
+    /// Our id in the runtime's list of handles while we're listening
+    handle: Option<usize>,
+    /// `None` while it's running
+    on_connection: Option<Box<dyn FnMut(Socket)>>,
+    on_error: Listeners,
+    on_close: Listeners,
+    /// Set for servers made with `create_tls_server`
+    #[cfg(feature = "tls")]
+    tls: Option<Arc<rustls::ServerConfig>>,
+}
+
+enum Listener {
+    Tcp(TcpListener),
+    /// The path is removed again when we stop listening
+    Unix(UnixListener, PathBuf),
+}
+
+impl Server {
+    /// Starts accepting connections on `addr`. `cb` gets `Js::Undefined` once
+    /// we're listening, or the error if we couldn't bind to the address. A
+    /// socket file left behind at a `unix:` path is replaced if nobody is
+    /// listening on it anymore.
+    pub fn listen(&self, addr: impl ToAddress, cb: impl FnOnce(Js) + 'static) -> Server {
+        let res = addr.to_address().and_then(|addr| self.bind(addr));
+
+        match res {
+            Ok(()) => {
+                if let Some(addr) = self.address() {
+                    print(format!("Server listening on {}", addr));
+                }
+                defer(cb);
+            }
+            Err(e) => {
+                self.close_listener();
+                defer(move |_| cb(Js::Error(e)));
+            }
+        }
+
+        self.clone()
+    }
+
+    fn bind(&self, addr: Address) -> io::Result<()> {
+        let listener = match addr {
+            Address::Unix(path) => {
+                #[cfg(feature = "tls")]
+                if self.inner.borrow().tls.is_some() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "a TLS server can't listen on a Unix socket",
+                    ));
+                }
+                let listener = bind_unix(&path)?;
+                listener.set_nonblocking(true)?;
+                Listener::Unix(listener, path)
+            }
+            addr => {
+                let listener = TcpListener::bind(&addr.resolve()?[..])?;
+                listener.set_nonblocking(true)?;
+                Listener::Tcp(listener)
+            }
+        };
+
+        let rt = unsafe { &mut *RUNTIME };
+        let handle = ServerHandle(Rc::downgrade(&self.inner));
+        let mut state = self.inner.borrow_mut();
+        state.reg = Some(Registration::new(listener.as_raw_fd()));
+        state.listener = Some(listener);
+        state.handle = Some(rt.add_handle(Rc::new(handle)));
+        drop(state);
+        self.arm()
+    }
+
+    /// The TCP address we're listening on. Useful when listening on port 0.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        match self.inner.borrow().listener.as_ref()? {
+            Listener::Tcp(listener) => listener.local_addr().ok(),
+            Listener::Unix(..) => None,
+        }
+    }
+
+    /// The address we're listening on, TCP or Unix
+    pub fn address(&self) -> Option<Address> {
+        match self.inner.borrow().listener.as_ref()? {
+            Listener::Tcp(listener) => listener.local_addr().ok().map(Address::Ip),
+            Listener::Unix(_, path) => Some(Address::Unix(path.clone())),
+        }
+    }
+
+    pub fn is_listening(&self) -> bool {
+        self.inner.borrow().listener.is_some()
+    }
+
+    /// Number of open connections
+    pub fn connections(&self) -> usize {
+        self.inner.borrow().connections
+    }
+
+    /// Emitted if accepting connections fails, or on a TLS server if a
+    /// handshake fails
+    pub fn on_error(&self, cb: impl FnMut(Js) + 'static) {
+        self.inner.borrow_mut().on_error.add(cb);
+    }
+
+    /// Emitted once the server is closed and all connections have ended
+    pub fn on_close(&self, cb: impl FnMut(Js) + 'static) {
+        self.inner.borrow_mut().on_close.add(cb);
+    }
+
+    /// Stops accepting new connections. Existing connections are kept open, and
+    /// `cb` is called once they have all closed.
+    pub fn close(&self, cb: impl FnOnce(Js) + 'static) {
+        {
+            let mut state = self.inner.borrow_mut();
+            if state.closing {
+                drop(state);
+                defer(move |_| cb(Js::Error(io::Error::other("server is not running"))));
+                return;
+            }
+            state.closing = true;
+        }
+
+        let mut cb = Some(cb);
+        self.on_close(move |res| {
+            if let Some(cb) = cb.take() {
+                cb(res)
+            }
+        });
+
+        self.close_listener();
+        self.maybe_emit_close();
+    }
+
+    fn arm(&self) -> io::Result<()> {
+        let server = self.clone();
+        let mut state = self.inner.borrow_mut();
+        match state.reg.as_mut() {
+            Some(reg) => reg.arm(Interests::READABLE, move |_| server.on_acceptable()),
+            None => Ok(()),
+        }
+    }
+
+    fn on_acceptable(&self) {
+        match self.inner.borrow_mut().reg.as_mut() {
+            Some(reg) => reg.fired(),
+            None => return,
+        }
+
+        // Accept everything that's waiting, we only get one event for all of them
+        loop {
+            let res = match self.inner.borrow().listener.as_ref() {
+                Some(listener) => listener.accept(),
+                None => return,
+            };
+
+            match res {
+                Ok((stream, from)) => {
+                    print(format!("Accepted connection from {}", from));
+                    match stream
+                        .set_nonblocking()
+                        .and_then(|_| self.transport(stream))
+                    {
+                        Ok(stream) => self.on_socket(Socket::from_stream(stream)),
+                        Err(e) => print(format!("Dropping connection from {}: {}", from, e)),
+                    }
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                // Errors like running out of file descriptors don't stop the
+                // server, we'll try again on the next event
+                Err(e) => {
+                    emit(&self.inner, |s| &mut s.on_error, Js::Error(e));
+                    break;
+                }
+            }
+        }
+
+        if let Err(e) = self.arm() {
+            self.close_listener();
+            emit(&self.inner, |s| &mut s.on_error, Js::Error(e));
+        }
+    }
+
+    /// Puts TLS on top of an accepted stream if this is a TLS server
+    fn transport(&self, stream: Transport) -> io::Result<Transport> {
+        #[cfg(feature = "tls")]
+        if let Some(config) = self.inner.borrow().tls.clone() {
+            if let Transport::Tcp(tcp) = stream {
+                let stream = TlsStream::server(tcp, config)?;
+                return Ok(Transport::Tls(Box::new(stream)));
+            }
+            return Ok(stream);
+        }
+        Ok(stream)
+    }
+
+    fn on_socket(&self, socket: Socket) {
+        self.inner.borrow_mut().connections += 1;
+
+        let server = self.clone();
+        socket.on_close(move |_| {
+            server.inner.borrow_mut().connections -= 1;
+            server.maybe_emit_close();
+        });
+
+        // A TLS socket is handed over once its handshake is done
+        if socket.is_connecting() {
+            let server = self.clone();
+            let s = socket.clone();
+            socket.on_connect(move |_| server.emit_connection(s.clone()));
+            let server = self.clone();
+            let s = socket.clone();
+            socket.on_error(move |e| {
+                if s.is_connecting() {
+                    emit(&server.inner, |s| &mut s.on_error, e);
+                }
+            });
+            return;
+        }
+
+        self.emit_connection(socket);
+    }
+
+    fn emit_connection(&self, socket: Socket) {
+        let on_connection = self.inner.borrow_mut().on_connection.take();
+        if let Some(mut cb) = on_connection {
+            cb(socket);
+            let mut state = self.inner.borrow_mut();
+            if state.on_connection.is_none() {
+                state.on_connection = Some(cb);
+            }
+        }
+    }
+
+    fn close_listener(&self) {
+        let mut state = self.inner.borrow_mut();
+        if let Some(mut reg) = state.reg.take() {
+            reg.disarm();
+        }
+        if let Some(handle) = state.handle.take() {
+            let rt = unsafe { &mut *RUNTIME };
+            rt.remove_handle(handle);
+        }
+        if let Some(Listener::Unix(_, path)) = state.listener.take() {
+            let _ = fs::remove_file(path);
+        }
+    }
+
+    fn maybe_emit_close(&self) {
+        {
+            let state = self.inner.borrow();
+            if !state.closing || state.connections > 0 {
+                return;
+            }
+        }
+
+        let server = self.clone();
+        defer(move |_| {
+            emit(&server.inner, |s| &mut s.on_close, Js::Undefined);
+            let mut state = server.inner.borrow_mut();
+            state.on_connection = None;
+            state.on_error = Listeners::default();
+            state.on_close = Listeners::default();
+        });
+    }
+}
+
+/// A shutdown closes the server, which stops it from accepting connections
+struct ServerHandle(Weak<RefCell<ServerState>>);
+
+impl ServerHandle {
+    fn close(&self) {
+        if let Some(inner) = self.0.upgrade() {
+            if !inner.borrow().closing {
+                Server { inner }.close(|_| ());
+            }
+        }
+    }
+}
+
+impl Handle for ServerHandle {
+    fn describe(&self) -> Option<String> {
+        let server = Server {
+            inner: self.0.upgrade()?,
+        };
+        match server.address() {
+            Some(addr) => Some(format!("server listening on {}", addr)),
+            None => Some("server".to_string()),
+        }
+    }
+
+    fn stop(&self) {
+        self.close();
+    }
+
+    fn force_close(&self) {
+        self.close();
+    }
+}
+
+// ===== SOCKET =====
+
+/// A TCP connection. The socket is closed once both sides are done, or when
+/// `destroy` is called. When the other end ends its side we end ours as well,
+/// unless `set_allow_half_open` is used.
+#[derive(Clone)]
+pub struct Socket {
+    inner: Rc<RefCell<SocketState>>,
+    duplex: Duplex,
+}
+
+struct SocketState {
+    /// `None` until we have a stream to connect and after the socket is closed
+    stream: Option<Transport>,
+    reg: Option<Registration>,
+    /// We're waiting for a non-blocking connect to finish
+    connecting: bool,
+    /// We're connected and waiting for the TLS handshake to finish
+    handshaking: bool,
+    /// The readable side wants more data
+    reading: bool,
+    /// A chunk we couldn't write without blocking: the data, how much of it
+    /// is written and the callback to call once all of it is
+    pending_write: Option<(Vec<u8>, usize, Callback)>,
+    /// `end` was called before we were connected, or there is encrypted data
+    /// we have to send before we can shut down
+    pending_shutdown: Option<Callback>,
+    /// Options set before we were connected. If the connect fails we try the
+    /// next address with a new stream, which needs them as well.
+    pending_options: Vec<SocketOption>,
+    /// The addresses left to try if the connect fails
+    fallback: Option<(VecDeque<SocketAddr>, Rc<WrapStream>)>,
+    /// How much we have written in total, so `write_fds` knows when its
+    /// data is up
+    bytes_written: u64,
+    /// File descriptors from `write_fds` and where in the written data
+    /// they go
+    fds_to_send: VecDeque<(u64, Vec<OwnedFd>)>,
+    /// File descriptors we received and nobody has taken yet
+    received_fds: Vec<OwnedFd>,
+    /// Emit `timeout` after this long without activity, 0 means never
+    timeout_ms: u64,
+    timer: Option<usize>,
+    /// When we last read or wrote something
+    last_activity: Instant,
+    /// Keep our side open when the other end has ended its side
+    allow_half_open: bool,
+    /// Our id in the runtime's list of handles
+    handle: usize,
+    /// The socket keeps the event loop alive, see `unref`
+    refed: bool,
+    closed: bool,
+    /// A copy of the handle's duplex so we can get from the state to a handle
+    duplex: Duplex,
+    on_connect: Listeners,
+    on_timeout: Listeners,
+    /// Listeners from `set_timeout`, which only get the next `timeout`
+    on_timeout_once: Vec<Box<dyn FnOnce(Js)>>,
+    on_close: Listeners,
+}
+
+impl Socket {
+    /// A socket which is connecting until `attach` is called with a stream
+    fn new() -> Self {
+        let inner = Rc::new_cyclic(|weak: &Weak<RefCell<SocketState>>| {
+            // The streams only get a weak reference to the socket, the socket
+            // is kept alive by its handles and by its registration while armed
+            let socket = weak.clone();
+            let readable = Readable::new(HIGH_WATER_MARK, move |_| {
+                if let Some(socket) = Socket::upgrade(&socket) {
+                    let buffered = {
+                        let mut state = socket.inner.borrow_mut();
+                        state.reading = true;
+                        state
+                            .stream
+                            .as_mut()
+                            .is_some_and(Transport::has_buffered_data)
+                    };
+                    socket.update_interests();
+                    // Epoll doesn't know about data TLS has decrypted already
+                    if buffered {
+                        defer(move |_| socket.read_available());
+                    }
+                }
+            });
+
+            let socket = weak.clone();
+            let writable = Writable::new(HIGH_WATER_MARK, move |chunk, cb| {
+                match Socket::upgrade(&socket) {
+                    Some(socket) => socket.write_chunk(chunk, cb),
+                    None => cb(Err(io::ErrorKind::NotConnected.into())),
+                }
+            });
+
+            let socket = weak.clone();
+            writable.set_final(move |cb| match Socket::upgrade(&socket) {
+                Some(socket) => socket.shutdown(cb),
+                None => cb(Ok(())),
+            });
+
+            let rt = unsafe { &mut *RUNTIME };
+            let handle = rt.add_handle(Rc::new(SocketHandle(weak.clone())));
+
+            RefCell::new(SocketState {
+                stream: None,
+                reg: None,
+                connecting: true,
+                handshaking: false,
+                reading: false,
+                pending_write: None,
+                pending_shutdown: None,
+                pending_options: vec![],
+                fallback: None,
+                bytes_written: 0,
+                fds_to_send: VecDeque::new(),
+                received_fds: vec![],
+                timeout_ms: 0,
+                timer: None,
+                last_activity: Instant::now(),
+                allow_half_open: false,
+                handle,
+                refed: true,
+                closed: false,
+                duplex: Duplex::new(readable, writable),
+                on_connect: Listeners::default(),
+                on_timeout: Listeners::default(),
+                on_timeout_once: vec![],
+                on_close: Listeners::default(),
+            })
+        });
+
+        let duplex = inner.borrow().duplex.clone();
+        let socket = Socket { inner, duplex };
+
+        let s = socket.clone();
+        socket.duplex.readable.on_end(move |_| {
+            if !s.inner.borrow().allow_half_open {
+                s.duplex.writable.end();
+            }
+        });
+
+        let s = socket.clone();
+        socket.duplex.readable.on_close(move |_| s.maybe_close());
+        let s = socket.clone();
+        socket.duplex.writable.on_close(move |_| s.maybe_close());
+
+        socket
+    }
+
+    /// Wraps a connected stream, like the ones we get from `accept`
+    fn from_stream(stream: Transport) -> Self {
+        let socket = Socket::new();
+        socket.attach(stream, false);
+        socket
+    }
+
+    /// A socket for a stream we just started to connect
+    fn connecting(stream: io::Result<Transport>) -> Self {
+        match stream {
+            Ok(stream) => {
+                let socket = Socket::new();
+                socket.attach(stream, true);
+                socket
+            }
+            Err(e) => Socket::failed(e),
+        }
+    }
+
+    /// A socket which emits `e` as `error` and closes
+    fn failed(e: io::Error) -> Self {
+        let socket = Socket::new();
+        let s = socket.clone();
+        defer(move |_| s.duplex.destroy(Some(e)));
+        socket
+    }
+
+    /// Hands the socket its stream. If `connecting` is set the stream is in
+    /// the middle of a non-blocking connect, and we wait for it to become
+    /// writable. A TLS stream does its handshake after that.
+    fn attach(&self, stream: Transport, connecting: bool) {
+        let res = {
+            let mut state = self.inner.borrow_mut();
+            if state.closed {
+                return;
+            }
+            let res = state
+                .pending_options
+                .iter()
+                .try_for_each(|opt| opt.apply(&stream));
+            if !connecting {
+                state.pending_options.clear();
+            }
+
+            let mut reg = Registration::new(stream.as_raw_fd());
+            reg.set_ref(state.refed);
+            state.reg = Some(reg);
+            state.connecting = connecting;
+            state.handshaking = stream.is_tls();
+            state.stream = Some(stream);
+            res
+        };
+
+        if let Err(e) = res {
+            self.duplex.destroy(Some(e));
+            return;
+        }
+
+        if !connecting {
+            self.flush_pending_write();
+        }
+        self.update_interests();
+    }
+
+    fn upgrade(weak: &Weak<RefCell<SocketState>>) -> Option<Socket> {
+        let inner = weak.upgrade()?;
+        let duplex = inner.borrow().duplex.clone();
+        Some(Socket { inner, duplex })
+    }
+
+    /// Emitted once a socket created with `Net::connect` is connected, for
+    /// TLS sockets once the handshake is done
+    pub fn on_connect(&self, cb: impl FnMut(Js) + 'static) {
+        self.inner.borrow_mut().on_connect.add(cb);
+    }
+
+    /// Attaching a `data` listener starts reading from the socket
+    pub fn on_data(&self, cb: impl FnMut(Js) + 'static) {
+        self.duplex.readable.on_data(cb);
+    }
+
+    /// Emitted when the other end has ended its side
+    pub fn on_end(&self, cb: impl FnMut(Js) + 'static) {
+        self.duplex.readable.on_end(cb);
+    }
+
+    pub fn on_drain(&self, cb: impl FnMut(Js) + 'static) {
+        self.duplex.writable.on_drain(cb);
+    }
+
+    pub fn on_error(&self, cb: impl FnMut(Js) + 'static) {
+        self.duplex.on_error(cb);
+    }
+
+    /// Emitted once the socket is fully closed
+    pub fn on_close(&self, cb: impl FnMut(Js) + 'static) {
+        self.inner.borrow_mut().on_close.add(cb);
+    }
+
+    /// Returns false if the data was buffered because the socket can't keep
+    /// up, in which case we should wait for `drain` before writing more
+    pub fn write(&self, data: impl Into<Vec<u8>>) -> bool {
+        self.duplex.writable.write(data.into())
+    }
+
+    /// Sends a FIN once everything written is sent. We can still receive data
+    /// until the other end ends its side.
+    pub fn end(&self) {
+        self.duplex.writable.end();
+    }
+
+    /// Closes the socket right away, discarding anything not sent yet
+    pub fn destroy(&self) {
+        self.duplex.destroy(None);
+    }
+
+    pub fn pause(&self) {
+        self.duplex.readable.pause();
+    }
+
+    pub fn resume(&self) {
+        self.duplex.readable.resume();
+    }
+
+    /// `None` for Unix sockets, which have no IP address
+    pub fn remote_addr(&self) -> Option<SocketAddr> {
+        let state = self.inner.borrow();
+        state.stream.as_ref()?.tcp()?.peer_addr().ok()
+    }
+
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        let state = self.inner.borrow();
+        state.stream.as_ref()?.tcp()?.local_addr().ok()
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        let state = self.inner.borrow();
+        state.stream.as_ref().is_some_and(Transport::is_tls)
+    }
+
+    /// The protocol picked with ALPN during the TLS handshake
+    #[cfg(feature = "tls")]
+    pub fn alpn_protocol(&self) -> Option<String> {
+        match self.inner.borrow().stream.as_ref()? {
+            Transport::Tls(stream) => stream.alpn_protocol(),
+            _ => None,
+        }
+    }
+
+    /// The certificate chain the other end sent, DER encoded with its own
+    /// certificate first. Empty if it didn't send one.
+    #[cfg(feature = "tls")]
+    pub fn peer_certificates(&self) -> Vec<Vec<u8>> {
+        match self.inner.borrow().stream.as_ref() {
+            Some(Transport::Tls(stream)) => stream.peer_certificates(),
+            _ => vec![],
+        }
+    }
+
+    /// On a TLS server, the host name the client asked for with SNI
+    #[cfg(feature = "tls")]
+    pub fn server_name(&self) -> Option<String> {
+        match self.inner.borrow().stream.as_ref()? {
+            Transport::Tls(stream) => stream.server_name(),
+            _ => None,
+        }
+    }
+
+    /// Sends `fds` to the other end of a Unix socket along with `data`, which
+    /// can't be empty. They arrive with the first byte of `data`, and the
+    /// other process gets its own copies of them, see `take_fds`. Ours are
+    /// closed once they're sent. Returns the same as `write`.
+    pub fn write_fds(&self, data: impl Into<Vec<u8>>, fds: Vec<OwnedFd>) -> io::Result<bool> {
+        let data = data.into();
+        {
+            let mut state = self.inner.borrow_mut();
+            match state.stream.as_ref() {
+                Some(Transport::Unix(_)) if !data.is_empty() => (),
+                Some(Transport::Unix(_)) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "file descriptors need at least one byte of data",
+                    ))
+                }
+                Some(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "file descriptors can only be sent over a Unix socket",
+                    ))
+                }
+                None => return Err(io::ErrorKind::NotConnected.into()),
+            }
+
+            // Everything written before is either sent or waiting in the
+            // writable side, the part of the chunk being written included
+            let sent_of_pending = state.pending_write.as_ref().map_or(0, |p| p.1 as u64);
+            let at = state.bytes_written - sent_of_pending + self.duplex.writable.length() as u64;
+            state.fds_to_send.push_back((at, fds));
+        }
+        Ok(self.duplex.writable.write(data))
+    }
+
+    /// The file descriptors the other end sent with `write_fds` that we
+    /// have received so far. They come with the data, so the `data` listener
+    /// is a good place to take them. Whatever isn't taken is closed with the
+    /// socket.
+    pub fn take_fds(&self) -> Vec<OwnedFd> {
+        mem::take(&mut self.inner.borrow_mut().received_fds)
+    }
+
+    /// By default we end our side as soon as the other end has ended its
+    /// side. With this set it's up to us to call `end`.
+    pub fn set_allow_half_open(&self, allow: bool) {
+        self.inner.borrow_mut().allow_half_open = allow;
+    }
+
+    /// Emits `timeout` once the socket has been idle for `ms` milliseconds.
+    /// Reading or writing anything starts the wait over. The socket is not
+    /// closed, that's up to the `timeout` listener. `cb` only gets the next
+    /// `timeout`, use `on_timeout` for all of them. 0 turns the timeout off
+    /// and removes the listeners added here, in which case `cb` is dropped.
+    pub fn set_timeout(&self, ms: u64, cb: impl FnOnce(Js) + 'static) {
+        {
+            let mut state = self.inner.borrow_mut();
+            if state.closed {
+                return;
+            }
+            state.timeout_ms = ms;
+            if ms > 0 {
+                state.on_timeout_once.push(Box::new(cb));
+            } else {
+                state.on_timeout_once.clear();
+            }
+            // The timer we have might be for the old timeout
+            if let Some(timer) = state.timer.take() {
+                crate::clear_timeout(timer);
+            }
+        }
+        self.touch();
+    }
+
+    pub fn on_timeout(&self, cb: impl FnMut(Js) + 'static) {
+        self.inner.borrow_mut().on_timeout.add(cb);
+    }
+
+    /// Disables Nagle's algorithm so small writes are sent right away
+    pub fn set_no_delay(&self, no_delay: bool) -> io::Result<()> {
+        self.set_option(SocketOption::NoDelay(no_delay))
+    }
+
+    /// Enables TCP keepalive probes, starting after the connection has been
+    /// idle for `initial_delay_ms`. 0 keeps the system default.
+    pub fn set_keep_alive(&self, enable: bool, initial_delay_ms: u64) -> io::Result<()> {
+        self.set_option(SocketOption::KeepAlive(enable, initial_delay_ms))
+    }
+
+    /// With `Some(duration)` closing the socket waits up to `duration` for
+    /// unsent data to be sent. `Some(Duration::ZERO)` makes closing send a
+    /// reset instead of a FIN. `None` is the default behavior.
+    pub fn set_linger(&self, linger: Option<Duration>) -> io::Result<()> {
+        self.set_option(SocketOption::Linger(linger))
+    }
+
+    fn set_option(&self, opt: SocketOption) -> io::Result<()> {
+        let mut state = self.inner.borrow_mut();
+        match state.stream.as_ref() {
+            Some(stream) => opt.apply(stream)?,
+            None if state.closed => return Err(io::ErrorKind::NotConnected.into()),
+            None => (),
+        }
+        if state.connecting {
+            state.pending_options.push(opt);
+        }
+        Ok(())
+    }
+
+    /// Lets the event loop exit even though the socket is open, like
+    /// `socket.unref()` in Node
+    pub fn unref(&self) {
+        self.set_ref(false);
+    }
+
+    /// Undoes `unref`
+    pub fn ref_(&self) {
+        self.set_ref(true);
+    }
+
+    fn set_ref(&self, refed: bool) {
+        let mut state = self.inner.borrow_mut();
+        state.refed = refed;
+        if let Some(reg) = state.reg.as_mut() {
+            reg.set_ref(refed);
+        }
+        if let Some(timer) = state.timer {
+            crate::set_event_ref(timer, refed);
+        }
+    }
+
+    /// Starts the idle timeout over. Called whenever there is activity, so
+    /// we don't move the timer here, we only check the time when it fires.
+    fn touch(&self) {
+        let mut state = self.inner.borrow_mut();
+        state.last_activity = Instant::now();
+        if state.timer.is_none() {
+            let ms = state.timeout_ms;
+            drop(state);
+            self.start_timer(ms);
+        }
+    }
+
+    fn start_timer(&self, ms: u64) {
+        let mut state = self.inner.borrow_mut();
+        if state.timeout_ms == 0 || state.closed {
+            return;
+        }
+
+        let socket = self.clone();
+        let timer = crate::set_timeout(ms, move |_| socket.on_timer());
+        if !state.refed {
+            crate::set_event_ref(timer, false);
+        }
+        state.timer = Some(timer);
+    }
+
+    /// Emits `timeout` if we've been idle for long enough, if not we wait for
+    /// the rest of it
+    fn on_timer(&self) {
+        let (idle, timeout) = {
+            let mut state = self.inner.borrow_mut();
+            state.timer = None;
+            (
+                state.last_activity.elapsed(),
+                Duration::from_millis(state.timeout_ms),
+            )
+        };
+
+        if idle < timeout {
+            // Rounded up, so we don't fire just before the socket is idle
+            let left = (timeout - idle).as_micros().div_ceil(1000);
+            self.start_timer(left as u64);
+            return;
+        }
+
+        let once = mem::take(&mut self.inner.borrow_mut().on_timeout_once);
+        emit(&self.inner, |s| &mut s.on_timeout, Js::Undefined);
+        for cb in once {
+            cb(Js::Undefined);
+        }
+    }
+
+    /// True until we're connected and done with the TLS handshake
+    pub fn is_connecting(&self) -> bool {
+        let state = self.inner.borrow();
+        state.connecting || state.handshaking
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.inner.borrow().closed
+    }
+
+    /// Arms or disarms our registration depending on what we're waiting for
+    fn update_interests(&self) {
+        let mut state = self.inner.borrow_mut();
+        let state = &mut *state;
+        let reg = match state.reg.as_mut() {
+            Some(reg) if !state.closed => reg,
+            _ => return,
+        };
+
+        // During the handshake we read and write for TLS, not for the streams
+        let open = !state.connecting && !state.handshaking;
+        let handshaking = state.handshaking && !state.connecting;
+        let wants_write = state.stream.as_ref().is_some_and(Transport::wants_write);
+
+        let readable = (state.reading && open) || handshaking;
+        let writable = state.connecting || (state.pending_write.is_some() && open) || wants_write;
+
+        let interests = match (readable, writable) {
+            (true, true) => Interests::READABLE | Interests::WRITABLE,
+            (true, false) => Interests::READABLE,
+            (false, true) => Interests::WRITABLE,
+            (false, false) => {
+                reg.disarm();
+                return;
+            }
+        };
+
+        let socket = self.clone();
+        let res = reg.arm(interests, move |_| socket.on_ready());
+
+        if let Err(e) = res {
+            self.duplex.destroy(Some(e));
+        }
+    }
+
+    /// We don't know what the socket is ready for, so we try everything we're
+    /// waiting for. Whatever isn't ready just returns `WouldBlock`.
+    fn on_ready(&self) {
+        if let Some(reg) = self.inner.borrow_mut().reg.as_mut() {
+            reg.fired();
+        }
+
+        if self.inner.borrow().connecting {
+            self.finish_connect();
+        }
+        if self.inner.borrow().handshaking {
+            self.continue_handshake();
+        }
+
+        self.flush_pending_write();
+        self.read_available();
+        self.flush_pending_shutdown();
+        self.update_interests();
+    }
+
+    /// A connecting socket becomes writable once the connect has finished,
+    /// successfully or not
+    fn finish_connect(&self) {
+        let res = {
+            let state = self.inner.borrow();
+            let stream = state.stream.as_ref().expect("connecting socket");
+            match stream.take_error() {
+                Ok(Some(e)) | Err(e) => Err(e),
+                Ok(None) => stream.check_connected(),
+            }
+        };
+
+        match res {
+            Ok(()) => {
+                let handshaking = {
+                    let mut state = self.inner.borrow_mut();
+                    state.connecting = false;
+                    state.pending_options.clear();
+                    state.fallback = None;
+                    state.handshaking
+                };
+                if !handshaking {
+                    self.connected();
+                }
+            }
+            // Woken up before the connect finished
+            Err(ref e) if e.kind() == io::ErrorKind::NotConnected => (),
+            Err(e) => {
+                let fallback = self.inner.borrow_mut().fallback.take();
+                match fallback {
+                    Some((addrs, wrap)) if !addrs.is_empty() => {
+                        print(format!("Connect failed: {}, trying the next address", e));
+                        {
+                            let mut state = self.inner.borrow_mut();
+                            // Has to happen before the stream is closed
+                            if let Some(mut reg) = state.reg.take() {
+                                reg.disarm();
+                            }
+                            state.stream = None;
+                        }
+                        self.connect_any(addrs, wrap);
+                    }
+                    _ => self.duplex.destroy(Some(e)),
+                }
+            }
+        }
+    }
+
+    /// Connects to the first of `addrs` which we can start a connect to. The
+    /// rest are kept in case that connect fails later on. If none of them
+    /// work we fail with the last error.
+    fn connect_any(&self, mut addrs: VecDeque<SocketAddr>, wrap: Rc<WrapStream>) {
+        let mut err = io::Error::new(io::ErrorKind::NotFound, "no addresses to connect to");
+        while let Some(addr) = addrs.pop_front() {
+            match connect_non_blocking(addr).and_then(|stream| wrap(stream)) {
+                Ok(stream) => {
+                    self.inner.borrow_mut().fallback = Some((addrs, wrap));
+                    self.attach(stream, true);
+                    return;
+                }
+                Err(e) => err = e,
+            }
+        }
+
+        // We might be called before anyone had a chance to listen for errors
+        let socket = self.clone();
+        defer(move |_| socket.duplex.destroy(Some(err)));
+    }
+
+    /// Takes the TLS handshake as far as it goes until the socket would block
+    fn continue_handshake(&self) {
+        let res = {
+            let mut state = self.inner.borrow_mut();
+            if state.connecting || state.closed {
+                return;
+            }
+            state.stream.as_mut().expect("open socket").handshake()
+        };
+
+        match res {
+            Ok(true) => {
+                self.inner.borrow_mut().handshaking = false;
+                self.connected();
+            }
+            Ok(false) => (),
+            Err(e) => self.duplex.destroy(Some(e)),
+        }
+    }
+
+    fn connected(&self) {
+        self.touch();
+        emit(&self.inner, |s| &mut s.on_connect, Js::Undefined);
+    }
+
+    fn write_chunk(&self, chunk: Vec<u8>, cb: Callback) {
+        let mut state = self.inner.borrow_mut();
+        if state.closed {
+            drop(state);
+            cb(Err(io::ErrorKind::NotConnected.into()));
+            return;
+        }
+
+        state.pending_write = Some((chunk, 0, cb));
+        drop(state);
+
+        self.flush_pending_write();
+        self.update_interests();
+    }
+
+    /// Writes as much of the pending chunk as the socket accepts
+    fn flush_pending_write(&self) {
+        let mut state = self.inner.borrow_mut();
+        if state.connecting || state.handshaking || state.closed {
+            return;
+        }
+
+        let (chunk, mut written, cb) = match state.pending_write.take() {
+            Some(pending) => pending,
+            None => {
+                // TLS can have encrypted data left to send even though all
+                // writes are done
+                let res = state.stream.as_mut().expect("open socket").flush();
+                drop(state);
+                if let Err(e) = res {
+                    self.duplex.destroy(Some(e));
+                }
+                return;
+            }
+        };
+        let written_before = written;
+
+        let res = loop {
+            let state = &mut *state;
+            let stream = state.stream.as_mut().expect("open socket");
+            let fds = match state.fds_to_send.front() {
+                Some((at, fds)) if *at == state.bytes_written => &fds[..],
+                _ => &[],
+            };
+            let with_fds = !fds.is_empty();
+            match stream.write_with_fds(&chunk[written..], fds) {
+                Ok(n) => {
+                    if with_fds {
+                        state.fds_to_send.pop_front();
+                    }
+                    written += n;
+                    state.bytes_written += n as u64;
+                    if written == chunk.len() {
+                        break Some(Ok(()));
+                    }
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break None,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => break Some(Err(e)),
+            }
+        };
+
+        // `None` means we have to wait for the socket to become writable
+        let done = match res {
+            Some(res) => Some((cb, res)),
+            None => {
+                state.pending_write = Some((chunk, written, cb));
+                None
+            }
+        };
+        drop(state);
+
+        if written > written_before {
+            self.touch();
+        }
+        if let Some((cb, res)) = done {
+            cb(res);
+        }
+    }
+
+    /// Reads until the socket would block or the readable side is full
+    fn read_available(&self) {
+        let mut buffer = vec![0; READ_BUFFER_SIZE];
+
+        loop {
+            let mut state = self.inner.borrow_mut();
+            if !state.reading || state.connecting || state.handshaking || state.closed {
+                return;
+            }
+
+            let res = {
+                let state = &mut *state;
+                let stream = state.stream.as_mut().expect("open socket");
+                stream.read_with_fds(&mut buffer, &mut state.received_fds)
+            };
+            match res {
+                Ok(0) => {
+                    state.reading = false;
+                    drop(state);
+                    self.duplex.readable.push_end();
+                    return;
+                }
+                Ok(n) => {
+                    // `push` calls our read function again if it wants more
+                    state.reading = false;
+                    drop(state);
+                    self.touch();
+                    self.duplex.readable.push(buffer[..n].to_vec());
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    drop(state);
+                    self.duplex.destroy(Some(e));
+                    return;
+                }
+            }
+        }
+    }
+
+    fn shutdown(&self, cb: Callback) {
+        let mut state = self.inner.borrow_mut();
+        if state.connecting || state.handshaking {
+            state.pending_shutdown = Some(cb);
+            return;
+        }
+
+        let res = match state.stream.as_mut() {
+            Some(stream) => {
+                stream.send_close_notify();
+                match stream.flush() {
+                    Ok(()) if stream.wants_write() => {
+                        // We shut down once the socket has taken the rest
+                        state.pending_shutdown = Some(cb);
+                        drop(state);
+                        self.update_interests();
+                        return;
+                    }
+                    Ok(()) => stream.shutdown_write(),
+                    Err(e) => Err(e),
+                }
+            }
+            None => Ok(()),
+        };
+        drop(state);
+
+        match res {
+            // The other end might have closed the connection already
+            Err(ref e) if e.kind() == io::ErrorKind::NotConnected => cb(Ok(())),
+            res => cb(res),
+        }
+    }
+
+    /// Shuts down if `end` is waiting for us to connect or to send the rest
+    /// of the encrypted data
+    fn flush_pending_shutdown(&self) {
+        let cb = {
+            let mut state = self.inner.borrow_mut();
+            let wants_write = state.stream.as_ref().is_some_and(Transport::wants_write);
+            if state.connecting || state.handshaking || state.closed || wants_write {
+                return;
+            }
+            state.pending_shutdown.take()
+        };
+        if let Some(cb) = cb {
+            self.shutdown(cb);
+        }
+    }
+
+    /// Closes the socket once both sides are closed
+    fn maybe_close(&self) {
+        if !self.duplex.readable.is_destroyed() || !self.duplex.writable.is_destroyed() {
+            return;
+        }
+
+        {
+            let mut state = self.inner.borrow_mut();
+            if state.closed {
+                return;
+            }
+            state.closed = true;
+            if let Some(mut reg) = state.reg.take() {
+                reg.disarm();
+            }
+            let rt = unsafe { &mut *RUNTIME };
+            rt.remove_handle(state.handle);
+            state.stream = None;
+            state.pending_write = None;
+            state.pending_shutdown = None;
+            state.fds_to_send.clear();
+            state.received_fds.clear();
+            if let Some(timer) = state.timer.take() {
+                crate::clear_timeout(timer);
+            }
+        }
+
+        emit(&self.inner, |s| &mut s.on_close, Js::Undefined);
+
+        let mut state = self.inner.borrow_mut();
+        state.on_connect = Listeners::default();
+        state.on_timeout = Listeners::default();
+        state.on_timeout_once.clear();
+        state.on_close = Listeners::default();
+    }
+}
+
+/// A shutdown lets sockets finish what they're doing, and destroys the ones
+/// still open once the grace period is over
+struct SocketHandle(Weak<RefCell<SocketState>>);
+
+impl Handle for SocketHandle {
+    fn describe(&self) -> Option<String> {
+        let socket = Socket::upgrade(&self.0)?;
+        match socket.remote_addr() {
+            Some(addr) => Some(format!("socket connected to {}", addr)),
+            None => Some("socket".to_string()),
+        }
+    }
+
+    fn force_close(&self) {
+        if let Some(socket) = Socket::upgrade(&self.0) {
+            socket.destroy();
+        }
+    }
+}
+
+impl AsReadable for Socket {
+    fn readable(&self) -> &Readable {
+        &self.duplex.readable
+    }
+}
+
+impl AsWritable for Socket {
+    fn writable(&self) -> &Writable {
+        &self.duplex.writable
+    }
+}
+
+impl From<Socket> for crate::stream::Stream {
+    fn from(socket: Socket) -> Self {
+        crate::stream::Stream::Duplex(socket.duplex)
+    }
+}
+
+// ===== TRANSPORT =====
+
+/// What a socket reads from and writes to
+enum Transport {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+    #[cfg(feature = "tls")]
+    Tls(Box<TlsStream>),
+}
+
+impl Transport {
+    /// The TCP stream underneath, Unix sockets don't have one
+    fn tcp(&self) -> Option<&TcpStream> {
+        match self {
+            Transport::Tcp(stream) => Some(stream),
+            Transport::Unix(_) => None,
+            #[cfg(feature = "tls")]
+            Transport::Tls(stream) => Some(stream.tcp()),
+        }
+    }
+
+    fn is_tls(&self) -> bool {
+        match self {
+            Transport::Tcp(_) | Transport::Unix(_) => false,
+            #[cfg(feature = "tls")]
+            Transport::Tls(_) => true,
+        }
+    }
+
+    fn set_nonblocking(&self) -> io::Result<()> {
+        match self {
+            Transport::Unix(stream) => stream.set_nonblocking(true),
+            stream => stream.tcp().expect("tcp stream").set_nonblocking(true),
+        }
+    }
+
+    fn take_error(&self) -> io::Result<Option<io::Error>> {
+        match self {
+            Transport::Unix(stream) => stream.take_error(),
+            stream => stream.tcp().expect("tcp stream").take_error(),
+        }
+    }
+
+    /// Fails with `NotConnected` while a connect is still in progress
+    fn check_connected(&self) -> io::Result<()> {
+        match self {
+            Transport::Unix(stream) => stream.peer_addr().map(|_| ()),
+            stream => stream.tcp().expect("tcp stream").peer_addr().map(|_| ()),
+        }
+    }
+
+    fn shutdown_write(&self) -> io::Result<()> {
+        match self {
+            Transport::Unix(stream) => stream.shutdown(Shutdown::Write),
+            stream => stream.tcp().expect("tcp stream").shutdown(Shutdown::Write),
+        }
+    }
+
+    /// Returns true once the TLS handshake is done, which a plain stream
+    /// doesn't have
+    fn handshake(&mut self) -> io::Result<bool> {
+        match self {
+            #[cfg(feature = "tls")]
+            Transport::Tls(stream) => stream.handshake(),
+            _ => Ok(true),
+        }
+    }
+
+    /// There is encrypted data we haven't been able to send yet
+    fn wants_write(&self) -> bool {
+        match self {
+            #[cfg(feature = "tls")]
+            Transport::Tls(stream) => stream.wants_write(),
+            _ => false,
+        }
+    }
+
+    /// There is data to read which isn't in the socket anymore
+    fn has_buffered_data(&mut self) -> bool {
+        match self {
+            #[cfg(feature = "tls")]
+            Transport::Tls(stream) => stream.has_buffered_data(),
+            _ => false,
+        }
+    }
+
+    fn send_close_notify(&mut self) {
+        #[cfg(feature = "tls")]
+        if let Transport::Tls(stream) = self {
+            stream.send_close_notify();
+        }
+    }
+
+    /// Like `write`, with file descriptors for the other end if there are
+    /// any, which only works on a Unix socket
+    fn write_with_fds(&mut self, buf: &[u8], fds: &[OwnedFd]) -> io::Result<usize> {
+        match self {
+            Transport::Unix(stream) if !fds.is_empty() => send_with_fds(stream, buf, fds),
+            stream => stream.write(buf),
+        }
+    }
+
+    /// Like `read`, and on a Unix socket it also collects the file
+    /// descriptors that came with the data
+    fn read_with_fds(&mut self, buf: &mut [u8], fds: &mut Vec<OwnedFd>) -> io::Result<usize> {
+        match self {
+            Transport::Unix(stream) => recv_with_fds(stream, buf, fds),
+            stream => stream.read(buf),
+        }
+    }
+}
+
+impl AsRawFd for Transport {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Transport::Unix(stream) => stream.as_raw_fd(),
+            stream => stream.tcp().expect("tcp stream").as_raw_fd(),
+        }
+    }
+}
+
+impl Read for Transport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Transport::Tcp(stream) => stream.read(buf),
+            Transport::Unix(stream) => stream.read(buf),
+            #[cfg(feature = "tls")]
+            Transport::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Transport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Transport::Tcp(stream) => stream.write(buf),
+            Transport::Unix(stream) => stream.write(buf),
+            #[cfg(feature = "tls")]
+            Transport::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Transport::Tcp(stream) => stream.flush(),
+            Transport::Unix(stream) => stream.flush(),
+            #[cfg(feature = "tls")]
+            Transport::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+impl Listener {
+    /// Accepts a connection and tells where it came from
+    fn accept(&self) -> io::Result<(Transport, String)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, addr) = listener.accept()?;
+                Ok((Transport::Tcp(stream), addr.to_string()))
+            }
+            Listener::Unix(listener, path) => {
+                let (stream, _) = listener.accept()?;
+                Ok((Transport::Unix(stream), format!("unix:{}", path.display())))
+            }
+        }
+    }
+}
+
+impl AsRawFd for Listener {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Listener::Tcp(listener) => listener.as_raw_fd(),
+            Listener::Unix(listener, _) => listener.as_raw_fd(),
+        }
+    }
+}
+
+/// Connects to an IP address right away, and to a host name once
+/// `Dns::lookup` has found its addresses. We try them in order until one of
+/// them takes the connection. `wrap` gets each stream as soon as its connect
+/// is started.
+fn connect_tcp(
+    addr: Address,
+    wrap: impl Fn(TcpStream) -> io::Result<Transport> + 'static,
+) -> Socket {
+    print(format!("Connecting to {}", addr));
+    let wrap: Rc<WrapStream> = Rc::new(wrap);
+    let socket = Socket::new();
+
+    match addr {
+        Address::Host(host, port) => {
+            // The socket is connecting until the lookup is done, so it buffers
+            // whatever is written to it in the meantime
+            let s = socket.clone();
+            Dns::lookup(&host, move |res| match res {
+                Ok(ips) => {
+                    let addrs = ips.into_iter().map(|ip| SocketAddr::new(ip, port));
+                    s.connect_any(addrs.collect(), wrap);
+                }
+                Err(e) => s.duplex.destroy(Some(e)),
+            });
+        }
+        Address::Ip(addr) => socket.connect_any(VecDeque::from([addr]), wrap),
+        Address::Unix(_) => unreachable!("unix: paths are connected to with `connect_unix`"),
+    }
+    socket
+}
+
+/// Starts a connect without waiting for it to finish. The stream becomes
+/// writable once the connect is done.
+fn connect_non_blocking(addr: SocketAddr) -> io::Result<TcpStream> {
+    let domain = match addr {
+        SocketAddr::V4(_) => libc::AF_INET,
+        SocketAddr::V6(_) => libc::AF_INET6,
+    };
+
+    let flags = libc::SOCK_STREAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC;
+    let fd = unsafe { libc::socket(domain, flags, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // From here on the fd is closed when `stream` is dropped
+    let stream = unsafe { TcpStream::from_raw_fd(fd) };
+
+    let (raw_addr, len) = raw_socket_addr(&addr);
+    let res = unsafe { libc::connect(fd, &raw_addr as *const _ as *const libc::sockaddr, len) };
+    if res < 0 {
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::EINPROGRESS) {
+            return Err(err);
+        }
+    }
+
+    Ok(stream)
+}
+
+/// Same as `connect_non_blocking` for a Unix socket. These connect right
+/// away, or fail with `WouldBlock` if the server has too many connections
+/// waiting to be accepted.
+fn connect_unix(path: &Path) -> io::Result<UnixStream> {
+    let flags = libc::SOCK_STREAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC;
+    let fd = unsafe { libc::socket(libc::AF_UNIX, flags, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let stream = unsafe { UnixStream::from_raw_fd(fd) };
+
+    let (raw_addr, len) = raw_unix_addr(path)?;
+    let res = unsafe { libc::connect(fd, &raw_addr as *const _ as *const libc::sockaddr, len) };
+    if res < 0 {
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::EINPROGRESS) {
+            return Err(err);
+        }
+    }
+
+    Ok(stream)
+}
+
+fn bind_unix(path: &Path) -> io::Result<UnixListener> {
+    match UnixListener::bind_addr(&unix_socket_addr(path)?) {
+        Err(ref e) if e.kind() == io::ErrorKind::AddrInUse && is_stale_socket(path) => {
+            print(format!("Removing stale socket file {}", path.display()));
+            fs::remove_file(path)?;
+            UnixListener::bind_addr(&unix_socket_addr(path)?)
+        }
+        res => res,
+    }
+}
+
+/// A socket file nobody is listening on anymore, usually left behind by a
+/// process which crashed. Anything else at the path is never stale.
+pub(crate) fn is_stale_socket(path: &Path) -> bool {
+    let is_socket = fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket());
+    is_socket
+        && matches!(
+            UnixStream::connect(path),
+            Err(ref e) if e.kind() == io::ErrorKind::ConnectionRefused
+        )
+}
+
+/// Sends `buf` with `fds` attached as SCM_RIGHTS
+fn send_with_fds(socket: &impl AsRawFd, buf: &[u8], fds: &[OwnedFd]) -> io::Result<usize> {
+    let fds: Vec<RawFd> = fds.iter().map(AsRawFd::as_raw_fd).collect();
+    let fds_len = mem::size_of_val(&fds[..]) as u32;
+    let mut control = vec![0u8; unsafe { libc::CMSG_SPACE(fds_len) } as usize];
+
+    let mut iov = libc::iovec {
+        iov_base: buf.as_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = control.len();
+
+    let n = unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(fds_len) as usize;
+        std::ptr::copy_nonoverlapping(
+            fds.as_ptr() as *const u8,
+            libc::CMSG_DATA(cmsg),
+            fds_len as usize,
+        );
+        libc::sendmsg(socket.as_raw_fd(), &msg, libc::MSG_NOSIGNAL)
+    };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(n as usize)
+}
+
+/// Reads into `buf` and adds the file descriptors that came with the data
+/// to `fds`
+fn recv_with_fds(
+    socket: &impl AsRawFd,
+    buf: &mut [u8],
+    fds: &mut Vec<OwnedFd>,
+) -> io::Result<usize> {
+    let space = unsafe { libc::CMSG_SPACE((MAX_FDS * mem::size_of::<RawFd>()) as u32) };
+    let mut control = vec![0u8; space as usize];
+
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = control.len();
+
+    let n = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut msg, libc::MSG_CMSG_CLOEXEC) };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let data = libc::CMSG_DATA(cmsg);
+                let len = (*cmsg).cmsg_len - (data as usize - cmsg as usize);
+                for i in 0..len / mem::size_of::<RawFd>() {
+                    let fd = std::ptr::read_unaligned((data as *const RawFd).add(i));
+                    fds.push(OwnedFd::from_raw_fd(fd));
+                }
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+    if msg.msg_flags & libc::MSG_CTRUNC != 0 {
+        print(format!(
+            "Received more than {} file descriptors, closed the rest",
+            MAX_FDS
+        ));
+    }
+
+    Ok(n as usize)
+}
+
+/// Converts `addr` to the C representation the socket calls expect
+pub(crate) fn raw_socket_addr(addr: &SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+    let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+
+    let len = match addr {
+        SocketAddr::V4(addr) => {
+            let raw = libc::sockaddr_in {
+                sin_family: libc::AF_INET as libc::sa_family_t,
+                sin_port: addr.port().to_be(),
+                sin_addr: libc::in_addr {
+                    s_addr: u32::from_ne_bytes(addr.ip().octets()),
+                },
+                sin_zero: [0; 8],
+            };
+            unsafe { std::ptr::write(&mut storage as *mut _ as *mut libc::sockaddr_in, raw) };
+            mem::size_of::<libc::sockaddr_in>()
+        }
+        SocketAddr::V6(addr) => {
+            let raw = libc::sockaddr_in6 {
+                sin6_family: libc::AF_INET6 as libc::sa_family_t,
+                sin6_port: addr.port().to_be(),
+                sin6_flowinfo: addr.flowinfo(),
+                sin6_addr: libc::in6_addr {
+                    s6_addr: addr.ip().octets(),
+                },
+                sin6_scope_id: addr.scope_id(),
+            };
+            unsafe { std::ptr::write(&mut storage as *mut _ as *mut libc::sockaddr_in6, raw) };
+            mem::size_of::<libc::sockaddr_in6>()
+        }
+    };
+
+    (storage, len as libc::socklen_t)
+}
+
+/// The C representation of a Unix socket path, see `raw_socket_addr`
+pub(crate) fn raw_unix_addr(path: &Path) -> io::Result<(libc::sockaddr_un, libc::socklen_t)> {
+    let mut raw: libc::sockaddr_un = unsafe { mem::zeroed() };
+    raw.sun_family = libc::AF_UNIX as libc::sa_family_t;
+
+    let bytes = path.as_os_str().as_bytes();
+    // Paths need a NUL at the end, abstract names start with one instead
+    let nul = Address::abstract_name(path).is_none() as usize;
+    if bytes.is_empty() || bytes.len() + nul > raw.sun_path.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Unix socket paths have to be between 1 and 107 bytes",
+        ));
+    }
+    for (dst, src) in raw.sun_path.iter_mut().zip(bytes) {
+        *dst = *src as libc::c_char;
+    }
+
+    let len = mem::size_of::<libc::sa_family_t>() + bytes.len() + nul;
+    Ok((raw, len as libc::socklen_t))
+}
+
+/// Same as `raw_unix_addr` for the std functions
+fn unix_socket_addr(path: &Path) -> io::Result<unix::SocketAddr> {
+    match Address::abstract_name(path) {
+        Some(name) => unix::SocketAddr::from_abstract_name(name),
+        None => unix::SocketAddr::from_pathname(path),
+    }
+}
+
+/// The other way around, for the addresses we get from `accept` and
+/// `recv_from`. Sockets which aren't bound to anything have an empty path.
+pub(crate) fn unix_address(addr: &unix::SocketAddr) -> Address {
+    let path = match (addr.as_pathname(), addr.as_abstract_name()) {
+        (Some(path), _) => path.to_path_buf(),
+        (None, Some(name)) => PathBuf::from(std::ffi::OsStr::from_bytes(&[b"\0", name].concat())),
+        (None, None) => PathBuf::new(),
+    };
+    Address::Unix(path)
+}
+
+/// Options which are set with `setsockopt`
+enum SocketOption {
+    NoDelay(bool),
+    KeepAlive(bool, u64),
+    Linger(Option<Duration>),
+}
+
+impl SocketOption {
+    /// These are all about TCP, Unix sockets ignore them like they do in Node
+    fn apply(&self, stream: &Transport) -> io::Result<()> {
+        let stream = match stream.tcp() {
+            Some(stream) => stream,
+            None => return Ok(()),
+        };
+        match *self {
+            SocketOption::NoDelay(no_delay) => stream.set_nodelay(no_delay),
+            SocketOption::KeepAlive(enable, initial_delay_ms) => {
+                setsockopt(
+                    stream,
+                    libc::SOL_SOCKET,
+                    libc::SO_KEEPALIVE,
+                    enable as libc::c_int,
+                )?;
+                if enable && initial_delay_ms > 0 {
+                    // The kernel counts in seconds
+                    let secs = (initial_delay_ms / 1000).max(1) as libc::c_int;
+                    setsockopt(stream, libc::IPPROTO_TCP, libc::TCP_KEEPIDLE, secs)?;
+                }
+                Ok(())
+            }
+            SocketOption::Linger(linger) => {
+                let value = libc::linger {
+                    l_onoff: linger.is_some() as libc::c_int,
+                    // The kernel counts in seconds, and we'd rather wait a bit
+                    // too long than reset a socket which asked to linger
+                    l_linger: linger.map_or(0, |d| d.as_millis().div_ceil(1000) as libc::c_int),
+                };
+                setsockopt(stream, libc::SOL_SOCKET, libc::SO_LINGER, value)
+            }
+        }
+    }
+}
+
+pub(crate) fn setsockopt<T>(
+    socket: &impl AsRawFd,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: T,
+) -> io::Result<()> {
+    let res = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &value as *const T as *const libc::c_void,
+            mem::size_of::<T>() as libc::socklen_t,
+        )
+    };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    /// Connects to a server which accepts the connection and never sends
+    /// anything. `f` gets the client socket, and should close the server
+    /// when it's done.
+    fn with_silent_server(f: impl Fn(Socket, Server) + 'static) {
+        let f = Rc::new(f);
+        crate::run_test(move || {
+            let accepted = Rc::new(RefCell::new(vec![]));
+            let server = Net::create_server(move |socket| accepted.borrow_mut().push(socket));
+            let s = server.clone();
+            let f = f.clone();
+            server.listen("127.0.0.1:0", move |_| {
+                let socket = Net::connect(s.local_addr().unwrap().to_string());
+                f(socket, s.clone());
+            });
+        });
+    }
+
+    #[test]
+    fn idle_socket_times_out() {
+        let fired = Rc::new(Cell::new(0));
+        let timeouts = Rc::new(Cell::new(0));
+
+        let (f, t) = (fired.clone(), timeouts.clone());
+        with_silent_server(move |socket, server| {
+            let f = f.clone();
+            socket.set_timeout(50, move |_| f.set(f.get() + 1));
+
+            // Writing starts the wait over, and the second timeout only
+            // reaches the permanent listener
+            let (t, s) = (t.clone(), socket.clone());
+            socket.on_timeout(move |_| {
+                t.set(t.get() + 1);
+                if t.get() == 1 {
+                    s.write("still here");
+                } else {
+                    s.destroy();
+                    server.close(|_| ());
+                }
+            });
+        });
+
+        assert_eq!(fired.get(), 1);
+        assert_eq!(timeouts.get(), 2);
+    }
+
+    #[test]
+    fn activity_pushes_the_timeout_back() {
+        let elapsed = Rc::new(Cell::new(None));
+
+        let e = elapsed.clone();
+        with_silent_server(move |socket, server| {
+            let started = Instant::now();
+            let e = e.clone();
+            let s = socket.clone();
+            socket.set_timeout(100, move |_| {
+                e.set(Some(started.elapsed()));
+                s.destroy();
+                server.close(|_| ());
+            });
+
+            let s = socket.clone();
+            crate::set_timeout(60, move |_| {
+                s.write("still here");
+            });
+        });
+
+        let elapsed = elapsed.get().unwrap();
+        assert!(elapsed >= Duration::from_millis(160), "{:?}", elapsed);
+    }
+
+    /// Reads a socket option as a `T`
+    fn getsockopt<T: Default>(socket: &Socket, level: libc::c_int, name: libc::c_int) -> T {
+        let state = socket.inner.borrow();
+        let fd = state.stream.as_ref().unwrap().as_raw_fd();
+        let mut value = T::default();
+        let mut len = mem::size_of::<T>() as libc::socklen_t;
+        let ptr = &mut value as *mut T as *mut libc::c_void;
+        let res = unsafe { libc::getsockopt(fd, level, name, ptr, &mut len) };
+        assert_eq!(res, 0, "{}", io::Error::last_os_error());
+        value
+    }
+
+    #[test]
+    fn socket_options() {
+        let options = Rc::new(RefCell::new(vec![]));
+
+        let o = options.clone();
+        with_silent_server(move |socket, server| {
+            // Set before we're connected, so they're applied once we are
+            socket.set_no_delay(true).unwrap();
+            socket.set_keep_alive(true, 3000).unwrap();
+            // Rounded up to whole seconds
+            socket
+                .set_linger(Some(Duration::from_millis(1500)))
+                .unwrap();
+
+            let (o, s) = (o.clone(), socket.clone());
+            socket.on_connect(move |_| {
+                let mut o = o.borrow_mut();
+                o.push(getsockopt::<libc::c_int>(
+                    &s,
+                    libc::IPPROTO_TCP,
+                    libc::TCP_NODELAY,
+                ));
+                o.push(getsockopt::<libc::c_int>(
+                    &s,
+                    libc::SOL_SOCKET,
+                    libc::SO_KEEPALIVE,
+                ));
+                o.push(getsockopt::<libc::c_int>(
+                    &s,
+                    libc::IPPROTO_TCP,
+                    libc::TCP_KEEPIDLE,
+                ));
+
+                let linger = getsockopt::<[libc::c_int; 2]>(&s, libc::SOL_SOCKET, libc::SO_LINGER);
+                o.extend(linger);
+
+                s.set_linger(None).unwrap();
+                let linger = getsockopt::<[libc::c_int; 2]>(&s, libc::SOL_SOCKET, libc::SO_LINGER);
+                o.push(linger[0]);
+
+                s.destroy();
+                server.close(|_| ());
+            });
+        });
+
+        assert_eq!(*options.borrow(), [1, 1, 3, 1, 2, 0]);
+    }
+
+    #[test]
+    fn set_timeout_zero_removes_listeners() {
+        let removed = Rc::new(Cell::new(false));
+        let fired = Rc::new(Cell::new(false));
+
+        let (r, f) = (removed.clone(), fired.clone());
+        with_silent_server(move |socket, server| {
+            let r = r.clone();
+            socket.set_timeout(50, move |_| r.set(true));
+            socket.set_timeout(0, |_| ());
+
+            let (f, s) = (f.clone(), socket.clone());
+            socket.set_timeout(50, move |_| {
+                f.set(true);
+                s.destroy();
+                server.close(|_| ());
+            });
+        });
+
+        assert!(!removed.get());
+        assert!(fired.get());
+    }
+
+    #[test]
+    fn accepts_concurrent_clients() {
+        const CLIENTS: usize = 5;
+        let replies = Rc::new(RefCell::new(vec![]));
+        let connections = Rc::new(Cell::new(0));
+        let listening = Rc::new(RefCell::new(vec![]));
+
+        let (r, c, l) = (replies.clone(), connections.clone(), listening.clone());
+        crate::run_test(move || {
+            // Echoes everything back
+            let server = Net::create_server(|socket| {
+                let s = socket.clone();
+                socket.on_data(move |data| {
+                    s.write(data.into_bytes().unwrap());
+                });
+            });
+
+            let (s, r, c, l) = (server.clone(), r.clone(), c.clone(), l.clone());
+            server.listen("127.0.0.1:0", move |_| {
+                l.borrow_mut().push(s.is_listening());
+                let addr = s.local_addr().unwrap();
+                let clients = Rc::new(RefCell::new(vec![]));
+                let closed = Rc::new(Cell::new(0));
+
+                for i in 0..CLIENTS {
+                    let socket = Net::connect(addr);
+                    socket.write(format!("client {}", i));
+                    clients.borrow_mut().push(socket.clone());
+
+                    // Nobody ends before every client has its reply, so the
+                    // server has all of them open at the same time
+                    let (r, c, server, clients) =
+                        (r.clone(), c.clone(), s.clone(), clients.clone());
+                    let socket2 = socket.clone();
+                    socket.on_data(move |data| {
+                        let reply = String::from_utf8(data.into_bytes().unwrap()).unwrap();
+                        let peer = socket2.remote_addr().unwrap();
+                        assert_eq!(peer, addr);
+                        assert_ne!(socket2.local_addr().unwrap(), addr);
+                        assert!(!socket2.is_encrypted());
+                        r.borrow_mut().push(reply);
+                        if r.borrow().len() == CLIENTS {
+                            c.set(server.connections());
+                            clients.borrow().iter().for_each(Socket::end);
+                        }
+                    });
+
+                    let (s, l, closed) = (s.clone(), l.clone(), closed.clone());
+                    socket.on_close(move |_| {
+                        closed.set(closed.get() + 1);
+                        if closed.get() == CLIENTS {
+                            let (s2, l) = (s.clone(), l.clone());
+                            s.close(move |_| l.borrow_mut().push(s2.is_listening()));
+                        }
+                    });
+                }
+            });
+        });
+
+        let mut replies = replies.take();
+        replies.sort();
+        let expected: Vec<_> = (0..CLIENTS).map(|i| format!("client {}", i)).collect();
+        assert_eq!(replies, expected);
+        assert_eq!(connections.get(), CLIENTS);
+        assert_eq!(*listening.borrow(), [true, false]);
+    }
+
+    #[test]
+    fn data_arrives_in_chunks_before_end() {
+        let chunks = Rc::new(RefCell::new(vec![]));
+        let events = Rc::new(RefCell::new(vec![]));
+
+        let (c, e) = (chunks.clone(), events.clone());
+        crate::run_test(move || {
+            // Writes one chunk every 10 ms and ends after the third
+            let server = Net::create_server(|socket| {
+                fn write_next(socket: Socket, n: usize) {
+                    socket.write(format!("chunk {};", n));
+                    if n == 3 {
+                        socket.end();
+                        return;
+                    }
+                    crate::set_timeout(10, move |_| write_next(socket, n + 1));
+                }
+                write_next(socket, 1);
+            });
+
+            let (s, c, e) = (server.clone(), c.clone(), e.clone());
+            server.listen("127.0.0.1:0", move |_| {
+                let socket = Net::connect(s.local_addr().unwrap());
+                let c2 = c.clone();
+                socket.on_data(move |data| c2.borrow_mut().push(data.into_bytes().unwrap()));
+
+                let (c, e2) = (c.clone(), e.clone());
+                socket.on_end(move |_| e2.borrow_mut().push(format!("end {}", c.borrow().len())));
+                let (e, s) = (e.clone(), s.clone());
+                socket.on_close(move |_| {
+                    e.borrow_mut().push("close".to_string());
+                    s.close(|_| ());
+                });
+            });
+        });
+
+        let chunks = chunks.borrow();
+        assert_eq!(chunks.concat(), b"chunk 1;chunk 2;chunk 3;");
+        assert!(chunks.len() >= 2, "{:?}", chunks);
+        let end = format!("end {}", chunks.len());
+        assert_eq!(*events.borrow(), [end, "close".to_string()]);
+    }
+
+    #[test]
+    fn write_returns_false_until_drain() {
+        const LEN: usize = 16 * 1024 * 1024;
+        let results = Rc::new(RefCell::new(vec![]));
+        let received = Rc::new(Cell::new(0));
+
+        let (r, rec) = (results.clone(), received.clone());
+        crate::run_test(move || {
+            // Doesn't read anything before the client has had time to fill the
+            // socket buffers
+            let drained = Rc::new(Cell::new(false));
+            let (d, r2, rec) = (drained.clone(), r.clone(), rec.clone());
+            let server = Net::create_server(move |socket| {
+                let (d, r, rec, s) = (d.clone(), r2.clone(), rec.clone(), socket.clone());
+                crate::set_timeout(50, move |_| {
+                    r.borrow_mut()
+                        .push(format!("drained before reading: {}", d.get()));
+                    let rec = rec.clone();
+                    s.on_data(move |data| rec.set(rec.get() + data.into_bytes().unwrap().len()));
+                });
+            });
+
+            let (s, r) = (server.clone(), r.clone());
+            server.listen("127.0.0.1:0", move |_| {
+                let socket = Net::connect(s.local_addr().unwrap());
+                r.borrow_mut()
+                    .push(format!("write: {}", socket.write(vec![0; LEN])));
+
+                let (d, r, sock) = (drained.clone(), r.clone(), socket.clone());
+                socket.on_drain(move |_| {
+                    d.set(true);
+                    r.borrow_mut().push("drain".to_string());
+                    sock.end();
+                });
+                // We only see the server end its side if we read
+                socket.resume();
+                let s = s.clone();
+                socket.on_close(move |_| s.close(|_| ()));
+            });
+        });
+
+        let expected = ["write: false", "drained before reading: false", "drain"];
+        assert_eq!(*results.borrow(), expected);
+        assert_eq!(received.get(), LEN);
+    }
+
+    /// An address nobody listens on
+    fn unused_addr() -> SocketAddr {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap()
+    }
+
+    #[test]
+    fn refused_connection_emits_error_then_close() {
+        let addr = unused_addr();
+        let events = Rc::new(RefCell::new(vec![]));
+
+        let e = events.clone();
+        crate::run_test(move || {
+            let socket = Net::connect(addr);
+            let e2 = e.clone();
+            socket.on_error(move |err| {
+                if let Js::Error(err) = err {
+                    e2.borrow_mut().push(format!("error: {:?}", err.kind()));
+                }
+            });
+            let e2 = e.clone();
+            socket.on_connect(move |_| e2.borrow_mut().push("connect".to_string()));
+            let e = e.clone();
+            socket.on_close(move |_| e.borrow_mut().push("close".to_string()));
+        });
+
+        assert_eq!(*events.borrow(), ["error: ConnectionRefused", "close"]);
+    }
+
+    /// Connects to `addrs` in order with `set_no_delay` set first. With
+    /// `listening` a server which takes the connection is added at the end.
+    /// Returns the address we're connected to and whether the option is set.
+    fn connect_any(addrs: Vec<SocketAddr>, listening: bool) -> io::Result<(SocketAddr, i32)> {
+        let result = Rc::new(RefCell::new(None));
+
+        let r = result.clone();
+        crate::run_test(move || {
+            let server = Net::create_server(|socket| socket.end());
+            let (s, r, addrs) = (server.clone(), r.clone(), addrs.clone());
+            server.listen("127.0.0.1:0", move |_| {
+                let mut addrs: VecDeque<_> = addrs.clone().into();
+                if listening {
+                    addrs.push_back(s.local_addr().unwrap());
+                }
+                let socket = Socket::new();
+                socket.set_no_delay(true).unwrap();
+                socket.connect_any(addrs, Rc::new(|stream| Ok(Transport::Tcp(stream))));
+
+                let (r2, sock) = (r.clone(), socket.clone());
+                socket.on_connect(move |_| {
+                    let no_delay = getsockopt(&sock, libc::IPPROTO_TCP, libc::TCP_NODELAY);
+                    *r2.borrow_mut() = Some(Ok((sock.remote_addr().unwrap(), no_delay)));
+                    sock.destroy();
+                });
+                let r = r.clone();
+                socket.on_error(move |err| {
+                    if let Js::Error(err) = err {
+                        *r.borrow_mut() = Some(Err(err));
+                    }
+                });
+                let s = s.clone();
+                socket.on_close(move |_| s.close(|_| ()));
+            });
+        });
+
+        let result = result.borrow_mut().take();
+        result.unwrap()
+    }
+
+    #[test]
+    fn connect_tries_the_next_address() {
+        let (addr, no_delay) = connect_any(vec![unused_addr(), unused_addr()], true).unwrap();
+        assert_eq!(addr.ip(), Ipv4Addr::LOCALHOST);
+        // The options are set on the stream which is connected in the end
+        assert_eq!(no_delay, 1);
+    }
+
+    #[test]
+    fn connect_fails_when_every_address_fails() {
+        let err = connect_any(vec![unused_addr(), unused_addr()], false).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+        let err = connect_any(vec![], false).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+
+    /// Starts a server on the Unix socket at `path` which answers every
+    /// connection with `reply(socket, data)`, and connects a client which
+    /// `f` gets. The server is closed once the client is.
+    fn with_unix_server(
+        path: &Path,
+        reply: impl Fn(&Socket, Vec<u8>) + 'static,
+        f: impl Fn(Socket) + 'static,
+    ) {
+        let addr = format!("unix:{}", path.display());
+        let (reply, f) = (Rc::new(reply), Rc::new(f));
+        crate::run_test(move || {
+            let reply = reply.clone();
+            let server = Net::create_server(move |socket| {
+                let (sock, reply) = (socket.clone(), reply.clone());
+                socket.on_data(move |data| reply(&sock, data.into_bytes().unwrap()));
+            });
+            let (s, f, a) = (server.clone(), f.clone(), addr.clone());
+            server.listen(addr.as_str(), move |res| {
+                assert!(matches!(res, Js::Undefined));
+                let socket = Net::connect(a.as_str());
+                let s = s.clone();
+                socket.on_close(move |_| s.close(|_| ()));
+                f(socket);
+            });
+        });
+    }
+
+    #[test]
+    fn unix_round_trip() {
+        let path = crate::temp_path("echo.sock");
+        let received = Rc::new(RefCell::new(vec![]));
+
+        let (r, p) = (received.clone(), path.clone());
+        with_unix_server(
+            &path,
+            |socket, data| {
+                socket.write(data);
+                socket.end();
+            },
+            move |socket| {
+                assert!(p.exists());
+                assert_eq!(socket.remote_addr(), None);
+                let r = r.clone();
+                socket.on_data(move |data| r.borrow_mut().extend(data.into_bytes().unwrap()));
+                socket.write("hello");
+            },
+        );
+
+        assert_eq!(&received.borrow()[..], b"hello");
+        // The socket file goes away with the server
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn unix_address() {
+        let path = crate::temp_path("address.sock");
+        let address = Rc::new(RefCell::new(None));
+
+        let (a, p) = (address.clone(), path.clone());
+        crate::run_test(move || {
+            let server = Net::create_server(|_| ());
+            let (a, s) = (a.clone(), server.clone());
+            server.listen(p.as_path(), move |_| {
+                *a.borrow_mut() = Some((s.address(), s.local_addr()));
+                s.close(|_| ());
+            });
+        });
+
+        let address = address.borrow_mut().take().unwrap();
+        assert_eq!(address, (Some(Address::Unix(path.clone())), None));
+        assert_eq!(
+            address.0.unwrap().to_string(),
+            format!("unix:{}", path.display())
+        );
+    }
+
+    #[test]
+    fn stale_socket_file_is_replaced() {
+        let path = crate::temp_path("stale.sock");
+        // Dropping the listener leaves the socket file behind
+        drop(UnixListener::bind(&path).unwrap());
+        assert!(is_stale_socket(&path));
+
+        let result = Rc::new(RefCell::new(None));
+        let (r, p) = (result.clone(), path.clone());
+        crate::run_test(move || {
+            let server = Net::create_server(|_| ());
+            let (r, s) = (r.clone(), server.clone());
+            server.listen(p.as_path(), move |res| {
+                *r.borrow_mut() = Some(matches!(res, Js::Undefined));
+                s.close(|_| ());
+            });
+        });
+
+        assert_eq!(*result.borrow(), Some(true));
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn live_socket_file_is_kept() {
+        let path = crate::temp_path("live.sock");
+        let listener = UnixListener::bind(&path).unwrap();
+        assert!(!is_stale_socket(&path));
+        let other = crate::temp_path("not_a_socket");
+        fs::write(&other, "data").unwrap();
+        assert!(!is_stale_socket(&other));
+
+        let errors = Rc::new(RefCell::new(vec![]));
+        let (e, paths) = (errors.clone(), vec![path.clone(), other.clone()]);
+        crate::run_test(move || {
+            for path in &paths {
+                let e = e.clone();
+                Net::create_server(|_| ()).listen(path.as_path(), move |res| match res {
+                    Js::Error(err) => e.borrow_mut().push(err.kind()),
+                    _ => panic!("listening on a file in use"),
+                });
+            }
+        });
+
+        let kinds = [io::ErrorKind::AddrInUse, io::ErrorKind::AddrInUse];
+        assert_eq!(&errors.borrow()[..], &kinds[..]);
+        // The other listener still works
+        UnixStream::connect(&path).unwrap();
+        listener.accept().unwrap();
+        assert_eq!(fs::read_to_string(&other).unwrap(), "data");
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&other).unwrap();
+    }
+
+    #[test]
+    fn pass_fds() {
+        let path = crate::temp_path("fds.sock");
+        let file = crate::temp_path("fds.txt");
+        fs::write(&file, "secret").unwrap();
+        let received = Rc::new(RefCell::new(vec![]));
+
+        let (r, f) = (received.clone(), file.clone());
+        with_unix_server(
+            &path,
+            |socket, data| {
+                assert_eq!(data, b"x");
+                for fd in socket.take_fds() {
+                    let mut contents = vec![];
+                    fs::File::from(fd).read_to_end(&mut contents).unwrap();
+                    socket.write(contents);
+                }
+                socket.end();
+            },
+            move |socket| {
+                let r = r.clone();
+                socket.on_data(move |data| r.borrow_mut().extend(data.into_bytes().unwrap()));
+                let (sock, f) = (socket.clone(), f.clone());
+                socket.on_connect(move |_| {
+                    let err = sock.write_fds("", vec![]).unwrap_err();
+                    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+                    let fds = vec![fs::File::open(&f).unwrap().into()];
+                    sock.write_fds("x", fds).unwrap();
+                    assert!(sock.take_fds().is_empty());
+                });
+            },
+        );
+
+        assert_eq!(&received.borrow()[..], b"secret");
+        fs::remove_file(&file).unwrap();
+    }
+
+    #[test]
+    fn fds_need_a_unix_socket() {
+        let result = Rc::new(RefCell::new(None));
+        let r = result.clone();
+        with_silent_server(move |socket, server| {
+            let (sock, r) = (socket.clone(), r.clone());
+            socket.on_connect(move |_| {
+                let fds = vec![fs::File::open("/dev/null").unwrap().into()];
+                *r.borrow_mut() = Some(sock.write_fds("x", fds).map_err(|e| e.kind()));
+                sock.destroy();
+            });
+            socket.on_close(move |_| server.close(|_| ()));
+        });
+        assert_eq!(*result.borrow(), Some(Err(io::ErrorKind::InvalidInput)));
+    }
+}
@@ -0,0 +1,440 @@
+// ===== EPOLL =====
+// This is a small epoll wrapper with the same shape as `minimio` which we used
+// before. The difference is that we can register any file descriptor, not only
+// TCP streams, which we need for things like inotify, listening sockets and
+// signals. It also lets us register interest in a socket becoming writable,
+// re-arm a registration and remove it again.
+//
+// Just like in `minimio` all registrations are `EPOLLONESHOT`, so every
+// registration results in at most one event. To get another one we need to
+// call `reregister`.
+
+use std::io;
+use std::ops::BitOr;
+use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// The token we use for the eventfd which wakes up the poll thread, either to
+/// close the loop or to make it start over with a new timeout
+const WAKE_TOKEN: usize = usize::MAX;
+
+pub type Events = Vec<Event>;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Interests(u32);
+
+impl Interests {
+    pub const READABLE: Interests = Interests(libc::EPOLLIN as u32);
+    pub const WRITABLE: Interests = Interests(libc::EPOLLOUT as u32);
+}
+
+impl BitOr for Interests {
+    type Output = Interests;
+
+    fn bitor(self, other: Interests) -> Interests {
+        Interests(self.0 | other.0)
+    }
+}
+
+/// `libc::epoll_event` is `#[repr(packed)]` on x86_64, so we copy the fields
+/// out instead of handing out references to them
+#[derive(Clone, Copy)]
+#[repr(transparent)]
+pub struct Event(libc::epoll_event);
+
+impl Event {
+    pub fn id(&self) -> usize {
+        self.0.u64 as usize
+    }
+}
+
+pub struct Poll {
+    fd: RawFd,
+    wake_fd: RawFd,
+    is_poll_dead: Arc<AtomicBool>,
+}
+
+impl Poll {
+    pub fn new() -> io::Result<Poll> {
+        let fd = syscall(unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) })?;
+        let wake_fd = syscall(unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) })?;
+
+        let mut event = libc::epoll_event {
+            events: libc::EPOLLIN as u32,
+            u64: WAKE_TOKEN as u64,
+        };
+        syscall(unsafe { libc::epoll_ctl(fd, libc::EPOLL_CTL_ADD, wake_fd, &mut event) })?;
+
+        Ok(Poll {
+            fd,
+            wake_fd,
+            is_poll_dead: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    pub fn registrator(&self) -> Registrator {
+        Registrator {
+            fd: self.fd,
+            wake_fd: self.wake_fd,
+            is_poll_dead: self.is_poll_dead.clone(),
+        }
+    }
+
+    /// Blocks until at least one event is ready, the timeout expires or we're
+    /// woken up by `Registrator::wake`. None means we wait forever. Returns an
+    /// error of kind `Interrupted` once the loop is closed by
+    /// `Registrator::close_loop`.
+    pub fn poll(&mut self, events: &mut Events, timeout_ms: Option<i32>) -> io::Result<usize> {
+        let timeout = timeout_ms.unwrap_or(-1);
+        events.clear();
+
+        let res = unsafe {
+            libc::epoll_wait(
+                self.fd,
+                events.as_mut_ptr() as *mut libc::epoll_event,
+                events.capacity() as i32,
+                timeout,
+            )
+        };
+
+        let n = match syscall(res) {
+            Ok(n) => n as usize,
+            // A signal interrupted the wait, which is not the same as closing the loop
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => 0,
+            Err(e) => return Err(e),
+        };
+
+        // This is safe since `epoll_wait` has initialized `n` events for us
+        unsafe { events.set_len(n) };
+
+        if events.iter().any(|e| e.id() == WAKE_TOKEN) {
+            if self.is_poll_dead.load(Ordering::SeqCst) {
+                return Err(io::Error::new(io::ErrorKind::Interrupted, "Poll closed."));
+            }
+
+            // Reset the eventfd so it's not ready anymore
+            let mut val: u64 = 0;
+            unsafe { libc::read(self.wake_fd, &mut val as *mut u64 as *mut libc::c_void, 8) };
+            events.retain(|e| e.id() != WAKE_TOKEN);
+        }
+
+        Ok(events.len())
+    }
+}
+
+impl Drop for Poll {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.wake_fd);
+            libc::close(self.fd);
+        }
+    }
+}
+
+/// Registers interest in events on the `Poll` instance it was created from. It
+/// can be sent to other threads.
+pub struct Registrator {
+    fd: RawFd,
+    wake_fd: RawFd,
+    is_poll_dead: Arc<AtomicBool>,
+}
+
+impl Registrator {
+    /// Registers interest in `source` becoming ready. We'll get one event with
+    /// `token` as id.
+    pub fn register(&self, source: RawFd, token: usize, interests: Interests) -> io::Result<()> {
+        self.ctl(libc::EPOLL_CTL_ADD, source, token, interests)
+    }
+
+    /// Re-arms a registration once its event has fired. Can also be used to
+    /// change the token or the interests of a registration.
+    pub fn reregister(&self, source: RawFd, token: usize, interests: Interests) -> io::Result<()> {
+        self.ctl(libc::EPOLL_CTL_MOD, source, token, interests)
+    }
+
+    /// Removes `source` from the epoll instance. Closing a file descriptor
+    /// removes it as well, so this is only needed if we want to keep using it.
+    pub fn deregister(&self, source: RawFd) -> io::Result<()> {
+        self.check_alive()?;
+        let res =
+            unsafe { libc::epoll_ctl(self.fd, libc::EPOLL_CTL_DEL, source, std::ptr::null_mut()) };
+        syscall(res).map(|_| ())
+    }
+
+    /// Makes the thread blocked in `Poll::poll` return early. If the call
+    /// happens before it starts waiting, the next call to `poll` returns
+    /// immediately instead.
+    pub fn wake(&self) -> io::Result<()> {
+        self.check_alive()?;
+        self.write_wake_fd()
+    }
+
+    /// Wakes up the thread blocked in `Poll::poll` and makes it return an
+    /// error of kind `Interrupted`
+    pub fn close_loop(&self) -> io::Result<()> {
+        if self.is_poll_dead.swap(true, Ordering::SeqCst) {
+            return Err(io::Error::new(
+                io::ErrorKind::Interrupted,
+                "Poll instance closed.",
+            ));
+        }
+
+        self.write_wake_fd()
+    }
+
+    fn write_wake_fd(&self) -> io::Result<()> {
+        let val: u64 = 1;
+        let res =
+            unsafe { libc::write(self.wake_fd, &val as *const u64 as *const libc::c_void, 8) };
+        syscall(res as i32).map(|_| ())
+    }
+
+    fn ctl(&self, op: i32, source: RawFd, token: usize, interests: Interests) -> io::Result<()> {
+        self.check_alive()?;
+
+        let mut event = libc::epoll_event {
+            events: interests.0 | libc::EPOLLONESHOT as u32,
+            u64: token as u64,
+        };
+        syscall(unsafe { libc::epoll_ctl(self.fd, op, source, &mut event) }).map(|_| ())
+    }
+
+    fn check_alive(&self) -> io::Result<()> {
+        if self.is_poll_dead.load(Ordering::SeqCst) {
+            return Err(io::Error::new(
+                io::ErrorKind::Interrupted,
+                "Poll instance closed.",
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn syscall(res: i32) -> io::Result<i32> {
+    if res < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(res)
+    }
+}
+
+// ===== REGISTRATIONS =====
+
+/// Connects a file descriptor to the event loop. Since registrations are
+/// one-shot, the owner of the fd calls `arm` every time it wants another event
+/// and `fired` first thing in the callback. While it's armed, the registration
+/// keeps the event loop alive unless `set_ref(false)` is used.
+pub(crate) struct Registration {
+    fd: RawFd,
+    token: usize,
+    /// The fd is in the epoll set
+    added: bool,
+    /// The fd is in the epoll set and we have a callback waiting for the event
+    armed: bool,
+    /// Waiting for the event keeps the event loop alive
+    refed: bool,
+}
+
+impl Registration {
+    pub(crate) fn new(fd: RawFd) -> Self {
+        let rt = unsafe { &mut *crate::RUNTIME };
+        Registration {
+            fd,
+            token: rt.generate_cb_identity(),
+            added: false,
+            armed: false,
+            refed: true,
+        }
+    }
+
+    /// Waits for `interests`. If we're already armed the interests are updated
+    /// but the callback registered before stays and `cb` is dropped.
+    pub(crate) fn arm(
+        &mut self,
+        interests: Interests,
+        cb: impl FnOnce(crate::Js) + 'static,
+    ) -> io::Result<()> {
+        let rt = unsafe { &mut *crate::RUNTIME };
+        if self.added {
+            rt.epoll_registrator
+                .reregister(self.fd, self.token, interests)?;
+        } else {
+            rt.epoll_registrator
+                .register(self.fd, self.token, interests)?;
+            self.added = true;
+        }
+
+        if !self.armed {
+            rt.register_event_epoll(self.token, cb);
+            self.armed = true;
+            if !self.refed {
+                rt.set_ref(self.token, false);
+            }
+        }
+        Ok(())
+    }
+
+    /// Decides if waiting for the event keeps the event loop alive
+    pub(crate) fn set_ref(&mut self, refed: bool) {
+        self.refed = refed;
+        if self.armed {
+            crate::set_event_ref(self.token, refed);
+        }
+    }
+
+    /// Must be called when the event has fired
+    pub(crate) fn fired(&mut self) {
+        self.armed = false;
+    }
+
+    /// Cancels the callback and removes the fd from the epoll set. This must
+    /// happen before the fd is closed, since a new fd could get the same number.
+    pub(crate) fn disarm(&mut self) {
+        let rt = unsafe { &mut *crate::RUNTIME };
+        if self.armed {
+            rt.deregister_event_epoll(self.token);
+            self.armed = false;
+        }
+        if self.added {
+            let _ = rt.epoll_registrator.deregister(self.fd);
+            self.added = false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::{Cell, RefCell};
+    use std::io::{Read, Write};
+    use std::os::unix::io::AsRawFd;
+    use std::os::unix::net::UnixStream;
+    use std::rc::Rc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    /// Reads what's in `stream` and arms `reg` again until we've had `left`
+    /// events
+    fn read_events(
+        reg: Rc<RefCell<Registration>>,
+        mut stream: UnixStream,
+        mut writer: UnixStream,
+        count: Rc<Cell<usize>>,
+        left: usize,
+    ) {
+        let r = reg.clone();
+        let res = reg.borrow_mut().arm(Interests::READABLE, move |_| {
+            r.borrow_mut().fired();
+            stream.read_exact(&mut [0; 1]).unwrap();
+            count.set(count.get() + 1);
+            if left > 1 {
+                writer.write_all(b"x").unwrap();
+                read_events(r, stream, writer, count, left - 1);
+            } else {
+                r.borrow_mut().disarm();
+            }
+        });
+        res.unwrap();
+    }
+
+    #[test]
+    fn rearm_after_fired() {
+        let count = Rc::new(Cell::new(0));
+
+        let c = count.clone();
+        crate::run_test(move || {
+            let (reader, mut writer) = UnixStream::pair().unwrap();
+            let reg = Rc::new(RefCell::new(Registration::new(reader.as_raw_fd())));
+            writer.write_all(b"x").unwrap();
+            read_events(reg, reader, writer, c.clone(), 3);
+        });
+
+        assert_eq!(count.get(), 3);
+    }
+
+    #[test]
+    fn disarm_cancels_the_callback() {
+        let count = Rc::new(Cell::new(0));
+
+        let c = count.clone();
+        crate::run_test(move || {
+            let (reader, mut writer) = UnixStream::pair().unwrap();
+            let mut reg = Registration::new(reader.as_raw_fd());
+            let c = c.clone();
+            reg.arm(Interests::READABLE, move |_| c.set(c.get() + 1))
+                .unwrap();
+            writer.write_all(b"x").unwrap();
+            reg.disarm();
+        });
+
+        assert_eq!(count.get(), 0);
+    }
+
+    /// Arms a registration for a socket which a thread writes to after 50 ms
+    fn wait_for_thread(refed: bool) -> usize {
+        let count = Rc::new(Cell::new(0));
+        let (reader, mut writer) = UnixStream::pair().unwrap();
+        let handle = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            writer.write_all(b"x").unwrap();
+        });
+
+        let c = count.clone();
+        let fd = reader.as_raw_fd();
+        crate::run_test(move || {
+            let reg = Rc::new(RefCell::new(Registration::new(fd)));
+            reg.borrow_mut().set_ref(refed);
+            let (r, c) = (reg.clone(), c.clone());
+            let res = reg.borrow_mut().arm(Interests::READABLE, move |_| {
+                r.borrow_mut().fired();
+                c.set(c.get() + 1);
+            });
+            res.unwrap();
+        });
+
+        handle.join().unwrap();
+        count.get()
+    }
+
+    #[test]
+    fn refed_registration_keeps_the_loop_alive() {
+        assert_eq!(wait_for_thread(true), 1);
+    }
+
+    #[test]
+    fn unrefed_registration_lets_the_loop_exit() {
+        assert_eq!(wait_for_thread(false), 0);
+    }
+
+    #[test]
+    fn new_timer_wakes_up_the_poll_thread() {
+        let elapsed = Rc::new(Cell::new(None));
+
+        let e = elapsed.clone();
+        crate::run_test(move || {
+            // Keeps the epoll thread waiting without a timeout
+            let (reader, writer) = UnixStream::pair().unwrap();
+            let reg = Rc::new(RefCell::new(Registration::new(reader.as_raw_fd())));
+            reg.borrow_mut()
+                .arm(Interests::READABLE, |_| unreachable!())
+                .unwrap();
+
+            // The timer is set from a callback while the epoll thread is
+            // already waiting
+            let e = e.clone();
+            crate::Fs::stat(".", move |_| {
+                let started = Instant::now();
+                crate::set_timeout(10, move |_| {
+                    e.set(Some(started.elapsed()));
+                    reg.borrow_mut().disarm();
+                    drop((reader, writer));
+                });
+            });
+        });
+
+        let elapsed = elapsed.get().unwrap();
+        assert!(elapsed >= Duration::from_millis(10));
+        assert!(elapsed < Duration::from_secs(1), "{:?}", elapsed);
+    }
+}
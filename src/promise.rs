@@ -0,0 +1,92 @@
+// ===== PROMISES =====
+// A `Promise` is a future which is resolved by a callback running on the event
+// loop thread. It lets us wrap any of our callback based APIs so they can be
+// used from async code spawned with `spawn`. Just like in javascript the work
+// starts when the promise is created, not when it's first awaited.
+
+use std::cell::RefCell;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+use crate::{Js, ThreadPoolTaskKind, RUNTIME};
+
+pub struct Promise<T> {
+    state: Rc<RefCell<PromiseState<T>>>,
+}
+
+struct PromiseState<T> {
+    value: Option<T>,
+    waker: Option<Waker>,
+}
+
+impl<T: 'static> Promise<T> {
+    /// Same as `new Promise(resolve => ...)` in javascript. `f` gets a function
+    /// which resolves the promise, which is typically passed on as the callback
+    /// to one of our callback based APIs.
+    pub fn new(f: impl FnOnce(Box<dyn FnOnce(T)>)) -> Self {
+        let state = Rc::new(RefCell::new(PromiseState {
+            value: None,
+            waker: None,
+        }));
+
+        let resolve_state = state.clone();
+        f(Box::new(move |value| {
+            let mut state = resolve_state.borrow_mut();
+            state.value = Some(value);
+            if let Some(waker) = state.waker.take() {
+                waker.wake();
+            }
+        }));
+
+        Promise { state }
+    }
+
+    /// A promise which is already resolved
+    pub fn resolve(value: T) -> Self {
+        Promise::new(|resolve| resolve(value))
+    }
+}
+
+impl<T: Send + 'static> Promise<T> {
+    /// Runs `work` on the threadpool and resolves to its result. The result
+    /// doesn't need to be a `Js` value since we hand it over through a shared
+    /// slot instead of our event channel.
+    pub fn from_threadpool(
+        kind: ThreadPoolTaskKind,
+        work: impl FnOnce() -> T + Send + 'static,
+    ) -> Self {
+        let slot = Arc::new(Mutex::new(None));
+        let task_slot = slot.clone();
+
+        let task = move || {
+            *task_slot.lock().unwrap() = Some(work());
+            Js::Undefined
+        };
+
+        Promise::new(move |resolve| {
+            let rt = unsafe { &mut *RUNTIME };
+            rt.register_event_threadpool(task, kind, move |_| {
+                let value = slot.lock().unwrap().take().expect("threadpool task result");
+                resolve(value);
+            });
+        })
+    }
+}
+
+impl<T> Future for Promise<T> {
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<T> {
+        let mut state = self.state.borrow_mut();
+        match state.value.take() {
+            Some(value) => Poll::Ready(value),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
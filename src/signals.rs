@@ -0,0 +1,417 @@
+// ===== SIGNALS =====
+// Handling signals, like `process.on("SIGINT", ...)` in Node. A handler
+// installed with `sigaction` runs in the middle of whatever the thread happens
+// to be doing, where it can't safely touch any of our state. So instead of
+// installing one we block the signal, which leaves it pending, and read it from
+// a signalfd registered with our epoll instance. Our handlers then run as
+// normal callbacks on the main thread.
+//
+//     Signals::on(libc::SIGINT, |_| print("Got Ctrl-C, not stopping"))?;
+//
+// Like in Node, handlers don't keep the event loop alive: a program which does
+// nothing but wait for a signal exits right away. Use `SignalHandler::ref_` for
+// a handler which should keep it alive. Once the last handler of a signal is
+// removed, the signal is unblocked and gets its default behaviour back, which
+// for most signals means the process is killed.
+//
+// Our other threads have all signals blocked, see `Runtime::new`, so a signal
+// sent to the process always ends up with the main thread.
+//
+// A signal sent to the main thread alone, like one from `raise`, doesn't make
+// the signalfd readable for the epoll thread, which only sees signals pending
+// for the whole process or for itself. The loop checks for those with
+// `run_raised` before it waits for events.
+
+use std::cell::RefCell;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::io;
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+use std::ptr;
+
+use crate::poll::{Interests, Registration};
+use crate::{print, RUNTIME};
+
+type SignalCallback = Box<dyn FnMut(i32)>;
+
+thread_local! {
+    /// A watcher for every signal which has handlers
+    static WATCHERS: RefCell<HashMap<i32, Watcher>> = RefCell::new(HashMap::new());
+}
+
+pub struct Signals;
+
+impl Signals {
+    /// Calls `cb` with the signal every time the process gets `signal`, e.g.
+    /// `libc::SIGINT`. SIGKILL and SIGSTOP can't be handled.
+    pub fn on(signal: i32, cb: impl FnMut(i32) + 'static) -> io::Result<SignalHandler> {
+        if signal == libc::SIGKILL || signal == libc::SIGSTOP {
+            let msg = format!("signal {} can't be handled", signal);
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        }
+
+        let rt = unsafe { &mut *RUNTIME };
+        let handler = Handler {
+            id: rt.generate_cb_identity(),
+            refed: false,
+            cb: Some(Box::new(cb)),
+        };
+        let id = handler.id;
+
+        WATCHERS.with(|watchers| {
+            let mut watchers = watchers.borrow_mut();
+            let watcher = match watchers.entry(signal) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => entry.insert(Watcher::new(signal)?),
+            };
+            watcher.handlers.push(handler);
+            watcher.update_ref();
+            Ok::<_, io::Error>(())
+        })?;
+
+        Ok(SignalHandler { signal, id })
+    }
+}
+
+/// A handle to a handler added with `Signals::on`
+#[derive(Debug, Clone)]
+pub struct SignalHandler {
+    signal: i32,
+    id: usize,
+}
+
+impl SignalHandler {
+    pub fn signal(&self) -> i32 {
+        self.signal
+    }
+
+    /// Removes the handler. If it was the last one for its signal, the
+    /// signal gets its default behaviour back.
+    pub fn remove(&self) {
+        let watcher = WATCHERS.with(|watchers| {
+            let mut watchers = watchers.borrow_mut();
+            let watcher = watchers.get_mut(&self.signal)?;
+            watcher.handlers.retain(|h| h.id != self.id);
+            if watcher.handlers.is_empty() {
+                return watchers.remove(&self.signal);
+            }
+            watcher.update_ref();
+            None
+        });
+
+        if let Some(watcher) = watcher {
+            watcher.close();
+        }
+    }
+
+    /// Makes the handler keep the event loop alive
+    pub fn ref_(&self) {
+        self.set_ref(true);
+    }
+
+    /// Undoes `ref_`, which is how handlers start out
+    pub fn unref(&self) {
+        self.set_ref(false);
+    }
+
+    fn set_ref(&self, refed: bool) {
+        WATCHERS.with(|watchers| {
+            if let Some(watcher) = watchers.borrow_mut().get_mut(&self.signal) {
+                if let Some(handler) = watcher.handlers.iter_mut().find(|h| h.id == self.id) {
+                    handler.refed = refed;
+                }
+                watcher.update_ref();
+            }
+        });
+    }
+}
+
+struct Handler {
+    id: usize,
+    refed: bool,
+    /// `None` while it's running
+    cb: Option<SignalCallback>,
+}
+
+/// The signalfd for one signal, and the handlers of that signal
+struct Watcher {
+    signal: i32,
+    fd: OwnedFd,
+    reg: Registration,
+    handlers: Vec<Handler>,
+}
+
+impl Watcher {
+    fn new(signal: i32) -> io::Result<Watcher> {
+        let mask = signal_set(signal)?;
+
+        // The signal has to be blocked, or it's handled the default way
+        // before the signalfd ever sees it
+        let res = unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &mask, ptr::null_mut()) };
+        if res != 0 {
+            return Err(io::Error::from_raw_os_error(res));
+        }
+        let fd = unsafe { libc::signalfd(-1, &mask, libc::SFD_NONBLOCK | libc::SFD_CLOEXEC) };
+        if fd < 0 {
+            let e = io::Error::last_os_error();
+            unsafe { libc::pthread_sigmask(libc::SIG_UNBLOCK, &mask, ptr::null_mut()) };
+            return Err(e);
+        }
+
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        let mut watcher = Watcher {
+            signal,
+            reg: Registration::new(fd.as_raw_fd()),
+            fd,
+            handlers: vec![],
+        };
+        watcher.update_ref();
+        watcher.arm()?;
+        Ok(watcher)
+    }
+
+    fn arm(&mut self) -> io::Result<()> {
+        let signal = self.signal;
+        self.reg
+            .arm(Interests::READABLE, move |_| Watcher::on_ready(signal))
+    }
+
+    /// The watcher only keeps the event loop alive if one of its handlers
+    /// wants to
+    fn update_ref(&mut self) {
+        let refed = self.handlers.iter().any(|h| h.refed);
+        self.reg.set_ref(refed);
+    }
+
+    /// Reads how many times we got the signal. The same signal can arrive
+    /// several times before we read it, but then it's counted only once.
+    fn read_signals(&self) -> usize {
+        let mut info: libc::signalfd_siginfo = unsafe { mem::zeroed() };
+        let size = mem::size_of::<libc::signalfd_siginfo>();
+        let buf = &mut info as *mut libc::signalfd_siginfo as *mut libc::c_void;
+
+        let mut count = 0;
+        while unsafe { libc::read(self.fd.as_raw_fd(), buf, size) } == size as isize {
+            count += 1;
+        }
+        count
+    }
+
+    fn on_ready(signal: i32) {
+        WATCHERS.with(|watchers| {
+            if let Some(watcher) = watchers.borrow_mut().get_mut(&signal) {
+                watcher.reg.fired();
+            }
+        });
+        Watcher::run_handlers(signal);
+    }
+
+    /// Calls the handlers once for every time we got the signal, and waits
+    /// for the next one
+    fn run_handlers(signal: i32) {
+        let taken = WATCHERS.with(|watchers| {
+            let mut watchers = watchers.borrow_mut();
+            let watcher = watchers.get_mut(&signal)?;
+
+            let count = watcher.read_signals();
+            let callbacks: Vec<(usize, SignalCallback)> = watcher
+                .handlers
+                .iter_mut()
+                .filter_map(|h| Some((h.id, h.cb.take()?)))
+                .collect();
+            Some((count, callbacks))
+        });
+        let (count, mut callbacks) = match taken {
+            Some(taken) => taken,
+            None => return,
+        };
+
+        for _ in 0..count {
+            for (_, cb) in callbacks.iter_mut() {
+                cb(signal);
+            }
+        }
+
+        // The handlers are free to remove themselves or each other, so we
+        // only put back the ones which are still there
+        let failed = WATCHERS.with(|watchers| {
+            let mut watchers = watchers.borrow_mut();
+            let watcher = watchers.get_mut(&signal)?;
+            for (id, cb) in callbacks.drain(..) {
+                if let Some(handler) = watcher.handlers.iter_mut().find(|h| h.id == id) {
+                    handler.cb = Some(cb);
+                }
+            }
+            let e = watcher.arm().err()?;
+            Some((watchers.remove(&signal)?, e))
+        });
+
+        // Without the signalfd we'd never hear about the signal again, so we
+        // let it do what it does by default instead
+        if let Some((watcher, e)) = failed {
+            watcher.close();
+            print(format!("Stopped handling signal {}: {}", signal, e));
+        }
+    }
+
+    /// Lets the signal do what it does by default again. Anything still
+    /// pending is read first, so it doesn't get delivered the moment we
+    /// unblock the signal.
+    fn close(mut self) {
+        self.reg.disarm();
+        self.read_signals();
+        if let Ok(mask) = signal_set(self.signal) {
+            unsafe { libc::pthread_sigmask(libc::SIG_UNBLOCK, &mask, ptr::null_mut()) };
+        }
+    }
+}
+
+/// Runs the handlers of signals which are pending for the main thread alone,
+/// see the top of this file
+pub(crate) fn run_raised() {
+    let signals: Vec<i32> = WATCHERS.with(|watchers| watchers.borrow().keys().copied().collect());
+    if signals.is_empty() {
+        return;
+    }
+
+    let mut pending: libc::sigset_t = unsafe { mem::zeroed() };
+    if unsafe { libc::sigpending(&mut pending) } < 0 {
+        return;
+    }
+    for signal in signals {
+        if unsafe { libc::sigismember(&pending, signal) } == 1 {
+            Watcher::run_handlers(signal);
+        }
+    }
+}
+
+fn signal_set(signal: i32) -> io::Result<libc::sigset_t> {
+    let mut set: libc::sigset_t = unsafe { mem::zeroed() };
+    unsafe { libc::sigemptyset(&mut set) };
+    if unsafe { libc::sigaddset(&mut set, signal) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(set)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    fn is_blocked(signal: i32) -> bool {
+        let mut mask: libc::sigset_t = unsafe { mem::zeroed() };
+        unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, ptr::null(), &mut mask) };
+        unsafe { libc::sigismember(&mask, signal) == 1 }
+    }
+
+    fn is_pending(signal: i32) -> bool {
+        let mut set: libc::sigset_t = unsafe { mem::zeroed() };
+        unsafe { libc::sigpending(&mut set) };
+        unsafe { libc::sigismember(&set, signal) == 1 }
+    }
+
+    fn has_default_action(signal: i32) -> bool {
+        let mut action: libc::sigaction = unsafe { mem::zeroed() };
+        unsafe { libc::sigaction(signal, ptr::null(), &mut action) };
+        action.sa_sigaction == libc::SIG_DFL
+    }
+
+    #[test]
+    fn raise_runs_the_handlers_on_the_loop() {
+        let calls = Rc::new(RefCell::new(vec![]));
+        let c = calls.clone();
+        crate::run_test(move || {
+            let raised = Rc::new(Cell::new(false));
+            let handlers = Rc::new(RefCell::new(vec![]));
+            for &name in &["first", "second"] {
+                let (c, r, h) = (c.clone(), raised.clone(), handlers.clone());
+                let handler = Signals::on(libc::SIGUSR1, move |signal| {
+                    // Not in the middle of `raise`
+                    assert!(r.get());
+                    c.borrow_mut().push((name, signal));
+                    for handler in h.borrow().iter() {
+                        SignalHandler::remove(handler);
+                    }
+                })
+                .unwrap();
+                handlers.borrow_mut().push(handler);
+            }
+            // Keeps the loop alive until the signal arrives
+            handlers.borrow()[0].ref_();
+
+            let r = raised.clone();
+            crate::set_timeout(10, move |_| {
+                unsafe { libc::raise(libc::SIGUSR1) };
+                r.set(true);
+            });
+        });
+
+        // The second handler is removed by the first, but it runs for the
+        // signal which arrived while it was still there
+        let expected = [("first", libc::SIGUSR1), ("second", libc::SIGUSR1)];
+        assert_eq!(&calls.borrow()[..], &expected[..]);
+    }
+
+    #[test]
+    fn unrefed_handler_lets_run_return() {
+        let called = Rc::new(Cell::new(false));
+        let removed = Rc::new(Cell::new(false));
+        let (c, r) = (called.clone(), removed.clone());
+        crate::run_test(move || {
+            let c = c.clone();
+            let handler = Signals::on(libc::SIGUSR2, move |_| c.set(true)).unwrap();
+            handler.ref_();
+            handler.unref();
+            assert_eq!(handler.signal(), libc::SIGUSR2);
+
+            // Only runs once nothing keeps the loop alive anymore
+            let r = r.clone();
+            let mut handler = Some(handler);
+            crate::on_before_exit(move || {
+                if let Some(handler) = handler.take() {
+                    handler.remove();
+                    r.set(true);
+                }
+            });
+        });
+
+        assert!(removed.get());
+        assert!(!called.get());
+    }
+
+    #[test]
+    fn removing_the_last_handler_restores_the_default() {
+        let states = Rc::new(RefCell::new(vec![]));
+        let s = states.clone();
+        crate::run_test(move || {
+            let state = || {
+                let signal = libc::SIGUSR1;
+                (
+                    is_blocked(signal),
+                    is_pending(signal),
+                    has_default_action(signal),
+                )
+            };
+            let first = Signals::on(libc::SIGUSR1, |_| ()).unwrap();
+            let second = Signals::on(libc::SIGUSR1, |_| ()).unwrap();
+            unsafe { libc::raise(libc::SIGUSR1) };
+            s.borrow_mut().push(state());
+
+            first.remove();
+            s.borrow_mut().push(state());
+            // The pending signal is thrown away instead of killing us
+            second.remove();
+            s.borrow_mut().push(state());
+            second.remove();
+
+            let err = Signals::on(libc::SIGKILL, |_| ()).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        });
+
+        let expected = [(true, true, true), (true, true, true), (false, false, true)];
+        assert_eq!(&states.borrow()[..], &expected[..]);
+    }
+}
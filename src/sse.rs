@@ -0,0 +1,433 @@
+// ===== SERVER-SENT EVENTS =====
+// Server push over a plain HTTP response, like `EventSource` expects it. The
+// response never ends by itself: we keep it open and write an event to it
+// whenever we have something to say. Events are text, one `field: value`
+// per line with an empty line after the event:
+//
+//     id: 42
+//     event: price
+//     data: {"symbol":"ACME","price":12.5}
+//
+// Proxies like to close connections which look idle, so we send a comment
+// line when nothing else has been sent for a while. When the client goes away
+// epoll reports the socket as readable and the read hits end of file, or the
+// next write fails if the connection was reset. Either way `close` is emitted
+// and the keepalive timer is cleared.
+//
+//     let router = Router::new().get(
+//         "/events",
+//         sse::handler(Default::default(), |stream, _req| {
+//             stream.send(sse::Event::new("hello").event("greeting"));
+//         }),
+//     );
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::http_server::{IncomingRequest, ServerResponse};
+use crate::stream::{emit, Listeners};
+use crate::{clear_timeout, set_timeout, Js};
+
+#[derive(Debug, Clone)]
+pub struct SseOptions {
+    /// A comment is sent when nothing has been sent for this long, 0 turns
+    /// keepalives off
+    pub keep_alive_ms: u64,
+    /// Tells the client how long to wait before it reconnects
+    pub retry_ms: Option<u64>,
+}
+
+impl Default for SseOptions {
+    fn default() -> Self {
+        SseOptions {
+            keep_alive_ms: 15_000,
+            retry_ms: None,
+        }
+    }
+}
+
+/// Turns `on_stream` into a handler for `Http::create_server` or a `Router`
+/// route. Every request is answered with an event stream.
+pub fn handler(
+    opts: SseOptions,
+    mut on_stream: impl FnMut(EventStream, IncomingRequest) + 'static,
+) -> impl FnMut(IncomingRequest, ServerResponse) {
+    move |req, res| {
+        let stream = EventStream::new(&req, res, opts.clone());
+        on_stream(stream, req);
+    }
+}
+
+// ===== EVENT =====
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Event {
+    /// The event type, `message` if it's not set
+    pub event: Option<String>,
+    /// Can span several lines
+    pub data: String,
+    /// The client sends the last id it got in `Last-Event-ID` when it
+    /// reconnects
+    pub id: Option<String>,
+    pub retry_ms: Option<u64>,
+}
+
+impl Event {
+    pub fn new(data: impl Into<String>) -> Self {
+        Event {
+            data: data.into(),
+            ..Default::default()
+        }
+    }
+
+    pub fn event(mut self, event: impl Into<String>) -> Self {
+        self.event = Some(event.into());
+        self
+    }
+
+    pub fn id(mut self, id: impl Into<String>) -> Self {
+        self.id = Some(id.into());
+        self
+    }
+
+    pub fn retry(mut self, ms: u64) -> Self {
+        self.retry_ms = Some(ms);
+        self
+    }
+
+    /// The event as it's sent on the wire. A line break in `event` or `id`
+    /// would start a new field, so we leave them out.
+    fn encode(&self) -> String {
+        let mut out = String::new();
+        if let Some(id) = &self.id {
+            out.push_str(&format!("id: {}\n", single_line(id)));
+        }
+        if let Some(event) = &self.event {
+            out.push_str(&format!("event: {}\n", single_line(event)));
+        }
+        if let Some(ms) = self.retry_ms {
+            out.push_str(&format!("retry: {}\n", ms));
+        }
+        // Clients accept all three kinds of line breaks
+        let data = self.data.replace("\r\n", "\n").replace('\r', "\n");
+        for line in data.split('\n') {
+            out.push_str(&format!("data: {}\n", line));
+        }
+        out.push('\n');
+        out
+    }
+}
+
+impl From<&str> for Event {
+    fn from(data: &str) -> Self {
+        Event::new(data)
+    }
+}
+
+impl From<String> for Event {
+    fn from(data: String) -> Self {
+        Event::new(data)
+    }
+}
+
+fn single_line(s: &str) -> String {
+    s.chars()
+        .filter(|c| !matches!(c, '\r' | '\n' | '\0'))
+        .collect()
+}
+
+// ===== EVENT STREAM =====
+
+#[derive(Clone)]
+pub struct EventStream {
+    inner: Rc<RefCell<EventStreamState>>,
+}
+
+struct EventStreamState {
+    res: ServerResponse,
+    last_event_id: Option<String>,
+    keep_alive_ms: u64,
+    keep_alive_timer: Option<usize>,
+    closed: bool,
+    on_close: Listeners,
+}
+
+impl EventStream {
+    /// Sends the head of the response right away, so the client knows the
+    /// stream is open before the first event
+    pub fn new(req: &IncomingRequest, res: ServerResponse, opts: SseOptions) -> Self {
+        res.write_head(
+            200,
+            vec![
+                ("Content-Type", "text/event-stream"),
+                ("Cache-Control", "no-cache"),
+                ("Connection", "keep-alive"),
+                // Keeps nginx from buffering the events
+                ("X-Accel-Buffering", "no"),
+            ],
+        );
+
+        let state = EventStreamState {
+            res: res.clone(),
+            last_event_id: req.headers.get("Last-Event-ID").map(String::from),
+            keep_alive_ms: opts.keep_alive_ms,
+            keep_alive_timer: None,
+            closed: false,
+            on_close: Listeners::default(),
+        };
+        let stream = EventStream {
+            inner: Rc::new(RefCell::new(state)),
+        };
+
+        // The client never sends anything after the request, so end of file
+        // means it's gone and not that it's waiting for the rest of the
+        // response
+        let socket = res.socket();
+        let s = stream.clone();
+        socket.on_end(move |_| s.disconnect(true));
+        let s = stream.clone();
+        socket.on_close(move |_| s.disconnect(false));
+
+        match opts.retry_ms {
+            Some(ms) => stream.write(format!("retry: {}\n\n", ms)),
+            None => stream.write(":\n\n".to_string()),
+        };
+        stream
+    }
+
+    /// The `Last-Event-ID` the client sent if it's reconnecting
+    pub fn last_event_id(&self) -> Option<String> {
+        self.inner.borrow().last_event_id.clone()
+    }
+
+    /// Returns false if the event was buffered because the client can't keep
+    /// up, or if the stream is closed
+    pub fn send(&self, event: impl Into<Event>) -> bool {
+        self.write(event.into().encode())
+    }
+
+    /// Sends a comment, which clients ignore
+    pub fn comment(&self, text: &str) -> bool {
+        let mut out = String::new();
+        for line in text.split('\n') {
+            out.push_str(&format!(": {}\n", line.trim_end_matches('\r')));
+        }
+        out.push('\n');
+        self.write(out)
+    }
+
+    /// Called once when the stream is closed, by us or by the client
+    pub fn on_close(&self, cb: impl FnMut(Js) + 'static) {
+        self.inner.borrow_mut().on_close.add(cb);
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.inner.borrow().closed
+    }
+
+    /// Ends the response. The client will reconnect unless it's told
+    /// otherwise, so to stop it for good answer the reconnect with a status
+    /// other than 200.
+    pub fn close(&self) {
+        let res = self.inner.borrow().res.clone();
+        if self.close_state() {
+            res.end("");
+            emit(&self.inner, |s| &mut s.on_close, Js::Undefined);
+            self.drop_listeners();
+        }
+    }
+
+    fn write(&self, data: String) -> bool {
+        let res = {
+            let state = self.inner.borrow();
+            if state.closed {
+                return false;
+            }
+            state.res.clone()
+        };
+        let ok = res.write(data);
+        self.schedule_keep_alive();
+        ok
+    }
+
+    /// Starts the wait for the next keepalive over
+    fn schedule_keep_alive(&self) {
+        let mut state = self.inner.borrow_mut();
+        if let Some(timer) = state.keep_alive_timer.take() {
+            clear_timeout(timer);
+        }
+        if state.closed || state.keep_alive_ms == 0 {
+            return;
+        }
+
+        let s = self.clone();
+        let timer = set_timeout(state.keep_alive_ms, move |_| {
+            s.inner.borrow_mut().keep_alive_timer = None;
+            s.write(":\n\n".to_string());
+        });
+        state.keep_alive_timer = Some(timer);
+    }
+
+    fn disconnect(&self, hung_up: bool) {
+        let socket = self.inner.borrow().res.socket();
+        if self.close_state() {
+            if hung_up {
+                socket.destroy();
+            }
+            emit(&self.inner, |s| &mut s.on_close, Js::Undefined);
+            self.drop_listeners();
+        }
+    }
+
+    /// Marks the stream closed and stops the keepalives. Returns false if it
+    /// was closed already.
+    fn close_state(&self) -> bool {
+        let mut state = self.inner.borrow_mut();
+        if state.closed {
+            return false;
+        }
+        state.closed = true;
+        if let Some(timer) = state.keep_alive_timer.take() {
+            clear_timeout(timer);
+        }
+        true
+    }
+
+    /// The listeners usually hold handles to this stream
+    fn drop_listeners(&self) {
+        self.inner.borrow_mut().on_close = Listeners::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::{self, RequestOptions};
+    use crate::http_server::{HttpServer, Router};
+    use std::cell::Cell;
+
+    /// Serves `on_stream` at `/events` and calls `f` with its URL once the
+    /// server is listening
+    fn with_server(
+        opts: SseOptions,
+        on_stream: impl FnMut(EventStream, IncomingRequest) + Clone + 'static,
+        f: impl Fn(String, HttpServer) + 'static,
+    ) {
+        let f = Rc::new(f);
+        crate::run_test(move || {
+            let router = Router::new().get("/events", handler(opts.clone(), on_stream.clone()));
+            let server = HttpServer::new(router.into_handler());
+            let (s, f) = (server.clone(), f.clone());
+            server.listen("127.0.0.1:0", move |_| {
+                f(
+                    format!("http://{}/events", s.local_addr().unwrap()),
+                    s.clone(),
+                )
+            });
+        });
+    }
+
+    #[test]
+    fn encode_events() {
+        let event = Event::new("one\r\ntwo\rthree\nfour")
+            .event("pri\nce")
+            .id("4\r2\0")
+            .retry(10);
+        let expected = "id: 42\nevent: price\nretry: 10\n\
+                        data: one\ndata: two\ndata: three\ndata: four\n\n";
+        assert_eq!(event.encode(), expected);
+
+        assert_eq!(Event::from("").encode(), "data: \n\n");
+        assert_eq!(Event::from("a".to_string()), Event::new("a"));
+    }
+
+    #[test]
+    fn stream_events_and_keepalives() {
+        let body = Rc::new(RefCell::new(String::new()));
+        let last_event_id = Rc::new(RefCell::new(None));
+
+        let opts = SseOptions {
+            keep_alive_ms: 30,
+            retry_ms: Some(1000),
+        };
+        let l = last_event_id.clone();
+        let on_stream = move |stream: EventStream, _req| {
+            *l.borrow_mut() = stream.last_event_id();
+            assert!(stream.send(Event::new("hello").event("greeting").id("8")));
+            assert!(stream.comment("hi\nthere"));
+            // Nothing else is sent, so the keepalives take over
+            set_timeout(100, move |_| {
+                stream.close();
+                assert!(stream.is_closed());
+                assert!(!stream.send("too late"));
+            });
+        };
+
+        let b = body.clone();
+        with_server(opts, on_stream, move |url, server| {
+            let mut opts = RequestOptions::get(url);
+            opts.headers.set("Last-Event-ID", "7");
+            let b = b.clone();
+            http::request_stream(opts, move |res| {
+                let res = res.unwrap();
+                assert_eq!(res.headers.get("Content-Type"), Some("text/event-stream"));
+                let b = b.clone();
+                res.body.on_data(move |chunk| {
+                    b.borrow_mut()
+                        .push_str(&String::from_utf8(chunk.into_bytes().unwrap()).unwrap())
+                });
+                res.body.on_end(move |_| server.close(|_| ()));
+            });
+        });
+
+        assert_eq!(*last_event_id.borrow(), Some("7".to_string()));
+        let body = body.borrow();
+        let start = "retry: 1000\n\nid: 8\nevent: greeting\ndata: hello\n\n: hi\n: there\n\n";
+        assert!(body.starts_with(start), "{:?}", body);
+        let keep_alives = &body[start.len()..];
+        assert!(keep_alives.len() >= 2 * ":\n\n".len(), "{:?}", body);
+        assert_eq!(keep_alives.replace(":\n\n", ""), "");
+    }
+
+    #[test]
+    fn client_disconnect_closes_the_stream() {
+        let closed = Rc::new(Cell::new(0));
+        let server = Rc::new(RefCell::new(None::<HttpServer>));
+
+        let opts = SseOptions {
+            keep_alive_ms: 0,
+            retry_ms: None,
+        };
+        let (c, s) = (closed.clone(), server.clone());
+        let on_stream = move |stream: EventStream, _req| {
+            let (c, s, st) = (c.clone(), s.clone(), stream.clone());
+            stream.on_close(move |_| {
+                c.set(c.get() + 1);
+                assert!(st.is_closed());
+                assert!(!st.send("anyone there?"));
+                s.borrow_mut().take().unwrap().close(|_| ());
+            });
+            stream.send("first");
+        };
+
+        let s = server.clone();
+        with_server(opts, on_stream, move |url, server| {
+            *s.borrow_mut() = Some(server);
+            http::request_stream(RequestOptions::get(url), move |res| {
+                let body = res.unwrap().body;
+                let b = body.clone();
+                // Hanging up after the first event closes the connection
+                body.on_data(move |chunk| {
+                    if String::from_utf8(chunk.into_bytes().unwrap())
+                        .unwrap()
+                        .contains("first")
+                    {
+                        b.destroy(None);
+                    }
+                });
+            });
+        });
+
+        assert_eq!(closed.get(), 1);
+    }
+}
@@ -0,0 +1,1068 @@
+// ===== STREAMS =====
+// This is a small version of Node's `stream` module. A `Readable` gets its data
+// from a `read` function which is called whenever the stream wants more data,
+// and which eventually calls `push`. A `Writable` hands chunks one by one to a
+// `write` function which calls a callback once the chunk is handled. The
+// buffering in between is what gives us backpressure: `push` and `write`
+// return false once `high_water_mark` bytes are buffered.
+//
+// All handles are cheap to clone and all clones refer to the same stream.
+// Events are delivered to every listener registered for them, and all
+// listeners are dropped once the stream emits `close`.
+
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::io;
+use std::rc::Rc;
+
+use crate::Js;
+
+/// Called by the `write` and `final` functions of a `Writable` when done
+pub type Callback = Box<dyn FnOnce(io::Result<()>)>;
+
+type ReadFn = Box<dyn FnMut(&Readable)>;
+type WriteFn = Box<dyn FnMut(Vec<u8>, Callback)>;
+
+#[derive(Default)]
+pub(crate) struct Listeners(Vec<Box<dyn FnMut(Js)>>);
+
+impl Listeners {
+    pub(crate) fn add(&mut self, cb: impl FnMut(Js) + 'static) {
+        self.0.push(Box::new(cb));
+    }
+}
+
+/// Calls all listeners returned by `slot` with `data`. The listeners are taken
+/// out of the stream state while they run so they are free to call back into
+/// the stream, e.g. calling `pause` from a `data` listener.
+pub(crate) fn emit<S>(state: &RefCell<S>, slot: impl Fn(&mut S) -> &mut Listeners, data: Js) {
+    let mut listeners = std::mem::take(slot(&mut state.borrow_mut()));
+    if listeners.0.is_empty() {
+        return;
+    }
+
+    for cb in listeners.0.iter_mut() {
+        cb(data.clone());
+    }
+
+    let mut state = state.borrow_mut();
+    let current = slot(&mut state);
+    // Listeners added while we were emitting go after the existing ones
+    listeners.0.append(&mut current.0);
+    *current = listeners;
+}
+
+/// Implemented by everything that has a readable side
+pub trait AsReadable {
+    fn readable(&self) -> &Readable;
+}
+
+/// Implemented by everything that has a writable side
+pub trait AsWritable {
+    fn writable(&self) -> &Writable;
+}
+
+// ===== READABLE =====
+
+#[derive(Clone)]
+pub struct Readable {
+    inner: Rc<RefCell<ReadableState>>,
+}
+
+struct ReadableState {
+    high_water_mark: usize,
+    /// Chunks pushed by the source which are not consumed yet
+    buffer: VecDeque<Vec<u8>>,
+    /// Number of bytes in `buffer`
+    length: usize,
+    flowing: bool,
+    /// We don't ask for data before someone has started consuming the stream
+    started: bool,
+    /// `read` is called and we're waiting for the source to push
+    reading: bool,
+    /// The source has called `push_end`
+    ended: bool,
+    end_emitted: bool,
+    destroyed: bool,
+    /// The source. It's `None` while it's running.
+    read: Option<ReadFn>,
+    on_data: Listeners,
+    on_end: Listeners,
+    on_error: Listeners,
+    on_close: Listeners,
+}
+
+impl Readable {
+    /// `read` is called every time the stream wants more data. It should
+    /// (sooner or later) call `push` with the next chunk or `push_end` if
+    /// there is no more data.
+    pub fn new(high_water_mark: usize, read: impl FnMut(&Readable) + 'static) -> Self {
+        let state = ReadableState {
+            high_water_mark,
+            buffer: VecDeque::new(),
+            length: 0,
+            flowing: false,
+            started: false,
+            reading: false,
+            ended: false,
+            end_emitted: false,
+            destroyed: false,
+            read: Some(Box::new(read)),
+            on_data: Listeners::default(),
+            on_end: Listeners::default(),
+            on_error: Listeners::default(),
+            on_close: Listeners::default(),
+        };
+
+        Readable {
+            inner: Rc::new(RefCell::new(state)),
+        }
+    }
+
+    /// Adds a chunk to the stream. Returns false if the buffer has reached
+    /// `high_water_mark` and the source should stop producing data until
+    /// `read` is called again.
+    pub fn push(&self, chunk: Vec<u8>) -> bool {
+        let mut state = self.inner.borrow_mut();
+        if state.ended || state.destroyed {
+            return false;
+        }
+        state.reading = false;
+
+        if !chunk.is_empty() {
+            state.length += chunk.len();
+            state.buffer.push_back(chunk);
+        }
+
+        let in_read = state.read.is_none();
+        drop(state);
+
+        // In flowing mode the chunk is usually gone again right away
+        self.flow();
+        let ok = {
+            let state = self.inner.borrow();
+            state.length < state.high_water_mark
+        };
+        // If we're called from inside `read`, `maybe_read` will call it again
+        // when it returns instead
+        if !in_read {
+            self.maybe_read();
+        }
+
+        ok
+    }
+
+    /// Signals that the source has no more data
+    pub fn push_end(&self) {
+        {
+            let mut state = self.inner.borrow_mut();
+            if state.ended || state.destroyed {
+                return;
+            }
+            state.ended = true;
+            state.reading = false;
+        }
+
+        self.flow();
+    }
+
+    /// Attaching a `data` listener switches the stream into flowing mode
+    pub fn on_data(&self, cb: impl FnMut(Js) + 'static) {
+        self.inner.borrow_mut().on_data.add(cb);
+        self.resume();
+    }
+
+    pub fn on_end(&self, cb: impl FnMut(Js) + 'static) {
+        self.inner.borrow_mut().on_end.add(cb);
+    }
+
+    pub fn on_error(&self, cb: impl FnMut(Js) + 'static) {
+        self.inner.borrow_mut().on_error.add(cb);
+    }
+
+    pub fn on_close(&self, cb: impl FnMut(Js) + 'static) {
+        self.inner.borrow_mut().on_close.add(cb);
+    }
+
+    /// Stops emitting `data` events. Data is still read until the buffer
+    /// reaches `high_water_mark`.
+    pub fn pause(&self) {
+        self.inner.borrow_mut().flowing = false;
+    }
+
+    pub fn resume(&self) {
+        {
+            let mut state = self.inner.borrow_mut();
+            if state.flowing || state.destroyed {
+                return;
+            }
+            state.flowing = true;
+            state.started = true;
+        }
+
+        self.flow();
+        self.maybe_read();
+    }
+
+    pub fn is_paused(&self) -> bool {
+        !self.inner.borrow().flowing
+    }
+
+    /// True once someone has started consuming the stream, even if it's
+    /// paused right now
+    pub fn is_started(&self) -> bool {
+        self.inner.borrow().started
+    }
+
+    pub fn is_destroyed(&self) -> bool {
+        self.inner.borrow().destroyed
+    }
+
+    /// Discards all buffered data and closes the stream. If `err` is given
+    /// it's emitted as an `error` event first.
+    pub fn destroy(&self, err: Option<io::Error>) {
+        {
+            let mut state = self.inner.borrow_mut();
+            if state.destroyed {
+                return;
+            }
+            state.destroyed = true;
+            state.flowing = false;
+            state.buffer.clear();
+            state.length = 0;
+        }
+
+        if let Some(err) = err {
+            emit(&self.inner, |s| &mut s.on_error, Js::Error(err));
+        }
+
+        self.close();
+    }
+
+    /// Collects everything left in the stream and calls `cb` with
+    /// `Js::Bytes` once it has ended, or with the error if it fails
+    pub fn read_to_end(&self, cb: impl FnOnce(Js) + 'static) {
+        let cb = Rc::new(RefCell::new(Some(cb)));
+        let data = Rc::new(RefCell::new(Vec::new()));
+
+        let d = data.clone();
+        let c = cb.clone();
+        self.on_end(move |_| {
+            if let Some(cb) = c.borrow_mut().take() {
+                cb(Js::Bytes(d.take()));
+            }
+        });
+
+        self.on_error(move |err| {
+            if let Some(cb) = cb.borrow_mut().take() {
+                cb(err);
+            }
+        });
+
+        // This starts the flow, which can end the stream right away if all
+        // the data is buffered already, so it goes last
+        self.on_data(move |chunk| data.borrow_mut().extend(chunk.into_bytes().unwrap()));
+    }
+
+    /// Writes all data from this stream to `dest`, pausing whenever `dest`
+    /// can't keep up. `dest` is ended when this stream ends. If `dest` closes
+    /// or fails first, we stop writing to it and pause. Errors are not
+    /// forwarded, use `pipeline` for that.
+    pub fn pipe<W: AsWritable>(&self, dest: W) -> W {
+        let writable = dest.writable().clone();
+        let piped = Rc::new(Cell::new(true));
+
+        let (src, p) = (self.clone(), piped.clone());
+        let unpipe = move |_| {
+            if p.replace(false) {
+                src.pause();
+            }
+        };
+        writable.on_close(unpipe.clone());
+        writable.on_error(unpipe);
+
+        let (src, p) = (self.clone(), piped.clone());
+        writable.on_drain(move |_| {
+            if p.get() {
+                src.resume();
+            }
+        });
+
+        let (end_writable, p) = (writable.clone(), piped.clone());
+        self.on_end(move |_| {
+            if p.get() {
+                end_writable.end();
+            }
+        });
+
+        let src = self.clone();
+        self.on_data(move |chunk| {
+            if !piped.get() {
+                return;
+            }
+            let chunk = chunk.into_bytes().unwrap();
+            if !writable.write(chunk) {
+                src.pause();
+            }
+        });
+
+        dest
+    }
+
+    /// Emits buffered chunks while we're flowing, and `end` once everything
+    /// is consumed
+    fn flow(&self) {
+        loop {
+            let mut state = self.inner.borrow_mut();
+            if !state.flowing || state.destroyed {
+                return;
+            }
+
+            match state.buffer.pop_front() {
+                Some(chunk) => {
+                    state.length -= chunk.len();
+                    drop(state);
+                    emit(&self.inner, |s| &mut s.on_data, Js::Bytes(chunk));
+                }
+                None if state.ended && !state.end_emitted => {
+                    state.end_emitted = true;
+                    drop(state);
+                    emit(&self.inner, |s| &mut s.on_end, Js::Undefined);
+                    self.close();
+                    return;
+                }
+                None => return,
+            }
+        }
+    }
+
+    /// Asks the source for more data as long as there is room in the buffer.
+    /// If the source pushes synchronously we just loop and ask again.
+    fn maybe_read(&self) {
+        loop {
+            let mut read = {
+                let mut state = self.inner.borrow_mut();
+                let wants_data = state.started
+                    && !state.reading
+                    && !state.ended
+                    && !state.destroyed
+                    && state.length < state.high_water_mark;
+
+                if !wants_data {
+                    return;
+                }
+
+                match state.read.take() {
+                    Some(read) => {
+                        state.reading = true;
+                        read
+                    }
+                    None => return,
+                }
+            };
+
+            read(self);
+
+            let mut state = self.inner.borrow_mut();
+            if state.destroyed {
+                return;
+            }
+            state.read = Some(read);
+
+            if state.reading {
+                return;
+            }
+        }
+    }
+
+    fn close(&self) {
+        {
+            let mut state = self.inner.borrow_mut();
+            state.destroyed = true;
+            state.flowing = false;
+        }
+
+        emit(&self.inner, |s| &mut s.on_close, Js::Undefined);
+
+        // Listeners often hold handles to this stream, so we drop them to break
+        // the reference cycles
+        let mut state = self.inner.borrow_mut();
+        state.read = None;
+        state.on_data = Listeners::default();
+        state.on_end = Listeners::default();
+        state.on_error = Listeners::default();
+        state.on_close = Listeners::default();
+    }
+}
+
+impl AsReadable for Readable {
+    fn readable(&self) -> &Readable {
+        self
+    }
+}
+
+// ===== WRITABLE =====
+
+#[derive(Clone)]
+pub struct Writable {
+    inner: Rc<RefCell<WritableState>>,
+}
+
+struct WritableState {
+    high_water_mark: usize,
+    /// Chunks waiting for the write in progress to finish
+    buffer: VecDeque<Vec<u8>>,
+    /// Bytes buffered or being written right now
+    length: usize,
+    /// A chunk is handed to `write` and we're waiting for the callback
+    writing: bool,
+    /// `write` has returned false and we owe the user a `drain` event
+    need_drain: bool,
+    ending: bool,
+    finished: bool,
+    destroyed: bool,
+    /// The sink. It's `None` while it's running.
+    write: Option<WriteFn>,
+    /// Called when `end` is called and all data is written
+    final_: Option<Box<dyn FnOnce(Callback)>>,
+    on_drain: Listeners,
+    on_finish: Listeners,
+    on_error: Listeners,
+    on_close: Listeners,
+}
+
+impl Writable {
+    /// `write` is called with one chunk at a time and must call the callback
+    /// exactly once when the chunk is handled.
+    pub fn new(high_water_mark: usize, write: impl FnMut(Vec<u8>, Callback) + 'static) -> Self {
+        let state = WritableState {
+            high_water_mark,
+            buffer: VecDeque::new(),
+            length: 0,
+            writing: false,
+            need_drain: false,
+            ending: false,
+            finished: false,
+            destroyed: false,
+            write: Some(Box::new(write)),
+            final_: None,
+            on_drain: Listeners::default(),
+            on_finish: Listeners::default(),
+            on_error: Listeners::default(),
+            on_close: Listeners::default(),
+        };
+
+        Writable {
+            inner: Rc::new(RefCell::new(state)),
+        }
+    }
+
+    /// Sets a function which runs after `end` is called and everything is
+    /// written, but before `finish` is emitted. It must call the callback
+    /// when done.
+    pub fn set_final(&self, f: impl FnOnce(Callback) + 'static) {
+        self.inner.borrow_mut().final_ = Some(Box::new(f));
+    }
+
+    /// Queues `chunk` to be written. Returns false if the amount of buffered
+    /// data has reached `high_water_mark`, in which case the caller should stop
+    /// writing until `drain` is emitted.
+    pub fn write(&self, chunk: Vec<u8>) -> bool {
+        let mut state = self.inner.borrow_mut();
+
+        if state.ending || state.destroyed {
+            drop(state);
+            let err = io::Error::other("write after end");
+            emit(&self.inner, |s| &mut s.on_error, Js::Error(err));
+            return false;
+        }
+
+        state.length += chunk.len();
+        state.buffer.push_back(chunk);
+
+        let ok = state.length < state.high_water_mark;
+        if !ok {
+            state.need_drain = true;
+        }
+        drop(state);
+
+        self.write_next();
+        ok
+    }
+
+    /// Signals that no more data will be written. `finish` is emitted once
+    /// everything buffered is written.
+    pub fn end(&self) {
+        let idle = {
+            let mut state = self.inner.borrow_mut();
+            if state.ending || state.destroyed {
+                return;
+            }
+            state.ending = true;
+            !state.writing && state.buffer.is_empty()
+        };
+
+        if idle {
+            self.after_writes();
+        }
+    }
+
+    pub fn on_drain(&self, cb: impl FnMut(Js) + 'static) {
+        self.inner.borrow_mut().on_drain.add(cb);
+    }
+
+    pub fn on_finish(&self, cb: impl FnMut(Js) + 'static) {
+        self.inner.borrow_mut().on_finish.add(cb);
+    }
+
+    pub fn on_error(&self, cb: impl FnMut(Js) + 'static) {
+        self.inner.borrow_mut().on_error.add(cb);
+    }
+
+    pub fn on_close(&self, cb: impl FnMut(Js) + 'static) {
+        self.inner.borrow_mut().on_close.add(cb);
+    }
+
+    /// True if `write` has returned false and `drain` has not been emitted yet
+    pub fn needs_drain(&self) -> bool {
+        self.inner.borrow().need_drain
+    }
+
+    /// Bytes buffered or being written, `writableLength` in Node
+    pub fn length(&self) -> usize {
+        self.inner.borrow().length
+    }
+
+    pub fn is_destroyed(&self) -> bool {
+        self.inner.borrow().destroyed
+    }
+
+    /// Discards everything not written yet and closes the stream. If `err`
+    /// is given it's emitted as an `error` event first.
+    pub fn destroy(&self, err: Option<io::Error>) {
+        {
+            let mut state = self.inner.borrow_mut();
+            if state.destroyed {
+                return;
+            }
+            state.destroyed = true;
+            state.buffer.clear();
+            state.length = 0;
+        }
+
+        if let Some(err) = err {
+            emit(&self.inner, |s| &mut s.on_error, Js::Error(err));
+        }
+
+        self.close();
+    }
+
+    /// Hands buffered chunks to `write` one at a time. If `write` calls the
+    /// callback synchronously we just loop and write the next one.
+    fn write_next(&self) {
+        loop {
+            let (mut write, chunk) = {
+                let mut state = self.inner.borrow_mut();
+                if state.writing || state.destroyed {
+                    return;
+                }
+
+                // `None` means we're called from inside `write`, and the loop
+                // further up the stack takes care of the next chunk
+                let write = match state.write.take() {
+                    Some(write) => write,
+                    None => return,
+                };
+
+                match state.buffer.pop_front() {
+                    Some(chunk) => {
+                        state.writing = true;
+                        (write, chunk)
+                    }
+                    None => {
+                        state.write = Some(write);
+                        drop(state);
+                        self.after_writes();
+                        return;
+                    }
+                }
+            };
+
+            let len = chunk.len();
+            let writable = self.clone();
+            write(chunk, Box::new(move |res| writable.on_written(len, res)));
+
+            let mut state = self.inner.borrow_mut();
+            if state.destroyed {
+                return;
+            }
+            state.write = Some(write);
+
+            if state.writing {
+                return;
+            }
+        }
+    }
+
+    fn on_written(&self, len: usize, res: io::Result<()>) {
+        let in_write = {
+            let mut state = self.inner.borrow_mut();
+            state.writing = false;
+            state.length -= len.min(state.length);
+            state.write.is_none()
+        };
+
+        if let Err(e) = res {
+            self.destroy(Some(e));
+            return;
+        }
+
+        if !in_write {
+            self.write_next();
+        }
+    }
+
+    fn after_writes(&self) {
+        let mut state = self.inner.borrow_mut();
+        if state.destroyed {
+            return;
+        }
+
+        if state.ending {
+            if state.finished {
+                return;
+            }
+            state.finished = true;
+            let final_ = state.final_.take();
+            drop(state);
+
+            let writable = self.clone();
+            let done: Callback = Box::new(move |res| match res {
+                Ok(()) => {
+                    emit(&writable.inner, |s| &mut s.on_finish, Js::Undefined);
+                    writable.close();
+                }
+                Err(e) => writable.destroy(Some(e)),
+            });
+
+            match final_ {
+                Some(f) => f(done),
+                None => done(Ok(())),
+            }
+        } else if state.need_drain {
+            state.need_drain = false;
+            drop(state);
+            emit(&self.inner, |s| &mut s.on_drain, Js::Undefined);
+        }
+    }
+
+    fn close(&self) {
+        {
+            let mut state = self.inner.borrow_mut();
+            state.destroyed = true;
+        }
+
+        emit(&self.inner, |s| &mut s.on_close, Js::Undefined);
+
+        let mut state = self.inner.borrow_mut();
+        state.write = None;
+        state.final_ = None;
+        state.on_drain = Listeners::default();
+        state.on_finish = Listeners::default();
+        state.on_error = Listeners::default();
+        state.on_close = Listeners::default();
+    }
+}
+
+impl AsWritable for Writable {
+    fn writable(&self) -> &Writable {
+        self
+    }
+}
+
+// ===== DUPLEX AND TRANSFORM =====
+
+/// A stream with both a readable and a writable side, like a socket. The two
+/// sides are independent except for errors: if one side fails the other side
+/// is destroyed as well.
+#[derive(Clone)]
+pub struct Duplex {
+    pub readable: Readable,
+    pub writable: Writable,
+}
+
+impl Duplex {
+    pub fn new(readable: Readable, writable: Writable) -> Self {
+        let other = writable.clone();
+        readable.on_error(move |_| other.destroy(None));
+        let other = readable.clone();
+        writable.on_error(move |_| other.destroy(None));
+
+        Duplex { readable, writable }
+    }
+
+    /// Listens for errors on both sides
+    pub fn on_error(&self, cb: impl FnMut(Js) + 'static) {
+        let cb = Rc::new(RefCell::new(cb));
+        let cb2 = cb.clone();
+        self.readable.on_error(move |e| (cb.borrow_mut())(e));
+        self.writable.on_error(move |e| (cb2.borrow_mut())(e));
+    }
+
+    pub fn destroy(&self, err: Option<io::Error>) {
+        self.writable.destroy(None);
+        self.readable.destroy(err);
+    }
+
+    pub fn pipe<W: AsWritable>(&self, dest: W) -> W {
+        self.readable.pipe(dest)
+    }
+}
+
+impl AsReadable for Duplex {
+    fn readable(&self) -> &Readable {
+        &self.readable
+    }
+}
+
+impl AsWritable for Duplex {
+    fn writable(&self) -> &Writable {
+        &self.writable
+    }
+}
+
+/// A `Transform` is a `Duplex` where the output is computed from the input,
+/// like compression. A chunk isn't accepted from the writable side before the
+/// previous output has found room in the readable side.
+pub struct Transform;
+
+// A transform is nothing more than a duplex stream, so that's what we return
+#[allow(clippy::new_ret_no_self)]
+impl Transform {
+    pub fn new(transform: impl FnMut(Vec<u8>) -> io::Result<Vec<u8>> + 'static) -> Duplex {
+        Transform::with_flush(transform, || Ok(vec![]))
+    }
+
+    /// `flush` is called when the writable side ends and its output is the
+    /// last chunk emitted from the readable side
+    pub fn with_flush(
+        mut transform: impl FnMut(Vec<u8>) -> io::Result<Vec<u8>> + 'static,
+        flush: impl FnOnce() -> io::Result<Vec<u8>> + 'static,
+    ) -> Duplex {
+        const HIGH_WATER_MARK: usize = 16 * 1024;
+
+        // The callback for the last written chunk is held back here while the
+        // readable side is full
+        let held: Rc<RefCell<Option<Callback>>> = Rc::new(RefCell::new(None));
+
+        let held_read = held.clone();
+        let readable = Readable::new(HIGH_WATER_MARK, move |_| {
+            if let Some(cb) = held_read.borrow_mut().take() {
+                cb(Ok(()));
+            }
+        });
+
+        let output = readable.clone();
+        let writable = Writable::new(HIGH_WATER_MARK, move |chunk, cb| match transform(chunk) {
+            Ok(out) => {
+                if out.is_empty() || output.push(out) {
+                    cb(Ok(()));
+                } else {
+                    *held.borrow_mut() = Some(cb);
+                }
+            }
+            Err(e) => cb(Err(e)),
+        });
+
+        let output = readable.clone();
+        writable.set_final(move |cb| match flush() {
+            Ok(out) => {
+                output.push(out);
+                output.push_end();
+                cb(Ok(()));
+            }
+            Err(e) => cb(Err(e)),
+        });
+
+        Duplex::new(readable, writable)
+    }
+}
+
+// ===== PIPELINE =====
+
+/// Any stream which can be part of a `pipeline`
+pub enum Stream {
+    Readable(Readable),
+    Writable(Writable),
+    Duplex(Duplex),
+}
+
+impl Stream {
+    fn readable(&self) -> Option<&Readable> {
+        match self {
+            Stream::Readable(r) => Some(r),
+            Stream::Duplex(d) => Some(&d.readable),
+            Stream::Writable(_) => None,
+        }
+    }
+
+    fn writable(&self) -> Option<&Writable> {
+        match self {
+            Stream::Writable(w) => Some(w),
+            Stream::Duplex(d) => Some(&d.writable),
+            Stream::Readable(_) => None,
+        }
+    }
+
+    fn destroy(&self) {
+        match self {
+            Stream::Readable(r) => r.destroy(None),
+            Stream::Writable(w) => w.destroy(None),
+            Stream::Duplex(d) => d.destroy(None),
+        }
+    }
+}
+
+impl From<Readable> for Stream {
+    fn from(r: Readable) -> Self {
+        Stream::Readable(r)
+    }
+}
+
+impl From<Writable> for Stream {
+    fn from(w: Writable) -> Self {
+        Stream::Writable(w)
+    }
+}
+
+impl From<Duplex> for Stream {
+    fn from(d: Duplex) -> Self {
+        Stream::Duplex(d)
+    }
+}
+
+/// Pipes the streams together. The first stream must be readable, the last
+/// writable and all streams in between duplex. `cb` is called once with
+/// `Js::Undefined` when the last stream has finished, or with `Js::Error` if
+/// any of the streams fails, in which case all streams are destroyed.
+pub fn pipeline(streams: Vec<Stream>, cb: impl FnOnce(Js) + 'static) {
+    let n = streams.len();
+    let valid = n >= 2
+        && streams.iter().enumerate().all(|(i, s)| {
+            (i == n - 1 || s.readable().is_some()) && (i == 0 || s.writable().is_some())
+        });
+
+    if !valid {
+        let msg = "pipeline needs a readable, any number of duplex streams and a writable";
+        cb(Js::Error(io::Error::new(io::ErrorKind::InvalidInput, msg)));
+        return;
+    }
+
+    let streams = Rc::new(streams);
+    let done = Rc::new(Cell::new(false));
+    let cb = RefCell::new(Some(cb));
+
+    // Runs the callback the first time we get here. On errors we tear down
+    // the whole pipeline.
+    let complete = {
+        let streams = streams.clone();
+        move |res: Js| {
+            if done.replace(true) {
+                return;
+            }
+
+            if let Js::Error(_) = res {
+                for stream in streams.iter() {
+                    stream.destroy();
+                }
+            }
+
+            if let Some(cb) = cb.borrow_mut().take() {
+                cb(res);
+            }
+        }
+    };
+    let complete = Rc::new(complete);
+
+    for stream in streams.iter() {
+        let on_error = complete.clone();
+        let on_error = move |e: Js| on_error(e);
+        match stream {
+            Stream::Readable(r) => r.on_error(on_error),
+            Stream::Writable(w) => w.on_error(on_error),
+            Stream::Duplex(d) => d.on_error(on_error),
+        }
+    }
+
+    let last = streams[n - 1].writable().unwrap();
+    let on_finish = complete.clone();
+    last.on_finish(move |_| on_finish(Js::Undefined));
+
+    let on_close = complete;
+    last.on_close(move |_| {
+        let err = io::Error::new(io::ErrorKind::BrokenPipe, "premature close");
+        on_close(Js::Error(err));
+    });
+
+    for pair in streams.windows(2) {
+        pair[0]
+            .readable()
+            .unwrap()
+            .pipe(pair[1].writable().unwrap().clone());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A source which pushes all of `chunks` on the next tick
+    fn source(chunks: &[&str]) -> Readable {
+        let chunks: Vec<Vec<u8>> = chunks.iter().map(|c| c.as_bytes().to_vec()).collect();
+        let chunks = Rc::new(RefCell::new(Some(chunks)));
+        Readable::new(4, move |readable| {
+            let (chunks, readable) = (chunks.clone(), readable.clone());
+            crate::defer(move |_| match chunks.borrow_mut().take() {
+                Some(chunks) => chunks.into_iter().for_each(|chunk| {
+                    readable.push(chunk);
+                }),
+                None => readable.push_end(),
+            });
+        })
+    }
+
+    /// A sink which collects what's written to it. Every write is done on the
+    /// next tick, so data piles up when it's written faster than that.
+    fn sink(written: Rc<RefCell<Vec<u8>>>) -> Writable {
+        Writable::new(4, move |chunk, cb| {
+            written.borrow_mut().extend(chunk);
+            crate::defer(move |_| cb(Ok(())));
+        })
+    }
+
+    #[test]
+    fn pipe_backpressure() {
+        let written = Rc::new(RefCell::new(vec![]));
+        let paused_on_drain = Rc::new(RefCell::new(vec![]));
+        let full = Rc::new(Cell::new(None));
+
+        let (w, p, f) = (written.clone(), paused_on_drain.clone(), full.clone());
+        crate::run_test(move || {
+            let src = source(&["ab", "cd", "ef", "gh", "ij"]);
+            let dest = sink(w.clone());
+
+            // Registered before `pipe`, so we see the source before the pipe
+            // resumes it
+            let (p, s) = (p.clone(), src.clone());
+            dest.on_drain(move |_| p.borrow_mut().push(s.is_paused()));
+
+            // Registered after `pipe`, so we see the destination right after
+            // each write
+            let (f, d) = (f.clone(), dest.clone());
+            src.pipe(dest);
+            src.on_data(move |_| {
+                if f.get().is_none() && d.needs_drain() {
+                    f.set(Some(d.length()));
+                }
+            });
+        });
+
+        assert_eq!(*written.borrow(), b"abcdefghij");
+        assert!(!paused_on_drain.borrow().is_empty());
+        assert!(paused_on_drain.borrow().iter().all(|&paused| paused));
+        assert!(full.get().unwrap() >= 4);
+    }
+
+    #[test]
+    fn pipe_stops_at_destroyed_destination() {
+        let writes = Rc::new(Cell::new(0));
+        let src_paused = Rc::new(RefCell::new(vec![]));
+
+        let (w, p) = (writes.clone(), src_paused.clone());
+        crate::run_test(move || {
+            let src = source(&["ab", "cd", "ef"]);
+            let w = w.clone();
+            let dest = Writable::new(16, move |_, cb| {
+                w.set(w.get() + 1);
+                cb(Ok(()));
+            });
+
+            src.pipe(dest.clone());
+            let (s, p) = (src.clone(), p.clone());
+            src.on_data(move |_| {
+                dest.destroy(None);
+                p.borrow_mut().push(s.is_paused());
+            });
+        });
+
+        // The source pauses as soon as the destination closes, so the other
+        // chunks are never emitted
+        assert_eq!(writes.get(), 1);
+        assert_eq!(*src_paused.borrow(), [true]);
+    }
+
+    #[test]
+    fn transform() {
+        let written = Rc::new(RefCell::new(vec![]));
+        let finished = Rc::new(Cell::new(false));
+
+        let (w, f) = (written.clone(), finished.clone());
+        crate::run_test(move || {
+            let upper =
+                Transform::with_flush(|chunk| Ok(chunk.to_ascii_uppercase()), || Ok(b"!".to_vec()));
+            let dest = sink(w.clone());
+            let f = f.clone();
+            dest.on_finish(move |_| f.set(true));
+            source(&["hello", " ", "world"]).pipe(upper).pipe(dest);
+        });
+
+        assert!(finished.get());
+        assert_eq!(*written.borrow(), b"HELLO WORLD!");
+    }
+
+    #[test]
+    fn pipeline_error_destroys_every_stream() {
+        let result = Rc::new(RefCell::new(None));
+        let destroyed = Rc::new(Cell::new(false));
+
+        let (r, d) = (result.clone(), destroyed.clone());
+        crate::run_test(move || {
+            let src = source(&["ok", "bad", "never"]);
+            let check = Transform::new(|chunk| match chunk.as_slice() {
+                b"bad" => Err(io::Error::new(io::ErrorKind::InvalidData, "bad chunk")),
+                _ => Ok(chunk),
+            });
+            let dest = sink(Rc::new(RefCell::new(vec![])));
+
+            let streams = vec![
+                src.clone().into(),
+                check.clone().into(),
+                dest.clone().into(),
+            ];
+            let (r, d) = (r.clone(), d.clone());
+            pipeline(streams, move |res| {
+                if let Js::Error(e) = res {
+                    *r.borrow_mut() = Some(e);
+                }
+                d.set(src.is_destroyed() && check.writable.is_destroyed() && dest.is_destroyed());
+            });
+        });
+
+        let err = result.borrow_mut().take().expect("pipeline error");
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(destroyed.get());
+    }
+
+    #[test]
+    fn pipeline_needs_a_readable_and_a_writable() {
+        let result = Rc::new(RefCell::new(None));
+        let r = result.clone();
+        pipeline(vec![sink(Rc::default()).into()], move |res| {
+            if let Js::Error(e) = res {
+                *r.borrow_mut() = Some(e);
+            }
+        });
+        let err = result.borrow_mut().take().expect("pipeline error");
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
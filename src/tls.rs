@@ -0,0 +1,652 @@
+// ===== TLS =====
+// Encrypted sockets on top of our non-blocking TCP sockets, using rustls.
+// Only built with `--features tls`.
+//
+// rustls doesn't do any I/O itself. We hand it the bytes we read from the
+// socket and it gives us the plaintext, and the other way around for writes.
+// That fits the event loop nicely: the handshake is just a few rounds of
+// "read what the socket has, write what rustls wants to send" whenever epoll
+// says the socket is ready, and the socket only emits `connect` once it's
+// done.
+//
+//     let socket = Net::connect_tls("example.com", 443, TlsConnectOptions {
+//         alpn_protocols: vec!["http/1.1".to_string()],
+//         ..Default::default()
+//     });
+//     socket.on_connect(move |_| print("handshake done"));
+//
+// Certificates and keys are PEM encoded, like the `cert`, `key` and `ca`
+// options in Node. For local testing a self-signed certificate will do:
+//
+//     openssl req -x509 -newkey rsa:2048 -nodes -days 365 -subj /CN=localhost \
+//         -addext subjectAltName=DNS:localhost -keyout key.pem -out cert.pem
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::hash::{Hash, Hasher};
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+
+use rustls::crypto::ring::sign::any_supported_type;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier};
+use rustls::sign::CertifiedKey;
+use rustls::{
+    ClientConfig, ClientConnection, Connection, RootCertStore, ServerConfig, ServerConnection,
+};
+
+/// Options for `Net::connect_tls`, and for `https://` and `wss://` URLs
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct TlsConnectOptions {
+    /// The name we send with SNI and expect in the server's certificate.
+    /// Defaults to the host we connect to.
+    pub server_name: Option<String>,
+    /// Protocols we offer with ALPN, the one we prefer first
+    pub alpn_protocols: Vec<String>,
+    /// CA certificates to trust instead of the bundled Mozilla roots, for
+    /// servers with a self-signed certificate
+    pub ca: Option<Vec<u8>>,
+    /// Our certificate chain and its key, for servers which ask for a client
+    /// certificate
+    pub cert: Option<Vec<u8>>,
+    pub key: Option<Vec<u8>>,
+}
+
+/// Options for `Net::create_tls_server`
+#[derive(Debug, Clone, Default)]
+pub struct TlsServerOptions {
+    /// The certificate chain we send, our own certificate first
+    pub cert: Vec<u8>,
+    pub key: Vec<u8>,
+    /// Certificates for other host names, picked by the name the client asks
+    /// for with SNI: `(host name, cert, key)`. Clients which ask for a name
+    /// we don't know, or for none, get `cert`.
+    pub sni: Vec<(String, Vec<u8>, Vec<u8>)>,
+    /// Protocols we accept with ALPN, the one we prefer first. The handshake
+    /// fails if the client offers none of them.
+    pub alpn_protocols: Vec<String>,
+    /// Asks clients for a certificate signed by one of these CAs. Clients
+    /// which send one that isn't are rejected.
+    pub ca: Option<Vec<u8>>,
+    /// With `ca` set, also reject clients which don't send a certificate
+    pub require_client_cert: bool,
+}
+
+impl TlsConnectOptions {
+    /// Something to tell connections with different options apart, so a
+    /// connection pool doesn't hand out a connection with the wrong ones
+    pub(crate) fn fingerprint(&self) -> u64 {
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        self.hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+pub(crate) fn client_config(opts: &TlsConnectOptions) -> io::Result<Arc<ClientConfig>> {
+    let mut roots = RootCertStore::empty();
+    match &opts.ca {
+        Some(ca) => {
+            for cert in parse_certs(ca)? {
+                roots.add(cert).map_err(invalid_input)?;
+            }
+        }
+        None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+    }
+
+    let builder = ClientConfig::builder().with_root_certificates(roots);
+    let mut config = match (&opts.cert, &opts.key) {
+        (Some(cert), Some(key)) => builder
+            .with_client_auth_cert(parse_certs(cert)?, parse_key(key)?)
+            .map_err(invalid_input)?,
+        (None, None) => builder.with_no_client_auth(),
+        _ => {
+            return Err(invalid_input(
+                "a client certificate needs both cert and key",
+            ))
+        }
+    };
+    config.alpn_protocols = alpn(&opts.alpn_protocols);
+    Ok(Arc::new(config))
+}
+
+pub(crate) fn server_config(opts: &TlsServerOptions) -> io::Result<Arc<ServerConfig>> {
+    let builder = ServerConfig::builder();
+    let builder = match &opts.ca {
+        Some(ca) => {
+            let mut roots = RootCertStore::empty();
+            for cert in parse_certs(ca)? {
+                roots.add(cert).map_err(invalid_input)?;
+            }
+            let verifier = WebPkiClientVerifier::builder(Arc::new(roots));
+            let verifier = match opts.require_client_cert {
+                true => verifier,
+                false => verifier.allow_unauthenticated(),
+            };
+            builder.with_client_cert_verifier(verifier.build().map_err(invalid_input)?)
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let mut names = HashMap::new();
+    for (name, cert, key) in &opts.sni {
+        names.insert(name.to_ascii_lowercase(), certified_key(cert, key)?);
+    }
+    let resolver = SniResolver {
+        default: certified_key(&opts.cert, &opts.key)?,
+        names,
+    };
+
+    let mut config = builder.with_cert_resolver(Arc::new(resolver));
+    config.alpn_protocols = alpn(&opts.alpn_protocols);
+    Ok(Arc::new(config))
+}
+
+/// Picks the certificate for the name the client asked for
+#[derive(Debug)]
+struct SniResolver {
+    default: Arc<CertifiedKey>,
+    names: HashMap<String, Arc<CertifiedKey>>,
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let name = hello.server_name().map(|name| name.to_ascii_lowercase());
+        let key = name.and_then(|name| self.names.get(&name));
+        Some(key.unwrap_or(&self.default).clone())
+    }
+}
+
+fn certified_key(cert: &[u8], key: &[u8]) -> io::Result<Arc<CertifiedKey>> {
+    let certs = parse_certs(cert)?;
+    let signing_key = any_supported_type(&parse_key(key)?).map_err(invalid_input)?;
+    let certified = CertifiedKey::new(certs, signing_key);
+    certified.keys_match().map_err(invalid_input)?;
+    Ok(Arc::new(certified))
+}
+
+fn parse_certs(pem: &[u8]) -> io::Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_slice_iter(pem)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(invalid_input)?;
+    if certs.is_empty() {
+        return Err(invalid_input("no certificate found in PEM data"));
+    }
+    Ok(certs)
+}
+
+fn parse_key(pem: &[u8]) -> io::Result<PrivateKeyDer<'static>> {
+    PrivateKeyDer::from_pem_slice(pem).map_err(invalid_input)
+}
+
+fn alpn(protocols: &[String]) -> Vec<Vec<u8>> {
+    protocols.iter().map(|p| p.as_bytes().to_vec()).collect()
+}
+
+fn invalid_input(e: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, e)
+}
+
+// ===== TLS STREAM =====
+
+/// A non-blocking TCP stream with a TLS session on top. Reads and writes
+/// work on plaintext and return `WouldBlock` just like the TCP stream does.
+pub(crate) struct TlsStream {
+    tcp: TcpStream,
+    conn: Connection,
+}
+
+impl TlsStream {
+    pub(crate) fn client(
+        tcp: TcpStream,
+        config: Arc<ClientConfig>,
+        server_name: &str,
+    ) -> io::Result<Self> {
+        let name = ServerName::try_from(server_name.to_string()).map_err(invalid_input)?;
+        let conn = ClientConnection::new(config, name).map_err(invalid_input)?;
+        Ok(TlsStream {
+            tcp,
+            conn: conn.into(),
+        })
+    }
+
+    pub(crate) fn server(tcp: TcpStream, config: Arc<ServerConfig>) -> io::Result<Self> {
+        let conn = ServerConnection::new(config).map_err(invalid_input)?;
+        Ok(TlsStream {
+            tcp,
+            conn: conn.into(),
+        })
+    }
+
+    pub(crate) fn tcp(&self) -> &TcpStream {
+        &self.tcp
+    }
+
+    /// Takes the handshake as far as it gets without blocking. Returns true
+    /// once it's done.
+    pub(crate) fn handshake(&mut self) -> io::Result<bool> {
+        loop {
+            self.flush_tls()?;
+            if !self.conn.is_handshaking() {
+                return Ok(true);
+            }
+            if self.conn.wants_write() {
+                return Ok(false);
+            }
+            match self.read_tls() {
+                Ok(0) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::ConnectionAborted,
+                        "connection closed during the TLS handshake",
+                    ))
+                }
+                Ok(_) => (),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// There is encrypted data we haven't been able to send yet
+    pub(crate) fn wants_write(&self) -> bool {
+        self.conn.wants_write()
+    }
+
+    /// Decrypted data is waiting to be read. Errors are reported as data
+    /// waiting, so the next read returns them.
+    pub(crate) fn has_buffered_data(&mut self) -> bool {
+        match self.conn.process_new_packets() {
+            Ok(state) => state.plaintext_bytes_to_read() > 0 || state.peer_has_closed(),
+            Err(_) => true,
+        }
+    }
+
+    /// Tells the other end we won't send anything else. It's sent with the
+    /// next flush.
+    pub(crate) fn send_close_notify(&mut self) {
+        self.conn.send_close_notify();
+    }
+
+    pub(crate) fn alpn_protocol(&self) -> Option<String> {
+        let protocol = self.conn.alpn_protocol()?;
+        Some(String::from_utf8_lossy(protocol).into_owned())
+    }
+
+    /// DER encoded, the peer's own certificate first
+    pub(crate) fn peer_certificates(&self) -> Vec<Vec<u8>> {
+        let certs = self.conn.peer_certificates().unwrap_or_default();
+        certs.iter().map(|cert| cert.to_vec()).collect()
+    }
+
+    /// The name a client asked for with SNI
+    pub(crate) fn server_name(&self) -> Option<String> {
+        match &self.conn {
+            Connection::Server(conn) => conn.server_name().map(String::from),
+            Connection::Client(_) => None,
+        }
+    }
+
+    /// Reads what the socket has and decrypts it. A protocol error is sent to
+    /// the other end as an alert before we give up.
+    fn read_tls(&mut self) -> io::Result<usize> {
+        let n = loop {
+            match self.conn.read_tls(&mut self.tcp) {
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                res => break res?,
+            }
+        };
+
+        if let Err(e) = self.conn.process_new_packets() {
+            let _ = self.flush_tls();
+            return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+        }
+        Ok(n)
+    }
+
+    /// Sends the encrypted data rustls has for us until the socket would block
+    fn flush_tls(&mut self) -> io::Result<()> {
+        while self.conn.wants_write() {
+            match self.conn.write_tls(&mut self.tcp) {
+                Ok(_) => (),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.conn.reader().read(buf) {
+                // Lots of servers just close the connection without a
+                // `close_notify`. Node treats that as a normal end, so do we.
+                Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(0),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                res => return res,
+            }
+
+            self.read_tls()?;
+            // Reading can make rustls answer, like with a key update
+            self.flush_tls()?;
+        }
+    }
+}
+
+impl Write for TlsStream {
+    /// Takes all of `buf` once the data from earlier writes is sent. Until
+    /// then it returns `WouldBlock`, so writers are held back by the socket
+    /// just like without TLS.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.flush_tls()?;
+        if self.conn.wants_write() {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        let n = self.conn.writer().write(buf)?;
+        self.flush_tls()?;
+        Ok(n)
+    }
+
+    /// Sends what's left of earlier writes, as far as it goes without
+    /// blocking
+    fn flush(&mut self) -> io::Result<()> {
+        self.flush_tls()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::{self, RequestOptions};
+    use crate::http_server::{IncomingRequest, ServerResponse};
+    use crate::net::Net;
+    use crate::Js;
+    use rcgen::{BasicConstraints, CertificateParams, DnType, IsCa, KeyPair};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// A CA made up on the spot, which signs certificates for the tests
+    struct Ca {
+        cert: rcgen::Certificate,
+        key: KeyPair,
+    }
+
+    /// A certificate and its key as PEM, and the certificate as DER to
+    /// compare with what a peer sent
+    #[derive(Clone)]
+    struct Issued {
+        cert: Vec<u8>,
+        key: Vec<u8>,
+        der: Vec<u8>,
+    }
+
+    impl Ca {
+        fn new(name: &str) -> Ca {
+            let mut params = CertificateParams::new(vec![]).unwrap();
+            params.distinguished_name.push(DnType::CommonName, name);
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            let key = KeyPair::generate().unwrap();
+            let cert = params.self_signed(&key).unwrap();
+            Ca { cert, key }
+        }
+
+        fn pem(&self) -> Vec<u8> {
+            self.cert.pem().into_bytes()
+        }
+
+        fn issue(&self, name: &str) -> Issued {
+            let mut params = CertificateParams::new(vec![name.to_string()]).unwrap();
+            params.distinguished_name.push(DnType::CommonName, name);
+            let key = KeyPair::generate().unwrap();
+            let cert = params.signed_by(&key, &self.cert, &self.key).unwrap();
+            Issued {
+                cert: cert.pem().into_bytes(),
+                key: key.serialize_pem().into_bytes(),
+                der: cert.der().to_vec(),
+            }
+        }
+    }
+
+    fn server_options(ca: &Ca) -> TlsServerOptions {
+        let localhost = ca.issue("localhost");
+        TlsServerOptions {
+            cert: localhost.cert,
+            key: localhost.key,
+            ..Default::default()
+        }
+    }
+
+    /// What both ends saw of one connection
+    #[derive(Debug, Default)]
+    struct Outcome {
+        /// What the server echoed back to the client
+        echoed: Vec<u8>,
+        /// Whether the client and the server saw an encrypted socket
+        encrypted: (bool, bool),
+        client_alpn: Option<String>,
+        /// The certificate the server sent, as DER
+        server_cert: Option<Vec<u8>>,
+        client_error: Option<String>,
+        /// The name the client asked for with SNI, as the server saw it
+        server_name: Option<String>,
+        server_alpn: Option<String>,
+        client_certs: usize,
+        server_error: Option<String>,
+    }
+
+    /// Starts an echo server with `server`, connects to it on `localhost`
+    /// with `client` and sends a hello
+    fn connect(server: TlsServerOptions, client: TlsConnectOptions) -> Outcome {
+        let outcome = Rc::new(RefCell::new(Outcome::default()));
+
+        let o = outcome.clone();
+        crate::run_test(move || {
+            let out = o.clone();
+            let server = Net::create_tls_server(server.clone(), move |socket| {
+                {
+                    let mut out = out.borrow_mut();
+                    out.server_name = socket.server_name();
+                    out.server_alpn = socket.alpn_protocol();
+                    out.client_certs = socket.peer_certificates().len();
+                    out.encrypted.1 = socket.is_encrypted();
+                }
+                let s = socket.clone();
+                socket.on_data(move |chunk| {
+                    s.write(chunk.into_bytes().unwrap());
+                });
+                let s = socket.clone();
+                socket.on_end(move |_| s.end());
+            })
+            .unwrap();
+            let out = o.clone();
+            server.on_error(move |e| out.borrow_mut().server_error = Some(format!("{:?}", e)));
+
+            let srv = server.clone();
+            let client = client.clone();
+            let out = o.clone();
+            server.listen("127.0.0.1:0", move |_| {
+                let port = srv.local_addr().unwrap().port();
+                let socket = Net::connect_tls("localhost", port, client.clone());
+
+                let (s, o) = (socket.clone(), out.clone());
+                socket.on_connect(move |_| {
+                    let mut o = o.borrow_mut();
+                    o.client_alpn = s.alpn_protocol();
+                    o.server_cert = s.peer_certificates().into_iter().next();
+                    o.encrypted.0 = s.is_encrypted();
+                    s.write("hello");
+                    s.end();
+                });
+                let o = out.clone();
+                socket.on_data(move |chunk| {
+                    let chunk = chunk.into_bytes().unwrap();
+                    o.borrow_mut().echoed.extend(chunk);
+                });
+                let o = out.clone();
+                socket.on_error(move |e| {
+                    if let Js::Error(e) = e {
+                        o.borrow_mut().client_error = Some(e.to_string());
+                    }
+                });
+                let srv = srv.clone();
+                socket.on_close(move |_| srv.close(|_| ()));
+            });
+        });
+
+        Rc::try_unwrap(outcome).unwrap().into_inner()
+    }
+
+    #[test]
+    fn handshake_with_custom_ca() {
+        let ca = Ca::new("test ca");
+        let client = TlsConnectOptions {
+            ca: Some(ca.pem()),
+            ..Default::default()
+        };
+        let outcome = connect(server_options(&ca), client);
+
+        assert_eq!(outcome.client_error, None);
+        assert_eq!(outcome.echoed, b"hello");
+        assert_eq!(outcome.server_name.as_deref(), Some("localhost"));
+        assert_eq!(outcome.encrypted, (true, true));
+    }
+
+    #[test]
+    fn server_signed_by_unknown_ca_is_rejected() {
+        let ca = Ca::new("test ca");
+        let other = Ca::new("other ca");
+
+        // Neither another CA nor the bundled roots know our CA
+        for roots in [Some(other.pem()), None] {
+            let client = TlsConnectOptions {
+                ca: roots,
+                ..Default::default()
+            };
+            let outcome = connect(server_options(&ca), client);
+
+            assert!(outcome.client_error.is_some());
+            assert!(outcome.echoed.is_empty());
+        }
+    }
+
+    #[test]
+    fn sni_picks_the_certificate() {
+        let ca = Ca::new("test ca");
+        let other = ca.issue("other.test");
+        let mut server = server_options(&ca);
+        server.sni = vec![("other.test".into(), other.cert.clone(), other.key)];
+
+        let client = TlsConnectOptions {
+            server_name: Some("other.test".into()),
+            ca: Some(ca.pem()),
+            ..Default::default()
+        };
+        let outcome = connect(server.clone(), client);
+        assert_eq!(outcome.client_error, None);
+        assert_eq!(outcome.server_name.as_deref(), Some("other.test"));
+        assert_eq!(outcome.server_cert, Some(other.der.clone()));
+
+        // A name the server doesn't know gets the default certificate, which
+        // isn't valid for that name
+        let client = TlsConnectOptions {
+            server_name: Some("unknown.test".into()),
+            ca: Some(ca.pem()),
+            ..Default::default()
+        };
+        let outcome = connect(server, client);
+        assert!(outcome.client_error.is_some());
+    }
+
+    #[test]
+    fn alpn_negotiates_a_protocol() {
+        let ca = Ca::new("test ca");
+        let mut server = server_options(&ca);
+        server.alpn_protocols = vec!["h2".into(), "echo".into()];
+
+        let client = TlsConnectOptions {
+            alpn_protocols: vec!["echo".into(), "http/1.1".into()],
+            ca: Some(ca.pem()),
+            ..Default::default()
+        };
+        let outcome = connect(server.clone(), client);
+        assert_eq!(outcome.client_alpn.as_deref(), Some("echo"));
+        assert_eq!(outcome.server_alpn.as_deref(), Some("echo"));
+        assert_eq!(outcome.echoed, b"hello");
+
+        // Nothing in common fails the handshake
+        let client = TlsConnectOptions {
+            alpn_protocols: vec!["nope".into()],
+            ca: Some(ca.pem()),
+            ..Default::default()
+        };
+        let outcome = connect(server, client);
+        assert!(outcome.client_error.is_some());
+        assert!(outcome.server_error.is_some());
+    }
+
+    #[test]
+    fn client_certificates() {
+        let ca = Ca::new("test ca");
+        let rogue = Ca::new("rogue ca");
+        let mut server = server_options(&ca);
+        server.ca = Some(ca.pem());
+        server.require_client_cert = true;
+
+        let client_options = |cert: Option<Issued>| TlsConnectOptions {
+            ca: Some(ca.pem()),
+            cert: cert.as_ref().map(|c| c.cert.clone()),
+            key: cert.map(|c| c.key),
+            ..Default::default()
+        };
+
+        let outcome = connect(server.clone(), client_options(Some(ca.issue("client"))));
+        assert_eq!(outcome.server_error, None);
+        assert_eq!(outcome.client_certs, 1);
+        assert_eq!(outcome.echoed, b"hello");
+
+        for cert in [None, Some(rogue.issue("client"))] {
+            let outcome = connect(server.clone(), client_options(cert));
+            assert!(outcome.server_error.is_some());
+            assert!(outcome.echoed.is_empty());
+        }
+    }
+
+    #[test]
+    fn https_server() {
+        let ca = Ca::new("test ca");
+        let result = Rc::new(RefCell::new(None));
+
+        let (r, opts, ca) = (result.clone(), server_options(&ca), ca.pem());
+        crate::run_test(move || {
+            let handler = |req: IncomingRequest, res: ServerResponse| {
+                assert!(res.socket().is_encrypted());
+                res.end(format!("secure {}", req.path));
+            };
+            let server = crate::Http::create_secure_server(opts.clone(), handler).unwrap();
+            let (s, r, ca) = (server.clone(), r.clone(), ca.clone());
+            server.listen("127.0.0.1:0", move |_| {
+                let port = s.local_addr().unwrap().port();
+                let mut opts = RequestOptions::get(format!("https://localhost:{}/x", port));
+                opts.tls.ca = Some(ca.clone());
+                let (s, r) = (s.clone(), r.clone());
+                http::request(opts, move |res| {
+                    *r.borrow_mut() = res.into_response();
+                    s.close(|_| ());
+                });
+            });
+        });
+
+        let res = result.borrow_mut().take().unwrap();
+        assert_eq!(res.text(), "secure /x");
+
+        // The certificate is checked right away
+        let handler = |_req: IncomingRequest, _res: ServerResponse| ();
+        match crate::Http::create_secure_server(Default::default(), handler) {
+            Err(e) => assert_eq!(e.kind(), io::ErrorKind::InvalidInput),
+            Ok(_) => panic!("expected an error"),
+        }
+    }
+}
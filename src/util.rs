@@ -0,0 +1,15 @@
+// ===== UTILITIES =====
+// Small helpers which more than one of our modules needs.
+
+use std::io;
+
+/// `N` random bytes from the kernel, for WebSocket keys and masks or DNS
+/// query ids
+pub(crate) fn random_bytes<const N: usize>() -> [u8; N] {
+    let mut buf = [0; N];
+    let n = unsafe { libc::getrandom(buf.as_mut_ptr() as *mut libc::c_void, N, 0) };
+    if n != N as isize {
+        panic!("getrandom failed: {}", io::Error::last_os_error());
+    }
+    buf
+}
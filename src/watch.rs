@@ -0,0 +1,587 @@
+// ===== FILE SYSTEM WATCHERS =====
+// `watch` is backed by inotify. The inotify file descriptor is registered with
+// our epoll instance just like a socket, so a change on disk is a normal epoll
+// event which we handle on the main thread. `watch_file` is the fallback which
+// works everywhere: it calls `stat` on the threadpool at a fixed interval and
+// compares the results.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ffi::{CString, OsStr};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::RawFd;
+use std::path::{Path, PathBuf};
+use std::rc::{Rc, Weak};
+
+use crate::poll::Interests;
+use crate::{print, Fs, Handle, Js, Runtime, Stats, RUNTIME};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FsEventKind {
+    /// A file was created, deleted or moved
+    Rename,
+    /// The content or the metadata of a file changed
+    Change,
+}
+
+impl fmt::Display for FsEventKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FsEventKind::Rename => write!(f, "rename"),
+            FsEventKind::Change => write!(f, "change"),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct WatchOptions {
+    /// Watch all subdirectories as well
+    pub recursive: bool,
+}
+
+const WATCH_MASK: u32 = libc::IN_ATTRIB
+    | libc::IN_CREATE
+    | libc::IN_MODIFY
+    | libc::IN_DELETE
+    | libc::IN_DELETE_SELF
+    | libc::IN_MOVE_SELF
+    | libc::IN_MOVED_FROM
+    | libc::IN_MOVED_TO;
+
+/// A handle to a running watcher. The watcher keeps the event loop alive
+/// until `close` is called.
+#[derive(Clone)]
+pub struct FsWatcher {
+    inner: Rc<RefCell<WatcherState>>,
+}
+
+struct WatcherState {
+    fd: RawFd,
+    token: usize,
+    root: PathBuf,
+    recursive: bool,
+    /// Maps a watch descriptor to the watched directory, relative to `root`
+    watches: HashMap<i32, PathBuf>,
+    closed: bool,
+    /// Our id in the runtime's list of handles
+    handle: usize,
+    listener: Option<Box<dyn FnMut(FsEventKind, PathBuf)>>,
+    on_error: Option<Box<dyn FnMut(Js)>>,
+}
+
+impl FsWatcher {
+    pub(crate) fn new(
+        path: &Path,
+        opts: WatchOptions,
+        listener: impl FnMut(FsEventKind, PathBuf) + 'static,
+    ) -> io::Result<Self> {
+        let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let rt = unsafe { &mut *RUNTIME };
+        let state = WatcherState {
+            fd,
+            token: rt.generate_cb_identity(),
+            root: path.to_path_buf(),
+            recursive: opts.recursive && path.is_dir(),
+            watches: HashMap::new(),
+            closed: false,
+            handle: 0,
+            listener: Some(Box::new(listener)),
+            on_error: None,
+        };
+
+        let watcher = FsWatcher {
+            inner: Rc::new(RefCell::new(state)),
+        };
+
+        let res = watcher.add_watch(PathBuf::new()).and_then(|_| {
+            rt.epoll_registrator
+                .register(fd, watcher.inner.borrow().token, Interests::READABLE)
+        });
+
+        if let Err(e) = res {
+            unsafe { libc::close(fd) };
+            return Err(e);
+        }
+
+        watcher.register(rt);
+        let handle = FsWatcherHandle(Rc::downgrade(&watcher.inner));
+        watcher.inner.borrow_mut().handle = rt.add_handle(Rc::new(handle));
+        Ok(watcher)
+    }
+
+    pub fn on_error(&self, cb: impl FnMut(Js) + 'static) {
+        self.inner.borrow_mut().on_error = Some(Box::new(cb));
+    }
+
+    /// Stops watching and lets the event loop exit if this was the last thing
+    /// keeping it alive
+    pub fn close(&self) {
+        let mut state = self.inner.borrow_mut();
+        if state.closed {
+            return;
+        }
+        state.closed = true;
+
+        let rt = unsafe { &mut *RUNTIME };
+        rt.deregister_event_epoll(state.token);
+        rt.remove_handle(state.handle);
+        // Closing the file descriptor removes it from the epoll instance as well
+        unsafe { libc::close(state.fd) };
+
+        // The listener might hold a handle to the watcher
+        state.listener = None;
+        state.on_error = None;
+    }
+
+    /// Adds a watch for `dir` (relative to the root), and for all directories
+    /// below it if we're watching recursively
+    fn add_watch(&self, dir: PathBuf) -> io::Result<()> {
+        let mut state = self.inner.borrow_mut();
+        let full_path = state.root.join(&dir);
+        let c_path = CString::new(full_path.as_os_str().as_bytes())?;
+
+        let wd = unsafe { libc::inotify_add_watch(state.fd, c_path.as_ptr(), WATCH_MASK) };
+        if wd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        state.watches.insert(wd, dir.clone());
+
+        if !state.recursive {
+            return Ok(());
+        }
+        drop(state);
+
+        for entry in fs::read_dir(&full_path)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                self.add_watch(dir.join(entry.file_name()))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn register(&self, rt: &mut Runtime) {
+        let token = self.inner.borrow().token;
+        let watcher = self.clone();
+        rt.register_event_epoll(token, move |_| watcher.on_readable());
+    }
+
+    fn on_readable(&self) {
+        if self.inner.borrow().closed {
+            return;
+        }
+
+        let res = self.read_events();
+
+        // The watcher could be closed from inside the listener
+        if self.inner.borrow().closed {
+            return;
+        }
+
+        let rt = unsafe { &mut *RUNTIME };
+        let res = res.and_then(|_| {
+            let state = self.inner.borrow();
+            rt.epoll_registrator
+                .reregister(state.fd, state.token, Interests::READABLE)
+        });
+
+        match res {
+            Ok(()) => self.register(rt),
+            Err(e) => {
+                let on_error = self.inner.borrow_mut().on_error.take();
+                self.close();
+                if let Some(mut cb) = on_error {
+                    cb(Js::Error(e));
+                }
+            }
+        }
+    }
+
+    /// Reads events until the inotify file descriptor would block
+    fn read_events(&self) -> io::Result<()> {
+        // Big enough for at least one event with the longest possible filename
+        let mut buffer = [0u8; 4096];
+        let header_len = mem::size_of::<libc::inotify_event>();
+
+        loop {
+            let fd = self.inner.borrow().fd;
+            let n =
+                unsafe { libc::read(fd, buffer.as_mut_ptr() as *mut libc::c_void, buffer.len()) };
+
+            if n < 0 {
+                let err = io::Error::last_os_error();
+                return match err.kind() {
+                    io::ErrorKind::WouldBlock => Ok(()),
+                    _ => Err(err),
+                };
+            }
+
+            let n = n as usize;
+            let mut offset = 0;
+            while offset + header_len <= n {
+                // The events in the buffer are not guaranteed to be aligned
+                let event: libc::inotify_event =
+                    unsafe { std::ptr::read_unaligned(buffer.as_ptr().add(offset) as *const _) };
+
+                let name_start = offset + header_len;
+                let name = &buffer[name_start..name_start + event.len as usize];
+                let name_len = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+                offset = name_start + event.len as usize;
+
+                self.handle_event(&event, OsStr::from_bytes(&name[..name_len]));
+
+                if self.inner.borrow().closed {
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    fn handle_event(&self, event: &libc::inotify_event, name: &OsStr) {
+        if event.mask & libc::IN_Q_OVERFLOW != 0 {
+            print("inotify event queue overflowed, events were lost");
+            return;
+        }
+
+        let dir = match self.inner.borrow().watches.get(&event.wd) {
+            Some(dir) => dir.clone(),
+            None => return,
+        };
+
+        // The kernel removed the watch, e.g. because the directory was deleted
+        if event.mask & libc::IN_IGNORED != 0 {
+            self.inner.borrow_mut().watches.remove(&event.wd);
+            return;
+        }
+
+        // Events on the watched file itself are reported with its own name
+        let filename = if name.is_empty() {
+            let state = self.inner.borrow();
+            match dir.file_name().or_else(|| state.root.file_name()) {
+                Some(name) => PathBuf::from(name),
+                None => return,
+            }
+        } else {
+            dir.join(name)
+        };
+
+        let is_new_dir = event.mask & libc::IN_ISDIR != 0
+            && event.mask & (libc::IN_CREATE | libc::IN_MOVED_TO) != 0;
+        if is_new_dir && self.inner.borrow().recursive {
+            // The directory might already be gone again, in which case we get
+            // the event for that later
+            let _ = self.add_watch(filename.clone());
+        }
+
+        // Same rule as libuv: anything but a modification is a rename
+        let kind = if event.mask & !(libc::IN_ATTRIB | libc::IN_MODIFY) != 0 {
+            FsEventKind::Rename
+        } else {
+            FsEventKind::Change
+        };
+
+        let listener = self.inner.borrow_mut().listener.take();
+        if let Some(mut cb) = listener {
+            cb(kind, filename);
+            let mut state = self.inner.borrow_mut();
+            if !state.closed && state.listener.is_none() {
+                state.listener = Some(cb);
+            }
+        }
+    }
+}
+
+/// Watchers only wait for things to happen, there is nothing to finish. A
+/// shutdown closes them right away.
+struct FsWatcherHandle(Weak<RefCell<WatcherState>>);
+
+impl Handle for FsWatcherHandle {
+    fn describe(&self) -> Option<String> {
+        let inner = self.0.upgrade()?;
+        let root = inner.borrow().root.display().to_string();
+        Some(format!("watcher for {}", root))
+    }
+
+    fn stop(&self) {
+        self.force_close();
+    }
+
+    fn force_close(&self) {
+        if let Some(inner) = self.0.upgrade() {
+            FsWatcher { inner }.close();
+        }
+    }
+}
+
+// ===== WATCH FILE =====
+
+pub struct WatchFileOptions {
+    /// How often we `stat` the file
+    pub interval_ms: u64,
+}
+
+impl Default for WatchFileOptions {
+    fn default() -> Self {
+        WatchFileOptions { interval_ms: 5007 }
+    }
+}
+
+/// A handle to a polling watcher. The watcher keeps the event loop alive
+/// until `stop` is called.
+#[derive(Clone)]
+pub struct StatWatcher {
+    inner: Rc<RefCell<StatWatcherState>>,
+}
+
+struct StatWatcherState {
+    path: PathBuf,
+    interval_ms: u64,
+    /// The result of the last `stat`. None until the first one has finished.
+    prev: Option<Stats>,
+    timer: Option<usize>,
+    stopped: bool,
+    /// Our id in the runtime's list of handles
+    handle: usize,
+    listener: Option<Box<dyn FnMut(Stats, Stats)>>,
+}
+
+impl StatWatcher {
+    pub(crate) fn new(
+        path: PathBuf,
+        opts: WatchFileOptions,
+        listener: impl FnMut(Stats, Stats) + 'static,
+    ) -> Self {
+        let state = StatWatcherState {
+            path,
+            interval_ms: opts.interval_ms,
+            prev: None,
+            timer: None,
+            stopped: false,
+            handle: 0,
+            listener: Some(Box::new(listener)),
+        };
+
+        let watcher = StatWatcher {
+            inner: Rc::new(RefCell::new(state)),
+        };
+
+        let rt = unsafe { &mut *RUNTIME };
+        let handle = StatWatcherHandle(Rc::downgrade(&watcher.inner));
+        watcher.inner.borrow_mut().handle = rt.add_handle(Rc::new(handle));
+
+        watcher.poll();
+        watcher
+    }
+
+    pub fn stop(&self) {
+        let mut state = self.inner.borrow_mut();
+        if state.stopped {
+            return;
+        }
+        state.stopped = true;
+        state.listener = None;
+
+        let rt = unsafe { &mut *RUNTIME };
+        rt.remove_handle(state.handle);
+
+        if let Some(timer) = state.timer.take() {
+            crate::clear_timeout(timer);
+        }
+    }
+
+    fn poll(&self) {
+        let path = self.inner.borrow().path.clone();
+        let watcher = self.clone();
+
+        Fs::stat(path, move |res| {
+            // A file which doesn't exist is reported as all zeroes just like
+            // in Node, so we notice when it's created
+            let curr = res.into_stats().unwrap_or_default();
+            watcher.on_stat(curr);
+        });
+    }
+
+    fn on_stat(&self, curr: Stats) {
+        let mut state = self.inner.borrow_mut();
+        if state.stopped {
+            return;
+        }
+
+        let prev = state.prev.replace(curr.clone());
+        if let Some(prev) = prev.filter(|prev| *prev != curr) {
+            let listener = state.listener.take();
+            drop(state);
+
+            if let Some(mut cb) = listener {
+                cb(curr, prev);
+                let mut state = self.inner.borrow_mut();
+                if !state.stopped && state.listener.is_none() {
+                    state.listener = Some(cb);
+                }
+            }
+        } else {
+            drop(state);
+        }
+
+        if self.inner.borrow().stopped {
+            return;
+        }
+
+        let interval_ms = self.inner.borrow().interval_ms;
+        let watcher = self.clone();
+        let timer = crate::set_timeout(interval_ms, move |_| {
+            watcher.inner.borrow_mut().timer = None;
+            watcher.poll();
+        });
+        self.inner.borrow_mut().timer = Some(timer);
+    }
+}
+
+struct StatWatcherHandle(Weak<RefCell<StatWatcherState>>);
+
+impl Handle for StatWatcherHandle {
+    fn describe(&self) -> Option<String> {
+        let inner = self.0.upgrade()?;
+        let path = inner.borrow().path.display().to_string();
+        Some(format!("polling watcher for {}", path))
+    }
+
+    fn stop(&self) {
+        self.force_close();
+    }
+
+    fn force_close(&self) {
+        if let Some(inner) = self.0.upgrade() {
+            StatWatcher { inner }.stop();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, SystemTime};
+
+    type Events = Rc<RefCell<Vec<(FsEventKind, PathBuf)>>>;
+
+    /// Watches `dir` and calls `on_event` with every event until it returns
+    /// true, then closes the watcher
+    fn watch_until(
+        dir: &Path,
+        opts: WatchOptions,
+        events: Events,
+        mut on_event: impl FnMut(&FsEventKind, &Path) -> bool + 'static,
+    ) -> FsWatcher {
+        let slot: Rc<RefCell<Option<FsWatcher>>> = Rc::new(RefCell::new(None));
+        let s = slot.clone();
+        let watcher = Fs::watch(dir, opts, move |kind, filename| {
+            let done = on_event(&kind, &filename);
+            events.borrow_mut().push((kind, filename));
+            if done {
+                s.borrow().as_ref().unwrap().close();
+            }
+        })
+        .unwrap();
+        *slot.borrow_mut() = Some(watcher.clone());
+        watcher
+    }
+
+    #[test]
+    fn watch_reports_changes_with_filename() {
+        let dir = crate::temp_path("watch");
+        fs::create_dir(&dir).unwrap();
+        let events: Events = Rc::new(RefCell::new(vec![]));
+
+        let (d, e) = (dir.clone(), events.clone());
+        crate::run_test(move || {
+            watch_until(&d, WatchOptions::default(), e.clone(), |kind, name| {
+                *kind == FsEventKind::Rename && name == Path::new("b.txt")
+            });
+            fs::write(d.join("a.txt"), "hello").unwrap();
+            fs::rename(d.join("a.txt"), d.join("b.txt")).unwrap();
+        });
+
+        let mut events = events.borrow().clone();
+        events.dedup();
+        let expected = [
+            (FsEventKind::Rename, PathBuf::from("a.txt")),
+            (FsEventKind::Change, PathBuf::from("a.txt")),
+            (FsEventKind::Rename, PathBuf::from("a.txt")),
+            (FsEventKind::Rename, PathBuf::from("b.txt")),
+        ];
+        assert_eq!(events, expected);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn recursive_watch_follows_new_directories() {
+        let dir = crate::temp_path("watch_recursive");
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        let events: Events = Rc::new(RefCell::new(vec![]));
+
+        let (d, e) = (dir.clone(), events.clone());
+        crate::run_test(move || {
+            let opts = WatchOptions { recursive: true };
+            let d2 = d.clone();
+            watch_until(&d, opts, e.clone(), move |_, name| {
+                // A directory created after we started is watched as well
+                if name == Path::new("new") {
+                    fs::write(d2.join("new").join("b.txt"), "").unwrap();
+                }
+                name == Path::new("new/b.txt")
+            });
+            fs::write(d.join("sub").join("a.txt"), "").unwrap();
+            fs::create_dir(d.join("new")).unwrap();
+        });
+
+        let events = events.borrow();
+        let names: Vec<_> = events.iter().map(|(_, name)| name.clone()).collect();
+        assert!(names.contains(&PathBuf::from("sub/a.txt")), "{:?}", names);
+        assert!(names.contains(&PathBuf::from("new")), "{:?}", names);
+        assert_eq!(names.last().unwrap(), Path::new("new/b.txt"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn watch_file_sees_a_new_mtime() {
+        let path = crate::temp_path("watch_file");
+        fs::write(&path, "hello").unwrap();
+        let changes = Rc::new(RefCell::new(vec![]));
+
+        let (p, c) = (path.clone(), changes.clone());
+        crate::run_test(move || {
+            let slot: Rc<RefCell<Option<StatWatcher>>> = Rc::new(RefCell::new(None));
+            let (s, c) = (slot.clone(), c.clone());
+            let opts = WatchFileOptions { interval_ms: 10 };
+            let watcher = Fs::watch_file(&p, opts, move |curr, prev| {
+                c.borrow_mut().push((curr.modified, prev.modified));
+                s.borrow().as_ref().unwrap().stop();
+            });
+            *slot.borrow_mut() = Some(watcher);
+
+            // Only the mtime changes, not the size
+            let p = p.clone();
+            crate::set_timeout(50, move |_| {
+                let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+                let file = fs::File::options().write(true).open(&p).unwrap();
+                file.set_modified(mtime).unwrap();
+            });
+        });
+
+        let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let changes = changes.borrow();
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].0, Some(mtime));
+        assert_ne!(changes[0].1, Some(mtime));
+        fs::remove_file(&path).unwrap();
+    }
+}
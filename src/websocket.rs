@@ -0,0 +1,1629 @@
+// ===== WEBSOCKET =====
+// WebSockets (RFC 6455) on top of our TCP sockets. A connection starts as an
+// HTTP request asking to upgrade, and once the server agrees both sides send
+// frames instead. A message can be split over several frames, and control
+// frames (ping, pong and close) can show up in between. Clients mask
+// everything they send, servers never do.
+//
+//     let server = Http::create_server(|_req, res| res.end("not a websocket"));
+//     let wss = WebSocketServer::new(&server, Default::default(), |ws, _req| {
+//         let w = ws.clone();
+//         ws.on_message(move |msg| w.send(msg.into_string().unwrap()).unwrap());
+//     });
+//     server.listen("127.0.0.1:8080", |_| ());
+//
+//     let ws = WebSocket::connect("ws://127.0.0.1:8080", Default::default())?;
+//     let w = ws.clone();
+//     ws.on_open(move |_| w.send("hello").unwrap());
+//     ws.on_message(|msg| print(format!("echo: {:?}", msg)));
+//
+// Closing is a handshake too: one side sends a close frame, the other answers
+// with one, and then the server closes the TCP connection.
+
+use std::cell::RefCell;
+use std::convert::TryInto;
+use std::io;
+use std::rc::Rc;
+
+use crate::http::{Headers, MessageKind, Parsed, Parser, StartLine, Url};
+use crate::http_server::{HttpServer, IncomingRequest};
+use crate::net::Socket;
+use crate::stream::{emit, Listeners};
+#[cfg(feature = "tls")]
+use crate::tls::TlsConnectOptions;
+use crate::util::random_bytes;
+use crate::{defer, print, Js};
+
+/// Every server hashes the client's key together with this to prove it
+/// understood the handshake
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Same default as the `ws` library
+const MAX_PAYLOAD: usize = 100 * 1024 * 1024;
+
+/// How long we wait for the other side to finish the closing handshake
+/// before we close the connection ourselves
+const CLOSE_TIMEOUT_MS: u64 = 30_000;
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xA;
+
+pub const CLOSE_NORMAL: u16 = 1000;
+pub const CLOSE_GOING_AWAY: u16 = 1001;
+pub const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+/// The close frame didn't have a code
+pub const CLOSE_NO_STATUS: u16 = 1005;
+/// The connection was closed without a closing handshake
+pub const CLOSE_ABNORMAL: u16 = 1006;
+pub const CLOSE_INVALID_DATA: u16 = 1007;
+pub const CLOSE_TOO_BIG: u16 = 1009;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+impl From<&str> for Message {
+    fn from(text: &str) -> Self {
+        Message::Text(text.to_string())
+    }
+}
+
+impl From<String> for Message {
+    fn from(text: String) -> Self {
+        Message::Text(text)
+    }
+}
+
+impl From<Vec<u8>> for Message {
+    fn from(data: Vec<u8>) -> Self {
+        Message::Binary(data)
+    }
+}
+
+impl From<&[u8]> for Message {
+    fn from(data: &[u8]) -> Self {
+        Message::Binary(data.to_vec())
+    }
+}
+
+/// Same as `readyState` in javascript
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadyState {
+    Connecting,
+    Open,
+    Closing,
+    Closed,
+}
+
+#[derive(Clone)]
+pub struct WebSocketOptions {
+    /// Subprotocols we offer, the server picks one of them
+    pub protocols: Vec<String>,
+    /// Extra headers for the handshake request
+    pub headers: Headers,
+    /// Messages bigger than this close the connection with 1009
+    pub max_payload: usize,
+    /// Messages we send are split into frames of this size, 0 means we send
+    /// every message as one frame
+    pub fragment_size: usize,
+    /// Used for `wss://` URLs
+    #[cfg(feature = "tls")]
+    pub tls: TlsConnectOptions,
+}
+
+impl Default for WebSocketOptions {
+    fn default() -> Self {
+        WebSocketOptions {
+            protocols: vec![],
+            headers: Headers::new(),
+            max_payload: MAX_PAYLOAD,
+            fragment_size: 0,
+            #[cfg(feature = "tls")]
+            tls: TlsConnectOptions::default(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct WebSocketServerOptions {
+    /// Subprotocols we support. We pick the first one the client offers
+    /// which is in this list.
+    pub protocols: Vec<String>,
+    pub max_payload: usize,
+    pub fragment_size: usize,
+}
+
+impl Default for WebSocketServerOptions {
+    fn default() -> Self {
+        WebSocketServerOptions {
+            protocols: vec![],
+            max_payload: MAX_PAYLOAD,
+            fragment_size: 0,
+        }
+    }
+}
+
+// ===== SERVER =====
+
+/// Accepts WebSocket connections on an HTTP server. Other requests are still
+/// handled by the server's handler.
+#[derive(Clone)]
+pub struct WebSocketServer {
+    clients: Rc<RefCell<Vec<WebSocket>>>,
+}
+
+impl WebSocketServer {
+    /// `on_connection` gets every new WebSocket along with the request which
+    /// opened it. The WebSocket is open already, so it can be used right away.
+    pub fn new(
+        server: &HttpServer,
+        opts: WebSocketServerOptions,
+        mut on_connection: impl FnMut(WebSocket, IncomingRequest) + 'static,
+    ) -> Self {
+        let clients: Rc<RefCell<Vec<WebSocket>>> = Rc::new(RefCell::new(vec![]));
+
+        let c = clients.clone();
+        server.on_upgrade(move |req, socket, head| {
+            let ws = match WebSocket::accept(&req, socket, &opts) {
+                Some(ws) => ws,
+                None => return,
+            };
+
+            c.borrow_mut().push(ws.clone());
+            let clients = c.clone();
+            let w = ws.clone();
+            ws.on_close(move |_, _| {
+                clients
+                    .borrow_mut()
+                    .retain(|c| !Rc::ptr_eq(&c.inner, &w.inner))
+            });
+
+            on_connection(ws.clone(), req);
+
+            // Frames sent right after the handshake wait until the listeners
+            // are in place
+            if !head.is_empty() {
+                defer(move |_| ws.on_data(head));
+            }
+        });
+
+        WebSocketServer { clients }
+    }
+
+    /// The connected clients
+    pub fn clients(&self) -> Vec<WebSocket> {
+        self.clients.borrow().clone()
+    }
+
+    /// Sends `msg` to every open client
+    pub fn broadcast(&self, msg: impl Into<Message>) {
+        let msg = msg.into();
+        for client in self.clients() {
+            if client.ready_state() == ReadyState::Open {
+                let _ = client.send(msg.clone());
+            }
+        }
+    }
+
+    /// Closes all clients with 1001 (going away)
+    pub fn close(&self) {
+        for client in self.clients() {
+            client.start_close(CLOSE_GOING_AWAY, "server shutting down");
+        }
+    }
+}
+
+// ===== WEBSOCKET =====
+
+#[derive(Clone)]
+pub struct WebSocket {
+    inner: Rc<RefCell<WebSocketState>>,
+}
+
+struct WebSocketState {
+    socket: Socket,
+    /// Clients mask what they send, and servers insist on it
+    client: bool,
+    ready_state: ReadyState,
+    /// The subprotocol the server picked, empty if none
+    protocol: String,
+    max_payload: usize,
+    fragment_size: usize,
+    /// The client's side of the handshake, `None` once it's done
+    handshake: Option<Handshake>,
+    /// Received data which isn't a complete frame yet
+    buffer: Vec<u8>,
+    /// The opcode and the data so far of a message which arrives in
+    /// fragments
+    fragments: Option<(u8, Vec<u8>)>,
+    /// The code and reason the connection is closing with. Once it's set we
+    /// don't read any more frames.
+    close_status: Option<(u16, String)>,
+    close_sent: bool,
+    close_timer: Option<usize>,
+    /// We only report the first thing that went wrong
+    errored: bool,
+    on_open: Listeners,
+    on_message: Listeners,
+    on_ping: Listeners,
+    on_pong: Listeners,
+    on_error: Listeners,
+    on_close: Vec<Box<dyn FnOnce(u16, String)>>,
+}
+
+struct Handshake {
+    parser: Parser,
+    key: String,
+    protocols: Vec<String>,
+}
+
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+/// Something the other side did wrong. The connection is closed with `code`.
+struct Violation {
+    code: u16,
+    msg: &'static str,
+}
+
+impl WebSocket {
+    /// Opens a connection to a `ws://` URL, or with the `tls` feature to a
+    /// `wss://` URL. The handshake happens in the background: `open` is
+    /// emitted once it's done, or `error` and `close` if it fails. Only an
+    /// invalid URL is an error right away.
+    pub fn connect(url: &str, opts: WebSocketOptions) -> io::Result<WebSocket> {
+        let url = Url::parse(url)?;
+        let secure = cfg!(feature = "tls") && url.scheme == "wss";
+        if url.scheme != "ws" && !secure {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("protocol not supported: {}", url.scheme),
+            ));
+        }
+
+        let key = base64(&random_bytes::<16>());
+        let mut head = format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+             Sec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\n",
+            url.path,
+            url.host_header(),
+            key
+        );
+        if !opts.protocols.is_empty() {
+            head.push_str(&format!(
+                "Sec-WebSocket-Protocol: {}\r\n",
+                opts.protocols.join(", ")
+            ));
+        }
+        for (name, value) in opts.headers.iter() {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str("\r\n");
+
+        #[cfg(feature = "tls")]
+        let socket = crate::http::connect(&url, &opts.tls);
+        #[cfg(not(feature = "tls"))]
+        let socket = crate::net::Net::connect((url.host.as_str(), url.port));
+        let handshake = Handshake {
+            parser: Parser::new(MessageKind::Response),
+            key,
+            protocols: opts.protocols,
+        };
+        let ws = WebSocket::new(socket.clone(), true, opts.max_payload, opts.fragment_size);
+        ws.inner.borrow_mut().handshake = Some(handshake);
+        socket.write(head);
+        Ok(ws)
+    }
+
+    /// Completes the server side of the handshake, or answers with an error
+    /// response if the request isn't a valid WebSocket handshake
+    fn accept(
+        req: &IncomingRequest,
+        socket: Socket,
+        opts: &WebSocketServerOptions,
+    ) -> Option<WebSocket> {
+        let headers = &req.headers;
+        let key = headers.get("Sec-WebSocket-Key").unwrap_or_default();
+        let rejection = if req.method != "GET" {
+            Some("405 Method Not Allowed")
+        } else if !headers.has_token("Upgrade", "websocket") {
+            Some("400 Bad Request")
+        } else if headers.get("Sec-WebSocket-Version") != Some("13") {
+            Some("426 Upgrade Required\r\nSec-WebSocket-Version: 13")
+        } else if !is_valid_key(key) {
+            Some("400 Bad Request")
+        } else {
+            None
+        };
+
+        if let Some(status) = rejection {
+            print(format!("WebSocket handshake rejected: {}", req.url));
+            socket.write(format!(
+                "HTTP/1.1 {}\r\nConnection: close\r\nContent-Length: 0\r\n\r\n",
+                status
+            ));
+            socket.end();
+            return None;
+        }
+
+        let protocol = headers
+            .get_all("Sec-WebSocket-Protocol")
+            .flat_map(|p| p.split(','))
+            .map(str::trim)
+            .find(|p| opts.protocols.iter().any(|ours| ours == p))
+            .unwrap_or_default()
+            .to_string();
+
+        let mut head = format!(
+            "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+             Sec-WebSocket-Accept: {}\r\n",
+            accept_key(key)
+        );
+        if !protocol.is_empty() {
+            head.push_str(&format!("Sec-WebSocket-Protocol: {}\r\n", protocol));
+        }
+        head.push_str("\r\n");
+        socket.write(head);
+
+        // The HTTP server lets clients end their side and wait for a
+        // response, but once a WebSocket is closed it's closed both ways
+        socket.set_allow_half_open(false);
+        let ws = WebSocket::new(socket, false, opts.max_payload, opts.fragment_size);
+        {
+            let mut state = ws.inner.borrow_mut();
+            state.ready_state = ReadyState::Open;
+            state.protocol = protocol;
+        }
+        Some(ws)
+    }
+
+    fn new(socket: Socket, client: bool, max_payload: usize, fragment_size: usize) -> Self {
+        let state = WebSocketState {
+            socket: socket.clone(),
+            client,
+            ready_state: ReadyState::Connecting,
+            protocol: String::new(),
+            max_payload,
+            fragment_size,
+            handshake: None,
+            buffer: vec![],
+            fragments: None,
+            close_status: None,
+            close_sent: false,
+            close_timer: None,
+            errored: false,
+            on_open: Listeners::default(),
+            on_message: Listeners::default(),
+            on_ping: Listeners::default(),
+            on_pong: Listeners::default(),
+            on_error: Listeners::default(),
+            on_close: vec![],
+        };
+        let ws = WebSocket {
+            inner: Rc::new(RefCell::new(state)),
+        };
+
+        let w = ws.clone();
+        socket.on_data(move |chunk| w.on_data(chunk.into_bytes().unwrap()));
+        let w = ws.clone();
+        socket.on_error(move |err| w.emit_error(err));
+        let w = ws.clone();
+        socket.on_close(move |_| w.on_socket_close());
+        ws
+    }
+
+    /// `Js::String` for text messages and `Js::Bytes` for binary ones
+    pub fn on_message(&self, cb: impl FnMut(Js) + 'static) {
+        self.inner.borrow_mut().on_message.add(cb);
+    }
+
+    /// Only emitted by clients, server side WebSockets are open from the
+    /// start
+    pub fn on_open(&self, cb: impl FnMut(Js) + 'static) {
+        self.inner.borrow_mut().on_open.add(cb);
+    }
+
+    /// Gets the payload as `Js::Bytes`. We answer pings with a pong
+    /// ourselves.
+    pub fn on_ping(&self, cb: impl FnMut(Js) + 'static) {
+        self.inner.borrow_mut().on_ping.add(cb);
+    }
+
+    pub fn on_pong(&self, cb: impl FnMut(Js) + 'static) {
+        self.inner.borrow_mut().on_pong.add(cb);
+    }
+
+    pub fn on_error(&self, cb: impl FnMut(Js) + 'static) {
+        self.inner.borrow_mut().on_error.add(cb);
+    }
+
+    /// Called with the close code and reason once the connection is closed.
+    /// The code is `CLOSE_ABNORMAL` if it was closed without a closing
+    /// handshake.
+    pub fn on_close(&self, cb: impl FnOnce(u16, String) + 'static) {
+        self.inner.borrow_mut().on_close.push(Box::new(cb));
+    }
+
+    pub fn ready_state(&self) -> ReadyState {
+        self.inner.borrow().ready_state
+    }
+
+    /// The subprotocol the server picked, empty if none
+    pub fn protocol(&self) -> String {
+        self.inner.borrow().protocol.clone()
+    }
+
+    pub fn send(&self, msg: impl Into<Message>) -> io::Result<()> {
+        self.check_open()?;
+        let (opcode, data) = match msg.into() {
+            Message::Text(text) => (OP_TEXT, text.into_bytes()),
+            Message::Binary(data) => (OP_BINARY, data),
+        };
+
+        let fragment_size = match self.inner.borrow().fragment_size {
+            0 => data.len().max(1),
+            n => n,
+        };
+        if data.is_empty() {
+            self.write_frame(true, opcode, &[]);
+        }
+        for (i, chunk) in data.chunks(fragment_size).enumerate() {
+            let fin = (i + 1) * fragment_size >= data.len();
+            let opcode = if i == 0 { opcode } else { OP_CONTINUATION };
+            self.write_frame(fin, opcode, chunk);
+        }
+        Ok(())
+    }
+
+    pub fn ping(&self, data: impl Into<Vec<u8>>) -> io::Result<()> {
+        self.send_control(OP_PING, data.into())
+    }
+
+    /// Pongs are sent for pings automatically, but an unsolicited pong works
+    /// as a one way heartbeat
+    pub fn pong(&self, data: impl Into<Vec<u8>>) -> io::Result<()> {
+        self.send_control(OP_PONG, data.into())
+    }
+
+    /// Starts the closing handshake. `code` is `CLOSE_NORMAL` or an
+    /// application code between 3000 and 4999.
+    pub fn close(&self, code: u16, reason: &str) -> io::Result<()> {
+        if code != CLOSE_NORMAL && !(3000..5000).contains(&code) {
+            let msg = format!("invalid close code: {}", code);
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        }
+        // The payload of a control frame is at most 125 bytes
+        if reason.len() > 123 {
+            let msg = "close reason is longer than 123 bytes";
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        }
+
+        self.start_close(code, reason);
+        Ok(())
+    }
+
+    /// `close` without checking the code, so we can use the ones reserved
+    /// for the protocol like `CLOSE_GOING_AWAY`
+    fn start_close(&self, code: u16, reason: &str) {
+        match self.ready_state() {
+            ReadyState::Connecting => self.terminate(),
+            ReadyState::Open => self.send_close(code, reason),
+            ReadyState::Closing | ReadyState::Closed => (),
+        }
+    }
+
+    /// Closes the connection right away, without a closing handshake
+    pub fn terminate(&self) {
+        let socket = self.inner.borrow().socket.clone();
+        socket.destroy();
+    }
+
+    fn check_open(&self) -> io::Result<()> {
+        match self.ready_state() {
+            ReadyState::Open => Ok(()),
+            _ => Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "WebSocket is not open",
+            )),
+        }
+    }
+
+    fn send_control(&self, opcode: u8, data: Vec<u8>) -> io::Result<()> {
+        self.check_open()?;
+        if data.len() > 125 {
+            let msg = "control frame payload is longer than 125 bytes";
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        }
+        self.write_frame(true, opcode, &data);
+        Ok(())
+    }
+
+    fn write_frame(&self, fin: bool, opcode: u8, payload: &[u8]) {
+        let state = self.inner.borrow();
+        let frame = encode_frame(fin, opcode, payload, state.client);
+        let socket = state.socket.clone();
+        drop(state);
+        socket.write(frame);
+    }
+
+    /// Sends our close frame and waits for the other side to finish the
+    /// handshake
+    fn send_close(&self, code: u16, reason: &str) {
+        {
+            let mut state = self.inner.borrow_mut();
+            if state.close_sent {
+                return;
+            }
+            state.close_sent = true;
+            state.ready_state = ReadyState::Closing;
+        }
+
+        let mut payload = vec![];
+        if code != CLOSE_NO_STATUS {
+            payload.extend_from_slice(&code.to_be_bytes());
+            payload.extend_from_slice(reason.as_bytes());
+        }
+        self.write_frame(true, OP_CLOSE, &payload);
+
+        let w = self.clone();
+        let timer = crate::set_timeout(CLOSE_TIMEOUT_MS, move |_| {
+            w.inner.borrow_mut().close_timer = None;
+            w.terminate();
+        });
+        self.inner.borrow_mut().close_timer = Some(timer);
+    }
+
+    fn on_data(&self, chunk: Vec<u8>) {
+        if self.inner.borrow().handshake.is_some() {
+            return self.on_handshake_data(&chunk);
+        }
+        self.inner.borrow_mut().buffer.extend_from_slice(&chunk);
+
+        loop {
+            let res = {
+                let mut state = self.inner.borrow_mut();
+                if state.close_status.is_some() {
+                    // Anything after the close frame is ignored
+                    state.buffer.clear();
+                    return;
+                }
+                let state = &mut *state;
+                parse_frame(&mut state.buffer, !state.client, state.max_payload)
+            };
+
+            match res {
+                Ok(Some(frame)) => self.on_frame(frame),
+                Ok(None) => return,
+                Err(violation) => return self.fail(violation),
+            }
+        }
+    }
+
+    fn on_handshake_data(&self, chunk: &[u8]) {
+        let res = {
+            let mut state = self.inner.borrow_mut();
+            let handshake = state.handshake.as_mut().unwrap();
+            handshake.parser.feed(chunk)
+        };
+
+        let head = match res {
+            Ok(parsed) => match parsed.into_iter().next() {
+                Some(Parsed::Head(head)) => head,
+                _ => return,
+            },
+            Err(e) => return self.fail_handshake(e.to_string()),
+        };
+
+        let mut handshake = self.inner.borrow_mut().handshake.take().unwrap();
+        let headers = &head.headers;
+        let protocol = headers.get("Sec-WebSocket-Protocol").unwrap_or_default();
+        let error = match head.start {
+            StartLine::Response { status, .. } if status != 101 => {
+                Some(format!("unexpected server response: {}", status))
+            }
+            _ if !headers.has_token("Upgrade", "websocket") => {
+                Some("invalid Upgrade header".to_string())
+            }
+            _ if headers.get("Sec-WebSocket-Accept")
+                != Some(accept_key(&handshake.key).as_str()) =>
+            {
+                Some("invalid Sec-WebSocket-Accept header".to_string())
+            }
+            _ if !protocol.is_empty() && !handshake.protocols.iter().any(|p| p == protocol) => {
+                Some(format!(
+                    "server picked a protocol we didn't offer: {}",
+                    protocol
+                ))
+            }
+            _ if headers.has("Sec-WebSocket-Extensions") => {
+                Some("server sent extensions we didn't ask for".to_string())
+            }
+            _ => None,
+        };
+        if let Some(error) = error {
+            return self.fail_handshake(error);
+        }
+
+        {
+            let mut state = self.inner.borrow_mut();
+            state.ready_state = ReadyState::Open;
+            state.protocol = protocol.to_string();
+        }
+        let rest = handshake.parser.take_buffer();
+        emit(&self.inner, |s| &mut s.on_open, Js::Undefined);
+        if !rest.is_empty() {
+            self.on_data(rest);
+        }
+    }
+
+    fn fail_handshake(&self, msg: String) {
+        let err = io::Error::new(io::ErrorKind::InvalidData, msg);
+        self.emit_error(Js::Error(err));
+        self.terminate();
+    }
+
+    fn on_frame(&self, frame: Frame) {
+        match frame.opcode {
+            OP_TEXT | OP_BINARY => {
+                let mut state = self.inner.borrow_mut();
+                if state.fragments.is_some() {
+                    drop(state);
+                    return self.fail(Violation {
+                        code: CLOSE_PROTOCOL_ERROR,
+                        msg: "expected a continuation frame",
+                    });
+                }
+                if !frame.fin {
+                    state.fragments = Some((frame.opcode, frame.payload));
+                    return;
+                }
+                drop(state);
+                self.deliver(frame.opcode, frame.payload);
+            }
+            OP_CONTINUATION => {
+                let mut state = self.inner.borrow_mut();
+                let max_payload = state.max_payload;
+                let (opcode, data) = match state.fragments.as_mut() {
+                    Some(fragments) => fragments,
+                    None => {
+                        drop(state);
+                        return self.fail(Violation {
+                            code: CLOSE_PROTOCOL_ERROR,
+                            msg: "unexpected continuation frame",
+                        });
+                    }
+                };
+                data.extend_from_slice(&frame.payload);
+                if data.len() > max_payload {
+                    drop(state);
+                    return self.fail(Violation {
+                        code: CLOSE_TOO_BIG,
+                        msg: "message too big",
+                    });
+                }
+                if frame.fin {
+                    let opcode = *opcode;
+                    let data = state.fragments.take().unwrap().1;
+                    drop(state);
+                    self.deliver(opcode, data);
+                }
+            }
+            OP_PING => {
+                if self.ready_state() == ReadyState::Open {
+                    self.write_frame(true, OP_PONG, &frame.payload);
+                }
+                emit(&self.inner, |s| &mut s.on_ping, Js::Bytes(frame.payload));
+            }
+            OP_PONG => emit(&self.inner, |s| &mut s.on_pong, Js::Bytes(frame.payload)),
+            OP_CLOSE => self.on_close_frame(frame.payload),
+            _ => unreachable!("parse_frame checks the opcode"),
+        }
+    }
+
+    fn deliver(&self, opcode: u8, data: Vec<u8>) {
+        let msg = if opcode == OP_TEXT {
+            match String::from_utf8(data) {
+                Ok(text) => Js::String(text),
+                Err(_) => {
+                    return self.fail(Violation {
+                        code: CLOSE_INVALID_DATA,
+                        msg: "invalid UTF-8 in a text message",
+                    })
+                }
+            }
+        } else {
+            Js::Bytes(data)
+        };
+        emit(&self.inner, |s| &mut s.on_message, msg);
+    }
+
+    fn on_close_frame(&self, payload: Vec<u8>) {
+        let (code, reason) = match payload.len() {
+            0 => (CLOSE_NO_STATUS, String::new()),
+            1 => {
+                return self.fail(Violation {
+                    code: CLOSE_PROTOCOL_ERROR,
+                    msg: "invalid close frame",
+                })
+            }
+            _ => {
+                let code = u16::from_be_bytes([payload[0], payload[1]]);
+                if !is_valid_close_code(code) {
+                    return self.fail(Violation {
+                        code: CLOSE_PROTOCOL_ERROR,
+                        msg: "invalid close code",
+                    });
+                }
+                match String::from_utf8(payload[2..].to_vec()) {
+                    Ok(reason) => (code, reason),
+                    Err(_) => {
+                        return self.fail(Violation {
+                            code: CLOSE_INVALID_DATA,
+                            msg: "invalid UTF-8 in the close reason",
+                        })
+                    }
+                }
+            }
+        };
+
+        let client = {
+            let mut state = self.inner.borrow_mut();
+            state.close_status = Some((code, reason));
+            state.client
+        };
+        // We answer with the same code, unless we have sent our close frame
+        // already and this is the answer
+        self.send_close(code, "");
+        // The server closes the TCP connection once both sides have sent
+        // their close frame. The client waits for that.
+        if !client {
+            self.inner.borrow().socket.clone().end();
+        }
+    }
+
+    /// Fails the connection because the other side broke the protocol
+    fn fail(&self, violation: Violation) {
+        {
+            let mut state = self.inner.borrow_mut();
+            if state.close_status.is_some() {
+                return;
+            }
+            let reason = violation.msg.to_string();
+            state.close_status = Some((violation.code, reason));
+        }
+
+        let err = io::Error::new(io::ErrorKind::InvalidData, violation.msg);
+        self.emit_error(Js::Error(err));
+        self.send_close(violation.code, violation.msg);
+        let socket = self.inner.borrow().socket.clone();
+        socket.end();
+    }
+
+    fn emit_error(&self, err: Js) {
+        let errored = std::mem::replace(&mut self.inner.borrow_mut().errored, true);
+        if !errored {
+            emit(&self.inner, |s| &mut s.on_error, err);
+        }
+    }
+
+    fn on_socket_close(&self) {
+        let (status, listeners, was_connecting) = {
+            let mut state = self.inner.borrow_mut();
+            if let Some(timer) = state.close_timer.take() {
+                crate::clear_timeout(timer);
+            }
+            let was_connecting = state.ready_state == ReadyState::Connecting;
+            state.ready_state = ReadyState::Closed;
+            state.handshake = None;
+            state.buffer.clear();
+            state.fragments = None;
+            // We only trust the status if both sides sent a close frame
+            let status = match state.close_status.take() {
+                Some(status) if state.close_sent => status,
+                _ => (CLOSE_ABNORMAL, String::new()),
+            };
+            (status, std::mem::take(&mut state.on_close), was_connecting)
+        };
+
+        if was_connecting {
+            let err = io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "connection closed before the handshake was done",
+            );
+            self.emit_error(Js::Error(err));
+        }
+
+        let (code, reason) = status;
+        for cb in listeners {
+            cb(code, reason.clone());
+        }
+
+        // The listeners usually hold handles to this WebSocket
+        let mut state = self.inner.borrow_mut();
+        state.on_open = Listeners::default();
+        state.on_message = Listeners::default();
+        state.on_ping = Listeners::default();
+        state.on_pong = Listeners::default();
+        state.on_error = Listeners::default();
+    }
+}
+
+// ===== FRAMES =====
+
+/// Takes the next complete frame off the front of `buf`
+fn parse_frame(
+    buf: &mut Vec<u8>,
+    expect_masked: bool,
+    max_payload: usize,
+) -> Result<Option<Frame>, Violation> {
+    let protocol_error = |msg| {
+        Err(Violation {
+            code: CLOSE_PROTOCOL_ERROR,
+            msg,
+        })
+    };
+
+    if buf.len() < 2 {
+        return Ok(None);
+    }
+    let fin = buf[0] & 0x80 != 0;
+    let opcode = buf[0] & 0x0F;
+    let masked = buf[1] & 0x80 != 0;
+
+    // The reserved bits are for extensions, and we don't do any
+    if buf[0] & 0x70 != 0 {
+        return protocol_error("reserved bits are set");
+    }
+    if !matches!(
+        opcode,
+        OP_CONTINUATION | OP_TEXT | OP_BINARY | OP_CLOSE | OP_PING | OP_PONG
+    ) {
+        return protocol_error("unknown opcode");
+    }
+    if masked != expect_masked {
+        return protocol_error(if expect_masked {
+            "frames from clients must be masked"
+        } else {
+            "frames from servers must not be masked"
+        });
+    }
+    let control = opcode & 0x08 != 0;
+    if control && (!fin || buf[1] & 0x7F > 125) {
+        return protocol_error("invalid control frame");
+    }
+
+    let (len, mut pos) = match buf[1] & 0x7F {
+        126 if buf.len() < 4 => return Ok(None),
+        126 => (u16::from_be_bytes([buf[2], buf[3]]) as u64, 4),
+        127 if buf.len() < 10 => return Ok(None),
+        127 => (u64::from_be_bytes(buf[2..10].try_into().unwrap()), 10),
+        n => (n as u64, 2),
+    };
+    if len > max_payload as u64 {
+        return Err(Violation {
+            code: CLOSE_TOO_BIG,
+            msg: "message too big",
+        });
+    }
+    let len = len as usize;
+
+    let mask = if masked {
+        if buf.len() < pos + 4 {
+            return Ok(None);
+        }
+        pos += 4;
+        Some([buf[pos - 4], buf[pos - 3], buf[pos - 2], buf[pos - 1]])
+    } else {
+        None
+    };
+    if buf.len() < pos + len {
+        return Ok(None);
+    }
+
+    let mut payload: Vec<u8> = buf.drain(..pos + len).skip(pos).collect();
+    if let Some(mask) = mask {
+        apply_mask(&mut payload, mask);
+    }
+    Ok(Some(Frame {
+        fin,
+        opcode,
+        payload,
+    }))
+}
+
+fn encode_frame(fin: bool, opcode: u8, payload: &[u8], masked: bool) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(payload.len() + 14);
+    frame.push(if fin { 0x80 } else { 0 } | opcode);
+
+    let mask_bit = if masked { 0x80 } else { 0 };
+    match payload.len() {
+        n if n <= 125 => frame.push(mask_bit | n as u8),
+        n if n <= u16::MAX as usize => {
+            frame.push(mask_bit | 126);
+            frame.extend_from_slice(&(n as u16).to_be_bytes());
+        }
+        n => {
+            frame.push(mask_bit | 127);
+            frame.extend_from_slice(&(n as u64).to_be_bytes());
+        }
+    }
+
+    if masked {
+        // The mask has to be unpredictable, so a script in a browser can't
+        // make us send bytes of its choosing to a proxy which doesn't
+        // understand WebSockets
+        let mask = random_bytes::<4>();
+        frame.extend_from_slice(&mask);
+        let start = frame.len();
+        frame.extend_from_slice(payload);
+        apply_mask(&mut frame[start..], mask);
+    } else {
+        frame.extend_from_slice(payload);
+    }
+    frame
+}
+
+fn apply_mask(data: &mut [u8], mask: [u8; 4]) {
+    for (i, b) in data.iter_mut().enumerate() {
+        *b ^= mask[i % 4];
+    }
+}
+
+fn is_valid_close_code(code: u16) -> bool {
+    matches!(code, 1000..=1003 | 1007..=1014 | 3000..=4999)
+}
+
+// ===== HANDSHAKE =====
+
+/// The client's key is 16 random bytes in base64
+fn is_valid_key(key: &str) -> bool {
+    key.len() == 24
+        && key.ends_with("==")
+        && key[..22]
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'+' || b == b'/')
+}
+
+/// What the server answers with in `Sec-WebSocket-Accept`
+fn accept_key(key: &str) -> String {
+    base64(&sha1(format!("{}{}", key, GUID).as_bytes()))
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let b = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// SHA-1 is broken for signatures, but the handshake only uses it to show
+/// the server understood the request
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+
+    let mut msg = data.to_vec();
+    msg.push(0x80);
+    while msg.len() % 64 != 56 {
+        msg.push(0);
+    }
+    msg.extend_from_slice(&(data.len() as u64 * 8).to_be_bytes());
+
+    for block in msg.chunks(64) {
+        let mut w = [0u32; 80];
+        for (i, word) in block.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes(word.try_into().unwrap());
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5A827999),
+                20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _ => (b ^ c ^ d, 0xCA62C1D6),
+            };
+            let temp = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(*word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+
+        for (h, v) in h.iter_mut().zip([a, b, c, d, e]) {
+            *h = h.wrapping_add(v);
+        }
+    }
+
+    let mut out = [0; 20];
+    for (chunk, v) in out.chunks_mut(4).zip(h) {
+        chunk.copy_from_slice(&v.to_be_bytes());
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::Net;
+    use std::net::SocketAddr;
+
+    type Events = Rc<RefCell<Vec<String>>>;
+
+    /// Runs an HTTP server with a WebSocket server on it until everything is
+    /// closed. `f` gets the URL once it's listening, the WebSocket server and
+    /// a callback which closes the HTTP server.
+    fn with_server(
+        opts: WebSocketServerOptions,
+        on_connection: impl FnMut(WebSocket, IncomingRequest) + Clone + 'static,
+        f: impl Fn(String, WebSocketServer, Rc<dyn Fn()>) + 'static,
+    ) {
+        let f = Rc::new(f);
+        crate::run_test(move || {
+            let server = HttpServer::new(|_req, res| res.end("not a websocket"));
+            let wss = WebSocketServer::new(&server, opts.clone(), on_connection.clone());
+            let (s, f) = (server.clone(), f.clone());
+            server.listen("127.0.0.1:0", move |_| {
+                let url = format!("ws://{}/chat", s.local_addr().unwrap());
+                let s = s.clone();
+                f(url, wss, Rc::new(move || s.close(|_| ())));
+            });
+        });
+    }
+
+    /// A server which echoes every message and logs what happens to `events`
+    fn echo(events: &Events) -> impl FnMut(WebSocket, IncomingRequest) + Clone + 'static {
+        let events = events.clone();
+        move |ws: WebSocket, req: IncomingRequest| {
+            let e = events.clone();
+            e.borrow_mut().push(format!("connection {}", req.url));
+            let w = ws.clone();
+            ws.on_message(move |msg| {
+                e.borrow_mut().push(format!("message {:?}", msg));
+                let msg = match msg {
+                    Js::String(text) => Message::from(text),
+                    msg => Message::from(msg.into_bytes().unwrap()),
+                };
+                w.send(msg).unwrap();
+            });
+            let e = events.clone();
+            ws.on_ping(move |data| e.borrow_mut().push(format!("ping {:?}", data)));
+            let e = events.clone();
+            ws.on_pong(move |data| e.borrow_mut().push(format!("pong {:?}", data)));
+            let e = events.clone();
+            ws.on_error(move |err| e.borrow_mut().push(format!("error {:?}", err)));
+            let e = events.clone();
+            ws.on_close(move |code, reason| {
+                e.borrow_mut().push(format!("close {} {:?}", code, reason))
+            });
+        }
+    }
+
+    /// Connects a plain socket to `addr` and does the handshake by hand, so
+    /// we can send frames a `WebSocket` never would. Once the handshake is
+    /// done `frames` are sent as they are, `on_frame` gets the opcode and
+    /// payload of every frame the server sends and `on_end` is called when
+    /// the server closes the connection.
+    fn raw_client(
+        addr: SocketAddr,
+        frames: Vec<Vec<u8>>,
+        mut on_frame: impl FnMut(u8, Vec<u8>) + 'static,
+        on_end: impl FnOnce() + 'static,
+    ) {
+        let socket = Net::connect(addr);
+        socket.write(
+            "GET / HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n\
+             Connection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+             Sec-WebSocket-Version: 13\r\n\r\n",
+        );
+
+        let mut received = vec![];
+        let mut upgraded = false;
+        let s = socket.clone();
+        socket.on_data(move |chunk| {
+            received.extend(chunk.into_bytes().unwrap());
+            if !upgraded {
+                let end = match received.windows(4).position(|w| w == b"\r\n\r\n") {
+                    Some(i) => i + 4,
+                    None => return,
+                };
+                assert!(received.starts_with(b"HTTP/1.1 101 "));
+                received.drain(..end);
+                upgraded = true;
+                for frame in &frames {
+                    s.write(frame.clone());
+                }
+            }
+            while let Ok(Some(frame)) = parse_frame(&mut received, false, MAX_PAYLOAD) {
+                on_frame(frame.opcode, frame.payload);
+            }
+        });
+        let mut on_end = Some(on_end);
+        socket.on_end(move |_| on_end.take().unwrap()());
+    }
+
+    fn close_payload(code: u16, reason: &str) -> Vec<u8> {
+        let mut payload = code.to_be_bytes().to_vec();
+        payload.extend_from_slice(reason.as_bytes());
+        payload
+    }
+
+    /// The code and message `parse_frame` fails with. Panics if it doesn't
+    /// fail.
+    fn violation(mut buf: Vec<u8>, expect_masked: bool) -> (u16, &'static str) {
+        match parse_frame(&mut buf, expect_masked, 1000) {
+            Err(Violation { code, msg }) => (code, msg),
+            Ok(_) => panic!("expected a violation"),
+        }
+    }
+
+    #[test]
+    fn frame_lengths() {
+        // The length takes 7, 7 + 16 or 7 + 64 bits
+        for (len, header) in [(0, 2), (125, 2), (126, 4), (65535, 4), (65536, 10)] {
+            let payload: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            for masked in [false, true] {
+                let mut buf = encode_frame(true, OP_BINARY, &payload, masked);
+                let mask_len = if masked { 4 } else { 0 };
+                assert_eq!(buf.len(), header + mask_len + len, "{}", len);
+
+                let frame = parse_frame(&mut buf, masked, MAX_PAYLOAD)
+                    .ok()
+                    .unwrap()
+                    .unwrap();
+                assert!(frame.fin);
+                assert_eq!(frame.opcode, OP_BINARY);
+                assert_eq!(frame.payload, payload);
+                assert!(buf.is_empty());
+            }
+        }
+    }
+
+    #[test]
+    fn masking() {
+        // The examples from RFC 6455 section 5.7
+        let mut unmasked = vec![0x81, 0x05, 0x48, 0x65, 0x6c, 0x6c, 0x6f];
+        let frame = parse_frame(&mut unmasked, false, MAX_PAYLOAD)
+            .ok()
+            .unwrap()
+            .unwrap();
+        assert_eq!(frame.payload, b"Hello");
+
+        let masked = vec![
+            0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58,
+        ];
+        let frame = parse_frame(&mut masked.clone(), true, MAX_PAYLOAD)
+            .ok()
+            .unwrap()
+            .unwrap();
+        assert_eq!(frame.payload, b"Hello");
+
+        let from_server = "frames from servers must not be masked";
+        assert_eq!(
+            violation(masked, false),
+            (CLOSE_PROTOCOL_ERROR, from_server)
+        );
+        let from_client = "frames from clients must be masked";
+        let unmasked = vec![0x81, 0x05, 0x48, 0x65, 0x6c, 0x6c, 0x6f];
+        assert_eq!(
+            violation(unmasked, true),
+            (CLOSE_PROTOCOL_ERROR, from_client)
+        );
+    }
+
+    #[test]
+    fn partial_frames() {
+        let mut frames = encode_frame(false, OP_TEXT, b"first", true);
+        frames.extend(encode_frame(true, OP_CONTINUATION, &[7; 300], true));
+        frames.extend(encode_frame(true, OP_PING, b"", true));
+
+        // Nothing is taken until a frame is complete
+        let mut buf = vec![];
+        let mut parsed = vec![];
+        for b in frames {
+            buf.push(b);
+            let len = buf.len();
+            match parse_frame(&mut buf, true, MAX_PAYLOAD).ok().unwrap() {
+                Some(frame) => parsed.push((frame.fin, frame.opcode, frame.payload.len())),
+                None => assert_eq!(buf.len(), len),
+            }
+        }
+        assert!(buf.is_empty());
+        let expected = [
+            (false, OP_TEXT, 5),
+            (true, OP_CONTINUATION, 300),
+            (true, OP_PING, 0),
+        ];
+        assert_eq!(parsed, expected);
+    }
+
+    #[test]
+    fn invalid_frames() {
+        let mut reserved = encode_frame(true, OP_TEXT, b"a", false);
+        reserved[0] |= 0x40;
+        let mut long_ping = encode_frame(true, OP_PING, &[0; 125], false);
+        long_ping[1] = 126;
+        let cases = [
+            (reserved, "reserved bits are set"),
+            (encode_frame(true, 0x3, b"", false), "unknown opcode"),
+            (long_ping, "invalid control frame"),
+            (
+                encode_frame(false, OP_PING, b"", false),
+                "invalid control frame",
+            ),
+        ];
+        for (frame, msg) in cases {
+            assert_eq!(violation(frame, false), (CLOSE_PROTOCOL_ERROR, msg));
+        }
+
+        // We don't wait for the payload of a frame we won't accept
+        let too_big = encode_frame(true, OP_BINARY, &[0; 1001], false);
+        assert_eq!(
+            violation(too_big[..4].to_vec(), false),
+            (CLOSE_TOO_BIG, "message too big")
+        );
+    }
+
+    #[test]
+    fn handshake_keys() {
+        // The example from RFC 6455 section 1.3
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+        assert!(is_valid_key("dGhlIHNhbXBsZSBub25jZQ=="));
+        assert!(!is_valid_key("dGhlIHNhbXBsZSBub25jZQ"));
+        assert!(!is_valid_key("dGhlIHNhbXBsZSB!b25jZQ=="));
+        assert_eq!(base64(b"fo"), "Zm8=");
+    }
+
+    #[test]
+    fn echo_and_close_handshake() {
+        let server_events: Events = Default::default();
+        let client_events: Events = Default::default();
+
+        let opts = WebSocketServerOptions {
+            protocols: vec!["chat".to_string()],
+            ..Default::default()
+        };
+        let c = client_events.clone();
+        with_server(opts, echo(&server_events), move |url, _wss, done| {
+            let opts = WebSocketOptions {
+                protocols: vec!["superchat".to_string(), "chat".to_string()],
+                ..Default::default()
+            };
+            let ws = WebSocket::connect(&url, opts).unwrap();
+            assert_eq!(ws.ready_state(), ReadyState::Connecting);
+
+            let (w, e) = (ws.clone(), c.clone());
+            ws.on_open(move |_| {
+                e.borrow_mut().push(format!("open {}", w.protocol()));
+                w.send("hello").unwrap();
+                w.send(&[1u8, 2, 3][..]).unwrap();
+            });
+            let (w, e) = (ws.clone(), c.clone());
+            ws.on_message(move |msg| {
+                e.borrow_mut().push(format!("message {:?}", msg));
+                if let Js::Bytes(_) = msg {
+                    // Only codes meant for applications are allowed
+                    let invalid = w.close(CLOSE_GOING_AWAY, "").unwrap_err();
+                    assert_eq!(invalid.kind(), io::ErrorKind::InvalidInput);
+                    w.close(CLOSE_NORMAL, "bye").unwrap();
+                    assert_eq!(w.ready_state(), ReadyState::Closing);
+                    let closing = w.send("too late").unwrap_err();
+                    assert_eq!(closing.kind(), io::ErrorKind::NotConnected);
+                }
+            });
+            let (w, e) = (ws.clone(), c.clone());
+            ws.on_close(move |code, reason| {
+                e.borrow_mut().push(format!("close {} {:?}", code, reason));
+                assert_eq!(w.ready_state(), ReadyState::Closed);
+                done();
+            });
+        });
+
+        let expected = [
+            "connection /chat",
+            "message String(\"hello\")",
+            "message Bytes([1, 2, 3])",
+            "close 1000 \"bye\"",
+        ];
+        assert_eq!(*server_events.borrow(), expected);
+        // The server answers with the code alone
+        let expected = [
+            "open chat",
+            "message String(\"hello\")",
+            "message Bytes([1, 2, 3])",
+            "close 1000 \"\"",
+        ];
+        assert_eq!(*client_events.borrow(), expected);
+    }
+
+    #[test]
+    fn fragmented_messages() {
+        let server_events: Events = Default::default();
+        let client_events: Events = Default::default();
+
+        let opts = WebSocketServerOptions {
+            fragment_size: 2,
+            ..Default::default()
+        };
+        let c = client_events.clone();
+        with_server(opts, echo(&server_events), move |url, _wss, done| {
+            let opts = WebSocketOptions {
+                fragment_size: 3,
+                ..Default::default()
+            };
+            let ws = WebSocket::connect(&url, opts).unwrap();
+            let w = ws.clone();
+            ws.on_open(move |_| w.send("hello world").unwrap());
+            let (w, e) = (ws.clone(), c.clone());
+            ws.on_message(move |msg| {
+                e.borrow_mut().push(format!("message {:?}", msg));
+                w.close(CLOSE_NORMAL, "").unwrap();
+            });
+            ws.on_close(move |_, _| done());
+        });
+
+        assert_eq!(server_events.borrow()[1], "message String(\"hello world\")");
+        assert_eq!(*client_events.borrow(), ["message String(\"hello world\")"]);
+    }
+
+    #[test]
+    fn control_frames_between_fragments() {
+        let server_events: Events = Default::default();
+        let received = Rc::new(RefCell::new(vec![]));
+
+        let r = received.clone();
+        with_server(
+            Default::default(),
+            echo(&server_events),
+            move |url, _wss, done| {
+                let frames = vec![
+                    encode_frame(false, OP_TEXT, b"hel", true),
+                    encode_frame(true, OP_PING, b"are you there", true),
+                    encode_frame(false, OP_CONTINUATION, b"lo ", true),
+                    encode_frame(true, OP_PONG, b"heartbeat", true),
+                    encode_frame(true, OP_CONTINUATION, b"there", true),
+                    encode_frame(true, OP_CLOSE, &[], true),
+                ];
+                let r = r.clone();
+                let addr = url.trim_start_matches("ws://").trim_end_matches("/chat");
+                raw_client(
+                    addr.parse().unwrap(),
+                    frames,
+                    move |opcode, payload| r.borrow_mut().push((opcode, payload)),
+                    move || done(),
+                );
+            },
+        );
+
+        let expected = [
+            "connection /",
+            "ping Bytes([97, 114, 101, 32, 121, 111, 117, 32, 116, 104, 101, 114, 101])",
+            "pong Bytes([104, 101, 97, 114, 116, 98, 101, 97, 116])",
+            "message String(\"hello there\")",
+            "close 1005 \"\"",
+        ];
+        assert_eq!(*server_events.borrow(), expected);
+        // The ping is answered right away, and a close frame without a code
+        // gets one without a code
+        let expected = [
+            (OP_PONG, b"are you there".to_vec()),
+            (OP_TEXT, b"hello there".to_vec()),
+            (OP_CLOSE, vec![]),
+        ];
+        assert_eq!(*received.borrow(), expected);
+    }
+
+    #[test]
+    fn violations_close_the_connection() {
+        let mut reserved = encode_frame(true, OP_CLOSE, &[3, 232], true);
+        reserved[0] |= 0x20;
+        let cases = [
+            (
+                encode_frame(true, OP_TEXT, &[0xff], true),
+                CLOSE_INVALID_DATA,
+            ),
+            (
+                encode_frame(true, OP_TEXT, b"a", false),
+                CLOSE_PROTOCOL_ERROR,
+            ),
+            (reserved, CLOSE_PROTOCOL_ERROR),
+            (
+                encode_frame(true, OP_CONTINUATION, b"a", true),
+                CLOSE_PROTOCOL_ERROR,
+            ),
+            (
+                encode_frame(true, OP_CLOSE, &[3], true),
+                CLOSE_PROTOCOL_ERROR,
+            ),
+            (
+                encode_frame(true, OP_CLOSE, &close_payload(999, ""), true),
+                CLOSE_PROTOCOL_ERROR,
+            ),
+            (
+                encode_frame(true, OP_CLOSE, &close_payload(1000, "\u{ff}")[..3], true),
+                CLOSE_INVALID_DATA,
+            ),
+            (encode_frame(true, OP_BINARY, &[0; 11], true), CLOSE_TOO_BIG),
+        ];
+
+        for (frame, code) in cases {
+            let server_events: Events = Default::default();
+            let received = Rc::new(RefCell::new(vec![]));
+
+            let opts = WebSocketServerOptions {
+                max_payload: 10,
+                ..Default::default()
+            };
+            let r = received.clone();
+            with_server(opts, echo(&server_events), move |url, _wss, done| {
+                let r = r.clone();
+                let addr = url.trim_start_matches("ws://").trim_end_matches("/chat");
+                raw_client(
+                    addr.parse().unwrap(),
+                    vec![frame.clone()],
+                    move |opcode, payload| r.borrow_mut().push((opcode, payload)),
+                    move || done(),
+                );
+            });
+
+            let events = server_events.borrow();
+            assert!(events[1].starts_with("error "), "{:?}", events);
+            assert!(
+                events[2].starts_with(&format!("close {} ", code)),
+                "{:?}",
+                events
+            );
+            let received = received.borrow();
+            assert_eq!(received.len(), 1);
+            assert_eq!(received[0].0, OP_CLOSE);
+            assert_eq!(received[0].1[..2], code.to_be_bytes());
+        }
+    }
+
+    #[test]
+    fn ping_pong() {
+        let server_events: Events = Default::default();
+        let client_events: Events = Default::default();
+
+        let c = client_events.clone();
+        with_server(
+            Default::default(),
+            echo(&server_events),
+            move |url, _wss, done| {
+                let ws = WebSocket::connect(&url, Default::default()).unwrap();
+                let w = ws.clone();
+                ws.on_open(move |_| {
+                    w.ping("x").unwrap();
+                    w.pong("heartbeat").unwrap();
+                    let too_long = w.ping(vec![0; 126]).unwrap_err();
+                    assert_eq!(too_long.kind(), io::ErrorKind::InvalidInput);
+                });
+                let (w, e) = (ws.clone(), c.clone());
+                ws.on_pong(move |data| {
+                    e.borrow_mut().push(format!("pong {:?}", data));
+                    w.close(CLOSE_NORMAL, "").unwrap();
+                });
+                ws.on_close(move |_, _| done());
+            },
+        );
+
+        assert_eq!(server_events.borrow()[1], "ping Bytes([120])");
+        let heartbeat = "pong Bytes([104, 101, 97, 114, 116, 98, 101, 97, 116])";
+        assert_eq!(server_events.borrow()[2], heartbeat);
+        assert_eq!(*client_events.borrow(), ["pong Bytes([120])"]);
+    }
+
+    #[test]
+    fn broadcast_and_server_close() {
+        let client_events: Events = Default::default();
+
+        let server = Rc::new(RefCell::new(None));
+
+        let (c, s) = (client_events.clone(), server.clone());
+        let on_connection = |_ws: WebSocket, _req: IncomingRequest| ();
+        with_server(Default::default(), on_connection, move |url, wss, done| {
+            let open = Rc::new(RefCell::new(0));
+            let closed = Rc::new(RefCell::new(0));
+            for i in 0..2 {
+                let ws = WebSocket::connect(&url, Default::default()).unwrap();
+                let (server, open) = (wss.clone(), open.clone());
+                ws.on_open(move |_| {
+                    *open.borrow_mut() += 1;
+                    if *open.borrow() == 2 {
+                        assert_eq!(server.clients().len(), 2);
+                        server.broadcast("news");
+                    }
+                });
+                let (server, e) = (wss.clone(), c.clone());
+                ws.on_message(move |msg| {
+                    e.borrow_mut().push(format!("{} message {:?}", i, msg));
+                    if e.borrow().len() == 2 {
+                        server.close();
+                    }
+                });
+                let (server, e, closed) = (s.clone(), c.clone(), closed.clone());
+                let wss = wss.clone();
+                let done = done.clone();
+                ws.on_close(move |code, reason| {
+                    e.borrow_mut().push(format!("close {} {}", code, reason));
+                    *closed.borrow_mut() += 1;
+                    if *closed.borrow() == 2 {
+                        *server.borrow_mut() = Some(wss.clone());
+                        done();
+                    }
+                });
+            }
+        });
+
+        let mut events = client_events.borrow().clone();
+        events.sort();
+        let expected = [
+            "0 message String(\"news\")",
+            "1 message String(\"news\")",
+            "close 1001 server shutting down",
+            "close 1001 server shutting down",
+        ];
+        assert_eq!(events, expected);
+        let server: WebSocketServer = server.borrow_mut().take().unwrap();
+        assert!(server.clients().is_empty());
+    }
+
+    #[test]
+    fn terminate_is_abnormal() {
+        let server_events: Events = Default::default();
+        let client_events: Events = Default::default();
+
+        let c = client_events.clone();
+        with_server(
+            Default::default(),
+            echo(&server_events),
+            move |url, _wss, done| {
+                let ws = WebSocket::connect(&url, Default::default()).unwrap();
+                let w = ws.clone();
+                ws.on_open(move |_| w.terminate());
+                let e = c.clone();
+                ws.on_close(move |code, _| {
+                    e.borrow_mut().push(format!("close {}", code));
+                    done();
+                });
+            },
+        );
+
+        assert_eq!(*client_events.borrow(), ["close 1006"]);
+        assert_eq!(server_events.borrow()[1], "close 1006 \"\"");
+    }
+}